@@ -9,16 +9,18 @@ pub fn ips_parse(ips: &Vec<String>) -> Result<Vec<(u32, u32, Ipv4Addr)>, String>
         } else {
             return Err(format!("ipv4/mask,ipv4 {:?}", x));
         };
-        let ip = if let Some(ip) = split.next() {
-            ip
-        } else {
+        // 可以指定多个网关，按顺序作为优先级
+        let mut gateways = vec![];
+        for ip in split {
+            if let Ok(ip) = ip.parse::<Ipv4Addr>() {
+                gateways.push(ip);
+            } else {
+                return Err(format!("not ipv4 {:?}", ip));
+            }
+        }
+        if gateways.is_empty() {
             return Err(format!("ipv4/mask,ipv4 {:?}", x));
-        };
-        let ip = if let Ok(ip) = ip.parse::<Ipv4Addr>() {
-            ip
-        } else {
-            return Err(format!("not ipv4 {:?}", ip));
-        };
+        }
         let mut split = net.split("/");
         let dest = if let Some(dest) = split.next() {
             dest
@@ -36,7 +38,9 @@ pub fn ips_parse(ips: &Vec<String>) -> Result<Vec<(u32, u32, Ipv4Addr)>, String>
             return Err(format!("not ipv4 {:?}", dest));
         };
         let mask = to_ip(mask)?;
-        in_ips_c.push((u32::from_be_bytes(dest.octets()), mask, ip));
+        for ip in gateways {
+            in_ips_c.push((u32::from_be_bytes(dest.octets()), mask, ip));
+        }
     }
     Ok(in_ips_c)
 }
@@ -1,7 +1,9 @@
 use std::process;
 
 use console::style;
-use vnt::{ConnectInfo, ErrorInfo, ErrorType, HandshakeInfo, RegisterInfo, VntCallback};
+use vnt::{
+    ConnectInfo, ErrorInfo, ErrorType, HandshakeInfo, NetworkChangeInfo, RegisterInfo, VntCallback,
+};
 
 #[derive(Clone)]
 pub struct VntHandler {}
@@ -29,6 +31,10 @@ impl VntCallback for VntHandler {
         true
     }
 
+    fn network_change(&self, info: NetworkChangeInfo) {
+        println!("network change {}", style(info).yellow())
+    }
+
     fn error(&self, info: ErrorInfo) {
         log::error!("error {:?}", info);
         println!("{}", style(format!("error {}", info)).red());
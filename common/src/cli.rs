@@ -53,6 +53,8 @@ pub fn parse_args_config() -> anyhow::Result<Option<(Config, Vec<String>, bool)>
     opts.optmulti("e", "", "stun服务器", "<stun-server>");
     opts.optflag("a", "", "使用tap模式");
     opts.optopt("", "nic", "虚拟网卡名称,windows下使用tap则必填", "<tun0>");
+    opts.optopt("", "tun-queues", "tun多队列数", "<n>");
+    opts.optflag("", "tun-offload", "tun开启TSO/GRO");
     opts.optmulti("i", "", "配置点对网(IP代理)入站时使用", "<in-ip>");
     opts.optmulti("o", "", "配置点对网出站时使用", "<out-ip>");
     opts.optopt("w", "", "客户端加密", "<password>");
@@ -79,6 +81,21 @@ pub fn parse_args_config() -> anyhow::Result<Option<(Config, Vec<String>, bool)>
     opts.optopt("", "local-dev", "指定本地ipv4网卡名称", "<NAME>");
     opts.optflag("", "disable-stats", "关闭流量统计");
     opts.optflag("", "allow-wg", "允许接入WireGuard");
+    opts.optopt("", "runtime-threads", "异步运行时线程数", "<n>");
+    opts.optflag("", "lan-discovery", "局域网发现");
+    opts.optmulti("", "accept-route", "接受网关通告的路由", "<cidr>");
+    opts.optflag("", "gateway-balance", "多个网关负载分担");
+    opts.optopt("", "exit-node", "出口节点", "<ip>");
+    opts.optmulti("", "exit-dns", "使用出口节点时的dns", "<ip>");
+    opts.optflag("", "allow-exit", "允许作为出口节点");
+    opts.optflag("", "masquerade", "使用内置nat");
+    opts.optopt("", "overlay-dns", "虚拟网络dns域名后缀", "<suffix>");
+    opts.optflag(
+        "",
+        "overlay-dns-resolved",
+        "通过systemd-resolved设置虚拟网络dns",
+    );
+    opts.optmulti("", "turn", "TURN服务器", "<user:password@host:port>");
     //"后台运行时,查看其他设备列表"
     opts.optflag("", "add", "后台运行时,添加地址");
     opts.optflag("", "list", "后台运行时,查看其他设备列表");
@@ -143,6 +160,18 @@ pub fn parse_args_config() -> anyhow::Result<Option<(Config, Vec<String>, bool)>
         }
         #[cfg(feature = "integrated_tun")]
         let device_name = matches.opt_str("nic");
+        #[cfg(feature = "integrated_tun")]
+        let tun_queues = match matches.opt_get::<usize>("tun-queues") {
+            Ok(tun_queues) => tun_queues.unwrap_or(1),
+            Err(e) => {
+                print_usage(&program, opts);
+                println!();
+                println!("--tun-queues: {}", e);
+                return Err(anyhow::anyhow!("example: --tun-queues 4"));
+            }
+        };
+        #[cfg(feature = "integrated_tun")]
+        let tun_offload = matches.opt_present("tun-offload");
         let token: String = matches.opt_get("k").unwrap().unwrap();
         let device_id = matches.opt_get_default("d", String::new()).unwrap();
         let device_id = if device_id.is_empty() {
@@ -285,6 +314,56 @@ pub fn parse_args_config() -> anyhow::Result<Option<(Config, Vec<String>, bool)>
 
         let disable_stats = matches.opt_present("disable-stats");
         let allow_wire_guard = matches.opt_present("allow-wg");
+        let runtime_threads = match matches.opt_get::<usize>("runtime-threads") {
+            Ok(runtime_threads) => runtime_threads.unwrap_or(0),
+            Err(e) => {
+                print_usage(&program, opts);
+                println!();
+                println!("--runtime-threads: {}", e);
+                return Err(anyhow::anyhow!("example: --runtime-threads 2"));
+            }
+        };
+        let lan_discovery = matches.opt_present("lan-discovery");
+        let gateway_balance = matches.opt_present("gateway-balance");
+        let exit_node = match matches.opt_get::<Ipv4Addr>("exit-node") {
+            Ok(exit_node) => exit_node,
+            Err(e) => {
+                print_usage(&program, opts);
+                println!();
+                println!("--exit-node: {}", e);
+                return Err(anyhow::anyhow!("example: --exit-node 10.26.0.2"));
+            }
+        };
+        let exit_dns = match matches
+            .opt_strs("exit-dns")
+            .iter()
+            .map(|v| Ipv4Addr::from_str(v))
+            .collect::<Result<Vec<_>, _>>()
+        {
+            Ok(exit_dns) => exit_dns,
+            Err(e) => {
+                print_usage(&program, opts);
+                println!();
+                println!("--exit-dns: {:?}", e);
+                return Err(anyhow::anyhow!("example: --exit-dns 1.1.1.1"));
+            }
+        };
+        let allow_exit = matches.opt_present("allow-exit");
+        let masquerade = matches.opt_present("masquerade");
+        let overlay_dns = matches.opt_str("overlay-dns");
+        let overlay_dns_resolved = matches.opt_present("overlay-dns-resolved");
+        let accept_route = matches.opt_strs("accept-route");
+        let accept_routes = match out_ips_parse(&accept_route) {
+            Ok(accept_routes) => accept_routes,
+            Err(e) => {
+                print_usage(&program, opts);
+                println!();
+                println!("--accept-route: {:?} {}", accept_route, e);
+                return Err(anyhow::anyhow!("example: --accept-route 192.168.0.0/16"));
+            }
+        };
+        #[cfg(feature = "turn")]
+        let turn_server = matches.opt_strs("turn");
         let compressor = if let Some(compressor) = matches.opt_str("compressor").as_ref() {
             Compressor::from_str(compressor)
                 .map_err(|e| anyhow!("{}", e))
@@ -318,6 +397,10 @@ pub fn parse_args_config() -> anyhow::Result<Option<(Config, Vec<String>, bool)>
             first_latency,
             #[cfg(feature = "integrated_tun")]
             device_name,
+            #[cfg(feature = "integrated_tun")]
+            tun_queues,
+            #[cfg(feature = "integrated_tun")]
+            tun_offload,
             use_channel_type,
             packet_loss,
             packet_delay,
@@ -327,6 +410,21 @@ pub fn parse_args_config() -> anyhow::Result<Option<(Config, Vec<String>, bool)>
             !disable_stats,
             allow_wire_guard,
             local_dev,
+            runtime_threads,
+            lan_discovery,
+            accept_routes,
+            gateway_balance,
+            exit_node,
+            exit_dns,
+            allow_exit,
+            masquerade,
+            overlay_dns,
+            overlay_dns_resolved,
+            vec![],
+            None,
+            #[cfg(feature = "turn")]
+            turn_server,
+            app_home().ok(),
         )?;
         (config, vnt_mapping_list, cmd)
     };
@@ -348,7 +446,7 @@ fn get_description(key: &str, language: &str) -> String {
         ("-d <id>", ("设备唯一标识符,不使用--ip参数时,服务端凭此参数分配虚拟ip,注意不能重复", "Device unique identifier, used by the server to allocate virtual IP when --ip parameter is not used, must be unique")),
         ("-s <server>", ("注册和中继服务器地址,协议支持使用tcp://和ws://和wss://,默认为udp://", "Registration and relay server address, protocols support using tcp://, ws://, and wss://, default is udp://")),
         ("-e <stun-server>", ("stun服务器,用于探测NAT类型,可使用多个地址,如-e stun.miwifi.com -e turn.cloudflare.com", "STUN server for detecting NAT type, can specify multiple addresses, e.g., -e stun.miwifi.com -e turn.cloudflare.com")),
-        ("-i <in-ip>", ("配置点对网(IP代理)时使用,-i 192.168.0.0/24,10.26.0.3表示允许接收网段192.168.0.0/24的数据并转发到10.26.0.3,可指定多个网段", "Used when configuring point-to-point network (IP proxy), -i 192.168.0.0/24,10.26.0.3 allows receiving data from subnet 192.168.0.0/24 and forwarding to 10.26.0.3, specify multiple subnets")),
+        ("-i <in-ip>", ("配置点对网(IP代理)时使用,-i 192.168.0.0/24,10.26.0.3表示允许接收网段192.168.0.0/24的数据并转发到10.26.0.3,可指定多个网段,-i 192.168.0.0/24,10.26.0.3,10.26.0.4表示10.26.0.3不可用时切换到备用网关10.26.0.4", "Used when configuring point-to-point network (IP proxy), -i 192.168.0.0/24,10.26.0.3 allows receiving data from subnet 192.168.0.0/24 and forwarding to 10.26.0.3, specify multiple subnets, -i 192.168.0.0/24,10.26.0.3,10.26.0.4 fails over to the backup gateway 10.26.0.4 when 10.26.0.3 is unavailable")),
         ("-o <out-ip>", ("配置点对网时使用,-o 192.168.0.0/24表示允许将数据转发到192.168.0.0/24,可指定多个网段", "Used when configuring point-to-point network, -o 192.168.0.0/24 allows forwarding data to 192.168.0.0/24, specify multiple subnets")),
         ("-w <password>", ("使用该密码生成的密钥对客户端数据进行加密,并且服务端无法解密,使用相同密码的客户端才能通信", "Encrypt client data with keys generated by this password, server cannot decrypt, clients must use the same password to communicate")),
         ("-W", ("加密当前客户端和服务端通信的数据,请留意服务端指纹是否正确", "Encrypt the data currently being communicated between the client and server, please pay attention to whether the server fingerprint is correct")),
@@ -364,6 +462,8 @@ fn get_description(key: &str, language: &str) -> String {
         ("--first-latency", ("优先低延迟的通道,默认情况优先使用p2p通道", "Prioritize low-latency channels, defaults to prioritizing p2p channel")),
         ("--use-channel <p2p>", ("使用通道 relay/p2p/all,默认两者都使用", "Use channel relay/p2p/all, defaults to using both")),
         ("--nic <tun0>", ("指定虚拟网卡名称", "Specify virtual network card name")),
+        ("--tun-offload", ("tun开启virtio-net头部(TSO/GRO),仅linux有效,可提升大流量tcp的吞吐", "Enable virtio-net header offload (TSO/GRO) on the tun (Linux only), improves throughput of large TCP transfers")),
+        ("--tun-queues <n>", ("tun多队列数,仅linux有效,每个队列一个线程并行处理数据,默认1", "Number of tun queues (Linux only), each queue is processed by its own thread, default 1")),
         ("--packet-loss <0>", ("模拟丢包,取值0~1之间的小数,程序会按设定的概率主动丢包,可用于模拟弱网", "Simulate packet loss, value between 0 and 1, program actively drops packets based on set probability, useful for simulating weak networks")),
         ("--packet-delay <0>", ("模拟延迟,正整数,单位毫秒,程序将根据设定值延迟发送数据包,可用于模拟弱网", "Simulate latency, integer, in milliseconds (ms). The program will delay sending packets according to the set value and can be used to simulate weak networks")),
         ("--dns <host:port>", ("DNS服务器地址,可使用多个dns,不指定时使用系统解析", "DNS server address, can specify multiple DNS servers, defaults to system resolution if not specified")),
@@ -374,6 +474,17 @@ fn get_description(key: &str, language: &str) -> String {
         ("--vnt-mapping <x>", ("vnt地址映射,例如 --vnt-mapping tcp:80-10.26.0.10:80 映射目标是vnt网络或其子网中的设备", "VNT address mapping, e.g., --vnt-mapping tcp:80-10.26.0.10:80 maps to a device in VNT network or its subnet")),
         ("--local-dev", ("本地出口网卡的名称", "name of local export network card")),
         ("--disable-stats", ("关闭流量统计", "Disable traffic statistics")),
+        ("--runtime-threads <n>", ("共用异步运行时的工作线程数,默认自动(最多2个)", "Worker threads of the shared async runtime, default auto (at most 2)")),
+        ("--accept-route <cidr>", ("接受其他网关通告的在此范围内的网段,自动添加路由,可多个,0.0.0.0/0表示全部接受", "Accept subnets advertised by other gateways that fall within this range and add routes automatically, repeatable, 0.0.0.0/0 accepts all")),
+        ("--gateway-balance", ("-i同一网段配置了多个网关时,按连接在可用的网关间分担流量,默认只使用优先级最高的可用网关", "When -i lists several gateways for the same subnet, share traffic across healthy gateways per flow instead of using only the highest priority one")),
+        ("--exit-node <ip>", ("使用出口节点,所有访问互联网的ipv4流量经过该节点,对端需要开启--allow-exit,ipv6流量不受影响,不支持macos", "Route all internet IPv4 traffic through this peer, the peer must enable --allow-exit. IPv6 traffic is not covered. Not supported on macOS")),
+        ("--exit-dns <ip>", ("使用出口节点时网卡上设置的dns,防止dns泄露,默认1.1.1.1和8.8.8.8", "DNS servers set on the tun when using an exit node to prevent DNS leaks, default 1.1.1.1 and 8.8.8.8")),
+        ("--allow-exit", ("允许其他设备把本机作为出口节点,等同于-o 0.0.0.0/0,关闭内置代理时在linux上使用内核nat", "Allow other devices to use this device as exit node, same as -o 0.0.0.0/0, uses kernel NAT on linux when the built-in proxy is disabled")),
+        ("--masquerade", ("使用内置的用户态nat转发-o网段的tcp、udp和icmp,不需要开启内核转发和iptables,仅linux有效,需要root", "Forward TCP, UDP and ICMP to -o subnets with the built-in user-space NAT, no kernel forwarding or iptables needed. Linux only, requires root")),
+        ("--overlay-dns <suffix>", ("在本机虚拟ip的53端口提供dns,把<设备名>.<后缀>解析成虚拟ip,支持反向解析,其他域名转发到上游", "Serve DNS on the virtual IP port 53, resolving <name>.<suffix> to virtual IPs with PTR records, other names are forwarded upstream")),
+        ("--overlay-dns-resolved", ("通过systemd-resolved把虚拟网络dns设置为网卡的dns,仅linux有效", "Register the overlay DNS as the per-interface resolver via systemd-resolved, Linux only")),
+        ("--lan-discovery", ("在局域网内广播信标,同网段的设备不经过服务器直接连接,服务器断开时仍可通信", "Broadcast beacons on the LAN so devices on the same segment connect directly without the server, and keep working while it is down")),
+        ("--turn <x>", ("标准TURN服务器,格式 user:password@host:port,无法打洞时经由该服务器中转", "Standard TURN server, format user:password@host:port, relays traffic when hole punching fails")),
         ("--allow-wg", ("允许接入WireGuard客户端", "Allow access to WireGuard client")),
         ("--list", ("后台运行时,查看其他设备列表", "View list of other devices when running in background")),
         ("--all", ("后台运行时,查看其他设备完整信息", "View complete information of other devices when running in background")),
@@ -516,6 +627,18 @@ fn print_usage(program: &str, _opts: Options) {
         "  --nic <tun0>        {}",
         get_description("--nic <tun0>", &language)
     );
+    #[cfg(feature = "integrated_tun")]
+    #[cfg(target_os = "linux")]
+    println!(
+        "  --tun-queues <n>    {}",
+        get_description("--tun-queues <n>", &language)
+    );
+    #[cfg(feature = "integrated_tun")]
+    #[cfg(target_os = "linux")]
+    println!(
+        "  --tun-offload       {}",
+        get_description("--tun-offload", &language)
+    );
     println!(
         "  --packet-loss <0>   {}",
         get_description("--packet-loss <0>", &language)
@@ -570,6 +693,51 @@ fn print_usage(program: &str, _opts: Options) {
         "  --allow-wg          {}",
         get_description("--allow-wg", &language)
     );
+    println!(
+        "  --runtime-threads <n> {}",
+        get_description("--runtime-threads <n>", &language)
+    );
+    println!(
+        "  --lan-discovery     {}",
+        get_description("--lan-discovery", &language)
+    );
+    println!(
+        "  --accept-route <cidr> {}",
+        get_description("--accept-route <cidr>", &language)
+    );
+    println!(
+        "  --gateway-balance   {}",
+        get_description("--gateway-balance", &language)
+    );
+    println!(
+        "  --exit-node <ip>    {}",
+        get_description("--exit-node <ip>", &language)
+    );
+    println!(
+        "  --exit-dns <ip>     {}",
+        get_description("--exit-dns <ip>", &language)
+    );
+    println!(
+        "  --allow-exit        {}",
+        get_description("--allow-exit", &language)
+    );
+    println!(
+        "  --masquerade        {}",
+        get_description("--masquerade", &language)
+    );
+    println!(
+        "  --overlay-dns <suffix> {}",
+        get_description("--overlay-dns <suffix>", &language)
+    );
+    println!(
+        "  --overlay-dns-resolved {}",
+        get_description("--overlay-dns-resolved", &language)
+    );
+    #[cfg(feature = "turn")]
+    println!(
+        "  --turn <x>          {}",
+        get_description("--turn <x>", &language)
+    );
     println!();
     #[cfg(feature = "command")]
     {
@@ -11,6 +11,29 @@ pub struct Info {
     pub connect_status: String,
     pub relay_server: String,
     pub nat_type: String,
+    // RFC 5780 nat行为
+    #[serde(default)]
+    pub nat_mapping: String,
+    #[serde(default)]
+    pub nat_filtering: String,
+    #[serde(default)]
+    pub hairpin: String,
+    #[serde(default)]
+    pub binding_lifetime: String,
+    // 对称网络的端口分配模型
+    #[serde(default)]
+    pub port_model: String,
+    #[serde(default)]
+    pub punch_stats: Vec<PunchStatItem>,
+    // 网关端口映射(UPnP、NAT-PMP、PCP)
+    #[serde(default)]
+    pub gateway_mappings: Vec<GatewayMappingItem>,
+    // TURN服务器分配的中继地址
+    #[serde(default)]
+    pub turn_relayed: String,
+    // 设备公钥，静态对端认证本机时使用
+    #[serde(default)]
+    pub public_key: String,
     pub public_ips: String,
     pub local_addr: String,
     pub ipv6_addr: String,
@@ -21,6 +44,23 @@ pub struct Info {
     pub tcp_listen_addr: String,
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PunchStatItem {
+    pub model: String,
+    pub attempts: u64,
+    pub success: u64,
+    pub predicted_hits: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GatewayMappingItem {
+    pub source: String,
+    pub protocol: String,
+    pub port: u16,
+    pub external: String,
+    pub status: String,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct RouteItem {
     pub destination: String,
@@ -55,6 +95,13 @@ pub struct ChartA {
     pub down_total: u64,
     pub up_map: HashMap<Ipv4Addr, u64>,
     pub down_map: HashMap<Ipv4Addr, u64>,
+    // 发送缓冲区池统计，alloc不再增长说明发送路径不再分配内存
+    #[serde(default)]
+    pub pool_alloc: u64,
+    #[serde(default)]
+    pub pool_reuse: u64,
+    #[serde(default)]
+    pub pool_oversize: u64,
 }
 
 #[derive(Serialize, Deserialize, Debug, Default)]
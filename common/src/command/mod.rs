@@ -4,7 +4,9 @@ use std::net::Ipv4Addr;
 use vnt::channel::ConnectProtocol;
 use vnt::core::Vnt;
 
-use crate::command::entity::{ChartA, ChartB, DeviceItem, Info, RouteItem};
+use crate::command::entity::{
+    ChartA, ChartB, DeviceItem, GatewayMappingItem, Info, PunchStatItem, RouteItem,
+};
 use crate::console_out;
 
 pub mod client;
@@ -127,6 +129,9 @@ pub fn command_route(vnt: &Vnt) -> Vec<RouteItem> {
                     format!("tcp@{}", route.addr)
                 }
                 ConnectProtocol::WS | ConnectProtocol::WSS => server_addr.clone(),
+                ConnectProtocol::TURN => {
+                    format!("turn@{}", route.addr)
+                }
             };
 
             let item = RouteItem {
@@ -241,6 +246,51 @@ pub fn command_info(vnt: &Vnt) -> Info {
         current_device.connect_server.to_string()
     };
     let nat_type = format!("{:?}", nat_info.nat_type);
+    let nat_mapping = format!("{:?}", nat_info.mapping);
+    let nat_filtering = format!("{:?}", nat_info.filtering);
+    let hairpin = match nat_info.hairpin {
+        Some(hairpin) => hairpin.to_string(),
+        None => "Unknown".to_string(),
+    };
+    let binding_lifetime = if nat_info.binding_lifetime == 0 {
+        "Unknown".to_string()
+    } else {
+        format!(">={}s", nat_info.binding_lifetime)
+    };
+    let port_model = match nat_info.port_model.delta() {
+        Some(delta) => format!("{}({:+})", nat_info.port_model.name(), delta),
+        None => nat_info.port_model.name().to_string(),
+    };
+    let punch_stats = vnt
+        .punch_stats()
+        .into_iter()
+        .map(|v| PunchStatItem {
+            model: v.model.to_string(),
+            attempts: v.attempts,
+            success: v.success,
+            predicted_hits: v.predicted_hits,
+        })
+        .collect();
+    let gateway_mappings = vnt
+        .gateway_mappings()
+        .into_iter()
+        .map(|v| GatewayMappingItem {
+            source: v.source.to_string(),
+            protocol: if v.tcp { "TCP" } else { "UDP" }.to_string(),
+            port: v.port,
+            external: v
+                .external
+                .map(|addr| addr.to_string())
+                .unwrap_or("None".to_string()),
+            status: match v.error {
+                Some(e) => e,
+                None if v.verified => "Ok".to_string(),
+                None => "Unverified".to_string(),
+            },
+        })
+        .collect();
+    let turn_relayed: Vec<String> = nat_info.relayed.iter().map(|v| v.to_string()).collect();
+    let turn_relayed = turn_relayed.join(",");
     let public_ips: Vec<String> = nat_info.public_ips.iter().map(|v| v.to_string()).collect();
     let public_ips = public_ips.join(",");
     let local_addr = nat_info
@@ -271,6 +321,15 @@ pub fn command_info(vnt: &Vnt) -> Info {
         connect_status,
         relay_server,
         nat_type,
+        nat_mapping,
+        nat_filtering,
+        hairpin,
+        binding_lifetime,
+        port_model,
+        punch_stats,
+        gateway_mappings,
+        turn_relayed,
+        public_key: vnt.public_key(),
         public_ips,
         local_addr,
         ipv6_addr,
@@ -291,12 +350,16 @@ pub fn command_chart_a(vnt: &Vnt) -> ChartA {
     }
     let (up_total, up_map) = vnt.up_stream_all().unwrap_or_default();
     let (down_total, down_map) = vnt.down_stream_all().unwrap_or_default();
+    let pool_stats = vnt.buffer_pool_stats().unwrap_or_default();
     ChartA {
         disable_stats,
         up_total,
         down_total,
         up_map,
         down_map,
+        pool_alloc: pool_stats.alloc,
+        pool_reuse: pool_stats.reuse,
+        pool_oversize: pool_stats.oversize,
     }
 }
 
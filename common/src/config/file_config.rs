@@ -1,5 +1,5 @@
 use anyhow::anyhow;
-use std::net::Ipv4Addr;
+use std::net::{Ipv4Addr, ToSocketAddrs};
 use std::str::FromStr;
 
 use crate::config::get_device_id;
@@ -7,9 +7,9 @@ use crate::{args_parse, config};
 use serde::{Deserialize, Serialize};
 use vnt::channel::punch::PunchModel;
 use vnt::channel::UseChannelType;
-use vnt::cipher::CipherModel;
+use vnt::cipher::{decode_public_key, CipherModel};
 use vnt::compression::Compressor;
-use vnt::core::Config;
+use vnt::core::{Config, StaticPeer};
 
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(default)]
@@ -39,6 +39,8 @@ pub struct FileConfig {
     pub cmd: bool,
     pub first_latency: bool,
     pub device_name: Option<String>,
+    pub tun_queues: usize,
+    pub tun_offload: bool,
     pub packet_loss: Option<f64>,
     pub packet_delay: u32,
     #[cfg(feature = "port_mapping")]
@@ -49,6 +51,35 @@ pub struct FileConfig {
     // 允许传递wg流量
     pub allow_wire_guard: bool,
     pub local_dev: Option<String>,
+    pub runtime_threads: usize,
+    pub lan_discovery: bool,
+    // 接受网关通告的路由
+    pub accept_routes: Vec<String>,
+    pub gateway_balance: bool,
+    // 出口节点
+    pub exit_node: Option<String>,
+    pub exit_dns: Vec<String>,
+    pub allow_exit: bool,
+    // 内置nat
+    pub masquerade: bool,
+    // 虚拟网络dns
+    pub overlay_dns: Option<String>,
+    pub overlay_dns_resolved: bool,
+    // 静态对端，配置后不连接服务器
+    pub static_peers: Vec<StaticPeerConfig>,
+    pub static_netmask: Option<String>,
+    #[cfg(feature = "turn")]
+    pub turn_server: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct StaticPeerConfig {
+    pub ip: String,
+    // 例如 [1.2.3.4:29872,[2001:db8::1]:29872]
+    pub endpoints: Vec<String>,
+    // 对端的设备公钥(十六进制)，可选，启动时会打印本机公钥
+    pub public_key: Option<String>,
 }
 
 impl Default for FileConfig {
@@ -86,6 +117,8 @@ impl Default for FileConfig {
             cmd: false,
             first_latency: false,
             device_name: None,
+            tun_queues: 1,
+            tun_offload: false,
             packet_loss: None,
             packet_delay: 0,
             #[cfg(feature = "port_mapping")]
@@ -95,6 +128,20 @@ impl Default for FileConfig {
             disable_stats: false,
             allow_wire_guard: false,
             local_dev: None,
+            runtime_threads: 0,
+            lan_discovery: false,
+            accept_routes: vec![],
+            gateway_balance: false,
+            exit_node: None,
+            exit_dns: vec![],
+            allow_exit: false,
+            masquerade: false,
+            overlay_dns: None,
+            overlay_dns_resolved: false,
+            static_peers: vec![],
+            static_netmask: None,
+            #[cfg(feature = "turn")]
+            turn_server: vec![],
         }
     }
 }
@@ -124,10 +171,39 @@ pub fn read_config(file_path: &str) -> anyhow::Result<(Config, Vec<String>, bool
             return Err(anyhow!("out_ips {:?} error:{}", &file_conf.out_ips, e));
         }
     };
+    let accept_routes = match args_parse::out_ips_parse(&file_conf.accept_routes) {
+        Ok(accept_routes) => accept_routes,
+        Err(e) => {
+            return Err(anyhow!(
+                "accept_routes {:?} error:{}",
+                &file_conf.accept_routes,
+                e
+            ));
+        }
+    };
+    let exit_node = match file_conf.exit_node.clone().map(|v| Ipv4Addr::from_str(&v)) {
+        None => None,
+        Some(r) => {
+            Some(r.map_err(|e| anyhow!("exit_node {:?} error:{}", &file_conf.exit_node, e))?)
+        }
+    };
+    let exit_dns = file_conf
+        .exit_dns
+        .iter()
+        .map(|v| Ipv4Addr::from_str(v))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| anyhow!("exit_dns {:?} error:{}", &file_conf.exit_dns, e))?;
     let virtual_ip = match file_conf.ip.clone().map(|v| Ipv4Addr::from_str(&v)) {
         None => None,
         Some(r) => Some(r.map_err(|e| anyhow!("ip {:?} error:{}", &file_conf.ip, e))?),
     };
+    let static_peers = static_peers(&file_conf.static_peers)?;
+    let static_netmask = match file_conf.static_netmask.as_ref() {
+        None => None,
+        Some(v) => {
+            Some(Ipv4Addr::from_str(v).map_err(|e| anyhow!("static_netmask {:?} error:{}", v, e))?)
+        }
+    };
     let cipher_model = if let Some(v) = file_conf.cipher_model {
         CipherModel::from_str(&v).map_err(|e| anyhow!("{}", e))?
     } else {
@@ -175,6 +251,10 @@ pub fn read_config(file_path: &str) -> anyhow::Result<(Config, Vec<String>, bool
         file_conf.first_latency,
         #[cfg(feature = "integrated_tun")]
         file_conf.device_name,
+        #[cfg(feature = "integrated_tun")]
+        file_conf.tun_queues,
+        #[cfg(feature = "integrated_tun")]
+        file_conf.tun_offload,
         use_channel_type,
         file_conf.packet_loss,
         file_conf.packet_delay,
@@ -184,7 +264,77 @@ pub fn read_config(file_path: &str) -> anyhow::Result<(Config, Vec<String>, bool
         !file_conf.disable_stats,
         file_conf.allow_wire_guard,
         file_conf.local_dev,
+        file_conf.runtime_threads,
+        file_conf.lan_discovery,
+        accept_routes,
+        file_conf.gateway_balance,
+        exit_node,
+        exit_dns,
+        file_conf.allow_exit,
+        file_conf.masquerade,
+        file_conf.overlay_dns,
+        file_conf.overlay_dns_resolved,
+        static_peers,
+        static_netmask,
+        #[cfg(feature = "turn")]
+        file_conf.turn_server,
+        crate::cli::app_home().ok(),
     )?;
 
     Ok((config, file_conf.vnt_mapping, file_conf.cmd))
 }
+
+fn static_peers(peers: &[StaticPeerConfig]) -> anyhow::Result<Vec<StaticPeer>> {
+    let mut static_peers = Vec::with_capacity(peers.len());
+    for peer in peers {
+        let ip = Ipv4Addr::from_str(&peer.ip)
+            .map_err(|e| anyhow!("static_peers ip {:?} error:{}", &peer.ip, e))?;
+        let mut endpoints = Vec::new();
+        for endpoint in &peer.endpoints {
+            let addrs = endpoint
+                .to_socket_addrs()
+                .map_err(|e| anyhow!("static_peers endpoint {:?} error:{}", endpoint, e))?;
+            endpoints.extend(addrs);
+        }
+        let public_key = match &peer.public_key {
+            None => None,
+            Some(v) => Some(
+                decode_public_key(v)
+                    .ok_or_else(|| anyhow!("static_peers public_key {:?} error", v))?,
+            ),
+        };
+        static_peers.push(StaticPeer::new(ip, endpoints, public_key));
+    }
+    Ok(static_peers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_static_peers() {
+        let yaml = r#"
+static_peers:
+  - ip: 10.26.0.2
+    endpoints: [127.0.0.1:29872, "[::1]:29872"]
+    public_key: 0101010101010101010101010101010101010101010101010101010101010101
+  - ip: 10.26.0.3
+    endpoints: [127.0.0.1:29873]
+"#;
+        let file_conf: FileConfig = serde_yaml::from_str(yaml).unwrap();
+        let peers = static_peers(&file_conf.static_peers).unwrap();
+        assert_eq!(peers.len(), 2);
+        assert_eq!(peers[0].virtual_ip, Ipv4Addr::new(10, 26, 0, 2));
+        assert_eq!(peers[0].endpoints.len(), 2);
+        assert_eq!(peers[0].public_key, Some([1u8; 32]));
+        assert_eq!(peers[1].public_key, None);
+
+        let mut bad_key = file_conf.static_peers;
+        bad_key[1].public_key = Some("0102".into());
+        assert!(static_peers(&bad_key).is_err());
+        bad_key[1].public_key = None;
+        bad_key[1].ip = "10.26.0".into();
+        assert!(static_peers(&bad_key).is_err());
+    }
+}
@@ -20,7 +20,19 @@ pub fn console_info(status: Info) {
         println!("Connection status: {}", style(status.connect_status).red());
     }
 
-    println!("NAT type: {}", style(status.nat_type).green());
+    println!("NAT type: {}", style(&status.nat_type).green());
+    if !status.nat_mapping.is_empty() {
+        println!("NAT mapping: {}", style(status.nat_mapping).green());
+        println!("NAT filtering: {}", style(status.nat_filtering).green());
+        println!("NAT hairpin: {}", style(status.hairpin).green());
+        println!(
+            "NAT binding lifetime: {}",
+            style(status.binding_lifetime).green()
+        );
+    }
+    if status.nat_type.eq_ignore_ascii_case("Symmetric") && !status.port_model.is_empty() {
+        println!("Port model: {}", style(status.port_model).green());
+    }
     println!("Relay server: {}", style(status.relay_server).green());
     println!(
         "Udp listen: {}",
@@ -28,7 +40,13 @@ pub fn console_info(status: Info) {
     );
     println!("Tcp listen: {}", style(status.tcp_listen_addr).green());
     println!("Public ips: {}", style(status.public_ips).green());
+    if !status.turn_relayed.is_empty() {
+        println!("TURN relayed: {}", style(status.turn_relayed).green());
+    }
     println!("Local addr: {}", style(status.local_addr).green());
+    if !status.public_key.is_empty() {
+        println!("Public key: {}", style(status.public_key).green());
+    }
     println!("IPv6: {}", style(status.ipv6_addr).green());
 
     if !status.port_mapping_list.is_empty() {
@@ -42,6 +60,26 @@ pub fn console_info(status: Info) {
             }
         }
     }
+    if !status.gateway_mappings.is_empty() {
+        println!("------------------------------------------");
+        println!("Gateway mapping {}", status.gateway_mappings.len());
+        for item in status.gateway_mappings {
+            println!(
+                "  {} {}: {} -> {} {}",
+                item.source, item.protocol, item.port, item.external, item.status
+            )
+        }
+    }
+    if !status.punch_stats.is_empty() {
+        println!("------------------------------------------");
+        println!("Punch stats");
+        for item in status.punch_stats {
+            println!(
+                "  {}: {}/{} success, {} predicted",
+                item.model, item.success, item.attempts, item.predicted_hits
+            )
+        }
+    }
     if !status.in_ips.is_empty() || !status.out_ips.is_empty() {
         println!("------------------------------------------");
     }
@@ -263,6 +301,12 @@ pub fn console_chart_a(chart_a: ChartA) {
         "Download total = {}",
         style(convert(chart_a.down_total)).green()
     );
+    println!(
+        "Buffer pool alloc = {}, reuse = {}, oversize = {}",
+        style(chart_a.pool_alloc).green(),
+        style(chart_a.pool_reuse).green(),
+        style(chart_a.pool_oversize).green()
+    );
     println!("-----------------------------------------------------------------");
     let up_keys: HashSet<_> = chart_a.up_map.keys().cloned().collect();
     let down_keys: HashSet<_> = chart_a.down_map.keys().cloned().collect();
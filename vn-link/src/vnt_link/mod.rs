@@ -47,7 +47,13 @@ impl VnLink {
         let tcp_listener = LwIPTcpListener::new()?;
         let (shutdown_tx, shutdown_rx) = channel(false);
         let (net_stack_write, mut net_stack_read) = stack.into_split();
-        let vnt = Vnt::new_device(vnt_config, callback, VntDevice { net_stack_write })?;
+        // vnt的异步任务直接运行在当前运行时上
+        let vnt = Vnt::new_device_with_handle(
+            vnt_config,
+            callback,
+            VntDevice { net_stack_write },
+            tokio::runtime::Handle::current(),
+        )?;
         let shutdown_tx_ = shutdown_tx.clone();
         let w = vnt.add_stop_listener("vnt-link".into(), move || {
             let _ = shutdown_tx_.send(true);
@@ -0,0 +1,83 @@
+//! 对比逐包收发和批量收发(recvmmsg/sendmmsg + GRO/GSO)的每秒包数
+//!
+//! cargo run --release --example udp_pps -- [包长度,默认1400] [测试秒数,默认5]
+#[cfg(target_os = "linux")]
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let size: usize = args.get(1).and_then(|v| v.parse().ok()).unwrap_or(1400);
+    let secs: u64 = args.get(2).and_then(|v| v.parse().ok()).unwrap_or(5);
+    let duration = std::time::Duration::from_secs(secs);
+    println!("packet size={} duration={:?}", size, duration);
+    let pps = bench::run(size, duration, false);
+    println!("recv_from/send_to      : {:>12.0} pps", pps);
+    let pps = bench::run(size, duration, true);
+    println!("recvmmsg/sendmmsg(gso) : {:>12.0} pps", pps);
+}
+
+#[cfg(not(target_os = "linux"))]
+fn main() {
+    println!("only linux");
+}
+
+#[cfg(target_os = "linux")]
+mod bench {
+    use std::net::UdpSocket;
+    use std::os::fd::AsRawFd;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use vnt::channel::udp_batch::{gso_supported, set_gro, RecvBatch, SendBatch, BATCH_SIZE};
+
+    pub fn run(size: usize, duration: Duration, batch: bool) -> f64 {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let dest = receiver.local_addr().unwrap();
+        receiver.set_nonblocking(true).unwrap();
+        let stop = Arc::new(AtomicBool::new(false));
+        let send_thread = {
+            let stop = stop.clone();
+            thread::spawn(move || {
+                let buf = vec![0u8; size];
+                if batch {
+                    let fd = sender.as_raw_fd();
+                    let mut send_batch = SendBatch::new(gso_supported(fd));
+                    while !stop.load(Ordering::Relaxed) {
+                        for _ in 0..BATCH_SIZE * 4 {
+                            send_batch.push(&buf, dest, ());
+                        }
+                        let _ = send_batch.flush(fd, |_, _, _| {});
+                    }
+                } else {
+                    while !stop.load(Ordering::Relaxed) {
+                        let _ = sender.send_to(&buf, dest);
+                    }
+                }
+            })
+        };
+        let fd = receiver.as_raw_fd();
+        let mut count: u64 = 0;
+        let mut buf = vec![0u8; 65536];
+        let gro = batch && set_gro(fd, true);
+        let mut recv_batch = RecvBatch::new(gro);
+        let start = Instant::now();
+        while start.elapsed() < duration {
+            if batch {
+                match recv_batch.recv(fd) {
+                    Ok(_) => recv_batch.for_each(|_, _| count += 1),
+                    Err(_) => thread::yield_now(),
+                }
+            } else {
+                match receiver.recv_from(&mut buf) {
+                    Ok(_) => count += 1,
+                    Err(_) => thread::yield_now(),
+                }
+            }
+        }
+        let elapsed = start.elapsed();
+        stop.store(true, Ordering::Relaxed);
+        send_thread.join().unwrap();
+        count as f64 / elapsed.as_secs_f64()
+    }
+}
@@ -0,0 +1,463 @@
+//! ICE(RFC 8445)风格的候选地址，按优先级配对检查，提名最优的候选对
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+use crate::channel::context::RouteTable;
+use crate::channel::punch::NatInfo;
+use crate::channel::Route;
+use crate::proto::message::{PunchCandidate, PunchCandidateType};
+
+/// 两次检查之间的间隔
+pub const CHECK_PACING: Duration = Duration::from_millis(5);
+/// 提名的是中继时，隔一段时间再尝试直连
+const RELAY_RECHECK_INTERVAL: Duration = Duration::from_secs(60);
+/// 提名的不是最优候选对时，隔一段时间重新检查
+const RECHECK_INTERVAL: Duration = Duration::from_secs(300);
+/// 没有路由的检查记录保留时间
+const CHECK_LIST_EXPIRE: Duration = Duration::from_secs(600);
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum CandidateKind {
+    // 本地网卡地址
+    Host,
+    // stun探测到的公网地址
+    ServerReflexive,
+    // UPnP/NAT-PMP/PCP网关映射的地址
+    Mapped,
+    // TURN服务器的中继地址
+    Relayed,
+}
+
+impl CandidateKind {
+    /// RFC 8445 推荐的类型优先级，网关映射的地址介于本地和反射地址之间
+    fn type_preference(&self) -> u32 {
+        match self {
+            CandidateKind::Host => 126,
+            CandidateKind::Mapped => 110,
+            CandidateKind::ServerReflexive => 100,
+            CandidateKind::Relayed => 0,
+        }
+    }
+    pub fn name(&self) -> &'static str {
+        match self {
+            CandidateKind::Host => "host",
+            CandidateKind::ServerReflexive => "srflx",
+            CandidateKind::Mapped => "mapped",
+            CandidateKind::Relayed => "relay",
+        }
+    }
+}
+
+impl From<PunchCandidateType> for CandidateKind {
+    fn from(value: PunchCandidateType) -> Self {
+        match value {
+            PunchCandidateType::Host => CandidateKind::Host,
+            PunchCandidateType::ServerReflexive => CandidateKind::ServerReflexive,
+            PunchCandidateType::Mapped => CandidateKind::Mapped,
+            PunchCandidateType::Relayed => CandidateKind::Relayed,
+        }
+    }
+}
+
+impl From<CandidateKind> for PunchCandidateType {
+    fn from(value: CandidateKind) -> Self {
+        match value {
+            CandidateKind::Host => PunchCandidateType::Host,
+            CandidateKind::ServerReflexive => PunchCandidateType::ServerReflexive,
+            CandidateKind::Mapped => PunchCandidateType::Mapped,
+            CandidateKind::Relayed => PunchCandidateType::Relayed,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Candidate {
+    pub kind: CandidateKind,
+    pub tcp: bool,
+    pub addr: SocketAddr,
+    pub priority: u32,
+    // 对端发出该地址的socket序号，两端的主socket按序号一一对应，
+    // 本地用相同序号的socket发送；None表示本地所有socket都要发送
+    pub socket_index: Option<usize>,
+}
+
+impl Candidate {
+    pub fn new(
+        kind: CandidateKind,
+        tcp: bool,
+        addr: SocketAddr,
+        socket_index: Option<usize>,
+    ) -> Self {
+        Self {
+            kind,
+            tcp,
+            addr,
+            priority: priority(kind, tcp, addr.is_ipv6()),
+            socket_index,
+        }
+    }
+    /// 猜测的地址，排在确定的同类地址后面
+    fn guessed(mut self) -> Self {
+        self.priority -= 0x1000 << 8;
+        self
+    }
+    pub fn into_proto(&self) -> PunchCandidate {
+        let mut candidate = PunchCandidate::new();
+        candidate.kind = protobuf::EnumOrUnknown::new(self.kind.into());
+        candidate.tcp = self.tcp;
+        candidate.ip = match self.addr.ip() {
+            IpAddr::V4(ip) => ip.octets().to_vec(),
+            IpAddr::V6(ip) => ip.octets().to_vec(),
+        };
+        candidate.port = self.addr.port() as u32;
+        candidate.priority = self.priority;
+        candidate.base = self.socket_index.map_or(0, |v| v as u32 + 1);
+        candidate
+    }
+    pub fn from_proto(candidate: &PunchCandidate) -> Option<Self> {
+        let ip = match candidate.ip.len() {
+            4 => IpAddr::V4(Ipv4Addr::from(
+                <[u8; 4]>::try_from(candidate.ip.as_slice()).unwrap(),
+            )),
+            16 => IpAddr::V6(Ipv6Addr::from(
+                <[u8; 16]>::try_from(candidate.ip.as_slice()).unwrap(),
+            )),
+            _ => return None,
+        };
+        if candidate.port == 0 || candidate.port > u16::MAX as u32 || ip.is_unspecified() {
+            return None;
+        }
+        let kind = candidate.kind.enum_value().ok()?.into();
+        let addr = SocketAddr::new(ip, candidate.port as u16);
+        let mut rs = Candidate::new(kind, candidate.tcp, addr, None);
+        if candidate.priority != 0 {
+            rs.priority = candidate.priority;
+        }
+        if candidate.base != 0 {
+            rs.socket_index = Some(candidate.base as usize - 1);
+        }
+        Some(rs)
+    }
+}
+
+/// priority = 2^24*类型优先级 + 2^8*本地优先级 + (256-组件id)，只有一个组件。
+/// 同类地址udp优先于tcp，ipv6优先于ipv4
+pub fn priority(kind: CandidateKind, tcp: bool, ipv6: bool) -> u32 {
+    let mut local_preference: u32 = 0x8000;
+    if !tcp {
+        local_preference += 0x4000;
+    }
+    if ipv6 {
+        local_preference += 0x2000;
+    }
+    (kind.type_preference() << 24) + (local_preference << 8) + 255
+}
+
+/// 从nat信息收集候选地址，旧版本对端没有发送候选列表时也用它推导
+pub fn gather(nat_info: &NatInfo) -> Vec<Candidate> {
+    let mut list: Vec<Candidate> = Vec::new();
+    let mut push = |candidate: Candidate| {
+        if candidate.addr.port() != 0
+            && !candidate.addr.ip().is_unspecified()
+            && !list
+                .iter()
+                .any(|v| v.addr == candidate.addr && v.tcp == candidate.tcp)
+        {
+            list.push(candidate);
+        }
+    };
+    if let Some(ip) = nat_info.local_ipv4() {
+        for (index, port) in nat_info.udp_ports.iter().enumerate() {
+            let addr = SocketAddrV4::new(ip, *port).into();
+            push(Candidate::new(
+                CandidateKind::Host,
+                false,
+                addr,
+                Some(index),
+            ));
+        }
+        let addr = SocketAddrV4::new(ip, nat_info.tcp_port).into();
+        push(Candidate::new(CandidateKind::Host, true, addr, None));
+    }
+    if let Some(ip) = nat_info.ipv6() {
+        for (index, port) in nat_info.udp_ports.iter().enumerate() {
+            let addr = SocketAddrV6::new(ip, *port, 0, 0).into();
+            push(Candidate::new(
+                CandidateKind::Host,
+                false,
+                addr,
+                Some(index),
+            ));
+        }
+        let addr = SocketAddrV6::new(ip, nat_info.tcp_port, 0, 0).into();
+        push(Candidate::new(CandidateKind::Host, true, addr, None));
+    }
+    for addr in &nat_info.mapped_udp {
+        push(Candidate::new(
+            CandidateKind::Mapped,
+            false,
+            (*addr).into(),
+            None,
+        ));
+    }
+    if let Some(addr) = nat_info.mapped_tcp {
+        push(Candidate::new(
+            CandidateKind::Mapped,
+            true,
+            addr.into(),
+            None,
+        ));
+    }
+    for ip in &nat_info.public_ips {
+        for (index, port) in nat_info.public_ports.iter().enumerate() {
+            let addr = SocketAddrV4::new(*ip, *port).into();
+            let candidate =
+                Candidate::new(CandidateKind::ServerReflexive, false, addr, Some(index));
+            push(candidate);
+        }
+        if nat_info.nat_type.is_cone() {
+            let addr = SocketAddrV4::new(*ip, nat_info.public_tcp_port).into();
+            push(Candidate::new(
+                CandidateKind::ServerReflexive,
+                true,
+                addr,
+                None,
+            ));
+        }
+    }
+    // 端口保持不变或者开放了端口的情况
+    for ip in &nat_info.public_ips {
+        for port in &nat_info.udp_ports {
+            let addr = SocketAddrV4::new(*ip, *port).into();
+            push(Candidate::new(CandidateKind::ServerReflexive, false, addr, None).guessed());
+        }
+        let addr = SocketAddrV4::new(*ip, nat_info.tcp_port).into();
+        push(Candidate::new(CandidateKind::ServerReflexive, true, addr, None).guessed());
+    }
+    for addr in &nat_info.relayed {
+        push(Candidate::new(
+            CandidateKind::Relayed,
+            false,
+            (*addr).into(),
+            None,
+        ));
+    }
+    list.sort_by(|a, b| b.priority.cmp(&a.priority));
+    list
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct CandidatePair {
+    // 对端看到的本地地址类型
+    pub local: CandidateKind,
+    pub remote: Candidate,
+    pub priority: u64,
+}
+
+impl CandidatePair {
+    /// 路由是否由该候选对建立
+    fn matches(&self, route: &Route) -> bool {
+        route.is_p2p()
+            && route.addr == self.remote.addr
+            && if self.remote.tcp {
+                route.protocol.is_tcp()
+            } else {
+                route.protocol.is_udp()
+            }
+    }
+}
+
+/// RFC 8445 候选对优先级，双方用同样的控制方计算，得到的顺序一致
+pub fn pair_priority(controlling: u32, controlled: u32) -> u64 {
+    let (g, d) = (controlling as u64, controlled as u64);
+    (g.min(d) << 32) + 2 * g.max(d) + if g > d { 1 } else { 0 }
+}
+
+/// 和对端的候选地址配对，按优先级从高到低排序。
+/// 对端是本地地址时本地也是本地地址，其他情况对端看到的是本地的公网地址
+pub fn pairs(remote: &[Candidate], controlling: bool) -> Vec<CandidatePair> {
+    let mut list: Vec<CandidatePair> = remote
+        .iter()
+        .map(|remote| {
+            let local = if remote.kind == CandidateKind::Host {
+                CandidateKind::Host
+            } else {
+                CandidateKind::ServerReflexive
+            };
+            let local_priority = priority(local, remote.tcp, remote.addr.is_ipv6());
+            let priority = if controlling {
+                pair_priority(local_priority, remote.priority)
+            } else {
+                pair_priority(remote.priority, local_priority)
+            };
+            CandidatePair {
+                local,
+                remote: *remote,
+                priority,
+            }
+        })
+        .collect();
+    list.sort_by(|a, b| b.priority.cmp(&a.priority));
+    list
+}
+
+struct CheckList {
+    pairs: Vec<CandidatePair>,
+    nominated: Option<CandidatePair>,
+    checked: Instant,
+}
+
+/// 记录每个对端的检查列表，根据路由表确认成功的候选对并提名
+#[derive(Clone, Default)]
+pub struct CandidateChecker {
+    inner: Arc<Mutex<HashMap<Ipv4Addr, CheckList>>>,
+}
+
+impl CandidateChecker {
+    /// 记录一轮检查，保留之前的提名
+    pub fn record(&self, id: Ipv4Addr, pairs: Vec<CandidatePair>) {
+        let mut guard = self.inner.lock();
+        let list = guard.entry(id).or_insert_with(|| CheckList {
+            pairs: Vec::new(),
+            nominated: None,
+            checked: Instant::now(),
+        });
+        list.pairs = pairs;
+        list.checked = Instant::now();
+    }
+    /// 提名成功的候选对中优先级最高的，直连成功后删除经过中继的路由
+    pub fn nominate(&self, route_table: &RouteTable) {
+        let mut prune = Vec::new();
+        {
+            let mut guard = self.inner.lock();
+            guard.retain(|id, list| {
+                let routes = route_table.route(id).unwrap_or_default();
+                let best = list
+                    .pairs
+                    .iter()
+                    .filter(|pair| routes.iter().any(|route| pair.matches(route)))
+                    .max_by_key(|pair| pair.priority)
+                    .copied();
+                if best != list.nominated {
+                    match best {
+                        Some(pair) => log::info!(
+                            "提名候选对 {} {}->{} {}{}",
+                            id,
+                            pair.local.name(),
+                            pair.remote.kind.name(),
+                            if pair.remote.tcp { "tcp@" } else { "" },
+                            pair.remote.addr
+                        ),
+                        None => log::info!("提名的候选对已失效 {}", id),
+                    }
+                    list.nominated = best;
+                }
+                if let Some(nominated) = list.nominated {
+                    if nominated.remote.kind != CandidateKind::Relayed {
+                        for route in &routes {
+                            let relayed = route.protocol.is_turn()
+                                || list.pairs.iter().any(|pair| {
+                                    pair.remote.kind == CandidateKind::Relayed
+                                        && pair.matches(route)
+                                });
+                            if relayed {
+                                prune.push((*id, *route));
+                            }
+                        }
+                    }
+                }
+                !routes.is_empty() || list.checked.elapsed() < CHECK_LIST_EXPIRE
+            });
+        }
+        for (id, route) in prune {
+            log::info!("已直连，删除中继路由 {} {:?}", id, route);
+            route_table.remove_route(&id, route.route_key());
+        }
+    }
+    /// 已经有路由，但提名的是中继或者不是最优的候选对，需要重新检查
+    pub fn needs_recheck(&self, id: &Ipv4Addr) -> bool {
+        let guard = self.inner.lock();
+        let Some(list) = guard.get(id) else {
+            return false;
+        };
+        let Some(nominated) = list.nominated else {
+            return false;
+        };
+        if nominated.remote.kind == CandidateKind::Relayed {
+            return list.checked.elapsed() >= RELAY_RECHECK_INTERVAL;
+        }
+        list.checked.elapsed() >= RECHECK_INTERVAL
+            && list
+                .pairs
+                .first()
+                .map_or(false, |best| best.priority > nominated.priority)
+    }
+    pub fn nominated(&self, id: &Ipv4Addr) -> Option<CandidatePair> {
+        self.inner.lock().get(id).and_then(|v| v.nominated)
+    }
+    pub fn remove(&self, id: &Ipv4Addr) {
+        self.inner.lock().remove(id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pair_order() {
+        let host = Candidate::new(
+            CandidateKind::Host,
+            false,
+            "192.168.1.2:5000".parse().unwrap(),
+            Some(0),
+        );
+        let srflx = Candidate::new(
+            CandidateKind::ServerReflexive,
+            false,
+            "1.2.3.4:6000".parse().unwrap(),
+            Some(0),
+        );
+        let relayed = Candidate::new(
+            CandidateKind::Relayed,
+            false,
+            "5.6.7.8:7000".parse().unwrap(),
+            None,
+        );
+        let tcp = Candidate::new(
+            CandidateKind::Host,
+            true,
+            "192.168.1.2:5001".parse().unwrap(),
+            None,
+        );
+        let remote = [relayed, srflx, tcp, host];
+        let a = pairs(&remote, true);
+        let b = pairs(&remote, false);
+        let order: Vec<SocketAddr> = a.iter().map(|v| v.remote.addr).collect();
+        assert_eq!(order, vec![host.addr, tcp.addr, srflx.addr, relayed.addr]);
+        // 控制方不同，但两端的排序一致
+        let order_b: Vec<SocketAddr> = b.iter().map(|v| v.remote.addr).collect();
+        assert_eq!(order, order_b);
+        assert!(srflx.guessed().priority < srflx.priority);
+        assert!(srflx.guessed().priority > relayed.priority);
+    }
+
+    #[test]
+    fn proto() {
+        let candidate = Candidate::new(
+            CandidateKind::Mapped,
+            true,
+            "[2001:db8::1]:443".parse().unwrap(),
+            Some(2),
+        );
+        let rs = Candidate::from_proto(&candidate.into_proto()).unwrap();
+        assert_eq!(rs, candidate);
+        let mut invalid = candidate.into_proto();
+        invalid.ip = vec![1, 2, 3];
+        assert!(Candidate::from_proto(&invalid).is_none());
+    }
+}
@@ -10,12 +10,16 @@ use crossbeam_utils::atomic::AtomicCell;
 use parking_lot::RwLock;
 use rand::Rng;
 
+use crate::channel::pending::PendingPackets;
 use crate::channel::punch::NatType;
 use crate::channel::sender::{AcceptSocketSender, PacketSender};
 use crate::channel::socket::LocalInterface;
+#[cfg(feature = "turn")]
+use crate::channel::turn::TurnRelay;
 use crate::channel::{ConnectProtocol, Route, RouteKey, UseChannelType, DEFAULT_RT};
 use crate::protocol::NetPacket;
 use crate::util::limit::TrafficMeterMultiAddress;
+use crate::util::{BufferPool, SharedBuf};
 
 /// 传输通道上下文，持有udp socket、tcp socket和路由信息
 #[derive(Clone)]
@@ -48,6 +52,14 @@ impl ChannelContext {
                 }
             })
             .unwrap_or(0);
+        #[cfg(target_os = "linux")]
+        let main_udp_gso = {
+            use std::os::fd::AsRawFd;
+            main_udp_socket
+                .iter()
+                .all(|udp| crate::channel::udp_batch::gso_supported(udp.as_raw_fd()))
+        };
+        let buffer_pool = BufferPool::default();
         let inner = ContextInner {
             main_udp_socket,
             v4_len,
@@ -61,6 +73,12 @@ impl ChannelContext {
             down_traffic_meter,
             default_interface,
             default_route_key: AtomicCell::default(),
+            pending: PendingPackets::new(buffer_pool.clone()),
+            buffer_pool,
+            #[cfg(feature = "turn")]
+            turn: TurnRelay::default(),
+            #[cfg(target_os = "linux")]
+            main_udp_gso,
         };
         Self {
             inner: Arc::new(inner),
@@ -100,6 +118,41 @@ pub struct ContextInner {
     pub(crate) down_traffic_meter: Option<TrafficMeterMultiAddress>,
     default_interface: LocalInterface,
     default_route_key: AtomicCell<Option<RouteKey>>,
+    // 发送路径复用的缓冲区
+    buffer_pool: BufferPool,
+    // 和服务端重连期间暂存的中转数据
+    pending: PendingPackets,
+    // TURN服务器上的中继
+    #[cfg(feature = "turn")]
+    turn: TurnRelay,
+    // 主udp socket支持GSO
+    #[cfg(target_os = "linux")]
+    main_udp_gso: bool,
+}
+
+/// 直连发送失败时的补发方式，和send_ipv4_by_id的处理一致
+#[derive(Copy, Clone, PartialEq)]
+struct Fallback {
+    id: Ipv4Addr,
+    server_addr: SocketAddr,
+    send_default: bool,
+}
+
+/// 每个发送线程各自缓存发往主udp socket的数据，按socket下标分开
+#[cfg(target_os = "linux")]
+struct UdpSendBatch {
+    active: bool,
+    batches: Vec<crate::channel::udp_batch::SendBatch<Option<Fallback>>>,
+}
+
+#[cfg(target_os = "linux")]
+thread_local! {
+    static UDP_SEND_BATCH: std::cell::RefCell<UdpSendBatch> = const {
+        std::cell::RefCell::new(UdpSendBatch {
+            active: false,
+            batches: Vec::new(),
+        })
+    };
 }
 
 impl ContextInner {
@@ -109,6 +162,12 @@ impl ContextInner {
     pub fn default_interface(&self) -> &LocalInterface {
         &self.default_interface
     }
+    pub fn buffer_pool(&self) -> &BufferPool {
+        &self.buffer_pool
+    }
+    pub fn pending(&self) -> &PendingPackets {
+        &self.pending
+    }
     pub fn set_default_route_key(&self, route_key: RouteKey) {
         self.default_route_key.store(Some(route_key));
     }
@@ -119,6 +178,10 @@ impl ContextInner {
     pub fn main_protocol(&self) -> ConnectProtocol {
         self.protocol
     }
+    #[cfg(feature = "turn")]
+    pub fn turn(&self) -> &TurnRelay {
+        &self.turn
+    }
     pub fn is_udp_main(&self, route_key: &RouteKey) -> bool {
         route_key.protocol().is_udp() && route_key.index < self.main_udp_socket.len()
     }
@@ -137,20 +200,7 @@ impl ContextInner {
                 if !write_guard.is_empty() {
                     return Ok(());
                 }
-                let mut vec = Vec::with_capacity(SYMMETRIC_CHANNEL_NUM);
-                for _ in 0..SYMMETRIC_CHANNEL_NUM {
-                    let udp = crate::channel::socket::bind_udp(
-                        "0.0.0.0:0".parse().unwrap(),
-                        &self.default_interface,
-                    )?;
-                    let udp: UdpSocket = udp.into();
-                    vec.push(udp);
-                }
-                let mut mio_vec = Vec::with_capacity(SYMMETRIC_CHANNEL_NUM);
-                for udp in vec.iter() {
-                    let udp_socket = mio::net::UdpSocket::from_std(udp.try_clone()?);
-                    mio_vec.push(udp_socket);
-                }
+                let (vec, mio_vec) = self.bind_sub_udp()?;
                 udp_socket_sender.try_add_socket(Some(mio_vec))?;
                 *write_guard = vec;
             }
@@ -164,6 +214,37 @@ impl ContextInner {
         }
         Ok(())
     }
+    /// 本地网络变化后重建对称模式的socket，旧socket在nat上的映射已经失效
+    pub fn rebind_sub_udp(
+        &self,
+        udp_socket_sender: &AcceptSocketSender<Option<Vec<mio::net::UdpSocket>>>,
+    ) -> anyhow::Result<()> {
+        let mut write_guard = self.sub_udp_socket.write();
+        if write_guard.is_empty() {
+            return Ok(());
+        }
+        let (vec, mio_vec) = self.bind_sub_udp()?;
+        udp_socket_sender.try_add_socket(Some(mio_vec))?;
+        *write_guard = vec;
+        Ok(())
+    }
+    fn bind_sub_udp(&self) -> anyhow::Result<(Vec<UdpSocket>, Vec<mio::net::UdpSocket>)> {
+        let mut vec = Vec::with_capacity(SYMMETRIC_CHANNEL_NUM);
+        for _ in 0..SYMMETRIC_CHANNEL_NUM {
+            let udp = crate::channel::socket::bind_udp(
+                "0.0.0.0:0".parse().unwrap(),
+                &self.default_interface,
+            )?;
+            let udp: UdpSocket = udp.into();
+            vec.push(udp);
+        }
+        let mut mio_vec = Vec::with_capacity(SYMMETRIC_CHANNEL_NUM);
+        for udp in vec.iter() {
+            let udp_socket = mio::net::UdpSocket::from_std(udp.try_clone()?);
+            mio_vec.push(udp_socket);
+        }
+        Ok((vec, mio_vec))
+    }
     #[inline]
     pub fn channel_num(&self) -> usize {
         self.v4_len
@@ -190,6 +271,17 @@ impl ContextInner {
             ))
         }
     }
+    /// 共享同一份数据发送到tcp通道，多个目标时不需要重复拷贝
+    pub fn send_tcp_shared(&self, buf: SharedBuf, route_key: &RouteKey) -> io::Result<()> {
+        if let Some(tcp) = self.packet_map.read().get(route_key) {
+            tcp.try_send_shared(buf)
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("dest={:?}", route_key),
+            ))
+        }
+    }
     pub fn send_main_udp(&self, index: usize, buf: &[u8], addr: SocketAddr) -> io::Result<()> {
         if let Some(udp) = self.main_udp_socket.get(index) {
             udp.send_to(buf, addr)?;
@@ -198,6 +290,85 @@ impl ContextInner {
             Err(io::Error::new(io::ErrorKind::Other, "overflow"))
         }
     }
+    /// 开始批量发送，在send_batch_end之前当前线程发往主udp socket的数据会先缓存起来，
+    /// 缓存的数据批量发送失败时逐包重发，仍然失败的按send_ipv4_by_id的方式转由服务器中转
+    #[cfg(target_os = "linux")]
+    pub fn send_batch_begin(&self) {
+        UDP_SEND_BATCH.with(|batch| batch.borrow_mut().active = true);
+    }
+    /// 结束批量发送，通过sendmmsg/GSO发出缓存的数据
+    #[cfg(target_os = "linux")]
+    pub fn send_batch_end(&self) {
+        UDP_SEND_BATCH.with(|batch| {
+            let mut batch = batch.borrow_mut();
+            if !batch.active {
+                return;
+            }
+            batch.active = false;
+            for (index, send_batch) in batch.batches.iter_mut().enumerate() {
+                self.flush_batch(index, send_batch);
+            }
+        });
+    }
+    /// 批量发送中则缓存数据，返回是否已缓存
+    #[cfg(target_os = "linux")]
+    fn send_batch_push(
+        &self,
+        index: usize,
+        buf: &[u8],
+        addr: SocketAddr,
+        fallback: Option<Fallback>,
+    ) -> bool {
+        UDP_SEND_BATCH.with(|batch| {
+            let mut batch = batch.borrow_mut();
+            if !batch.active {
+                return false;
+            }
+            while batch.batches.len() <= index {
+                batch
+                    .batches
+                    .push(crate::channel::udp_batch::SendBatch::new(self.main_udp_gso));
+            }
+            let send_batch = &mut batch.batches[index];
+            send_batch.push(buf, addr, fallback);
+            if send_batch.len() >= crate::channel::udp_batch::BATCH_SIZE {
+                self.flush_batch(index, send_batch);
+            }
+            true
+        })
+    }
+    #[cfg(target_os = "linux")]
+    fn flush_batch(
+        &self,
+        index: usize,
+        batch: &mut crate::channel::udp_batch::SendBatch<Option<Fallback>>,
+    ) {
+        use std::os::fd::AsRawFd;
+        if batch.is_empty() {
+            return;
+        }
+        if let Some(udp) = self.main_udp_socket.get(index) {
+            let rs = batch.flush(udp.as_raw_fd(), |buf, addr, fallback| {
+                if let Err(e) = udp.send_to(buf, addr) {
+                    match fallback {
+                        Some(fallback) => {
+                            if let Err(e) = NetPacket::new(buf)
+                                .and_then(|packet| self.send_fallback(&packet, e, fallback))
+                            {
+                                log::warn!("{}:{:?}", fallback.id, e);
+                            }
+                        }
+                        None => log::warn!("main_udp_{} {} {:?}", index, addr, e),
+                    }
+                }
+            });
+            if let Err(e) = rs {
+                log::debug!("main_udp_{} 批量发送失败,已逐包重发 {:?}", index, e);
+            }
+        } else {
+            batch.clear();
+        }
+    }
     /// 将数据发送到默认通道，一般发往服务器才用此方法
     pub fn send_default<B: AsRef<[u8]>>(
         &self,
@@ -260,24 +431,52 @@ impl ContextInner {
         if self.packet_delay > 0 {
             thread::sleep(Duration::from_millis(self.packet_delay as _));
         }
+        let fallback = Fallback {
+            id: *id,
+            server_addr,
+            send_default,
+        };
         //优先发到直连到地址
-        if let Err(e) = self.send_by_id(buf, id) {
-            if e.kind() != io::ErrorKind::NotFound {
-                log::warn!("{}:{:?}", id, e);
-            }
-            if !self.route_table.use_channel_type.is_only_p2p() && send_default {
+        if let Err(e) = self.send_by_id0(buf, id, Some(fallback)) {
+            self.send_fallback(buf, e, fallback)?;
+        }
+        Ok(())
+    }
+    /// 直连发送失败后的处理
+    fn send_fallback<B: AsRef<[u8]>>(
+        &self,
+        buf: &NetPacket<B>,
+        e: io::Error,
+        fallback: Fallback,
+    ) -> io::Result<()> {
+        if e.kind() != io::ErrorKind::NotFound {
+            log::warn!("{}:{:?}", fallback.id, e);
+        }
+        if !self.route_table.use_channel_type.is_only_p2p() {
+            if fallback.send_default {
                 //符合条件再发到服务器转发
-                self.send_default(buf, server_addr)?;
+                self.send_default(buf, fallback.server_addr)?;
+            } else if !fallback.server_addr.ip().is_unspecified() {
+                //正在重连服务端，暂存起来，重连成功后再发
+                self.pending.push(fallback.id, buf.buffer());
             }
         }
         Ok(())
     }
     /// 将数据发到指定id
     pub fn send_by_id<B: AsRef<[u8]>>(&self, buf: &NetPacket<B>, id: &Ipv4Addr) -> io::Result<()> {
+        self.send_by_id0(buf, id, None)
+    }
+    fn send_by_id0<B: AsRef<[u8]>>(
+        &self,
+        buf: &NetPacket<B>,
+        id: &Ipv4Addr,
+        fallback: Option<Fallback>,
+    ) -> io::Result<()> {
         let mut c = 0;
         loop {
             let route = self.route_table.get_route_by_id(c, id)?;
-            return if let Err(e) = self.send_by_key(buf, route.route_key()) {
+            return if let Err(e) = self.send_by_key0(buf, route.route_key(), fallback) {
                 //降低发送速率
                 if e.kind() == io::ErrorKind::WouldBlock {
                     c += 1;
@@ -297,11 +496,34 @@ impl ContextInner {
         &self,
         buf: &NetPacket<B>,
         route_key: RouteKey,
+    ) -> io::Result<()> {
+        self.send_by_key0(buf, route_key, None)
+    }
+    /// fallback为批量发送失败后的补发方式，没有时只记录日志
+    fn send_by_key0<B: AsRef<[u8]>>(
+        &self,
+        buf: &NetPacket<B>,
+        route_key: RouteKey,
+        fallback: Option<Fallback>,
     ) -> io::Result<()> {
         match route_key.protocol() {
             ConnectProtocol::UDP => {
                 if let Some(main_udp) = self.main_udp_socket.get(route_key.index) {
-                    main_udp.send_to(buf.buffer(), route_key.addr)?;
+                    #[cfg(target_os = "linux")]
+                    let cached = self.send_batch_push(
+                        route_key.index,
+                        buf.buffer(),
+                        route_key.addr,
+                        fallback,
+                    );
+                    #[cfg(not(target_os = "linux"))]
+                    let cached = {
+                        let _ = fallback;
+                        false
+                    };
+                    if !cached {
+                        main_udp.send_to(buf.buffer(), route_key.addr)?;
+                    }
                 } else {
                     if let Some(udp) = self
                         .sub_udp_socket
@@ -317,6 +539,13 @@ impl ContextInner {
             ConnectProtocol::TCP | ConnectProtocol::WS | ConnectProtocol::WSS => {
                 self.send_tcp(buf.buffer(), &route_key)?
             }
+            #[cfg(feature = "turn")]
+            ConnectProtocol::TURN => {
+                self.turn
+                    .send_to(route_key.index, buf.buffer(), route_key.addr)?
+            }
+            #[cfg(not(feature = "turn"))]
+            ConnectProtocol::TURN => Err(io::Error::from(io::ErrorKind::Unsupported))?,
         }
         if let Some(up_traffic_meter) = &self.up_traffic_meter {
             up_traffic_meter.add_traffic(buf.destination(), buf.data_len());
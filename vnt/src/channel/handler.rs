@@ -9,4 +9,8 @@ pub trait RecvChannelHandler: Clone + Send + Sync + 'static {
         route_key: RouteKey,
         context: &ChannelContext,
     );
+    /// 批量接收前调用
+    fn batch_begin(&self) {}
+    /// 批量接收的数据处理完后调用
+    fn batch_end(&self) {}
 }
@@ -12,16 +12,23 @@ use crate::channel::udp_channel::udp_listen;
 #[cfg(feature = "ws")]
 use crate::channel::ws_channel::ws_connect_accept;
 use crate::util::limit::TrafficMeterMultiAddress;
-use crate::util::StopManager;
+use crate::util::{StopManager, VntRuntime};
 
+pub mod candidate;
 pub mod context;
 pub mod handler;
 pub mod idle;
 pub mod notify;
+pub mod pending;
 pub mod punch;
+pub mod punch_telemetry;
 pub mod sender;
 pub mod socket;
 pub mod tcp_channel;
+#[cfg(feature = "turn")]
+pub mod turn;
+#[cfg(target_os = "linux")]
+pub mod udp_batch;
 pub mod udp_channel;
 #[cfg(feature = "ws")]
 pub mod ws_channel;
@@ -75,6 +82,8 @@ pub enum ConnectProtocol {
     TCP,
     WS,
     WSS,
+    // 经TURN服务器中转的udp
+    TURN,
 }
 
 impl ConnectProtocol {
@@ -94,6 +103,10 @@ impl ConnectProtocol {
     pub fn is_wss(&self) -> bool {
         self == &ConnectProtocol::WSS
     }
+    #[inline]
+    pub fn is_turn(&self) -> bool {
+        self == &ConnectProtocol::TURN
+    }
     pub fn is_transport(&self) -> bool {
         self.is_tcp() || self.is_udp()
     }
@@ -117,7 +130,7 @@ pub struct RouteSortKey {
     pub rt: i64,
 }
 
-const DEFAULT_RT: i64 = 9999;
+pub(crate) const DEFAULT_RT: i64 = 9999;
 
 impl Route {
     pub fn new(
@@ -336,7 +349,9 @@ pub(crate) fn init_channel<H>(
     tcp_listener: std::net::TcpListener,
     context: ChannelContext,
     stop_manager: StopManager,
+    runtime: &VntRuntime,
     recv_handler: H,
+    #[cfg(feature = "turn")] turn_servers: Vec<turn::TurnServer>,
 ) -> anyhow::Result<(
     AcceptSocketSender<Option<Vec<mio::net::UdpSocket>>>,
     ConnectUtil,
@@ -348,18 +363,25 @@ where
     let (ws_connect_s, _ws_connect_r) = channel(16);
     let connect_util = ConnectUtil::new(tcp_connect_s, ws_connect_s);
     // udp监听，udp_socket_sender 用于NAT类型切换
+    #[cfg(feature = "turn")]
+    turn::turn_start(
+        turn_servers,
+        stop_manager.clone(),
+        recv_handler.clone(),
+        context.clone(),
+    )?;
     let udp_socket_sender =
-        udp_listen(stop_manager.clone(), recv_handler.clone(), context.clone())?;
+        udp_listen(stop_manager, recv_handler.clone(), context.clone())?;
     // 建立tcp监听，tcp_socket_sender 用于tcp 直连
     tcp_listen(
         tcp_listener,
         tcp_connect_r,
         recv_handler.clone(),
         context.clone(),
-        stop_manager.clone(),
+        runtime,
     )?;
     #[cfg(feature = "ws")]
-    ws_connect_accept(_ws_connect_r, recv_handler, context.clone(), stop_manager)?;
+    ws_connect_accept(_ws_connect_r, recv_handler, context.clone(), runtime)?;
 
     Ok((udp_socket_sender, connect_util))
 }
@@ -0,0 +1,89 @@
+use std::collections::VecDeque;
+use std::net::Ipv4Addr;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+use crate::util::{BufferPool, PooledBuf};
+
+// 最多暂存的包数和字节数，超出时丢弃最早的
+const MAX_PACKETS: usize = 256;
+const MAX_BYTES: usize = 256 * 1024;
+// 重连超过这么久的包已经没有意义，直接丢弃
+const MAX_AGE: Duration = Duration::from_secs(10);
+
+/// 和服务端重连期间暂存需要中转的数据，重连成功后补发
+pub struct PendingPackets {
+    // (总字节数, 包)
+    queue: Mutex<(usize, VecDeque<(Ipv4Addr, PooledBuf, Instant)>)>,
+    pool: BufferPool,
+}
+
+impl PendingPackets {
+    pub fn new(pool: BufferPool) -> Self {
+        Self {
+            queue: Mutex::new((0, VecDeque::new())),
+            pool,
+        }
+    }
+    pub fn push(&self, dest: Ipv4Addr, buf: &[u8]) {
+        if buf.len() > MAX_BYTES {
+            return;
+        }
+        let now = Instant::now();
+        let mut guard = self.queue.lock();
+        let (bytes, queue) = &mut *guard;
+        while queue.len() >= MAX_PACKETS
+            || *bytes + buf.len() > MAX_BYTES
+            || queue.front().map_or(false, |v| now - v.2 > MAX_AGE)
+        {
+            let Some((_, old, _)) = queue.pop_front() else {
+                break;
+            };
+            *bytes -= old.len();
+        }
+        *bytes += buf.len();
+        queue.push_back((dest, self.pool.copy_from(buf), now));
+    }
+    /// 取出所有没有过期的包
+    pub fn take(&self) -> Vec<(Ipv4Addr, PooledBuf)> {
+        let now = Instant::now();
+        let mut guard = self.queue.lock();
+        guard.0 = 0;
+        guard
+            .1
+            .drain(..)
+            .filter(|v| now - v.2 <= MAX_AGE)
+            .map(|(dest, buf, _)| (dest, buf))
+            .collect()
+    }
+    pub fn clear(&self) {
+        let mut guard = self.queue.lock();
+        guard.0 = 0;
+        guard.1.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bounded() {
+        let pending = PendingPackets::new(BufferPool::default());
+        let dest = Ipv4Addr::new(10, 26, 0, 3);
+        for i in 0..MAX_PACKETS + 10 {
+            pending.push(dest, &(i as u32).to_be_bytes());
+        }
+        let list = pending.take();
+        assert_eq!(list.len(), MAX_PACKETS);
+        // 丢弃的是最早的
+        assert_eq!(&list[0].1[..], 10u32.to_be_bytes());
+        assert!(pending.take().is_empty());
+        pending.push(dest, &vec![0; MAX_BYTES]);
+        pending.push(dest, &[1]);
+        let list = pending.take();
+        assert_eq!(list.len(), 1);
+        assert_eq!((list[0].0, &list[0].1[..]), (dest, &[1u8][..]));
+    }
+}
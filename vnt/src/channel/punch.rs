@@ -1,5 +1,5 @@
 use std::collections::HashMap;
-use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
 use std::ops::{Div, Mul};
 use std::str::FromStr;
 use std::sync::Arc;
@@ -10,11 +10,14 @@ use crossbeam_utils::atomic::AtomicCell;
 use rand::prelude::SliceRandom;
 use rand::Rng;
 
+use crate::channel::candidate;
+use crate::channel::candidate::{gather, Candidate, CandidateChecker, CandidateKind, CHECK_PACING};
 use crate::channel::context::ChannelContext;
+use crate::channel::punch_telemetry::PunchTelemetry;
 use crate::channel::sender::ConnectUtil;
 use crate::handle::CurrentDeviceInfo;
 use crate::nat::{is_ipv4_global, NatTest};
-use crate::proto::message::{PunchNatModel, PunchNatType};
+use crate::proto::message::{PunchNatBehavior, PunchNatModel, PunchNatType, PunchPortModel};
 
 #[derive(Copy, Clone, Eq, PartialEq, Debug)]
 pub enum PunchModel {
@@ -113,6 +116,22 @@ pub struct NatInfo {
     pub tcp_port: u16,
     pub public_tcp_port: u16,
     pub punch_model: PunchModel,
+    // RFC 5780 映射行为和过滤行为
+    pub mapping: NatBehavior,
+    pub filtering: NatBehavior,
+    // 是否支持回环(从nat内部访问自己的公网映射地址)，None表示未知
+    pub hairpin: Option<bool>,
+    // 确认存活的nat绑定空闲时间(秒)，0表示未知
+    pub binding_lifetime: u32,
+    // 对称网络的端口分配规律
+    pub port_model: PortModel,
+    // 网关(NAT-PMP/PCP)映射出的公网地址
+    pub mapped_udp: Vec<SocketAddrV4>,
+    pub mapped_tcp: Option<SocketAddrV4>,
+    // TURN服务器分配的中继地址
+    pub relayed: Vec<SocketAddrV4>,
+    // 对端发来的候选地址，旧版本为空
+    pub candidates: Vec<Candidate>,
 }
 
 #[derive(Clone, Copy, PartialEq, Eq, Debug, Hash)]
@@ -144,6 +163,118 @@ impl Into<NatType> for PunchNatType {
     }
 }
 
+/// 对称网络的公网端口分配规律
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Hash)]
+pub enum PortModel {
+    Unknown,
+    /// 公网端口和本地端口相同
+    Preserve,
+    /// 每次新建映射端口按固定步长递增
+    Sequential(i32),
+    /// 同一个socket每个新目的地址分配的端口按固定步长递增
+    PerDestination(i32),
+    Random,
+}
+
+impl PortModel {
+    /// 可以按步长预测的端口变化
+    pub fn delta(&self) -> Option<i32> {
+        match self {
+            PortModel::Sequential(delta) | PortModel::PerDestination(delta) => Some(*delta),
+            _ => None,
+        }
+    }
+    pub fn name(&self) -> &'static str {
+        match self {
+            PortModel::Unknown => "Unknown",
+            PortModel::Preserve => "Preserve",
+            PortModel::Sequential(_) => "Sequential",
+            PortModel::PerDestination(_) => "PerDestination",
+            PortModel::Random => "Random",
+        }
+    }
+    pub fn into_proto(self) -> (PunchPortModel, i32) {
+        match self {
+            PortModel::Unknown => (PunchPortModel::UnknownPort, 0),
+            PortModel::Preserve => (PunchPortModel::Preserve, 0),
+            PortModel::Sequential(delta) => (PunchPortModel::Sequential, delta),
+            PortModel::PerDestination(delta) => (PunchPortModel::PerDestination, delta),
+            PortModel::Random => (PunchPortModel::Random, 0),
+        }
+    }
+    pub fn from_proto(model: PunchPortModel, delta: i32) -> Self {
+        match model {
+            PunchPortModel::UnknownPort => PortModel::Unknown,
+            PunchPortModel::Preserve => PortModel::Preserve,
+            PunchPortModel::Sequential if delta != 0 => PortModel::Sequential(delta),
+            PunchPortModel::PerDestination if delta != 0 => PortModel::PerDestination(delta),
+            PunchPortModel::Random => PortModel::Random,
+            _ => PortModel::Unknown,
+        }
+    }
+}
+
+/// 对称网络打洞时优先尝试的预测端口数
+const PREDICT_PORT_NUM: usize = 32;
+
+/// 按步长从基准端口向后预测num个端口，跳过0和越界的端口
+pub fn predict_ports(base: u16, delta: i32, num: usize) -> Vec<u16> {
+    let mut ports = Vec::with_capacity(num);
+    if base == 0 || delta == 0 {
+        return ports;
+    }
+    let mut port = base as i32;
+    for _ in 0..num {
+        port += delta;
+        if port <= 0 || port > u16::MAX as i32 {
+            break;
+        }
+        ports.push(port as u16);
+    }
+    ports
+}
+
+/// RFC 5780 定义的映射/过滤行为
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Hash)]
+pub enum NatBehavior {
+    Unknown,
+    EndpointIndependent,
+    AddressDependent,
+    AddressAndPortDependent,
+}
+
+impl NatBehavior {
+    pub fn is_unknown(&self) -> bool {
+        self == &NatBehavior::Unknown
+    }
+    /// 过滤行为不限制来源端口，对方只要向自己的ip发过包就能收到任意端口的数据
+    pub fn is_port_independent(&self) -> bool {
+        self == &NatBehavior::EndpointIndependent || self == &NatBehavior::AddressDependent
+    }
+}
+
+impl From<NatBehavior> for PunchNatBehavior {
+    fn from(value: NatBehavior) -> Self {
+        match value {
+            NatBehavior::Unknown => PunchNatBehavior::Unknown,
+            NatBehavior::EndpointIndependent => PunchNatBehavior::EndpointIndependent,
+            NatBehavior::AddressDependent => PunchNatBehavior::AddressDependent,
+            NatBehavior::AddressAndPortDependent => PunchNatBehavior::AddressAndPortDependent,
+        }
+    }
+}
+
+impl Into<NatBehavior> for PunchNatBehavior {
+    fn into(self) -> NatBehavior {
+        match self {
+            PunchNatBehavior::Unknown => NatBehavior::Unknown,
+            PunchNatBehavior::EndpointIndependent => NatBehavior::EndpointIndependent,
+            PunchNatBehavior::AddressDependent => NatBehavior::AddressDependent,
+            PunchNatBehavior::AddressAndPortDependent => NatBehavior::AddressAndPortDependent,
+        }
+    }
+}
+
 impl NatInfo {
     pub fn new(
         mut public_ips: Vec<Ipv4Addr>,
@@ -188,8 +319,56 @@ impl NatInfo {
             public_tcp_port,
             nat_type,
             punch_model,
+            mapping: NatBehavior::Unknown,
+            filtering: NatBehavior::Unknown,
+            hairpin: None,
+            binding_lifetime: 0,
+            port_model: PortModel::Unknown,
+            mapped_udp: Vec::new(),
+            mapped_tcp: None,
+            relayed: Vec::new(),
+            candidates: Vec::new(),
         }
     }
+    /// 设置RFC 5780探测到的nat行为
+    pub fn with_behavior(
+        mut self,
+        mapping: NatBehavior,
+        filtering: NatBehavior,
+        hairpin: Option<bool>,
+        binding_lifetime: u32,
+    ) -> Self {
+        self.mapping = mapping;
+        self.filtering = filtering;
+        self.hairpin = hairpin;
+        self.binding_lifetime = binding_lifetime;
+        self
+    }
+    /// 设置对端的端口分配模型
+    pub fn with_port_model(mut self, port_model: PortModel) -> Self {
+        self.port_model = port_model;
+        self
+    }
+    /// 设置网关映射的公网地址
+    pub fn with_mapped(
+        mut self,
+        mapped_udp: Vec<SocketAddrV4>,
+        mapped_tcp: Option<SocketAddrV4>,
+    ) -> Self {
+        self.mapped_udp = mapped_udp;
+        self.mapped_tcp = mapped_tcp;
+        self
+    }
+    /// 设置TURN中继地址
+    pub fn with_relayed(mut self, relayed: Vec<SocketAddrV4>) -> Self {
+        self.relayed = relayed;
+        self
+    }
+    /// 设置对端的候选地址
+    pub fn with_candidates(mut self, candidates: Vec<Candidate>) -> Self {
+        self.candidates = candidates;
+        self
+    }
     pub fn update_addr(&mut self, index: usize, ip: Ipv4Addr, port: u16) -> bool {
         let mut updated = false;
         if port != 0 {
@@ -281,6 +460,8 @@ pub struct Punch {
     connect_util: ConnectUtil,
     nat_test: NatTest,
     current_device: Arc<AtomicCell<CurrentDeviceInfo>>,
+    telemetry: PunchTelemetry,
+    checker: CandidateChecker,
 }
 
 impl Punch {
@@ -290,6 +471,7 @@ impl Punch {
         connect_util: ConnectUtil,
         nat_test: NatTest,
         current_device: Arc<AtomicCell<CurrentDeviceInfo>>,
+        telemetry: PunchTelemetry,
     ) -> Self {
         let mut port_vec: Vec<u16> = (1..65535).collect();
         port_vec.push(65535);
@@ -303,8 +485,13 @@ impl Punch {
             connect_util,
             nat_test,
             current_device,
+            telemetry,
+            checker: CandidateChecker::default(),
         }
     }
+    pub fn checker(&self) -> &CandidateChecker {
+        &self.checker
+    }
 }
 
 impl Punch {
@@ -325,7 +512,9 @@ impl Punch {
         punch_tcp: bool,
         count: usize,
     ) -> io::Result<()> {
-        if self.context.route_table.no_need_punch(&id) {
+        self.telemetry.settle(&self.context.route_table);
+        self.checker.nominate(&self.context.route_table);
+        if self.context.route_table.no_need_punch(&id) && !self.checker.needs_recheck(&id) {
             log::info!("已打洞成功,无需打洞:{:?}", id);
             return Ok(());
         }
@@ -336,152 +525,223 @@ impl Punch {
             .retain(|ip| is_ipv4_global(ip) && device_info.not_in_network(*ip));
         nat_info.public_ports.retain(|port| *port != 0);
         nat_info.udp_ports.retain(|port| *port != 0);
-
         nat_info.local_ipv4 = nat_info
             .local_ipv4
             .filter(|ip| device_info.not_in_network(*ip));
-        if punch_tcp && self.punch_model.use_tcp() && nat_info.punch_model.use_tcp() {
-            //向tcp发起连接
-            if self.punch_model.use_ipv6() && nat_info.punch_model.use_ipv6() {
-                if let Some(ipv6_addr) = nat_info.local_tcp_ipv6addr() {
-                    self.connect_tcp(buf, ipv6_addr)
-                }
+        let mut same_nat = false;
+        {
+            // 双方在同一个nat后面，且确认nat不支持回环，发往公网地址的包不会回到内网，
+            // 探测结果未知时仍然尝试公网地址
+            let local_nat = self.nat_test.nat_info();
+            if local_nat.hairpin == Some(false)
+                && nat_info
+                    .public_ips
+                    .iter()
+                    .any(|ip| local_nat.public_ips.contains(ip))
+            {
+                log::info!("同一nat下且不支持回环,只使用内网地址打洞:{:?}", id);
+                nat_info.public_ips.clear();
+                same_nat = true;
             }
-            if self.punch_model.use_ipv4() && nat_info.punch_model.use_ipv4() {
-                if let Some(ipv4_addr) = nat_info.local_tcp_ipv4addr() {
-                    self.connect_tcp(buf, ipv4_addr)
-                }
-                for ip in &nat_info.public_ips {
-                    let addr = SocketAddr::V4(SocketAddrV4::new(*ip, nat_info.tcp_port));
-                    self.connect_tcp(buf, addr);
-                }
-                if nat_info.nat_type.is_cone() && nat_info.public_tcp_port != 0 {
-                    for ip in &nat_info.public_ips {
-                        let addr = SocketAddr::V4(SocketAddrV4::new(*ip, nat_info.public_tcp_port));
-                        self.connect_tcp(buf, addr);
-                    }
+        }
+        // 旧版本没有候选列表，从其他字段推导
+        let candidates = if nat_info.candidates.is_empty() {
+            gather(&nat_info)
+        } else {
+            std::mem::take(&mut nat_info.candidates)
+        };
+        // 双方都是对称网络或多次打洞失败，才经TURN中继连接
+        let use_relay =
+            (!self.context.is_cone() && nat_info.nat_type == NatType::Symmetric) || count > 2;
+        let candidates: Vec<Candidate> = candidates
+            .into_iter()
+            .filter(|v| use_relay || v.kind != CandidateKind::Relayed)
+            .filter(|v| self.usable(v, &nat_info, &device_info, same_nat, punch_tcp))
+            .collect();
+        // 虚拟ip小的一方作为控制方，两端算出的候选对顺序一致
+        let pairs = candidate::pairs(&candidates, device_info.virtual_ip < id);
+        self.checker.record(id, pairs.clone());
+        // 对端可能从公网ip、stun探测到的映射地址或它自己的中继地址发往本地的中继地址，
+        // 都需要先创建权限
+        #[cfg(feature = "turn")]
+        if use_relay {
+            let mut ips = nat_info.public_ips.clone();
+            for addr in nat_info.mapped_udp.iter().chain(nat_info.relayed.iter()) {
+                if !ips.contains(addr.ip()) {
+                    ips.push(*addr.ip());
                 }
             }
+            self.context.turn().create_permission(&ips);
+        }
+        for pair in &pairs {
+            self.check(buf, &pair.remote, &nat_info);
+            thread::sleep(CHECK_PACING);
         }
-        if !self.punch_model.use_udp() || !nat_info.punch_model.use_udp() {
+        if !self.punch_model.use_udp()
+            || !nat_info.punch_model.use_udp()
+            || !self.punch_model.use_ipv4()
+            || !nat_info.punch_model.use_ipv4()
+        {
             return Ok(());
         }
-        let channel_num = self.context.channel_num();
-        let main_len = self.context.main_len();
+        if nat_info.nat_type.is_cone() {
+            // 锥形网络的公网地址已经在候选地址中检查过了
+            self.telemetry.record(id, nat_info.port_model, &[]);
+            return Ok(());
+        }
+        // 假设对方绑定n个端口，通过NAT对外映射出n个 公网ip:公网端口，自己随机尝试k次的情况下
+        // 猜中的概率 p = 1-((65535-n)/65535)*((65535-n-1)/(65535-1))*...*((65535-n-k+1)/(65535-k+1))
+        // n取76，k取600，猜中的概率就超过50%了
+        // 前提 自己是锥形网络，否则猜中了也通信不了
 
-        if self.punch_model.use_ipv6() && nat_info.punch_model.use_ipv6() {
-            for index in channel_num..main_len {
-                if let Some(ipv6_addr) = nat_info.local_udp_ipv6addr(index) {
-                    if !self.nat_test.is_local_address(false, ipv6_addr) {
-                        let rs = self.context.send_main_udp(index, buf, ipv6_addr);
-                        log::info!("发送到ipv6地址:{:?},rs={:?} {}", ipv6_addr, rs, id);
-                    }
-                }
-            }
+        //预测范围内最多发送max_k1个包
+        let max_k1 = 60;
+        //全局最多发送max_k2个包
+        let mut max_k2: usize = rand::thread_rng().gen_range(600..800);
+        if count > 2 {
+            //递减探测规模
+            max_k2 = max_k2.mul(2).div(count).max(max_k1 as usize);
         }
-        if !self.punch_model.use_ipv4() || !nat_info.punch_model.use_ipv4() {
-            return Ok(());
+        let port = nat_info.public_ports.get(0).map(|e| *e).unwrap_or(0);
+        if let Some(delta) = nat_info.port_model.delta() {
+            //端口按固定步长分配时，优先尝试预测的端口
+            let predicted = predict_ports(port, delta, PREDICT_PORT_NUM);
+            self.telemetry.record(id, nat_info.port_model, &predicted);
+            self.punch_symmetric(&predicted, buf, &nat_info.public_ips, max_k1 as usize)?;
+        } else {
+            self.telemetry.record(id, nat_info.port_model, &[]);
         }
-        for index in 0..channel_num {
-            if let Some(ipv4_addr) = nat_info.local_udp_ipv4addr(index) {
-                if !self.nat_test.is_local_address(false, ipv4_addr) {
-                    let _ = self.context.send_main_udp(index, buf, ipv4_addr);
-                }
+        if nat_info.public_port_range < max_k1 * 3 {
+            //端口变化不大时，在预测的范围内随机发送
+            let min_port = if port > nat_info.public_port_range {
+                port - nat_info.public_port_range
+            } else {
+                1
+            };
+            let (max_port, overflow) = port.overflowing_add(nat_info.public_port_range);
+            let max_port = if overflow { 65535 } else { max_port };
+            let k = if max_port - min_port + 1 > max_k1 {
+                max_k1 as usize
+            } else {
+                (max_port - min_port + 1) as usize
+            };
+            let mut nums: Vec<u16> = (min_port..=max_port).collect();
+            nums.shuffle(&mut rand::thread_rng());
+            self.punch_symmetric(&nums[..k], buf, &nat_info.public_ips, max_k1 as usize)?;
+        }
+        let start = *self.port_index.entry(id.clone()).or_insert(0);
+        let mut end = start + max_k2;
+        if end > self.port_vec.len() {
+            end = self.port_vec.len();
+        }
+        let mut index = start
+            + self.punch_symmetric(
+                &self.port_vec[start..end],
+                buf,
+                &nat_info.public_ips,
+                max_k2,
+            )?;
+        if index >= self.port_vec.len() {
+            index = 0
+        }
+        self.port_index.insert(id, index);
+        Ok(())
+    }
+    /// 过滤对端不可用的候选地址
+    fn usable(
+        &self,
+        candidate: &Candidate,
+        nat_info: &NatInfo,
+        device_info: &CurrentDeviceInfo,
+        same_nat: bool,
+        punch_tcp: bool,
+    ) -> bool {
+        if candidate.tcp {
+            if !punch_tcp || !self.punch_model.use_tcp() || !nat_info.punch_model.use_tcp() {
+                return false;
             }
+        } else if !self.punch_model.use_udp() || !nat_info.punch_model.use_udp() {
+            return false;
         }
-        // 可能是开放了端口的，需要打洞
-        for index in 0..channel_num {
-            for port in &nat_info.udp_ports {
-                if *port == 0 {
-                    continue;
+        match candidate.addr.ip() {
+            IpAddr::V4(ip) => {
+                if !self.punch_model.use_ipv4()
+                    || !nat_info.punch_model.use_ipv4()
+                    || !device_info.not_in_network(ip)
+                {
+                    return false;
                 }
-                for ip in &nat_info.public_ips {
-                    if ip.is_unspecified() {
-                        continue;
+                match candidate.kind {
+                    CandidateKind::Host => !self
+                        .nat_test
+                        .is_local_address(candidate.tcp, candidate.addr),
+                    CandidateKind::ServerReflexive | CandidateKind::Mapped => {
+                        !same_nat && is_ipv4_global(&ip)
                     }
-                    let addr = SocketAddrV4::new(*ip, *port);
-                    let _ = self.context.send_main_udp(index, buf, addr.into());
-                    thread::sleep(Duration::from_millis(3));
+                    CandidateKind::Relayed => true,
                 }
             }
+            IpAddr::V6(_) => {
+                self.punch_model.use_ipv6()
+                    && nat_info.punch_model.use_ipv6()
+                    && !self
+                        .nat_test
+                        .is_local_address(candidate.tcp, candidate.addr)
+            }
         }
-
-        match nat_info.nat_type {
-            NatType::Symmetric => {
-                // 假设对方绑定n个端口，通过NAT对外映射出n个 公网ip:公网端口，自己随机尝试k次的情况下
-                // 猜中的概率 p = 1-((65535-n)/65535)*((65535-n-1)/(65535-1))*...*((65535-n-k+1)/(65535-k+1))
-                // n取76，k取600，猜中的概率就超过50%了
-                // 前提 自己是锥形网络，否则猜中了也通信不了
-
-                //预测范围内最多发送max_k1个包
-                let max_k1 = 60;
-                //全局最多发送max_k2个包
-                let mut max_k2: usize = rand::thread_rng().gen_range(600..800);
-                if count > 2 {
-                    //递减探测规模
-                    max_k2 = max_k2.mul(2).div(count).max(max_k1 as usize);
-                }
-                let port = nat_info.public_ports.get(0).map(|e| *e).unwrap_or(0);
-                if nat_info.public_port_range < max_k1 * 3 {
-                    //端口变化不大时，在预测的范围内随机发送
-                    let min_port = if port > nat_info.public_port_range {
-                        port - nat_info.public_port_range
-                    } else {
-                        1
-                    };
-                    let (max_port, overflow) = port.overflowing_add(nat_info.public_port_range);
-                    let max_port = if overflow { 65535 } else { max_port };
-                    let k = if max_port - min_port + 1 > max_k1 {
-                        max_k1 as usize
-                    } else {
-                        (max_port - min_port + 1) as usize
-                    };
-                    let mut nums: Vec<u16> = (min_port..=max_port).collect();
-                    nums.shuffle(&mut rand::thread_rng());
-                    self.punch_symmetric(&nums[..k], buf, &nat_info.public_ips, max_k1 as usize)?;
-                }
-                let start = *self.port_index.entry(id.clone()).or_insert(0);
-                let mut end = start + max_k2;
-                if end > self.port_vec.len() {
-                    end = self.port_vec.len();
+    }
+    /// 向候选地址发送检查，对端回应PunchResponse后建立路由
+    fn check(&self, buf: &[u8], candidate: &Candidate, nat_info: &NatInfo) {
+        if candidate.tcp {
+            self.connect_tcp(buf, candidate.addr);
+            return;
+        }
+        let channel_num = self.context.channel_num();
+        if candidate.addr.is_ipv6() {
+            // ipv6的socket排在ipv4后面，端口一一对应
+            let v6_len = self.context.main_len() - channel_num;
+            if v6_len == 0 {
+                return;
+            }
+            let indexes = match candidate.socket_index {
+                Some(index) => index % v6_len..index % v6_len + 1,
+                None => 0..v6_len,
+            };
+            for index in indexes {
+                let _ = self
+                    .context
+                    .send_main_udp(channel_num + index, buf, candidate.addr);
+            }
+            return;
+        }
+        match candidate.socket_index {
+            Some(index)
+                if candidate.kind == CandidateKind::ServerReflexive && !self.context.is_cone() =>
+            {
+                //对称网络数据只发一遍
+                if index != 0 {
+                    return;
                 }
-                let mut index = start
-                    + self.punch_symmetric(
-                        &self.port_vec[start..end],
-                        buf,
-                        &nat_info.public_ips,
-                        max_k2,
-                    )?;
-                if index >= self.port_vec.len() {
-                    index = 0
+                if nat_info.nat_type.is_cone() && !nat_info.filtering.is_port_independent() {
+                    //只有一方是对称，则对称方要使用全部端口发送数据，符合上述计算的概率
+                    self.context.try_send_all(buf, candidate.addr);
+                } else {
+                    //对方的过滤不限制端口，对方向自己的ip发过包后，从任意端口发送都能到达
+                    let _ = self.context.send_main_udp(0, buf, candidate.addr);
                 }
-                self.port_index.insert(id, index);
             }
-            NatType::Cone => {
-                let is_cone = self.context.is_cone();
-                'a: for index in 0..nat_info.public_ports.len().min(channel_num) {
-                    for ip in &nat_info.public_ips {
-                        let port = nat_info.public_ports[index];
-                        if port == 0 || ip.is_unspecified() {
-                            continue;
-                        }
-                        let addr = SocketAddr::V4(SocketAddrV4::new(*ip, port));
-                        if is_cone {
-                            self.context.send_main_udp(index, buf, addr)?;
-                        } else {
-                            //只有一方是对称，则对称方要使用全部端口发送数据，符合上述计算的概率
-                            self.context.try_send_all(buf, addr);
-                        }
-                        thread::sleep(Duration::from_millis(2));
-                    }
-                    if !is_cone {
-                        //对称网络数据只发一遍
-                        break 'a;
-                    }
+            Some(index) => {
+                // 对端的socket比本地多时，取模映射到本地的socket
+                let _ = self
+                    .context
+                    .send_main_udp(index % channel_num, buf, candidate.addr);
+            }
+            None => {
+                for index in 0..channel_num {
+                    let _ = self.context.send_main_udp(index, buf, candidate.addr);
                 }
             }
         }
-        Ok(())
     }
 
     fn punch_symmetric(
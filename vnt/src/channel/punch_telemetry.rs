@@ -0,0 +1,95 @@
+//! 打洞结果统计，按对端的端口分配模型记录每次打洞是否成功、是否命中预测端口
+use std::collections::{HashMap, HashSet};
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+use crate::channel::context::RouteTable;
+use crate::channel::punch::PortModel;
+
+/// 超过这个时间还没有p2p路由，视为本次打洞失败
+const ATTEMPT_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Debug, Default)]
+pub struct PunchStat {
+    /// 对端的端口分配模型
+    pub model: &'static str,
+    pub attempts: u64,
+    pub success: u64,
+    /// 成功的连接使用的是预测出的端口
+    pub predicted_hits: u64,
+}
+
+struct Attempt {
+    model: PortModel,
+    predicted: HashSet<u16>,
+    start: Instant,
+}
+
+#[derive(Default)]
+struct TelemetryInner {
+    pending: HashMap<Ipv4Addr, Attempt>,
+    stats: HashMap<&'static str, PunchStat>,
+}
+
+#[derive(Clone, Default)]
+pub struct PunchTelemetry {
+    inner: Arc<Mutex<TelemetryInner>>,
+}
+
+impl PunchTelemetry {
+    /// 记录一次打洞，同一个对端未出结果前的多轮打洞合并为一次
+    pub fn record(&self, id: Ipv4Addr, model: PortModel, predicted: &[u16]) {
+        let mut guard = self.inner.lock();
+        let attempt = guard.pending.entry(id).or_insert_with(|| Attempt {
+            model,
+            predicted: HashSet::new(),
+            start: Instant::now(),
+        });
+        attempt.model = model;
+        attempt.predicted.extend(predicted);
+    }
+    /// 根据路由表结算进行中的打洞
+    pub fn settle(&self, route_table: &RouteTable) {
+        let mut guard = self.inner.lock();
+        let TelemetryInner { pending, stats } = &mut *guard;
+        pending.retain(|id, attempt| {
+            let route = route_table.route_one_p2p(id);
+            if route.is_none() && attempt.start.elapsed() < ATTEMPT_TIMEOUT {
+                return true;
+            }
+            let stat = stats
+                .entry(attempt.model.name())
+                .or_insert_with(|| PunchStat {
+                    model: attempt.model.name(),
+                    ..Default::default()
+                });
+            stat.attempts += 1;
+            if let Some(route) = route {
+                stat.success += 1;
+                let hit = attempt.predicted.contains(&route.addr.port());
+                if hit {
+                    stat.predicted_hits += 1;
+                }
+                log::info!(
+                    "打洞成功 {} model={:?} addr={} predicted={} time={:?}",
+                    id,
+                    attempt.model,
+                    route.addr,
+                    hit,
+                    attempt.start.elapsed()
+                );
+            } else {
+                log::info!("打洞失败 {} model={:?}", id, attempt.model);
+            }
+            false
+        });
+    }
+    pub fn stats(&self) -> Vec<PunchStat> {
+        let mut list: Vec<PunchStat> = self.inner.lock().stats.values().cloned().collect();
+        list.sort_by_key(|v| v.model);
+        list
+    }
+}
@@ -12,10 +12,11 @@ use crate::channel::context::ChannelContext;
 use crate::channel::notify::AcceptNotify;
 use crate::cipher::Cipher;
 use crate::compression::Compressor;
-use crate::external_route::ExternalRoute;
+use crate::external_route::{flow_hash, ExternalRoute};
 use crate::handle::{CurrentDeviceInfo, PeerDeviceInfo};
 use crate::protocol;
 use crate::protocol::{ip_turn_packet, NetPacket};
+use crate::util::{BufferPool, SharedBuf};
 
 #[derive(Clone)]
 pub struct IpPacketSender {
@@ -66,7 +67,7 @@ impl IpPacketSender {
         if src_ip.is_unspecified() {
             return Ok(());
         }
-        if let Some(v) = self.ip_route.route(&dest_ip) {
+        if let Some(v) = self.ip_route.route(&dest_ip, flow_hash(&buf[12..data_len])) {
             dest_ip = v;
         }
         if dest_ip.is_multicast() {
@@ -215,15 +216,19 @@ impl<T> AcceptSocketSender<T> {
 }
 #[derive(Clone)]
 pub struct PacketSender {
-    sender: Sender<Vec<u8>>,
+    sender: Sender<SharedBuf>,
+    pool: BufferPool,
 }
 
 impl PacketSender {
-    pub fn new(sender: Sender<Vec<u8>>) -> Self {
-        Self { sender }
+    pub fn new(sender: Sender<SharedBuf>, pool: BufferPool) -> Self {
+        Self { sender, pool }
     }
     pub fn try_send(&self, buf: &[u8]) -> io::Result<()> {
-        match self.sender.try_send(buf.to_vec()) {
+        self.try_send_shared(self.pool.copy_from(buf).freeze())
+    }
+    pub fn try_send_shared(&self, buf: SharedBuf) -> io::Result<()> {
+        match self.sender.try_send(buf) {
             Ok(_) => Ok(()),
             Err(tokio::sync::mpsc::error::TrySendError::Full(_)) => Err(io::Error::new(
                 io::ErrorKind::WouldBlock,
@@ -12,6 +12,10 @@ pub trait VntSocketTrait {
     fn set_ip_unicast_if(&self, _interface: &LocalInterface) -> anyhow::Result<()> {
         Ok(())
     }
+    /// windows下udp收到icmp端口不可达后recv会返回WSAECONNRESET，打洞时会频繁出现，需要关闭
+    fn disable_udp_connreset(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
 }
 
 #[derive(Clone, Debug, Default)]
@@ -105,6 +109,9 @@ pub fn bind_udp_ops(
             .with_context(|| format!("set_only_v6 failed: {}", &addr))?;
         socket
     };
+    if let Err(e) = socket.disable_udp_connreset() {
+        log::warn!("disable_udp_connreset {:?}", e)
+    }
     socket.set_nonblocking(true)?;
     socket.bind(&addr.into())?;
     Ok(socket)
@@ -116,6 +123,34 @@ pub fn bind_udp(
     bind_udp_ops(addr, true, default_interface).with_context(|| format!("{}", addr))
 }
 
+/// 访问dest时使用的网卡，用于默认路由改变前记录物理网卡
+pub fn get_default_interface(dest: Ipv4Addr) -> anyhow::Result<(LocalInterface, Ipv4Addr)> {
+    // udp connect不会发送数据，只是选出本地地址
+    let socket = std::net::UdpSocket::bind("0.0.0.0:0")?;
+    socket.connect(SocketAddr::new(IpAddr::V4(dest), 53))?;
+    let IpAddr::V4(local_ip) = socket.local_addr()?.ip() else {
+        return Err(anyhow!("not ipv4"));
+    };
+    let network_interfaces = NetworkInterface::show()?;
+    for iface in network_interfaces {
+        if iface
+            .addr
+            .iter()
+            .any(|addr| addr.ip() == IpAddr::V4(local_ip))
+        {
+            return Ok((
+                LocalInterface {
+                    index: iface.index,
+                    #[cfg(unix)]
+                    name: Some(iface.name),
+                },
+                local_ip,
+            ));
+        }
+    }
+    Err(anyhow!("No network card with ip {} found", local_ip))
+}
+
 pub fn get_interface(dest_name: String) -> anyhow::Result<(LocalInterface, Ipv4Addr)> {
     let network_interfaces = NetworkInterface::show()?;
     for iface in network_interfaces {
@@ -3,7 +3,7 @@ use std::os::windows::io::AsRawSocket;
 
 use windows_sys::core::PCSTR;
 use windows_sys::Win32::Networking::WinSock::{
-    htonl, setsockopt, IPPROTO_IP, IP_UNICAST_IF, SOCKET_ERROR,
+    htonl, setsockopt, WSAIoctl, IPPROTO_IP, IP_UNICAST_IF, SIO_UDP_CONNRESET, SOCKET_ERROR,
 };
 
 use crate::channel::socket::{LocalInterface, VntSocketTrait};
@@ -34,6 +34,30 @@ impl VntSocketTrait for socket2::Socket {
         }
         Ok(())
     }
+    fn disable_udp_connreset(&self) -> anyhow::Result<()> {
+        let enable: u32 = 0;
+        let mut bytes_returned: u32 = 0;
+        let result = unsafe {
+            WSAIoctl(
+                self.as_raw_socket() as usize,
+                SIO_UDP_CONNRESET,
+                &enable as *const _ as *const core::ffi::c_void,
+                mem::size_of_val(&enable) as u32,
+                std::ptr::null_mut(),
+                0,
+                &mut bytes_returned,
+                std::ptr::null_mut(),
+                None,
+            )
+        };
+        if result == SOCKET_ERROR {
+            Err(anyhow::anyhow!(
+                "Failed to set SIO_UDP_CONNRESET: {:?}",
+                std::io::Error::last_os_error()
+            ))?;
+        }
+        Ok(())
+    }
 }
 
 // pub fn get_best_interface(dest_ip: Ipv4Addr) -> anyhow::Result<LocalInterface> {
@@ -1,10 +1,9 @@
-use anyhow::{anyhow, Context};
+use anyhow::anyhow;
 use std::net::SocketAddr;
 #[cfg(unix)]
 use std::os::unix::io::AsRawFd;
 #[cfg(windows)]
 use std::os::windows::io::AsRawSocket;
-use std::thread;
 use std::time::Duration;
 use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::tcp::OwnedReadHalf;
@@ -16,7 +15,8 @@ use crate::channel::handler::RecvChannelHandler;
 use crate::channel::sender::PacketSender;
 use crate::channel::socket::create_tcp0;
 use crate::channel::{ConnectProtocol, RouteKey, BUFFER_SIZE, TCP_MAX_PACKET_SIZE};
-use crate::util::StopManager;
+use crate::util::SharedBuf;
+use crate::util::VntRuntime;
 
 /// 监听tcp端口，等待客户端连接
 pub fn tcp_listen<H>(
@@ -24,45 +24,26 @@ pub fn tcp_listen<H>(
     receiver: Receiver<(Vec<u8>, Option<u16>, SocketAddr)>,
     recv_handler: H,
     context: ChannelContext,
-    stop_manager: StopManager,
+    runtime: &VntRuntime,
 ) -> anyhow::Result<()>
 where
     H: RecvChannelHandler,
 {
-    let (stop_sender, stop_receiver) = tokio::sync::oneshot::channel::<()>();
-    let worker = stop_manager.add_listener("tcpChannel".into(), move || {
-        let _ = stop_sender.send(());
-    })?;
     let bind_port = tcp_server.local_addr()?.port();
-    let runtime = tokio::runtime::Builder::new_multi_thread()
-        .worker_threads(2)
-        .enable_all()
-        .build()
-        .context("tcp tokio runtime build failed")?;
-    thread::Builder::new()
-        .name("tcpChannel".into())
-        .spawn(move || {
-            runtime.spawn(async move {
-                {
-                    let recv_handler = recv_handler.clone();
-                    let context = context.clone();
-                    tokio::spawn(async move {
-                        if let Err(e) = tcp_accept(tcp_server, recv_handler, context).await {
-                            log::warn!("tcp_listen {:?}", e);
-                        }
-                    });
-                }
-                tokio::spawn(async move {
-                    connect_tcp_handle(receiver, recv_handler, context, bind_port).await
-                });
-            });
-            runtime.block_on(async {
-                let _ = stop_receiver.await;
-            });
-            runtime.shutdown_background();
-            worker.stop_all();
-        })
-        .context("tcp thread build failed")?;
+    {
+        let recv_handler = recv_handler.clone();
+        let context = context.clone();
+        let task_runtime = runtime.clone();
+        runtime.spawn(async move {
+            if let Err(e) = tcp_accept(tcp_server, recv_handler, context, task_runtime).await {
+                log::warn!("tcp_listen {:?}", e);
+            }
+        });
+    }
+    let task_runtime = runtime.clone();
+    runtime.spawn(async move {
+        connect_tcp_handle(receiver, recv_handler, context, bind_port, task_runtime).await
+    });
     Ok(())
 }
 
@@ -71,6 +52,7 @@ async fn connect_tcp_handle<H>(
     recv_handler: H,
     context: ChannelContext,
     listener_bind_port: u16,
+    runtime: VntRuntime,
 ) where
     H: RecvChannelHandler,
 {
@@ -82,8 +64,11 @@ async fn connect_tcp_handle<H>(
         } else {
             listener_bind_port
         };
-        tokio::spawn(async move {
-            if let Err(e) = connect_tcp0(data, addr, recv_handler, context, bind_port).await {
+        let task_runtime = runtime.clone();
+        runtime.spawn(async move {
+            if let Err(e) =
+                connect_tcp0(data, addr, recv_handler, context, bind_port, task_runtime).await
+            {
                 log::warn!("连接失败,链接终止:{:?},{:?}", addr, e);
             }
         });
@@ -96,6 +81,7 @@ async fn connect_tcp0<H>(
     recv_handler: H,
     context: ChannelContext,
     bind_port: u16,
+    runtime: VntRuntime,
 ) -> anyhow::Result<()>
 where
     H: RecvChannelHandler,
@@ -114,7 +100,7 @@ where
     let mut stream = tokio::time::timeout(Duration::from_secs(3), socket.connect(addr)).await??;
     tcp_write(&mut stream, &data).await?;
 
-    tcp_stream_handle(stream, addr, recv_handler, context).await;
+    tcp_stream_handle(stream, addr, recv_handler, context, &runtime).await;
     Ok(())
 }
 
@@ -122,6 +108,7 @@ async fn tcp_accept<H>(
     tcp_server: std::net::TcpListener,
     recv_handler: H,
     context: ChannelContext,
+    runtime: VntRuntime,
 ) -> anyhow::Result<()>
 where
     H: RecvChannelHandler,
@@ -131,7 +118,14 @@ where
     loop {
         let (stream, addr) = tcp_server.accept().await?;
 
-        tcp_stream_handle(stream, addr, recv_handler.clone(), context.clone()).await;
+        tcp_stream_handle(
+            stream,
+            addr,
+            recv_handler.clone(),
+            context.clone(),
+            &runtime,
+        )
+        .await;
     }
 }
 
@@ -140,6 +134,7 @@ pub async fn tcp_stream_handle<H>(
     addr: SocketAddr,
     recv_handler: H,
     context: ChannelContext,
+    runtime: &VntRuntime,
 ) where
     H: RecvChannelHandler,
 {
@@ -151,12 +146,12 @@ pub async fn tcp_stream_handle<H>(
     let index = stream.as_raw_fd() as usize;
     let route_key = RouteKey::new(ConnectProtocol::TCP, index, addr);
     let (r, mut w) = stream.into_split();
-    let (sender, mut receiver) = channel::<Vec<u8>>(100);
-    context
-        .packet_map
-        .write()
-        .insert(route_key, PacketSender::new(sender));
-    tokio::spawn(async move {
+    let (sender, mut receiver) = channel::<SharedBuf>(100);
+    context.packet_map.write().insert(
+        route_key,
+        PacketSender::new(sender, context.buffer_pool().clone()),
+    );
+    runtime.spawn(async move {
         while let Some(data) = receiver.recv().await {
             if let Err(e) = tcp_write(&mut w, &data).await {
                 log::info!("发送失败,tcp链接终止:{:?},{:?}", addr, e);
@@ -165,7 +160,7 @@ pub async fn tcp_stream_handle<H>(
         }
         let _ = w.shutdown().await;
     });
-    tokio::spawn(async move {
+    runtime.spawn(async move {
         if let Err(e) = tcp_read(r, addr, &context, recv_handler, route_key).await {
             log::warn!("tcp_read {:?} {local:?}-{addr}", e)
         }
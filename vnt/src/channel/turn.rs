@@ -0,0 +1,675 @@
+//! 标准TURN(RFC 5766/8656)客户端
+//!
+//! 双方都无法打洞时，在配置的TURN服务器上分配中继地址，通过PunchInfo告诉对端，
+//! 流量由就近的TURN服务器(如coturn)转发，不占用vnt服务器
+use std::collections::HashMap;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, ToSocketAddrs, UdpSocket};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use hmac::{Hmac, Mac};
+use md5::{Digest, Md5};
+use parking_lot::{Mutex, RwLock};
+use rand::Rng;
+use sha1::Sha1;
+use stun_format::{Attr, Msg, MsgBuilder, MsgType, TransportProtocol};
+
+use crate::channel::context::ChannelContext;
+use crate::channel::handler::RecvChannelHandler;
+use crate::channel::socket::bind_udp;
+use crate::channel::{ConnectProtocol, RouteKey, BUFFER_SIZE};
+use crate::util::StopManager;
+
+const MAGIC_COOKIE: u32 = 0x2112A442;
+
+const METHOD_ALLOCATE: u16 = 0x0003;
+const METHOD_REFRESH: u16 = 0x0004;
+const METHOD_SEND: u16 = 0x0006;
+const METHOD_DATA: u16 = 0x0007;
+const METHOD_CREATE_PERMISSION: u16 = 0x0008;
+const CLASS_INDICATION: u16 = 0x0010;
+const CLASS_SUCCESS: u16 = 0x0100;
+
+/// 请求的分配存活时间
+const ALLOCATION_LIFETIME: Duration = Duration::from_secs(600);
+/// 权限有效期是5分钟，提前续期
+const PERMISSION_LIFETIME: Duration = Duration::from_secs(300);
+const PERMISSION_REFRESH: Duration = Duration::from_secs(240);
+/// 一次CreatePermission最多携带的地址数，避免请求超过缓冲区
+const PERMISSION_BATCH: usize = 16;
+/// 等待后台创建的权限请求数，满了就丢弃，下次打洞会重新提交
+const PERMISSION_QUEUE: usize = 32;
+/// 请求重传间隔
+const RETRANSMIT: [Duration; 3] = [
+    Duration::from_millis(500),
+    Duration::from_millis(1000),
+    Duration::from_millis(2000),
+];
+
+/// TURN服务器配置，格式 user:password@host:port
+#[derive(Clone, Debug)]
+pub struct TurnServer {
+    pub address: String,
+    pub username: String,
+    pub password: String,
+}
+
+impl FromStr for TurnServer {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let s = s.strip_prefix("turn:").unwrap_or(s);
+        let Some((user_info, address)) = s.rsplit_once('@') else {
+            return Err(format!("'{}' format: user:password@host:port", s));
+        };
+        let Some((username, password)) = user_info.split_once(':') else {
+            return Err(format!("'{}' format: user:password@host:port", s));
+        };
+        if address.is_empty() || username.is_empty() {
+            return Err(format!("'{}' format: user:password@host:port", s));
+        }
+        let mut address = address.to_string();
+        if !address.contains(':') {
+            address.push_str(":3478");
+        }
+        Ok(TurnServer {
+            address,
+            username: username.to_string(),
+            password: password.to_string(),
+        })
+    }
+}
+
+#[derive(Clone)]
+struct Auth {
+    realm: String,
+    nonce: String,
+    // 长期凭证 key = MD5(username:realm:password)
+    key: [u8; 16],
+}
+
+#[derive(Copy, Clone)]
+struct Allocation {
+    relayed: SocketAddrV4,
+    lifetime: Duration,
+    refreshed: Instant,
+}
+
+struct Permission {
+    created: Instant,
+    used: Instant,
+}
+
+pub struct TurnClient {
+    index: usize,
+    server: TurnServer,
+    server_addr: SocketAddr,
+    socket: UdpSocket,
+    auth: Mutex<Option<Auth>>,
+    allocation: Mutex<Option<Allocation>>,
+    permissions: Mutex<HashMap<Ipv4Addr, Permission>>,
+    pending: Mutex<HashMap<u128, SyncSender<Vec<u8>>>>,
+    // 交给后台线程创建的权限
+    permission_sender: SyncSender<Vec<Ipv4Addr>>,
+}
+
+impl TurnClient {
+    fn new(
+        index: usize,
+        server: TurnServer,
+        context: &ChannelContext,
+    ) -> anyhow::Result<(Self, Receiver<Vec<Ipv4Addr>>)> {
+        let server_addr = server
+            .address
+            .to_socket_addrs()?
+            .find(|addr| addr.is_ipv4())
+            .ok_or_else(|| anyhow::anyhow!("turn server {} not found", server.address))?;
+        let socket: UdpSocket =
+            bind_udp("0.0.0.0:0".parse().unwrap(), context.default_interface())?.into();
+        socket.set_read_timeout(Some(Duration::from_secs(1)))?;
+        let (permission_sender, permission_receiver) = sync_channel(PERMISSION_QUEUE);
+        Ok((
+            Self {
+                index,
+                server,
+                server_addr,
+                socket,
+                auth: Mutex::new(None),
+                allocation: Mutex::new(None),
+                permissions: Mutex::new(HashMap::new()),
+                pending: Mutex::new(HashMap::new()),
+                permission_sender,
+            },
+            permission_receiver,
+        ))
+    }
+    pub fn server(&self) -> &str {
+        &self.server.address
+    }
+    pub fn relayed(&self) -> Option<SocketAddrV4> {
+        self.allocation.lock().map(|v| v.relayed)
+    }
+    /// 通过Send indication把数据发给对端
+    pub fn send_to(&self, buf: &[u8], peer: SocketAddr) -> io::Result<()> {
+        let SocketAddr::V4(peer) = peer else {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        };
+        let mut packet = vec![0u8; buf.len() + 64];
+        let mut msg = MsgBuilder::from(packet.as_mut_slice());
+        msg.typ(MsgType::from(METHOD_SEND | CLASS_INDICATION));
+        msg.tid(new_tid());
+        msg.add_attr(Attr::XorPeerAddress(stun_format::SocketAddr::V4(
+            peer.ip().octets(),
+            peer.port(),
+        )));
+        msg.add_attr(Attr::Data(buf));
+        self.socket.send_to(msg.as_bytes(), self.server_addr)?;
+        Ok(())
+    }
+    /// 提交到后台线程创建权限，不阻塞调用方
+    pub fn request_permission(&self, ips: &[Ipv4Addr]) {
+        if self.allocation.lock().is_none() {
+            return;
+        }
+        let ips = self.expired_permissions(ips);
+        if ips.is_empty() {
+            return;
+        }
+        if let Err(TrySendError::Full(ips)) = self.permission_sender.try_send(ips) {
+            log::warn!("TURN权限请求过多，丢弃 server={} {:?}", self.server(), ips);
+        }
+    }
+    /// 没有权限或者需要续期的地址
+    fn expired_permissions(&self, ips: &[Ipv4Addr]) -> Vec<Ipv4Addr> {
+        let guard = self.permissions.lock();
+        ips.iter()
+            .filter(|ip| {
+                guard
+                    .get(ip)
+                    .map_or(true, |v| v.created.elapsed() >= PERMISSION_REFRESH)
+            })
+            .copied()
+            .collect()
+    }
+    /// 为对端的地址创建权限，没有权限时TURN服务器会丢弃对端发来的数据
+    fn create_permission(&self, ips: &[Ipv4Addr]) -> io::Result<()> {
+        if self.allocation.lock().is_none() {
+            return Ok(());
+        }
+        let ips = self.expired_permissions(ips);
+        for ips in ips.chunks(PERMISSION_BATCH) {
+            self.authed_request(METHOD_CREATE_PERMISSION, |msg| {
+                for ip in ips {
+                    msg.add_attr(Attr::XorPeerAddress(stun_format::SocketAddr::V4(
+                        ip.octets(),
+                        0,
+                    )));
+                }
+            })?;
+            let now = Instant::now();
+            let mut guard = self.permissions.lock();
+            for ip in ips {
+                let permission = guard.entry(*ip).or_insert(Permission {
+                    created: now,
+                    used: now,
+                });
+                permission.created = now;
+            }
+        }
+        Ok(())
+    }
+    /// 分配或续期中继地址，并续期仍在使用的权限
+    pub fn maintain(&self) {
+        let allocation = *self.allocation.lock();
+        match allocation {
+            None => match self.allocate() {
+                Ok(relayed) => {
+                    log::info!("TURN分配成功 server={} relayed={}", self.server(), relayed)
+                }
+                Err(e) => log::warn!("TURN分配失败 server={} {:?}", self.server(), e),
+            },
+            Some(allocation) if allocation.refreshed.elapsed() >= allocation.lifetime / 2 => {
+                if let Err(e) = self.refresh() {
+                    log::warn!("TURN续期失败 server={} {:?}", self.server(), e);
+                    self.allocation.lock().take();
+                }
+            }
+            Some(_) => {}
+        }
+        let ips: Vec<Ipv4Addr> = {
+            let mut guard = self.permissions.lock();
+            guard.retain(|_, v| v.used.elapsed() < PERMISSION_LIFETIME);
+            guard
+                .iter()
+                .filter(|(_, v)| v.created.elapsed() >= PERMISSION_REFRESH)
+                .map(|(ip, _)| *ip)
+                .collect()
+        };
+        if !ips.is_empty() {
+            if let Err(e) = self.create_permission(&ips) {
+                log::warn!("TURN权限续期失败 server={} {:?}", self.server(), e);
+            }
+        }
+    }
+    fn allocate(&self) -> io::Result<SocketAddrV4> {
+        let response = self.authed_request(METHOD_ALLOCATE, |msg| {
+            msg.add_attr(Attr::RequestedTransport(TransportProtocol::UDP));
+            msg.add_attr(Attr::Lifetime(ALLOCATION_LIFETIME));
+        })?;
+        let msg = Msg::from(response.as_slice());
+        let Some(relayed) = msg.attrs_iter().find_map(|attr| match attr {
+            Attr::XorRelayedAddress(addr) => v4_addr(addr),
+            _ => None,
+        }) else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not XOR-RELAYED-ADDRESS",
+            ));
+        };
+        self.permissions.lock().clear();
+        *self.allocation.lock() = Some(Allocation {
+            relayed,
+            lifetime: parse_lifetime(&msg),
+            refreshed: Instant::now(),
+        });
+        Ok(relayed)
+    }
+    fn refresh(&self) -> io::Result<()> {
+        let response = self.authed_request(METHOD_REFRESH, |msg| {
+            msg.add_attr(Attr::Lifetime(ALLOCATION_LIFETIME));
+        })?;
+        if let Some(allocation) = self.allocation.lock().as_mut() {
+            allocation.lifetime = parse_lifetime(&Msg::from(response.as_slice()));
+            allocation.refreshed = Instant::now();
+        }
+        Ok(())
+    }
+    /// 删除分配，不等待响应
+    fn release(&self) {
+        if self.allocation.lock().take().is_none() {
+            return;
+        }
+        let packet = self.request_packet(METHOD_REFRESH, new_tid(), |msg| {
+            msg.add_attr(Attr::Lifetime(Duration::ZERO));
+        });
+        let _ = self.socket.send_to(&packet, self.server_addr);
+    }
+    fn request_packet(&self, method: u16, tid: u128, attrs: impl Fn(&mut MsgBuilder)) -> Vec<u8> {
+        let mut buf = [0u8; 512];
+        let mut msg = MsgBuilder::from(buf.as_mut_slice());
+        msg.typ(MsgType::from(method));
+        msg.tid(tid);
+        attrs(&mut msg);
+        if let Some(auth) = self.auth.lock().as_ref() {
+            msg.add_attr(Attr::Username(&self.server.username));
+            msg.add_attr(Attr::Realm(&auth.realm));
+            msg.add_attr(Attr::Nonce(&auth.nonce));
+            add_integrity(&mut msg, &auth.key);
+        }
+        msg.as_bytes().to_vec()
+    }
+    /// 带长期凭证的请求，收到401/438时更新realm和nonce后重试
+    fn authed_request(&self, method: u16, attrs: impl Fn(&mut MsgBuilder)) -> io::Result<Vec<u8>> {
+        for _ in 0..3 {
+            let tid = new_tid();
+            let packet = self.request_packet(method, tid, &attrs);
+            let response = self.transaction(tid, &packet)?;
+            let msg = Msg::from(response.as_slice());
+            if message_type(&msg) == Some(method | CLASS_SUCCESS) {
+                return Ok(response);
+            }
+            let mut nonce = None;
+            let mut realm = None;
+            let mut reason = String::new();
+            for attr in msg.attrs_iter() {
+                match attr {
+                    Attr::Nonce(v) => nonce = Some(v.to_string()),
+                    Attr::Realm(v) => realm = Some(v.to_string()),
+                    Attr::ErrorCode { desc, .. } => reason = desc.to_string(),
+                    _ => {}
+                }
+            }
+            // stun-format解析ERROR-CODE时取错了类别位，错误码不可信，
+            // 只有401和438的错误响应会带上NONCE，以此判断是否需要认证
+            let Some(nonce) = nonce else {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("turn error {}", reason),
+                ));
+            };
+            let realm = realm
+                .or_else(|| self.auth.lock().as_ref().map(|v| v.realm.clone()))
+                .unwrap_or_default();
+            let key = long_term_key(&self.server.username, &realm, &self.server.password);
+            *self.auth.lock() = Some(Auth { realm, nonce, key });
+        }
+        Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "turn authentication failed",
+        ))
+    }
+    /// 发送请求并等待接收线程转交的响应
+    fn transaction(&self, tid: u128, packet: &[u8]) -> io::Result<Vec<u8>> {
+        let (sender, receiver) = sync_channel(1);
+        self.pending.lock().insert(tid, sender);
+        let mut rs = Err(io::Error::from(io::ErrorKind::TimedOut));
+        for timeout in RETRANSMIT {
+            if let Err(e) = self.socket.send_to(packet, self.server_addr) {
+                rs = Err(e);
+                break;
+            }
+            match receiver.recv_timeout(timeout) {
+                Ok(response) => {
+                    rs = Ok(response);
+                    break;
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+        self.pending.lock().remove(&tid);
+        rs
+    }
+    fn handle<H: RecvChannelHandler>(
+        &self,
+        buf: &[u8],
+        data: &mut [u8],
+        extend: &mut [u8],
+        recv_handler: &H,
+        context: &ChannelContext,
+    ) {
+        let msg = Msg::from(buf);
+        let Some(msg_type) = message_type(&msg) else {
+            return;
+        };
+        if msg_type == METHOD_DATA | CLASS_INDICATION {
+            let mut peer = None;
+            let mut payload = None;
+            for attr in msg.attrs_iter() {
+                match attr {
+                    Attr::XorPeerAddress(addr) => peer = v4_addr(addr),
+                    Attr::Data(v) => payload = Some(v),
+                    _ => {}
+                }
+            }
+            let (Some(peer), Some(payload)) = (peer, payload) else {
+                return;
+            };
+            if let Some(permission) = self.permissions.lock().get_mut(peer.ip()) {
+                permission.used = Instant::now();
+            }
+            let len = payload.len();
+            data[..len].copy_from_slice(payload);
+            recv_handler.handle(
+                &mut data[..len],
+                extend,
+                RouteKey::new(ConnectProtocol::TURN, self.index, peer.into()),
+                context,
+            );
+        } else if msg_type & CLASS_SUCCESS != 0 {
+            let Some(tid) = msg.tid() else {
+                return;
+            };
+            if let Some(sender) = self.pending.lock().remove(&tid) {
+                let _ = sender.try_send(buf.to_vec());
+            }
+        }
+    }
+}
+
+/// 所有TURN服务器上的中继，下标即RouteKey的index
+#[derive(Default)]
+pub struct TurnRelay {
+    clients: RwLock<Vec<Arc<TurnClient>>>,
+}
+
+impl TurnRelay {
+    pub fn send_to(&self, index: usize, buf: &[u8], peer: SocketAddr) -> io::Result<()> {
+        if let Some(client) = self.clients.read().get(index) {
+            client.send_to(buf, peer)
+        } else {
+            Err(io::Error::from(io::ErrorKind::NotFound))
+        }
+    }
+    /// 已分配的中继地址
+    pub fn relayed_addrs(&self) -> Vec<SocketAddrV4> {
+        self.clients
+            .read()
+            .iter()
+            .filter_map(|client| client.relayed())
+            .collect()
+    }
+    /// 创建权限的请求在后台线程完成，不阻塞打洞
+    pub fn create_permission(&self, ips: &[Ipv4Addr]) {
+        for client in self.clients.read().iter() {
+            client.request_permission(ips);
+        }
+    }
+    pub fn maintain(&self) {
+        let clients = self.clients.read().clone();
+        for client in clients {
+            client.maintain();
+        }
+    }
+    pub fn is_empty(&self) -> bool {
+        self.clients.read().is_empty()
+    }
+}
+
+/// 启动TURN客户端的接收线程，分配由定时任务完成
+pub(crate) fn turn_start<H>(
+    servers: Vec<TurnServer>,
+    stop_manager: StopManager,
+    recv_handler: H,
+    context: ChannelContext,
+) -> anyhow::Result<()>
+where
+    H: RecvChannelHandler,
+{
+    for server in servers {
+        let index = context.turn().clients.read().len();
+        let address = server.address.clone();
+        let (client, permission_receiver) = match TurnClient::new(index, server, &context) {
+            Ok((client, permission_receiver)) => (Arc::new(client), permission_receiver),
+            Err(e) => {
+                log::warn!("TURN服务器不可用 {} {:?}", address, e);
+                continue;
+            }
+        };
+        let stopped = Arc::new(AtomicBool::new(false));
+        let stopped_ = stopped.clone();
+        let worker = stop_manager.add_listener(format!("turn_{}", address), move || {
+            stopped_.store(true, Ordering::Release);
+        })?;
+        context.turn().clients.write().push(client.clone());
+        {
+            let client = client.clone();
+            let stopped = stopped.clone();
+            thread::Builder::new()
+                .name(format!("turnPermission{}", index))
+                .spawn(move || loop {
+                    match permission_receiver.recv_timeout(Duration::from_secs(1)) {
+                        Ok(ips) => {
+                            if let Err(e) = client.create_permission(&ips) {
+                                log::warn!("TURN创建权限失败 server={} {:?}", client.server(), e);
+                            }
+                        }
+                        Err(RecvTimeoutError::Timeout) => {
+                            if stopped.load(Ordering::Acquire) {
+                                break;
+                            }
+                        }
+                        Err(RecvTimeoutError::Disconnected) => break,
+                    }
+                })?;
+        }
+        let recv_handler = recv_handler.clone();
+        let context = context.clone();
+        thread::Builder::new()
+            .name(format!("turnRecv{}", index))
+            .spawn(move || {
+                let mut buf = vec![0; BUFFER_SIZE];
+                let mut data = vec![0; BUFFER_SIZE];
+                let mut extend = vec![0; BUFFER_SIZE];
+                while !stopped.load(Ordering::Acquire) {
+                    match client.socket.recv_from(&mut buf) {
+                        Ok((len, addr)) => {
+                            if addr != client.server_addr {
+                                continue;
+                            }
+                            client.handle(
+                                &buf[..len],
+                                &mut data,
+                                &mut extend,
+                                &recv_handler,
+                                &context,
+                            );
+                        }
+                        Err(e) => {
+                            if e.kind() != io::ErrorKind::WouldBlock
+                                && e.kind() != io::ErrorKind::TimedOut
+                            {
+                                log::warn!("TURN接收 {:?}", e);
+                                thread::sleep(Duration::from_millis(100));
+                            }
+                        }
+                    }
+                }
+                client.release();
+                drop(worker);
+            })?;
+    }
+    Ok(())
+}
+
+fn new_tid() -> u128 {
+    rand::thread_rng().gen::<u128>() & ((1 << 96) - 1)
+}
+
+/// MESSAGE-INTEGRITY，计算时长度字段要包含该属性
+fn add_integrity(msg: &mut MsgBuilder, key: &[u8]) {
+    let mut buf = msg.as_bytes().to_vec();
+    let len = (buf.len() - 20 + 24) as u16;
+    buf[2..4].copy_from_slice(&len.to_be_bytes());
+    let mut mac = Hmac::<Sha1>::new_from_slice(key).unwrap();
+    mac.update(&buf);
+    let mut digest = [0u8; 20];
+    digest.copy_from_slice(&mac.finalize().into_bytes());
+    msg.add_attr(Attr::MessageIntegrity(&digest));
+}
+
+fn long_term_key(username: &str, realm: &str, password: &str) -> [u8; 16] {
+    let mut hasher = Md5::new();
+    hasher.update(format!("{}:{}:{}", username, realm, password).as_bytes());
+    hasher.finalize().into()
+}
+
+/// 校验STUN头，返回消息类型
+fn message_type(msg: &Msg) -> Option<u16> {
+    if msg.cookie() != Some(MAGIC_COOKIE) {
+        return None;
+    }
+    msg.typ().map(u16::from)
+}
+
+/// 只处理ipv4地址
+fn v4_addr(addr: stun_format::SocketAddr) -> Option<SocketAddrV4> {
+    match addr {
+        stun_format::SocketAddr::V4(ip, port) => Some(SocketAddrV4::new(Ipv4Addr::from(ip), port)),
+        stun_format::SocketAddr::V6(..) => None,
+    }
+}
+
+fn parse_lifetime(msg: &Msg) -> Duration {
+    let lifetime = msg
+        .attrs_iter()
+        .find_map(|attr| match attr {
+            Attr::Lifetime(lifetime) => Some(lifetime),
+            _ => None,
+        })
+        .unwrap_or(ALLOCATION_LIFETIME);
+    lifetime.max(Duration::from_secs(60))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn server_config() {
+        let server = TurnServer::from_str("user:p@ss@turn.example.com").unwrap();
+        assert_eq!(server.username, "user");
+        assert_eq!(server.password, "p@ss");
+        assert_eq!(server.address, "turn.example.com:3478");
+        assert!(TurnServer::from_str("turn.example.com:3478").is_err());
+    }
+
+    #[test]
+    fn message_integrity() {
+        // RFC 5769 2.4 长期凭证的请求示例
+        let tid = 0x78ad3433c6ad72c029da412e;
+        let username = "\u{30DE}\u{30C8}\u{30EA}\u{30C3}\u{30AF}\u{30B9}";
+        let mut buf = [0u8; 256];
+        let mut msg = MsgBuilder::from(buf.as_mut_slice());
+        msg.typ(MsgType::from(0x0001));
+        msg.tid(tid);
+        msg.add_attr(Attr::Username(username));
+        msg.add_attr(Attr::Nonce("f//499k954d6OL34oL9FSTvy64sA"));
+        msg.add_attr(Attr::Realm("example.org"));
+        add_integrity(
+            &mut msg,
+            &long_term_key(username, "example.org", "TheMatrIX"),
+        );
+        let buf = msg.as_bytes().to_vec();
+        let msg = Msg::from(buf.as_slice());
+        let integrity = msg.attrs_iter().find_map(|attr| match attr {
+            Attr::MessageIntegrity(v) => Some(*v),
+            _ => None,
+        });
+        assert_eq!(
+            integrity,
+            Some([
+                0xf6, 0x70, 0x24, 0x65, 0x6d, 0xd6, 0x4a, 0x3e, 0x02, 0xb8, 0xe0, 0x71, 0x2e, 0x85,
+                0xc9, 0xa2, 0x8c, 0xa8, 0x96, 0x66
+            ])
+        );
+        assert_eq!(message_type(&msg), Some(0x0001));
+        assert_eq!(msg.tid(), Some(tid));
+    }
+
+    #[test]
+    fn xor_address() {
+        let mut buf = [0u8; 64];
+        let mut msg = MsgBuilder::from(buf.as_mut_slice());
+        msg.typ(MsgType::from(METHOD_DATA | CLASS_INDICATION));
+        msg.tid(new_tid());
+        let peer: SocketAddrV4 = "192.0.2.1:32853".parse().unwrap();
+        msg.add_attr(Attr::XorPeerAddress(stun_format::SocketAddr::V4(
+            peer.ip().octets(),
+            peer.port(),
+        )));
+        msg.add_attr(Attr::Data(b"hello"));
+        msg.add_attr(Attr::RequestedTransport(TransportProtocol::UDP));
+        let buf = msg.as_bytes().to_vec();
+        // XOR-PEER-ADDRESS的端口和地址与cookie异或
+        assert_eq!(
+            &buf[20..32],
+            &[0, 0x12, 0, 8, 0, 1, 0xa1, 0x47, 0xe1, 0x12, 0xa6, 0x43]
+        );
+        // REQUESTED-TRANSPORT 的udp协议号
+        assert_eq!(&buf[44..52], &[0, 0x19, 0, 4, 17, 0, 0, 0]);
+        let msg = Msg::from(buf.as_slice());
+        assert_eq!(message_type(&msg), Some(METHOD_DATA | CLASS_INDICATION));
+        let mut attrs = msg.attrs_iter();
+        assert!(
+            matches!(attrs.next(), Some(Attr::XorPeerAddress(addr)) if v4_addr(addr) == Some(peer))
+        );
+        assert!(matches!(attrs.next(), Some(Attr::Data(b"hello"))));
+    }
+}
@@ -0,0 +1,415 @@
+//! linux下udp批量收发
+//!
+//! 接收使用recvmmsg，开启UDP_GRO后内核会把同一来源的多个包合并成一个大包，再按分段长度拆开；
+//! 发送使用sendmmsg，连续发往同一地址且长度相同的包通过UDP_SEGMENT(GSO)合并成一次发送。
+//! 内核不支持时调用方回退到逐包收发
+use std::io;
+use std::mem::{size_of, size_of_val};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::os::fd::RawFd;
+
+use libc::{c_int, c_uint, c_void, iovec, mmsghdr, sockaddr_storage, socklen_t};
+use socket2::SockAddr;
+
+use crate::channel::BUFFER_SIZE;
+
+/// 一次系统调用最多收发的消息数
+pub const BATCH_SIZE: usize = 16;
+// 未开启GRO时每个接收缓冲区的长度，mtu为1500的链路上不分片的udp包都能放下
+const SLOT_LEN: usize = 2048;
+// 开启GRO时缓冲区要能放下合并后的大包，一个大包最多有64个分段，少量缓冲区就够了
+const GRO_BATCH_SIZE: usize = 4;
+// libc中未必有这几个常量，直接按内核头文件定义
+const SOL_UDP: c_int = 17;
+const UDP_SEGMENT: c_int = 103;
+const UDP_GRO: c_int = 104;
+// 内核限制单个GSO包最多64个分段
+const MAX_GSO_SEGMENTS: usize = 64;
+// GSO合并后的总长度不能超过udp负载上限
+const MAX_GSO_LEN: usize = 65000;
+// 足够放下一个int类型的cmsg，用u64保证对齐
+const CMSG_BUF_LEN: usize = 4;
+
+/// 开启/关闭UDP_GRO，返回是否设置成功
+pub fn set_gro(fd: RawFd, enable: bool) -> bool {
+    let val: c_int = enable as c_int;
+    let rs = unsafe {
+        libc::setsockopt(
+            fd,
+            SOL_UDP,
+            UDP_GRO,
+            &val as *const c_int as *const c_void,
+            size_of::<c_int>() as socklen_t,
+        )
+    };
+    rs == 0
+}
+
+/// 内核是否支持UDP_SEGMENT(4.18+)
+pub fn gso_supported(fd: RawFd) -> bool {
+    let mut val: c_int = 0;
+    let mut len = size_of::<c_int>() as socklen_t;
+    let rs = unsafe {
+        libc::getsockopt(
+            fd,
+            SOL_UDP,
+            UDP_SEGMENT,
+            &mut val as *mut c_int as *mut c_void,
+            &mut len,
+        )
+    };
+    rs == 0
+}
+
+/// recvmmsg不可用时返回的错误，调用方据此回退到逐包接收
+pub fn is_unsupported(e: &io::Error) -> bool {
+    matches!(
+        e.raw_os_error(),
+        Some(libc::ENOSYS) | Some(libc::EOPNOTSUPP) | Some(libc::EINVAL)
+    )
+}
+
+/// 批量接收缓冲区，注意内部持有裸指针，只能在创建它的线程中使用
+///
+/// 所有消息共用一块连续内存，未开启GRO时按链路mtu分段，
+/// 收到放不下的大包(如ip分片重组后的包)后切换成大缓冲区
+pub struct RecvBatch {
+    data: Vec<u8>,
+    slot_len: usize,
+    count: usize,
+    addrs: Vec<sockaddr_storage>,
+    iovs: Vec<iovec>,
+    cmsgs: Vec<[u64; CMSG_BUF_LEN]>,
+    msgs: Vec<mmsghdr>,
+    len: usize,
+    truncated: bool,
+}
+
+impl RecvBatch {
+    pub fn new(gro: bool) -> Self {
+        if gro {
+            Self::with_slot(GRO_BATCH_SIZE, BUFFER_SIZE)
+        } else {
+            Self::with_slot(BATCH_SIZE, SLOT_LEN)
+        }
+    }
+    fn with_slot(count: usize, slot_len: usize) -> Self {
+        Self {
+            data: vec![0u8; count * slot_len],
+            slot_len,
+            count,
+            addrs: vec![unsafe { std::mem::zeroed() }; count],
+            iovs: vec![unsafe { std::mem::zeroed() }; count],
+            cmsgs: vec![[0; CMSG_BUF_LEN]; count],
+            msgs: vec![unsafe { std::mem::zeroed() }; count],
+            len: 0,
+            truncated: false,
+        }
+    }
+    /// 非阻塞接收一批数据，返回消息数，没有数据时返回WouldBlock
+    pub fn recv(&mut self, fd: RawFd) -> io::Result<usize> {
+        if self.truncated {
+            // 被截断的包已经丢失，后续的大包使用足够大的缓冲区
+            log::info!("收到超过{}字节的udp包,扩大批量接收缓冲区", self.slot_len);
+            *self = Self::with_slot(GRO_BATCH_SIZE, BUFFER_SIZE);
+        }
+        // 每次都重新设置指针，避免结构体移动后指针失效
+        for i in 0..self.count {
+            self.iovs[i] = iovec {
+                iov_base: self.data[i * self.slot_len..].as_mut_ptr() as *mut c_void,
+                iov_len: self.slot_len,
+            };
+            let msg = &mut self.msgs[i];
+            msg.msg_len = 0;
+            let hdr = &mut msg.msg_hdr;
+            hdr.msg_name = &mut self.addrs[i] as *mut sockaddr_storage as *mut c_void;
+            hdr.msg_namelen = size_of::<sockaddr_storage>() as socklen_t;
+            hdr.msg_iov = &mut self.iovs[i];
+            hdr.msg_iovlen = 1;
+            hdr.msg_control = self.cmsgs[i].as_mut_ptr() as *mut c_void;
+            hdr.msg_controllen = size_of_val(&self.cmsgs[i]) as _;
+            hdr.msg_flags = 0;
+        }
+        let rs = unsafe {
+            libc::recvmmsg(
+                fd,
+                self.msgs.as_mut_ptr(),
+                self.count as c_uint,
+                libc::MSG_DONTWAIT as _,
+                std::ptr::null_mut(),
+            )
+        };
+        if rs < 0 {
+            self.len = 0;
+            return Err(io::Error::last_os_error());
+        }
+        self.len = rs as usize;
+        Ok(self.len)
+    }
+    /// 遍历收到的数据包，GRO合并的包会按分段长度拆开
+    pub fn for_each<F: FnMut(&mut [u8], SocketAddr)>(&mut self, mut f: F) {
+        for i in 0..self.len {
+            let len = self.msgs[i].msg_len as usize;
+            let addr = match sockaddr_to_socket_addr(&self.addrs[i]) {
+                Some(addr) => addr,
+                None => continue,
+            };
+            if self.msgs[i].msg_hdr.msg_flags & libc::MSG_TRUNC != 0 {
+                self.truncated = true;
+                continue;
+            }
+            let segment_size = gro_segment_size(&self.msgs[i].msg_hdr);
+            let start = i * self.slot_len;
+            let buf = &mut self.data[start..start + len];
+            if segment_size > 0 && len > segment_size {
+                for chunk in buf.chunks_mut(segment_size) {
+                    f(chunk, addr);
+                }
+            } else {
+                f(buf, addr);
+            }
+        }
+        self.len = 0;
+    }
+}
+
+fn gro_segment_size(hdr: &libc::msghdr) -> usize {
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(hdr);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == SOL_UDP && (*cmsg).cmsg_type == UDP_GRO {
+                let size = std::ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const c_int);
+                return size.max(0) as usize;
+            }
+            cmsg = libc::CMSG_NXTHDR(hdr, cmsg);
+        }
+    }
+    0
+}
+
+fn sockaddr_to_socket_addr(addr: &sockaddr_storage) -> Option<SocketAddr> {
+    match addr.ss_family as c_int {
+        libc::AF_INET => {
+            let addr_in = unsafe { &*(addr as *const _ as *const libc::sockaddr_in) };
+            let ip = Ipv4Addr::from(u32::from_be(addr_in.sin_addr.s_addr));
+            let port = u16::from_be(addr_in.sin_port);
+            Some(SocketAddr::V4(SocketAddrV4::new(ip, port)))
+        }
+        libc::AF_INET6 => {
+            let addr_in6 = unsafe { &*(addr as *const _ as *const libc::sockaddr_in6) };
+            let ip = Ipv6Addr::from(addr_in6.sin6_addr.s6_addr);
+            let port = u16::from_be(addr_in6.sin6_port);
+            Some(SocketAddr::V6(SocketAddrV6::new(
+                ip,
+                port,
+                addr_in6.sin6_flowinfo,
+                addr_in6.sin6_scope_id,
+            )))
+        }
+        _ => None,
+    }
+}
+
+struct Entry<T> {
+    addr: SocketAddr,
+    // 调用方附带的信息，发送失败时原样交回
+    tag: T,
+    start: usize,
+    len: usize,
+    segment_size: usize,
+    count: usize,
+}
+
+/// 批量发送缓冲区，先push再flush
+pub struct SendBatch<T = ()> {
+    gso: bool,
+    data: Vec<u8>,
+    entries: Vec<Entry<T>>,
+    addrs: Vec<SockAddr>,
+    iovs: Vec<iovec>,
+    cmsgs: Vec<[u64; CMSG_BUF_LEN]>,
+    msgs: Vec<mmsghdr>,
+}
+
+impl<T: Copy + PartialEq> SendBatch<T> {
+    pub fn new(gso: bool) -> Self {
+        Self {
+            gso,
+            data: Vec::new(),
+            entries: Vec::with_capacity(BATCH_SIZE),
+            addrs: Vec::with_capacity(BATCH_SIZE),
+            iovs: Vec::with_capacity(BATCH_SIZE),
+            cmsgs: Vec::with_capacity(BATCH_SIZE),
+            msgs: Vec::with_capacity(BATCH_SIZE),
+        }
+    }
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+    /// 待发送的消息数，GSO合并后的算一条
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+    pub fn push(&mut self, buf: &[u8], addr: SocketAddr, tag: T) {
+        if buf.is_empty() {
+            return;
+        }
+        if self.gso {
+            if let Some(last) = self.entries.last_mut() {
+                // 同一目标，之前的分段都是满长度，且当前包不超过分段长度，才能合并
+                if last.addr == addr
+                    && last.tag == tag
+                    && buf.len() <= last.segment_size
+                    && last.len % last.segment_size == 0
+                    && last.len + buf.len() <= MAX_GSO_LEN
+                    && last.count < MAX_GSO_SEGMENTS
+                {
+                    self.data.extend_from_slice(buf);
+                    last.len += buf.len();
+                    last.count += 1;
+                    return;
+                }
+            }
+        }
+        self.entries.push(Entry {
+            addr,
+            tag,
+            start: self.data.len(),
+            len: buf.len(),
+            segment_size: buf.len(),
+            count: 1,
+        });
+        self.data.extend_from_slice(buf);
+    }
+    /// 发送全部数据，返回发出的数据包数(按GSO拆分前计算)，
+    /// 出错时没发出的数据包逐个交给failed处理，不在这里等待或重试
+    pub fn flush<F: FnMut(&[u8], SocketAddr, T)>(
+        &mut self,
+        fd: RawFd,
+        mut failed: F,
+    ) -> io::Result<usize> {
+        let rs = self.flush0(fd, &mut failed);
+        self.clear();
+        rs
+    }
+    /// 丢弃未发送的数据
+    pub fn clear(&mut self) {
+        self.data.clear();
+        self.entries.clear();
+    }
+    fn flush0<F: FnMut(&[u8], SocketAddr, T)>(
+        &mut self,
+        fd: RawFd,
+        failed: &mut F,
+    ) -> io::Result<usize> {
+        let mut sent = 0;
+        let mut offset = 0;
+        while offset < self.entries.len() {
+            let end = (offset + BATCH_SIZE).min(self.entries.len());
+            self.prepare(offset, end);
+            let rs =
+                unsafe { libc::sendmmsg(fd, self.msgs.as_mut_ptr(), (end - offset) as c_uint, 0) };
+            if rs < 0 {
+                let e = io::Error::last_os_error();
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                if self.gso && e.raw_os_error() == Some(libc::EIO) {
+                    // 网卡不支持GSO时内核返回EIO，关闭后逐个分段重发
+                    log::warn!("udp gso不可用,关闭gso {:?}", e);
+                    self.gso = false;
+                    return Ok(sent + self.send_split(fd, offset, failed));
+                }
+                self.fail_from(offset, failed);
+                return Err(e);
+            }
+            for entry in &self.entries[offset..offset + rs as usize] {
+                sent += entry.count;
+            }
+            offset += rs as usize;
+        }
+        Ok(sent)
+    }
+    /// 从offset开始的数据包都交给failed
+    fn fail_from<F: FnMut(&[u8], SocketAddr, T)>(&self, offset: usize, failed: &mut F) {
+        for entry in &self.entries[offset..] {
+            for chunk in self.data[entry.start..entry.start + entry.len].chunks(entry.segment_size)
+            {
+                failed(chunk, entry.addr, entry.tag);
+            }
+        }
+    }
+    fn prepare(&mut self, offset: usize, end: usize) {
+        self.addrs.clear();
+        self.iovs.clear();
+        self.cmsgs.clear();
+        self.msgs.clear();
+        // 先填满各个Vec再取指针，避免扩容导致指针失效
+        for entry in &self.entries[offset..end] {
+            self.addrs.push(SockAddr::from(entry.addr));
+            self.iovs.push(iovec {
+                iov_base: self.data[entry.start..].as_ptr() as *mut c_void,
+                iov_len: entry.len,
+            });
+            self.cmsgs.push([0; CMSG_BUF_LEN]);
+        }
+        for (i, entry) in self.entries[offset..end].iter().enumerate() {
+            let mut msg: mmsghdr = unsafe { std::mem::zeroed() };
+            let hdr = &mut msg.msg_hdr;
+            hdr.msg_name = self.addrs[i].as_ptr() as *mut c_void;
+            hdr.msg_namelen = self.addrs[i].len();
+            hdr.msg_iov = &mut self.iovs[i];
+            hdr.msg_iovlen = 1;
+            if entry.count > 1 {
+                hdr.msg_control = self.cmsgs[i].as_mut_ptr() as *mut c_void;
+                hdr.msg_controllen = unsafe { libc::CMSG_SPACE(size_of::<u16>() as _) } as _;
+                unsafe {
+                    let cmsg = libc::CMSG_FIRSTHDR(hdr);
+                    (*cmsg).cmsg_level = SOL_UDP;
+                    (*cmsg).cmsg_type = UDP_SEGMENT;
+                    (*cmsg).cmsg_len = libc::CMSG_LEN(size_of::<u16>() as _) as _;
+                    std::ptr::write_unaligned(
+                        libc::CMSG_DATA(cmsg) as *mut u16,
+                        entry.segment_size as u16,
+                    );
+                }
+            }
+            self.msgs.push(msg);
+        }
+    }
+    fn send_split<F: FnMut(&[u8], SocketAddr, T)>(
+        &self,
+        fd: RawFd,
+        offset: usize,
+        failed: &mut F,
+    ) -> usize {
+        let mut sent = 0;
+        for entry in &self.entries[offset..] {
+            let addr = SockAddr::from(entry.addr);
+            for chunk in self.data[entry.start..entry.start + entry.len].chunks(entry.segment_size)
+            {
+                loop {
+                    let rs = unsafe {
+                        libc::sendto(
+                            fd,
+                            chunk.as_ptr() as *const c_void,
+                            chunk.len(),
+                            0,
+                            addr.as_ptr(),
+                            addr.len(),
+                        )
+                    };
+                    if rs >= 0 {
+                        sent += 1;
+                    } else if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted {
+                        continue;
+                    } else {
+                        failed(chunk, entry.addr, entry.tag);
+                    }
+                    break;
+                }
+            }
+        }
+        sent
+    }
+}
@@ -25,6 +25,8 @@ where
 }
 
 const NOTIFY: Token = Token(0);
+// 连续接收出错超过这个次数先停止读取，等下一次可读事件，避免持续的错误导致线程空转
+const MAX_RECV_ERRORS: usize = 32;
 
 fn sub_udp_listen<H>(
     stop_manager: StopManager,
@@ -72,6 +74,9 @@ where
     let mut extend = [0; BUFFER_SIZE];
     let mut list: Vec<UdpSocket> = Vec::with_capacity(100);
     let main_len = context.main_len();
+    // 对称模式的socket数量多且流量小，不开启GRO
+    #[cfg(target_os = "linux")]
+    let mut batch = Some(super::udp_batch::RecvBatch::new(false));
     loop {
         if let Err(e) = poll.poll(&mut events, None) {
             crate::ignore_io_interrupted(e)?;
@@ -117,26 +122,34 @@ where
                     }
                 }
                 Token(index) => {
-                    if let Some(udp_socket) = list.get(index) {
-                        loop {
-                            match udp_socket.recv_from(&mut buf) {
-                                Ok((len, addr)) => {
-                                    recv_handler.handle(
-                                        &mut buf[..len],
-                                        &mut extend,
-                                        RouteKey::new(ConnectProtocol::UDP, index + main_len, addr),
-                                        &context,
-                                    );
-                                }
-                                Err(e) => {
-                                    if e.kind() == io::ErrorKind::WouldBlock {
-                                        break;
-                                    }
-                                    log::error!("{:?}", e);
-                                }
+                    let Some(udp_socket) = list.get(index) else {
+                        continue;
+                    };
+                    #[cfg(target_os = "linux")]
+                    if let Some(recv_batch) = batch.as_mut() {
+                        match udp_recv_batch(
+                            udp_socket,
+                            index + main_len,
+                            recv_batch,
+                            &mut extend,
+                            &recv_handler,
+                            &context,
+                        ) {
+                            Ok(_) => continue,
+                            Err(e) => {
+                                log::warn!("recvmmsg不可用,回退到recv_from {:?}", e);
+                                batch = None;
                             }
                         }
                     }
+                    udp_recv(
+                        udp_socket,
+                        index + main_len,
+                        &mut buf,
+                        &mut extend,
+                        &recv_handler,
+                        &context,
+                    );
                 }
             }
         }
@@ -262,6 +275,24 @@ where
 
     let mut events = Events::with_capacity(udps.len());
     let mut extend = [0; BUFFER_SIZE];
+    // linux下批量接收，不支持时回退到逐包接收
+    #[cfg(target_os = "linux")]
+    let mut batch = {
+        use std::os::fd::AsRawFd;
+        let mut gro = true;
+        for udp in &udps {
+            if !super::udp_batch::set_gro(udp.as_raw_fd(), true) {
+                gro = false;
+            }
+        }
+        if !gro {
+            log::info!("udp gro不可用");
+            for udp in &udps {
+                super::udp_batch::set_gro(udp.as_raw_fd(), false);
+            }
+        }
+        Some(super::udp_batch::RecvBatch::new(gro))
+    };
     loop {
         if let Err(e) = poll.poll(&mut events, None) {
             crate::ignore_io_interrupted(e)?;
@@ -279,92 +310,112 @@ where
                 log::error!("{:?}", x);
                 continue;
             };
-            loop {
-                match udp.recv_from(&mut buf) {
-                    Ok((len, addr)) => {
-                        recv_handler.handle(
-                            &mut buf[..len],
-                            &mut extend,
-                            RouteKey::new(ConnectProtocol::UDP, index, addr),
-                            &context,
-                        );
-                    }
+            #[cfg(target_os = "linux")]
+            if let Some(recv_batch) = batch.as_mut() {
+                match udp_recv_batch(udp, index, recv_batch, &mut extend, &recv_handler, &context) {
+                    Ok(_) => continue,
                     Err(e) => {
-                        if e.kind() == io::ErrorKind::WouldBlock {
-                            break;
+                        log::warn!("recvmmsg不可用,回退到recv_from {:?}", e);
+                        use std::os::fd::AsRawFd;
+                        for udp in &udps {
+                            super::udp_batch::set_gro(udp.as_raw_fd(), false);
                         }
-                        log::error!("main_udp_listen_{}={:?}", index, e);
+                        batch = None;
                     }
                 }
             }
+            udp_recv(udp, index, &mut buf, &mut extend, &recv_handler, &context);
+        }
+    }
+}
+
+/// 逐包读取直到没有数据，出错只记录日志，连续出错时等下一次可读事件再读
+fn udp_recv<H>(
+    udp: &UdpSocket,
+    index: usize,
+    buf: &mut [u8],
+    extend: &mut [u8],
+    recv_handler: &H,
+    context: &ChannelContext,
+) where
+    H: RecvChannelHandler,
+{
+    let mut errors = 0;
+    loop {
+        match udp.recv_from(buf) {
+            Ok((len, addr)) => {
+                errors = 0;
+                recv_handler.handle(
+                    &mut buf[..len],
+                    extend,
+                    RouteKey::new(ConnectProtocol::UDP, index, addr),
+                    context,
+                );
+            }
+            Err(e) => {
+                if e.kind() == io::ErrorKind::WouldBlock {
+                    return;
+                }
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                log::warn!("udp_recv_{}={:?}", index, e);
+                errors += 1;
+                if errors >= MAX_RECV_ERRORS {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// 批量读取直到没有数据，只有recvmmsg不可用时返回错误，其他错误的处理同udp_recv
+#[cfg(target_os = "linux")]
+fn udp_recv_batch<H>(
+    udp: &UdpSocket,
+    index: usize,
+    batch: &mut super::udp_batch::RecvBatch,
+    extend: &mut [u8],
+    recv_handler: &H,
+    context: &ChannelContext,
+) -> io::Result<()>
+where
+    H: RecvChannelHandler,
+{
+    use std::os::fd::AsRawFd;
+    let fd = udp.as_raw_fd();
+    let mut errors = 0;
+    loop {
+        match batch.recv(fd) {
+            Ok(_) => {
+                errors = 0;
+                recv_handler.batch_begin();
+                batch.for_each(|buf, addr| {
+                    recv_handler.handle(
+                        buf,
+                        extend,
+                        RouteKey::new(ConnectProtocol::UDP, index, addr),
+                        context,
+                    );
+                });
+                recv_handler.batch_end();
+            }
+            Err(e) => {
+                if e.kind() == io::ErrorKind::WouldBlock {
+                    return Ok(());
+                }
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                if super::udp_batch::is_unsupported(&e) {
+                    return Err(e);
+                }
+                log::warn!("udp_recv_batch_{}={:?}", index, e);
+                errors += 1;
+                if errors >= MAX_RECV_ERRORS {
+                    return Ok(());
+                }
+            }
         }
     }
 }
-// /// 用recvmmsg没什么帮助，这里记录下，以下是完整代码
-// #[cfg(unix)]
-// pub fn main_udp_listen0<H>(index: usize, mut recv_handler: H, context: Context) -> io::Result<()>
-//     where
-//         H: RecvChannelHandler,
-// {
-//     use libc::{c_uint, mmsghdr, sockaddr_storage, socklen_t, timespec};
-//     use std::os::fd::AsRawFd;
-//
-//     let udp_socket = context.main_udp_socket[index].try_clone()?;
-//     let fd = udp_socket.as_raw_fd();
-//     const MAX_MESSAGES: usize = 16;
-//     let mut iov: [libc::iovec; MAX_MESSAGES] = unsafe { std::mem::zeroed() };
-//     let mut buf: [[u8; BUFFER_SIZE]; MAX_MESSAGES] = [[0; BUFFER_SIZE]; MAX_MESSAGES];
-//     let mut msgs: [mmsghdr; MAX_MESSAGES] = unsafe { std::mem::zeroed() };
-//     let mut addrs: [sockaddr_storage; MAX_MESSAGES] = unsafe { std::mem::zeroed() };
-//     for i in 0..MAX_MESSAGES {
-//         iov[i].iov_base = buf[i].as_mut_ptr() as *mut libc::c_void;
-//         iov[i].iov_len = BUFFER_SIZE;
-//         msgs[i].msg_hdr.msg_iov = &mut iov[i];
-//         msgs[i].msg_hdr.msg_iovlen = 1;
-//         msgs[i].msg_hdr.msg_name = &mut addrs[i] as *const _ as *mut libc::c_void;
-//         msgs[i].msg_hdr.msg_namelen = std::mem::size_of::<sockaddr_storage>() as socklen_t;
-//     }
-//     let mut time: timespec = unsafe { std::mem::zeroed() };
-//     loop {
-//         if context.is_stop() {
-//             return Ok(());
-//         }
-//         let res =
-//             unsafe { libc::recvmmsg(fd, msgs.as_mut_ptr(), MAX_MESSAGES as c_uint, 0, &mut time) };
-//         if res == -1 {
-//             log::error!("main_udp_listen_{}={:?}", index, io::Error::last_os_error());
-//             continue;
-//         }
-//
-//         let nmsgs = res as usize;
-//         for i in 0..nmsgs {
-//             let msg = &mut buf[i][0..msgs[i].msg_len as usize];
-//             let addr = sockaddr_to_socket_addr(&addrs[i], msgs[i].msg_hdr.msg_namelen);
-//             if msg == b"stop" {
-//                 if context.is_stop() {
-//                     return Ok(());
-//                 }
-//             }
-//             recv_handler.handle(msg, RouteKey::new(false, index, addr), &context);
-//         }
-//     }
-// }
-//
-// #[cfg(unix)]
-// fn sockaddr_to_socket_addr(addr: &libc::sockaddr_storage, _len: libc::socklen_t) -> SocketAddr {
-//     match addr.ss_family as libc::c_int {
-//         libc::AF_INET => {
-//             let addr_in = unsafe { *(addr as *const _ as *const libc::sockaddr_in) };
-//             let ip = u32::from_be(addr_in.sin_addr.s_addr);
-//             let port = u16::from_be(addr_in.sin_port);
-//             SocketAddr::V4(std::net::SocketAddrV4::new(Ipv4Addr::from(ip), port))
-//         }
-//         libc::AF_INET6 => {
-//             let addr_in6 = unsafe { *(addr as *const _ as *const libc::sockaddr_in6) };
-//             let ip = std::net::Ipv6Addr::from(addr_in6.sin6_addr.s6_addr);
-//             let port = u16::from_be(addr_in6.sin6_port);
-//             SocketAddr::V6(std::net::SocketAddrV6::new(ip, port, 0, 0))
-//         }
-//         _ => panic!("Unsupported address family"),
-//     }
-// }
@@ -4,7 +4,6 @@ use futures_util::stream::SplitStream;
 use futures_util::{SinkExt, StreamExt};
 use std::convert::Into;
 use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
-use std::thread;
 use std::time::Duration;
 use tokio::net::TcpStream;
 use tokio::sync::mpsc::{channel, Receiver};
@@ -15,7 +14,7 @@ use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
 use crate::channel::context::ChannelContext;
 use crate::channel::handler::RecvChannelHandler;
 use crate::channel::sender::PacketSender;
-use crate::util::StopManager;
+use crate::util::{SharedBuf, VntRuntime};
 
 /// ws协议，
 /// 暂时只允许用ws连服务端，不能用ws打洞/连客户端
@@ -23,31 +22,15 @@ pub fn ws_connect_accept<H>(
     receiver: Receiver<(Vec<u8>, String)>,
     recv_handler: H,
     context: ChannelContext,
-    stop_manager: StopManager,
+    runtime: &VntRuntime,
 ) -> anyhow::Result<()>
 where
     H: RecvChannelHandler,
 {
-    let (stop_sender, stop_receiver) = tokio::sync::oneshot::channel::<()>();
-    let worker = stop_manager.add_listener("wsChannel".into(), move || {
-        let _ = stop_sender.send(());
-    })?;
-    let runtime = tokio::runtime::Builder::new_multi_thread()
-        .worker_threads(2)
-        .enable_all()
-        .build()
-        .context("ws tokio runtime build failed")?;
-    thread::Builder::new()
-        .name("wsChannel".into())
-        .spawn(move || {
-            runtime.spawn(async move { connect_ws_handle(receiver, recv_handler, context).await });
-            runtime.block_on(async {
-                let _ = stop_receiver.await;
-            });
-            runtime.shutdown_background();
-            worker.stop_all();
-        })
-        .context("ws thread build failed")?;
+    let task_runtime = runtime.clone();
+    runtime.spawn(
+        async move { connect_ws_handle(receiver, recv_handler, context, task_runtime).await },
+    );
     Ok(())
 }
 
@@ -55,6 +38,7 @@ async fn connect_ws_handle<H>(
     mut receiver: Receiver<(Vec<u8>, String)>,
     recv_handler: H,
     context: ChannelContext,
+    runtime: VntRuntime,
 ) where
     H: RecvChannelHandler,
 {
@@ -62,8 +46,10 @@ async fn connect_ws_handle<H>(
     while let Some((data, url)) = receiver.recv().await {
         let recv_handler = recv_handler.clone();
         let context = context.clone();
-        tokio::spawn(async move {
-            if let Err(e) = connect_ws(data, url, recv_handler, context, index).await {
+        let task_runtime = runtime.clone();
+        runtime.spawn(async move {
+            if let Err(e) = connect_ws(data, url, recv_handler, context, index, task_runtime).await
+            {
                 log::warn!("发送失败,ws链接终止:{:?}", e);
             }
         });
@@ -78,6 +64,7 @@ async fn connect_ws<H>(
     recv_handler: H,
     context: ChannelContext,
     index: usize,
+    runtime: VntRuntime,
 ) -> anyhow::Result<()>
 where
     H: RecvChannelHandler,
@@ -125,16 +112,17 @@ where
     log::info!("ws协议握手 {:?}", response);
     ws.send(Message::Binary(data)).await?;
     let (mut ws_write, ws_read) = ws.split();
-    let (sender, mut receiver) = channel::<Vec<u8>>(100);
+    let (sender, mut receiver) = channel::<SharedBuf>(100);
     let route_key = RouteKey::new(ConnectProtocol::WS, index, WS_ADDR);
 
-    context
-        .packet_map
-        .write()
-        .insert(route_key, PacketSender::new(sender));
-    tokio::spawn(async move {
+    context.packet_map.write().insert(
+        route_key,
+        PacketSender::new(sender, context.buffer_pool().clone()),
+    );
+    runtime.spawn(async move {
         while let Some(data) = receiver.recv().await {
-            if let Err(e) = ws_write.send(Message::Binary(data)).await {
+            // tungstenite的帧需要独占的Vec，直接取出池中的缓冲区，不再拷贝
+            if let Err(e) = ws_write.send(Message::Binary(data.into_vec())).await {
                 log::warn!("websocket err {:?}", e);
                 break;
             }
@@ -0,0 +1,119 @@
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use ring::rand::SystemRandom;
+use ring::signature::{Ed25519KeyPair, KeyPair, UnparsedPublicKey, ED25519};
+
+const FILE_NAME: &str = "device-key";
+pub const PUBLIC_KEY_LEN: usize = 32;
+pub const SIGNATURE_LEN: usize = 64;
+
+/// 设备身份密钥(Ed25519)，私钥保存在状态目录，重启后公钥不变。
+/// 和网络共享的密钥不同，签名只能由持有私钥的设备生成，可以证明消息来自哪个设备
+#[derive(Clone)]
+pub struct DeviceIdentity {
+    key_pair: Arc<Ed25519KeyPair>,
+}
+
+impl DeviceIdentity {
+    /// 读取状态目录中的私钥，不存在时生成新的。
+    /// 没有状态目录时使用临时密钥，每次启动公钥都会变化
+    pub fn load(dir: Option<PathBuf>) -> anyhow::Result<Self> {
+        let path = dir.map(|v| v.join(FILE_NAME));
+        if let Some(path) = &path {
+            match std::fs::read(path) {
+                Ok(pkcs8) => {
+                    let key_pair = Ed25519KeyPair::from_pkcs8(&pkcs8)
+                        .map_err(|e| anyhow::anyhow!("设备私钥 {:?} 无效 {}", path, e))?;
+                    return Ok(Self::new(key_pair));
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => Err(anyhow::anyhow!("读取设备私钥 {:?} 失败 {}", path, e))?,
+            }
+        }
+        let rng = SystemRandom::new();
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&rng)
+            .map_err(|e| anyhow::anyhow!("生成设备私钥失败 {}", e))?;
+        match &path {
+            Some(path) => {
+                if let Err(e) = save(path, pkcs8.as_ref()) {
+                    log::warn!("保存设备私钥 {:?} 失败,本次使用临时密钥 {:?}", path, e);
+                }
+            }
+            None => log::warn!("没有状态目录,使用临时设备密钥"),
+        }
+        let key_pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref())
+            .map_err(|e| anyhow::anyhow!("生成设备私钥失败 {}", e))?;
+        Ok(Self::new(key_pair))
+    }
+    fn new(key_pair: Ed25519KeyPair) -> Self {
+        Self {
+            key_pair: Arc::new(key_pair),
+        }
+    }
+    pub fn public_key(&self) -> [u8; PUBLIC_KEY_LEN] {
+        self.key_pair.public_key().as_ref().try_into().unwrap()
+    }
+    pub fn sign(&self, data: &[u8]) -> [u8; SIGNATURE_LEN] {
+        self.key_pair.sign(data).as_ref().try_into().unwrap()
+    }
+}
+
+fn save(path: &PathBuf, pkcs8: &[u8]) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        use std::io::Write;
+        use std::os::unix::fs::OpenOptionsExt;
+        // 私钥只允许当前用户读取
+        let mut file = std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(path)?;
+        file.write_all(pkcs8)
+    }
+    #[cfg(not(unix))]
+    std::fs::write(path, pkcs8)
+}
+
+/// 验证设备签名
+pub fn verify(public_key: &[u8; PUBLIC_KEY_LEN], data: &[u8], signature: &[u8]) -> bool {
+    UnparsedPublicKey::new(&ED25519, public_key)
+        .verify(data, signature)
+        .is_ok()
+}
+
+/// 公钥的文本形式(十六进制)，用于配置文件和信息展示
+pub fn encode_public_key(public_key: &[u8; PUBLIC_KEY_LEN]) -> String {
+    public_key.iter().map(|v| format!("{:02x}", v)).collect()
+}
+
+pub fn decode_public_key(text: &str) -> Option<[u8; PUBLIC_KEY_LEN]> {
+    let text = text.trim();
+    if text.len() != PUBLIC_KEY_LEN * 2 || !text.is_ascii() {
+        return None;
+    }
+    let mut public_key = [0u8; PUBLIC_KEY_LEN];
+    for (i, v) in public_key.iter_mut().enumerate() {
+        *v = u8::from_str_radix(&text[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(public_key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sign_verify() {
+        let identity = DeviceIdentity::load(None).unwrap();
+        let public_key = identity.public_key();
+        let signature = identity.sign(b"data");
+        assert!(verify(&public_key, b"data", &signature));
+        assert!(!verify(&public_key, b"other", &signature));
+        let text = encode_public_key(&public_key);
+        assert_eq!(decode_public_key(&text), Some(public_key));
+        assert_eq!(decode_public_key(&text[1..]), None);
+    }
+}
@@ -30,3 +30,11 @@ mod sm4_cbc;
 
 mod xor;
 pub use xor::simple_hash;
+
+mod network_key;
+pub use network_key::NetworkKey;
+
+mod identity;
+pub use identity::{
+    decode_public_key, encode_public_key, verify, DeviceIdentity, PUBLIC_KEY_LEN, SIGNATURE_LEN,
+};
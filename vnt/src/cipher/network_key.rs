@@ -0,0 +1,57 @@
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+/// 用组网token和密码派生的共享密钥(HMAC)，不同网络之间无法互相验证。
+/// 同一网络的成员持有相同的密钥，只能证明消息来自网络内，不能证明来自哪个设备
+#[derive(Clone, Copy)]
+pub struct NetworkKey {
+    key: [u8; 32],
+}
+
+impl NetworkKey {
+    /// domain区分不同用途，避免一种用途的签名被用在另一种上
+    pub fn new(domain: &str, token: &str, client_secret_hash: Option<&[u8; 16]>) -> Self {
+        let mut hasher = Sha256::new();
+        hasher.update(domain.as_bytes());
+        hasher.update(token.as_bytes());
+        if let Some(hash) = client_secret_hash {
+            hasher.update(hash);
+        }
+        Self {
+            key: hasher.finalize().into(),
+        }
+    }
+    /// HMAC-SHA256
+    pub fn sign(&self, data: &[u8]) -> [u8; 32] {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).unwrap();
+        mac.update(data);
+        mac.finalize().into_bytes().into()
+    }
+    /// 签名可以截断，但不能短于16字节
+    pub fn verify(&self, data: &[u8], tag: &[u8]) -> bool {
+        if tag.len() < 16 {
+            return false;
+        }
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).unwrap();
+        mac.update(data);
+        mac.verify_truncated_left(tag).is_ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hmac() {
+        // RFC 4231 测试用例2，密钥补零不影响结果
+        let mut key = NetworkKey::new("", "", None);
+        key.key = [0u8; 32];
+        key.key[..4].copy_from_slice(b"Jefe");
+        let tag = key.sign(b"what do ya want for nothing?");
+        assert_eq!(tag[..4], [0x5b, 0xdc, 0xc1, 0x46]);
+        assert!(key.verify(b"what do ya want for nothing?", &tag[..16]));
+        assert!(!key.verify(b"what do ya want for nothing", &tag[..16]));
+        assert!(!key.verify(b"what do ya want for nothing?", &tag[..8]));
+    }
+}
@@ -7,28 +7,33 @@ use std::time::Duration;
 use crossbeam_utils::atomic::AtomicCell;
 use parking_lot::{Mutex, RwLock};
 use rand::Rng;
+use tokio::runtime::Handle;
 
 use crate::channel::context::ChannelContext;
 use crate::channel::idle::Idle;
 use crate::channel::punch::{NatInfo, Punch};
+use crate::channel::punch_telemetry::{PunchStat, PunchTelemetry};
 use crate::channel::sender::IpPacketSender;
 use crate::channel::{init_channel, init_context, Route, RouteKey};
-use crate::cipher::Cipher;
 #[cfg(feature = "server_encrypt")]
 use crate::cipher::RsaCipher;
+use crate::cipher::{encode_public_key, Cipher, DeviceIdentity};
 use crate::compression::Compressor;
 use crate::core::Config;
 use crate::external_route::{AllowExternalRoute, ExternalRoute};
+use crate::handle::gossip::Gossip;
 use crate::handle::handshaker::Handshake;
 use crate::handle::maintain::PunchReceiver;
 use crate::handle::recv_data::RecvDataHandler;
+use crate::handle::state_cache::StateCache;
+use crate::handle::static_peers::StaticPeers;
 use crate::handle::{maintain, BaseConfigInfo, ConnectStatus, CurrentDeviceInfo, PeerDeviceInfo};
-use crate::nat::NatTest;
+use crate::nat::{GatewayMapping, NatTest, StunHealth};
 #[cfg(feature = "integrated_tun")]
 use crate::tun_tap_device::tun_create_helper::{DeviceAdapter, TunDeviceHelper};
 use crate::tun_tap_device::vnt_device::DeviceWrite;
 use crate::util::limit::TrafficMeterMultiAddress;
-use crate::util::{Scheduler, StopManager};
+use crate::util::{PoolStats, Scheduler, StopManager, VntRuntime};
 use crate::{nat, VntCallback};
 
 #[derive(Clone)]
@@ -39,7 +44,17 @@ pub struct Vnt {
 impl Vnt {
     #[cfg(feature = "integrated_tun")]
     pub fn new<Call: VntCallback>(config: Config, callback: Call) -> anyhow::Result<Self> {
-        let inner = Arc::new(VntInner::new(config, callback)?);
+        let inner = Arc::new(VntInner::new(config, callback, None)?);
+        Ok(Self { inner })
+    }
+    /// 使用嵌入方的tokio运行时，vnt不再创建自己的运行时
+    #[cfg(feature = "integrated_tun")]
+    pub fn new_with_handle<Call: VntCallback>(
+        config: Config,
+        callback: Call,
+        handle: Handle,
+    ) -> anyhow::Result<Self> {
+        let inner = Arc::new(VntInner::new(config, callback, Some(handle))?);
         Ok(Self { inner })
     }
     #[cfg(not(feature = "integrated_tun"))]
@@ -48,7 +63,23 @@ impl Vnt {
         callback: Call,
         device: Device,
     ) -> anyhow::Result<Self> {
-        let inner = Arc::new(VntInner::new_device(config, callback, device)?);
+        let inner = Arc::new(VntInner::new_device(config, callback, device, None)?);
+        Ok(Self { inner })
+    }
+    /// 使用嵌入方的tokio运行时，vnt不再创建自己的运行时
+    #[cfg(not(feature = "integrated_tun"))]
+    pub fn new_device_with_handle<Call: VntCallback, Device: DeviceWrite>(
+        config: Config,
+        callback: Call,
+        device: Device,
+        handle: Handle,
+    ) -> anyhow::Result<Self> {
+        let inner = Arc::new(VntInner::new_device(
+            config,
+            callback,
+            device,
+            Some(handle),
+        )?);
         Ok(Self { inner })
     }
 }
@@ -76,25 +107,33 @@ pub struct VntInner {
     external_route: ExternalRoute,
     up_traffic_meter: Option<TrafficMeterMultiAddress>,
     down_traffic_meter: Option<TrafficMeterMultiAddress>,
+    punch_telemetry: PunchTelemetry,
+    identity: DeviceIdentity,
 }
 
 impl VntInner {
     #[cfg(feature = "integrated_tun")]
-    pub fn new<Call: VntCallback>(config: Config, callback: Call) -> anyhow::Result<Self> {
-        VntInner::new_device0(config, callback, DeviceAdapter::default())
+    pub fn new<Call: VntCallback>(
+        config: Config,
+        callback: Call,
+        handle: Option<Handle>,
+    ) -> anyhow::Result<Self> {
+        VntInner::new_device0(config, callback, DeviceAdapter::default(), handle)
     }
     #[cfg(not(feature = "integrated_tun"))]
     pub fn new_device<Call: VntCallback, Device: DeviceWrite>(
         config: Config,
         callback: Call,
         device: Device,
+        handle: Option<Handle>,
     ) -> anyhow::Result<Self> {
-        VntInner::new_device0(config, callback, device)
+        VntInner::new_device0(config, callback, device, handle)
     }
     fn new_device0<Call: VntCallback, Device: DeviceWrite>(
         config: Config,
         callback: Call,
         device: Device,
+        handle: Option<Handle>,
     ) -> anyhow::Result<Self> {
         log::info!("config: {:?}", config);
         let (up_traffic_meter, down_traffic_meter) = if config.enable_traffic {
@@ -125,10 +164,19 @@ impl VntInner {
         //客户端对称加密
         let client_cipher =
             Cipher::new_password(config.cipher_model, config.password.clone(), finger)?;
-        //当前设备信息
-        let current_device = Arc::new(AtomicCell::new(CurrentDeviceInfo::new0(
-            config.server_address,
-        )));
+        //上次运行的状态
+        let state_cache = StateCache::load(
+            config.state_dir.clone(),
+            &config.token,
+            &config.device_id,
+            &config.server_address_str,
+        );
+        //当前设备信息，先使用上次的虚拟ip，注册完成前也能接收对端的打洞回应
+        let mut device_info = CurrentDeviceInfo::new0(config.server_address);
+        if let Some(ip) = config.ip.or_else(|| state_cache.virtual_ip()) {
+            device_info.virtual_ip = ip;
+        }
+        let current_device = Arc::new(AtomicCell::new(device_info));
         //设备列表
         let device_map: Arc<Mutex<(u16, HashMap<Ipv4Addr, PeerDeviceInfo>)>> =
             Arc::new(Mutex::new((0, HashMap::with_capacity(16))));
@@ -140,6 +188,11 @@ impl VntInner {
         let default_interface = config.local_interface.clone();
 
         //基础信息
+        #[cfg(feature = "ip_proxy")]
+        #[cfg(feature = "integrated_tun")]
+        let kernel_nat = config.allow_exit && config.no_proxy;
+        #[cfg(not(all(feature = "ip_proxy", feature = "integrated_tun")))]
+        let kernel_nat = config.allow_exit;
         let config_info = BaseConfigInfo::new(
             config.name.clone(),
             config.token.clone(),
@@ -156,19 +209,31 @@ impl VntInner {
             #[cfg(feature = "integrated_tun")]
             #[cfg(any(target_os = "windows", target_os = "linux", target_os = "macos"))]
             config.device_name.clone(),
+            #[cfg(feature = "integrated_tun")]
+            #[cfg(target_os = "linux")]
+            config.tun_queues,
+            #[cfg(feature = "integrated_tun")]
+            #[cfg(target_os = "linux")]
+            config.tun_offload,
             config.allow_wire_guard,
             default_interface.clone(),
+            config.exit_dns.clone(),
+            kernel_nat,
         );
         // 服务停止管理器
         let stop_manager = {
             let callback = callback.clone();
             StopManager::new(move || callback.stop())
         };
+        // 所有异步任务和定时任务共用一个运行时
+        let runtime = if let Some(handle) = handle {
+            VntRuntime::from_handle(handle)
+        } else {
+            VntRuntime::new(config.runtime_threads)?
+        };
+        runtime.bind_stop(&stop_manager)?;
         #[cfg(feature = "port_mapping")]
-        crate::port_mapping::start_port_mapping(
-            stop_manager.clone(),
-            config.port_mapping_list.clone(),
-        )?;
+        crate::port_mapping::start_port_mapping(&runtime, config.port_mapping_list.clone())?;
         let mut ports = config.ports.as_ref().map_or(vec![0, 0], |v| {
             if v.is_empty() {
                 vec![0, 0]
@@ -205,10 +270,18 @@ impl VntInner {
             tcp_port,
             config.local_ipv4.is_none(),
             config.punch_model,
+            StunHealth::load(config.state_dir.clone()),
         );
+        if let Some((nat_type, public_ips)) = state_cache.nat() {
+            nat_test.restore(nat_type, public_ips);
+        }
         // 定时器
-        let scheduler = Scheduler::new(stop_manager.clone())?;
-        let external_route = ExternalRoute::new(config.in_ips.clone());
+        let scheduler = Scheduler::new(runtime.clone())?;
+        let external_route = ExternalRoute::new(
+            config.in_ips.clone(),
+            config.accept_routes.clone(),
+            config.gateway_balance,
+        );
         let out_external_route = AllowExternalRoute::new(config.out_ips.clone());
 
         #[cfg(feature = "ip_proxy")]
@@ -216,7 +289,22 @@ impl VntInner {
         let proxy_map = if !config.out_ips.is_empty() && !config.no_proxy {
             Some(crate::ip_proxy::init_proxy(
                 context.clone(),
-                stop_manager.clone(),
+                &runtime,
+                current_device.clone(),
+                client_cipher.clone(),
+            )?)
+        } else {
+            None
+        };
+        #[cfg(target_os = "linux")]
+        let masquerade = if !config.out_ips.is_empty() && config.masquerade {
+            #[cfg(all(feature = "ip_proxy", feature = "integrated_tun"))]
+            if config.no_proxy {
+                log::warn!("内置nat不转发tcp,关闭ip代理后访问out_ips的tcp需要开启内核转发");
+            }
+            Some(crate::masquerade::Masquerade::new(
+                &runtime,
+                context.clone(),
                 current_device.clone(),
                 client_cipher.clone(),
             )?)
@@ -231,6 +319,8 @@ impl VntInner {
             rsa_cipher.clone(),
         );
         #[cfg(feature = "integrated_tun")]
+        let device_adapter = device.clone().into_device_adapter();
+        #[cfg(feature = "integrated_tun")]
         let tun_device_helper = {
             TunDeviceHelper::new(
                 stop_manager.clone(),
@@ -243,10 +333,46 @@ impl VntInner {
                 server_cipher.clone(),
                 device_map.clone(),
                 config.compressor,
-                device.clone().into_device_adapter(),
+                device_adapter.clone(),
+                #[cfg(target_os = "linux")]
+                config.tun_queues,
+                #[cfg(target_os = "linux")]
+                config.tun_offload,
             )
         };
 
+        #[cfg(feature = "integrated_tun")]
+        #[cfg(target_os = "linux")]
+        if config_info.kernel_nat {
+            crate::tun_tap_device::masquerade_release_on_stop(
+                &stop_manager,
+                tun_device_helper.masquerade().clone(),
+            )?;
+        }
+
+        // 设备身份密钥，静态对端用公钥认证本机
+        let identity = DeviceIdentity::load(config.state_dir.clone())?;
+        log::info!("设备公钥 {}", encode_public_key(&identity.public_key()));
+        let static_peers = if config.is_serverless() {
+            Some(StaticPeers::new(
+                config.static_peers.clone(),
+                identity.clone(),
+            ))
+        } else {
+            None
+        };
+        // 设备目录，服务器离线时维持设备列表
+        let trusted = config
+            .static_peers
+            .iter()
+            .filter_map(|peer| peer.public_key.map(|key| (peer.virtual_ip, key)))
+            .collect();
+        let gossip = Gossip::new(
+            &config.token,
+            config_info.client_secret_hash.as_ref(),
+            identity.clone(),
+            trusted,
+        );
         let handler = RecvDataHandler::new(
             #[cfg(feature = "server_encrypt")]
             rsa_cipher,
@@ -262,6 +388,10 @@ impl VntInner {
             peer_nat_info_map.clone(),
             external_route.clone(),
             out_external_route,
+            gossip.clone(),
+            static_peers.clone(),
+            #[cfg(target_os = "linux")]
+            masquerade,
             #[cfg(feature = "ip_proxy")]
             #[cfg(feature = "integrated_tun")]
             proxy_map.clone(),
@@ -269,32 +399,164 @@ impl VntInner {
             #[cfg(feature = "integrated_tun")]
             tun_device_helper,
         );
+        // 无服务器模式，不握手和注册，直接使用配置的虚拟ip
+        if let Some(ip) = config.ip.filter(|_| config.is_serverless()) {
+            handler.register_static(ip, config.static_netmask, &config.static_peers)?;
+        }
 
         //初始化网络数据通道
-        let (udp_socket_sender, connect_util) =
-            init_channel(tcp_listener, context.clone(), stop_manager.clone(), handler)?;
+        let (udp_socket_sender, connect_util) = init_channel(
+            tcp_listener,
+            context.clone(),
+            stop_manager.clone(),
+            &runtime,
+            handler,
+            #[cfg(feature = "turn")]
+            config.turn_server.clone(),
+        )?;
         // 打洞逻辑
+        let punch_telemetry = PunchTelemetry::default();
         let punch = Punch::new(
             context.clone(),
             config.punch_model,
             connect_util.clone(),
             nat_test.clone(),
             current_device.clone(),
+            punch_telemetry.clone(),
         );
 
         // #[cfg(not(target_os = "android"))]
         // tun_helper.start(device)?;
 
-        maintain::idle_gateway(
-            &scheduler,
+        // 向上次可用的对端地址打洞，不等注册和nat探测完成
+        if !config.use_channel_type.is_only_relay() {
+            maintain::resume_peers(
+                &scheduler,
+                context.clone(),
+                current_device.clone(),
+                connect_util.clone(),
+                client_cipher.clone(),
+                state_cache.clone(),
+                0,
+            );
+        }
+        maintain::save_state_on_stop(
+            &stop_manager,
             context.clone(),
             current_device.clone(),
-            config_info.clone(),
-            connect_util.clone(),
-            callback.clone(),
-            0,
-            handshake,
-        );
+            nat_test.clone(),
+            state_cache.clone(),
+        )?;
+        {
+            let (context, current_device, nat_test) =
+                (context.clone(), current_device.clone(), nat_test.clone());
+            scheduler.timeout(Duration::from_secs(60), move |s| {
+                maintain::save_state(s, context, current_device, nat_test, state_cache)
+            });
+        }
+
+        // 网关通告子网路由，接受的通告路由同步到网卡
+        if !config.out_ips.is_empty() {
+            let (context, current_device, device_map, client_cipher) = (
+                context.clone(),
+                current_device.clone(),
+                device_map.clone(),
+                client_cipher.clone(),
+            );
+            let out_ips = Arc::new(config.out_ips.clone());
+            scheduler.timeout(Duration::from_secs(3), move |s| {
+                maintain::route_advertise(
+                    s,
+                    context,
+                    current_device,
+                    device_map,
+                    client_cipher,
+                    out_ips,
+                )
+            });
+        }
+        if !config.in_ips.is_empty() || !config.accept_routes.is_empty() {
+            // 子网网关健康检测，不可用时切换到备用网关
+            maintain::gateway_health(
+                &scheduler,
+                context.clone(),
+                current_device.clone(),
+                device_map.clone(),
+                external_route.clone(),
+            );
+        }
+        if !config.accept_routes.is_empty() {
+            maintain::sync_external_route(
+                &scheduler,
+                external_route.clone(),
+                callback.clone(),
+                #[cfg(feature = "integrated_tun")]
+                device_adapter.clone(),
+                maintain::RouteSyncState::default(),
+            );
+        }
+        // 虚拟网络dns，把设备名解析成虚拟ip
+        if let Some(suffix) = &config.overlay_dns {
+            let dns = crate::overlay_dns::OverlayDns::new(
+                suffix.clone(),
+                config.name.clone(),
+                &config.name_servers,
+                &config.exit_dns,
+                runtime.clone(),
+                current_device.clone(),
+                device_map.clone(),
+            );
+            maintain::overlay_dns(
+                &scheduler,
+                dns,
+                #[cfg(feature = "integrated_tun")]
+                device_adapter,
+                maintain::OverlayDnsState::new(config.overlay_dns_resolved),
+            );
+        }
+
+        // 本地网络变化时立即重新探测和连接，无服务器模式由静态对端的打洞持续重试
+        #[cfg(target_os = "linux")]
+        if !config.is_serverless() {
+            if let Err(e) = maintain::network_watch(
+                &stop_manager,
+                context.clone(),
+                nat_test.clone(),
+                current_device.clone(),
+                config_info.clone(),
+                connect_util.clone(),
+                handshake.clone(),
+                udp_socket_sender.clone(),
+                callback.clone(),
+            ) {
+                log::warn!("网络变化监听启动失败 {:?}", e);
+            }
+        }
+        // 局域网发现，服务器不可用时同网段的设备也能直连
+        if config.lan_discovery && !config.use_channel_type.is_only_relay() {
+            if let Err(e) = maintain::lan_discovery(
+                &scheduler,
+                &stop_manager,
+                context.clone(),
+                current_device.clone(),
+                &config_info,
+                client_cipher.clone(),
+            ) {
+                log::warn!("局域网发现启动失败 {:?}", e);
+            }
+        }
+        if !config.is_serverless() {
+            maintain::idle_gateway(
+                &scheduler,
+                context.clone(),
+                current_device.clone(),
+                config_info.clone(),
+                connect_util.clone(),
+                callback.clone(),
+                0,
+                handshake,
+            );
+        }
         {
             let context = context.clone();
             let nat_test = nat_test.clone();
@@ -309,6 +571,36 @@ impl VntInner {
                     nat_test.clone(),
                     udp_socket_sender,
                 );
+                #[cfg(feature = "natpmp")]
+                {
+                    // 网关端口映射的续期和停止时删除
+                    maintain::natpmp_release_on_stop(&stop_manager, nat_test.clone())?;
+                    let nat_test = nat_test.clone();
+                    scheduler.timeout(Duration::from_secs(60), move |s| {
+                        maintain::natpmp_renew(s, nat_test)
+                    });
+                }
+                #[cfg(feature = "upnp")]
+                {
+                    maintain::upnp_release_on_stop(&stop_manager, nat_test.clone())?;
+                    let nat_test = nat_test.clone();
+                    scheduler.timeout(crate::util::UPNP_RENEW_INTERVAL, move |s| {
+                        maintain::upnp_renew(s, nat_test)
+                    });
+                }
+                // TURN中继的分配和续期
+                #[cfg(feature = "turn")]
+                if !context.turn().is_empty() {
+                    let (context, nat_test) = (context.clone(), nat_test.clone());
+                    scheduler.timeout(Duration::from_secs(3), move |s| {
+                        maintain::turn_refresh(s, context, nat_test)
+                    });
+                }
+                // nat绑定存活时间，等首次探测找到支持RFC 5780的服务器后开始
+                let (context, nat_test) = (context.clone(), nat_test.clone());
+                scheduler.timeout(Duration::from_secs(30), move |s| {
+                    maintain::binding_lifetime(s, context, nat_test)
+                });
             }
             let client_cipher = client_cipher.clone();
             let server_cipher = server_cipher.clone();
@@ -326,6 +618,8 @@ impl VntInner {
                     config_info,
                     punch,
                     callback,
+                    static_peers,
+                    gossip,
                 );
             });
         }
@@ -345,6 +639,8 @@ impl VntInner {
             external_route,
             up_traffic_meter,
             down_traffic_meter,
+            punch_telemetry,
+            identity,
         })
     }
 }
@@ -361,6 +657,8 @@ pub fn start<Call: VntCallback>(
     config_info: BaseConfigInfo,
     punch: Punch,
     callback: Call,
+    static_peers: Option<StaticPeers>,
+    gossip: Gossip,
 ) {
     // 定时心跳
     maintain::heartbeat(
@@ -391,7 +689,29 @@ pub fn start<Call: VntCallback>(
             client_cipher.clone(),
         );
     }
+    // 定时传播设备目录
+    maintain::gossip(
+        &scheduler,
+        context.clone(),
+        current_device.clone(),
+        device_map.clone(),
+        nat_test.clone(),
+        config_info.clone(),
+        client_cipher.clone(),
+        gossip,
+    );
 
+    if let Some(static_peers) = static_peers {
+        // 无服务器模式，地址探测、打洞协商和状态上报都依赖服务器
+        maintain::static_peer(
+            &scheduler,
+            context.clone(),
+            current_device.clone(),
+            static_peers,
+            client_cipher.clone(),
+        );
+        return;
+    }
     if !context.use_channel_type().is_only_relay() {
         // 定时地址探测
         maintain::addr_request(
@@ -429,6 +749,10 @@ impl VntInner {
     pub fn client_encrypt_hash(&self) -> Option<&[u8]> {
         self.client_secret_hash.as_ref().map(|v| v.as_ref())
     }
+    /// 设备公钥，静态对端配置的public_key
+    pub fn public_key(&self) -> String {
+        encode_public_key(&self.identity.public_key())
+    }
     pub fn current_device(&self) -> CurrentDeviceInfo {
         self.current_device.load()
     }
@@ -444,6 +768,10 @@ impl VntInner {
     pub fn nat_info(&self) -> NatInfo {
         self.nat_test.nat_info()
     }
+    /// 网关端口映射(UPnP、NAT-PMP、PCP)的状态
+    pub fn gateway_mappings(&self) -> Vec<GatewayMapping> {
+        self.nat_test.gateway_mappings()
+    }
     pub fn device_list(&self) -> Vec<PeerDeviceInfo> {
         let device_list_lock = self.device_map.lock();
         let (_epoch, device_list) = device_list_lock.clone();
@@ -479,6 +807,17 @@ impl VntInner {
     pub fn up_stream_history(&self) -> Option<(u64, HashMap<Ipv4Addr, (u64, Vec<usize>)>)> {
         self.up_traffic_meter.as_ref().map(|v| v.get_all_history())
     }
+    /// 发送缓冲区池的分配统计
+    pub fn buffer_pool_stats(&self) -> Option<PoolStats> {
+        self.context
+            .lock()
+            .as_ref()
+            .map(|context| context.buffer_pool().stats())
+    }
+    /// 按对端端口分配模型统计的打洞结果
+    pub fn punch_stats(&self) -> Vec<PunchStat> {
+        self.punch_telemetry.stats()
+    }
     pub fn down_stream(&self) -> u64 {
         self.down_traffic_meter.as_ref().map_or(0, |v| v.total())
     }
@@ -491,6 +830,17 @@ impl VntInner {
             .map(|v| v.get_all_history())
     }
     pub fn stop(&self) {
+        //退出前通知服务端和直连的对端
+        if let Some(context) = self.context.lock().as_ref() {
+            maintain::leave(
+                context,
+                &self.current_device.load(),
+                &self.client_cipher,
+                &self.server_cipher,
+            );
+            // 暂存的数据不再补发，缓冲区还给池
+            context.pending().clear();
+        }
         //退出协助回收资源
         let _ = self.context.lock().take();
         self.stop_manager.stop()
@@ -1,18 +1,44 @@
 use anyhow::anyhow;
-use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::PathBuf;
 use std::str::FromStr;
 
 pub use conn::Vnt;
 
 use crate::channel::punch::PunchModel;
 use crate::channel::socket::LocalInterface;
+#[cfg(feature = "turn")]
+use crate::channel::turn::TurnServer;
 use crate::channel::{ConnectProtocol, UseChannelType};
-use crate::cipher::CipherModel;
+use crate::cipher::{CipherModel, PUBLIC_KEY_LEN};
 use crate::compression::Compressor;
 use crate::util::{address_choose, dns_query_all};
 
 mod conn;
 
+/// 静态对端，无服务器模式下直接连接
+#[derive(Clone, Debug)]
+pub struct StaticPeer {
+    pub virtual_ip: Ipv4Addr,
+    pub endpoints: Vec<SocketAddr>,
+    // 对端的设备公钥，配置后只和能用对应私钥签名的对端建立路由
+    pub public_key: Option<[u8; PUBLIC_KEY_LEN]>,
+}
+
+impl StaticPeer {
+    pub fn new(
+        virtual_ip: Ipv4Addr,
+        endpoints: Vec<SocketAddr>,
+        public_key: Option<[u8; PUBLIC_KEY_LEN]>,
+    ) -> Self {
+        Self {
+            virtual_ip,
+            endpoints,
+            public_key,
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct Config {
     #[cfg(feature = "integrated_tun")]
@@ -43,6 +69,12 @@ pub struct Config {
     #[cfg(feature = "integrated_tun")]
     #[cfg(any(target_os = "windows", target_os = "linux", target_os = "macos"))]
     pub device_name: Option<String>,
+    // tun多队列数，仅linux有效
+    #[cfg(feature = "integrated_tun")]
+    pub tun_queues: usize,
+    // tun开启IFF_VNET_HDR(TSO/GRO)，仅linux有效
+    #[cfg(feature = "integrated_tun")]
+    pub tun_offload: bool,
     pub use_channel_type: UseChannelType,
     //控制丢包率
     pub packet_loss_rate: Option<f64>,
@@ -55,6 +87,34 @@ pub struct Config {
     pub allow_wire_guard: bool,
     pub local_ipv4: Option<Ipv4Addr>,
     pub local_interface: LocalInterface,
+    // 共用异步运行时的工作线程数，0表示自动
+    pub runtime_threads: usize,
+    // 在局域网内广播信标，发现同网段的设备并直连
+    pub lan_discovery: bool,
+    // 接受哪些网关通告的网段，为空则不接受通告的路由
+    pub accept_routes: Vec<(u32, u32)>,
+    // 同一网段有多个可用网关时按流分担，否则只用优先级最高的
+    pub gateway_balance: bool,
+    // 出口节点，所有访问互联网的ipv4流量经过该节点，只接管ipv4，ipv6流量仍走物理网卡
+    pub exit_node: Option<Ipv4Addr>,
+    // 使用出口节点时网卡上设置的dns，防止dns请求从物理网卡泄露
+    pub exit_dns: Vec<Ipv4Addr>,
+    // 允许其他设备把本机作为出口节点
+    pub allow_exit: bool,
+    // 使用内置的用户态nat转发out_ips的udp和icmp，仅linux有效
+    pub masquerade: bool,
+    // 虚拟网络dns的域名后缀，设置后把<设备名>.<后缀>解析成虚拟ip
+    pub overlay_dns: Option<String>,
+    // 通过systemd-resolved把虚拟网络dns设置到网卡上
+    pub overlay_dns_resolved: bool,
+    // 静态对端，不为空时不连接服务器，使用配置的虚拟ip
+    pub static_peers: Vec<StaticPeer>,
+    pub static_netmask: Ipv4Addr,
+    // 标准TURN服务器，无法打洞时用于中转
+    #[cfg(feature = "turn")]
+    pub turn_server: Vec<TurnServer>,
+    // 保存运行状态的目录，如stun服务器健康度
+    pub state_dir: Option<PathBuf>,
 }
 
 impl Config {
@@ -69,7 +129,7 @@ impl Config {
         mut name_servers: Vec<String>,
         mut stun_server: Vec<String>,
         mut in_ips: Vec<(u32, u32, Ipv4Addr)>,
-        out_ips: Vec<(u32, u32)>,
+        mut out_ips: Vec<(u32, u32)>,
         password: Option<String>,
         mtu: Option<u32>,
         ip: Option<Ipv4Addr>,
@@ -85,6 +145,8 @@ impl Config {
         #[cfg(feature = "integrated_tun")]
         #[cfg(not(target_os = "android"))]
         device_name: Option<String>,
+        #[cfg(feature = "integrated_tun")] tun_queues: usize,
+        #[cfg(feature = "integrated_tun")] tun_offload: bool,
         use_channel_type: UseChannelType,
         packet_loss_rate: Option<f64>,
         packet_delay: u32,
@@ -95,6 +157,21 @@ impl Config {
         // 允许传递wg流量
         allow_wire_guard: bool,
         local_dev: Option<String>,
+        runtime_threads: usize,
+        lan_discovery: bool,
+        accept_routes: Vec<(u32, u32)>,
+        gateway_balance: bool,
+        exit_node: Option<Ipv4Addr>,
+        exit_dns: Vec<Ipv4Addr>,
+        allow_exit: bool,
+        masquerade: bool,
+        overlay_dns: Option<String>,
+        overlay_dns_resolved: bool,
+        static_peers: Vec<StaticPeer>,
+        static_netmask: Option<Ipv4Addr>,
+        // 例如 [user:password@turn.example.com:3478]
+        #[cfg(feature = "turn")] turn_server: Vec<String>,
+        state_dir: Option<PathBuf>,
     ) -> anyhow::Result<Self> {
         for x in stun_server.iter_mut() {
             if !x.contains(":") {
@@ -117,8 +194,29 @@ impl Config {
         if name.is_empty() || name.len() > 128 {
             return Err(anyhow!("name too long"));
         }
+        let static_netmask = static_netmask.unwrap_or(Ipv4Addr::new(255, 255, 255, 0));
+        if !static_peers.is_empty() {
+            let Some(ip) = ip else {
+                return Err(anyhow!("static peers require ip"));
+            };
+            let network = u32::from(ip) & u32::from(static_netmask);
+            for peer in &static_peers {
+                if peer.virtual_ip == ip
+                    || u32::from(peer.virtual_ip) & u32::from(static_netmask) != network
+                {
+                    return Err(anyhow!("static peer {} not in network", peer.virtual_ip));
+                }
+                if peer.endpoints.is_empty() {
+                    return Err(anyhow!(
+                        "static peer {} endpoints is empty",
+                        peer.virtual_ip
+                    ));
+                }
+            }
+        }
         let mut server_address_str = server_address_str.to_lowercase();
-        let mut _query_dns = true;
+        // 无服务器模式不解析服务器地址
+        let mut _query_dns = static_peers.is_empty();
         let mut protocol = ConnectProtocol::UDP;
         if server_address_str.starts_with("ws://") {
             #[cfg(not(feature = "ws"))]
@@ -132,6 +230,9 @@ impl Config {
             protocol = ConnectProtocol::WSS;
             _query_dns = false;
         }
+        if !static_peers.is_empty() {
+            protocol = ConnectProtocol::UDP;
+        }
 
         let mut server_address = "0.0.0.0:0".parse().unwrap();
         if _query_dns {
@@ -165,7 +266,54 @@ impl Config {
 
         #[cfg(feature = "port_mapping")]
         let port_mapping_list = crate::port_mapping::convert(port_mapping_list)?;
+        #[cfg(feature = "turn")]
+        let turn_server = turn_server
+            .iter()
+            .map(|v| TurnServer::from_str(v).map_err(|e| anyhow!("{}", e)))
+            .collect::<anyhow::Result<Vec<_>>>()?;
 
+        // macos上没有实现把dns设置到虚拟网卡，dns请求会从物理网卡泄露
+        #[cfg(target_os = "macos")]
+        if exit_node.is_some() {
+            return Err(anyhow!("exit node is not supported on macos"));
+        }
+        let exit_dns = if let Some(exit_node) = exit_node {
+            let exit_dns = if exit_dns.is_empty() {
+                vec![Ipv4Addr::new(1, 1, 1, 1), Ipv4Addr::new(8, 8, 8, 8)]
+            } else {
+                exit_dns
+            };
+            // 拆成两条/1的路由，优先于原有的默认路由，又不用删除它
+            // 只覆盖ipv4，ipv6的默认路由不变，需要防泄露时自行禁用物理网卡的ipv6
+            in_ips.push((0, 0x8000_0000, exit_node));
+            in_ips.push((0x8000_0000, 0x8000_0000, exit_node));
+            // dns服务器即使在局域网内也经过出口节点
+            for dns in &exit_dns {
+                in_ips.push((u32::from(*dns), u32::MAX, exit_node));
+            }
+            exit_dns
+        } else {
+            vec![]
+        };
+        let overlay_dns = match overlay_dns {
+            Some(suffix) => {
+                let suffix = suffix.trim_matches('.').to_ascii_lowercase();
+                if suffix.is_empty()
+                    || suffix.split('.').any(|label| {
+                        label.is_empty()
+                            || label.len() > 63
+                            || !label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+                    })
+                {
+                    return Err(anyhow!("overlay dns suffix error {:?}", suffix));
+                }
+                Some(suffix)
+            }
+            None => None,
+        };
+        if allow_exit && !out_ips.iter().any(|(_, mask)| *mask == 0) {
+            out_ips.push((0, 0));
+        }
         for (dest, mask, _) in &mut in_ips {
             *dest = *mask & *dest;
         }
@@ -174,6 +322,22 @@ impl Config {
             let (default_interface, ip) = crate::channel::socket::get_interface(local_dev)?;
             log::info!("default_interface = {:?} local_ip= {ip}", default_interface);
             (default_interface, Some(ip))
+        } else if exit_node.is_some() {
+            // 默认路由会指向虚拟网卡，自身的连接(服务器、stun、打洞)要绑定到当前的物理网卡
+            let dest = match server_address.ip() {
+                IpAddr::V4(ip) if !ip.is_unspecified() => ip,
+                _ => Ipv4Addr::new(8, 8, 8, 8),
+            };
+            match crate::channel::socket::get_default_interface(dest) {
+                Ok((default_interface, ip)) => {
+                    log::info!("default_interface = {:?} local_ip= {ip}", default_interface);
+                    (default_interface, Some(ip))
+                }
+                Err(e) => {
+                    log::warn!("获取物理网卡失败,可以使用--local-dev指定 {:?}", e);
+                    (LocalInterface::default(), None)
+                }
+            }
         } else {
             (LocalInterface::default(), None)
         };
@@ -206,6 +370,10 @@ impl Config {
             #[cfg(feature = "integrated_tun")]
             #[cfg(not(target_os = "android"))]
             device_name,
+            #[cfg(feature = "integrated_tun")]
+            tun_queues: tun_queues.max(1),
+            #[cfg(feature = "integrated_tun")]
+            tun_offload,
             use_channel_type,
             packet_loss_rate,
             packet_delay,
@@ -216,11 +384,30 @@ impl Config {
             allow_wire_guard,
             local_ipv4,
             local_interface,
+            runtime_threads,
+            lan_discovery,
+            accept_routes,
+            gateway_balance,
+            exit_node,
+            exit_dns,
+            allow_exit,
+            masquerade,
+            overlay_dns,
+            overlay_dns_resolved,
+            static_peers,
+            static_netmask,
+            #[cfg(feature = "turn")]
+            turn_server,
+            state_dir,
         })
     }
 }
 
 impl Config {
+    /// 配置了静态对端则不连接服务器
+    pub fn is_serverless(&self) -> bool {
+        !self.static_peers.is_empty()
+    }
     pub fn password_hash(&self) -> Option<[u8; 16]> {
         if let Some(p) = self.password.as_ref() {
             match self.cipher_model {
@@ -1,38 +1,278 @@
+use std::collections::HashMap;
 use std::net::Ipv4Addr;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use parking_lot::{Mutex, RwLock};
+
+// 通告的路由超过这么久没有刷新则删除
+const ADVERTISED_EXPIRE: Duration = Duration::from_secs(65);
+
+/// 网关的健康状态，没有探测过的视为Up
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum GatewayHealth {
+    #[default]
+    Up,
+    // 可达但延迟高，没有其他可用网关时才使用
+    Slow,
+    Down,
+}
+
+#[derive(Default)]
+struct RouteTable {
+    // 目标网段，子网掩码，按优先级排列的网关
+    routes: Vec<(u32, u32, Vec<Ipv4Addr>)>,
+    health: HashMap<Ipv4Addr, GatewayHealth>,
+}
 
 // 目标网段，子网掩码，网关
 #[derive(Clone)]
 pub struct ExternalRoute {
-    route_table: Vec<(u32, u32, Ipv4Addr)>,
+    // 配置的路由，同一网段配置多个网关时按配置顺序作为优先级，优先于通告的路由
+    static_table: Arc<Vec<(u32, u32, Vec<Ipv4Addr>)>>,
+    // 网关通告的路由，网关 -> (路由, 收到的时间)
+    advertised: Arc<Mutex<HashMap<Ipv4Addr, (Vec<(u32, u32)>, Instant)>>>,
+    // 合并后的路由表
+    route_table: Arc<RwLock<RouteTable>>,
+    // 接受哪些通告的路由，为空则不接受
+    accept: Arc<Vec<(u32, u32)>>,
+    // 同一网段有多个可用网关时按流分担
+    balance: bool,
 }
 
 impl ExternalRoute {
-    pub fn new(mut route_table: Vec<(u32, u32, Ipv4Addr)>) -> Self {
-        for (dest, mask, _) in &mut route_table {
+    pub fn new(
+        route_table: Vec<(u32, u32, Ipv4Addr)>,
+        mut accept: Vec<(u32, u32)>,
+        balance: bool,
+    ) -> Self {
+        let mut static_table: Vec<(u32, u32, Vec<Ipv4Addr>)> = Vec::new();
+        for (dest, mask, gateway) in route_table {
+            let dest = mask & dest;
+            if let Some((_, _, gateways)) = static_table
+                .iter_mut()
+                .find(|(d, m, _)| *d == dest && *m == mask)
+            {
+                if !gateways.contains(&gateway) {
+                    gateways.push(gateway);
+                }
+            } else {
+                static_table.push((dest, mask, vec![gateway]));
+            }
+        }
+        static_table.sort_by(|(dest1, _, _), (dest2, _, _)| dest2.cmp(dest1));
+        longest_prefix_first(&mut static_table);
+        for (dest, mask) in &mut accept {
             *dest = *mask & *dest;
         }
-        route_table.sort_by(|(dest1, _, _), (dest2, _, _)| dest2.cmp(dest1));
-        Self { route_table }
+        Self {
+            route_table: Arc::new(RwLock::new(RouteTable {
+                routes: static_table.clone(),
+                health: HashMap::new(),
+            })),
+            static_table: Arc::new(static_table),
+            advertised: Default::default(),
+            accept: Arc::new(accept),
+            balance,
+        }
     }
-    pub fn route(&self, ip: &Ipv4Addr) -> Option<Ipv4Addr> {
-        if self.route_table.is_empty() {
+    /// flow是数据包的流哈希，开启负载分担时同一个流总是走同一个网关
+    pub fn route(&self, ip: &Ipv4Addr, flow: u32) -> Option<Ipv4Addr> {
+        let route_table = self.route_table.read();
+        if route_table.routes.is_empty() {
             return None;
         }
         let ip = u32::from_be_bytes(ip.octets());
-        for (dest, mask, gateway) in self.route_table.iter() {
+        for (dest, mask, gateways) in route_table.routes.iter() {
             if *mask & ip == *dest {
-                return Some(*gateway);
+                return Some(self.select(&route_table.health, gateways, flow));
             }
         }
         None
     }
+    fn select(
+        &self,
+        health: &HashMap<Ipv4Addr, GatewayHealth>,
+        gateways: &[Ipv4Addr],
+        flow: u32,
+    ) -> Ipv4Addr {
+        if gateways.len() == 1 {
+            return gateways[0];
+        }
+        let state = |gateway: &Ipv4Addr| health.get(gateway).copied().unwrap_or_default();
+        for expect in [GatewayHealth::Up, GatewayHealth::Slow] {
+            if self.balance {
+                let count = gateways.iter().filter(|v| state(v) == expect).count();
+                if count > 0 {
+                    let index = flow as usize % count;
+                    return *gateways
+                        .iter()
+                        .filter(|v| state(v) == expect)
+                        .nth(index)
+                        .unwrap();
+                }
+            } else if let Some(gateway) = gateways.iter().find(|v| state(v) == expect) {
+                return *gateway;
+            }
+        }
+        // 都不可用时使用优先级最高的，等待恢复
+        gateways[0]
+    }
+    /// 配置的路由，创建网卡时添加
     pub fn to_route(&self) -> Vec<(Ipv4Addr, Ipv4Addr)> {
-        self.route_table
+        self.static_table
             .iter()
             .map(|(dest, mask, _)| (Ipv4Addr::from(*dest), Ipv4Addr::from(*mask)))
             .collect::<Vec<(Ipv4Addr, Ipv4Addr)>>()
     }
+    /// 当前生效的通告路由，不包含和配置重复的
+    pub fn advertised_routes(&self) -> Vec<(Ipv4Addr, Ipv4Addr)> {
+        let route_table = self.route_table.read();
+        route_table.routes[self.static_table.len()..]
+            .iter()
+            .map(|(dest, mask, _)| (Ipv4Addr::from(*dest), Ipv4Addr::from(*mask)))
+            .collect()
+    }
+    /// 路由表中的所有网关
+    pub fn gateways(&self) -> Vec<Ipv4Addr> {
+        let route_table = self.route_table.read();
+        let mut list: Vec<Ipv4Addr> = route_table
+            .routes
+            .iter()
+            .flat_map(|(_, _, gateways)| gateways.iter().copied())
+            .collect();
+        list.sort();
+        list.dedup();
+        list
+    }
+    /// 更新网关的健康状态，返回状态有变化的网关
+    pub fn update_health(
+        &self,
+        health: HashMap<Ipv4Addr, GatewayHealth>,
+    ) -> Vec<(Ipv4Addr, GatewayHealth)> {
+        let mut route_table = self.route_table.write();
+        let changed = health
+            .iter()
+            .filter(|(gateway, state)| {
+                route_table.health.get(gateway).copied().unwrap_or_default() != **state
+            })
+            .map(|(gateway, state)| (*gateway, *state))
+            .collect();
+        route_table.health = health;
+        changed
+    }
+    pub fn accept(&self, dest: u32, mask: u32) -> bool {
+        self.accept
+            .iter()
+            .any(|(a_dest, a_mask)| mask & *a_mask == *a_mask && dest & *a_mask == *a_dest)
+    }
+    /// 更新网关通告的路由，只保留允许接受的，返回路由表是否变化
+    pub fn accept_advertised(&self, gateway: Ipv4Addr, routes: Vec<(u32, u32)>) -> bool {
+        let mut routes: Vec<(u32, u32)> = routes
+            .into_iter()
+            .map(|(dest, mask)| (dest & mask, mask))
+            // 默认路由只能通过--exit-node显式选择
+            .filter(|(dest, mask)| *mask != 0 && self.accept(*dest, *mask))
+            .collect();
+        routes.sort();
+        routes.dedup();
+        let mut advertised = self.advertised.lock();
+        if routes.is_empty() {
+            if advertised.remove(&gateway).is_none() {
+                return false;
+            }
+        } else if let Some((old, time)) = advertised.get_mut(&gateway) {
+            *time = Instant::now();
+            if *old == routes {
+                return false;
+            }
+            *old = routes;
+        } else {
+            advertised.insert(gateway, (routes, Instant::now()));
+        }
+        self.rebuild(&advertised);
+        true
+    }
+    /// 网关离开时删除它通告的路由
+    pub fn remove_advertised(&self, gateway: &Ipv4Addr) -> bool {
+        let mut advertised = self.advertised.lock();
+        if advertised.remove(gateway).is_none() {
+            return false;
+        }
+        self.rebuild(&advertised);
+        true
+    }
+    /// 删除过期的通告路由，返回路由表是否变化
+    pub fn expire_advertised(&self) -> bool {
+        let mut advertised = self.advertised.lock();
+        let len = advertised.len();
+        advertised.retain(|_, (_, time)| time.elapsed() < ADVERTISED_EXPIRE);
+        if advertised.len() == len {
+            return false;
+        }
+        self.rebuild(&advertised);
+        true
+    }
+    fn rebuild(&self, advertised: &HashMap<Ipv4Addr, (Vec<(u32, u32)>, Instant)>) {
+        let mut list: Vec<(u32, u32, Ipv4Addr)> = Vec::new();
+        for (gateway, (routes, _)) in advertised {
+            for (dest, mask) in routes {
+                list.push((*dest, *mask, *gateway));
+            }
+        }
+        // 掩码长的优先，同一网段多个网关时ip小的优先，保证各节点选择一致
+        list.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)).then(a.2.cmp(&b.2)));
+        let mut routes = self.static_table.to_vec();
+        for (dest, mask, gateway) in list {
+            // 和配置的网段相同时作为备用网关
+            if let Some((_, _, gateways)) =
+                routes.iter_mut().find(|(d, m, _)| *d == dest && *m == mask)
+            {
+                if !gateways.contains(&gateway) {
+                    gateways.push(gateway);
+                }
+            } else {
+                routes.push((dest, mask, vec![gateway]));
+            }
+        }
+        longest_prefix_first(&mut routes);
+        self.route_table.write().routes = routes;
+    }
+}
+
+/// route按顺序取第一个匹配的网段，掩码长的排在前面就是最长前缀匹配。
+/// 稳定排序，掩码相同时配置的网段仍在通告的前面
+fn longest_prefix_first(routes: &mut [(u32, u32, Vec<Ipv4Addr>)]) {
+    routes.sort_by_key(|(_, mask, _)| std::cmp::Reverse(mask.count_ones()));
+}
+
+/// 数据包的流哈希(源地址、目的地址、协议、端口)，ip_packet是完整的ipv4包
+pub fn flow_hash(ip_packet: &[u8]) -> u32 {
+    if ip_packet.len() < 20 {
+        return 0;
+    }
+    let header_len = ((ip_packet[0] & 0x0f) as usize) * 4;
+    let protocol = ip_packet[9];
+    let mut hash: u32 = 0x811c9dc5;
+    let mut update = |bytes: &[u8]| {
+        for b in bytes {
+            hash ^= *b as u32;
+            hash = hash.wrapping_mul(0x01000193);
+        }
+    };
+    update(&ip_packet[12..20]);
+    update(&[protocol]);
+    // 分片只有第一片带端口，分片时只用地址和协议，保证同一个包的所有分片走同一个网关
+    let more_fragments = ip_packet[6] & 0x20 != 0;
+    let fragment_offset = u16::from_be_bytes([ip_packet[6], ip_packet[7]]) & 0x1fff;
+    if more_fragments || fragment_offset != 0 {
+        return hash;
+    }
+    // tcp、udp加上端口
+    if (protocol == 6 || protocol == 17) && ip_packet.len() >= header_len + 4 {
+        update(&ip_packet[header_len..header_len + 4]);
+    }
+    hash
 }
 
 // 目标网段，子网掩码
@@ -64,3 +304,159 @@ impl AllowExternalRoute {
         false
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn advertised() {
+        let gateway1 = Ipv4Addr::new(10, 26, 0, 2);
+        let gateway2 = Ipv4Addr::new(10, 26, 0, 3);
+        let net = |a, b, c, d| u32::from(Ipv4Addr::new(a, b, c, d));
+        let route = ExternalRoute::new(
+            vec![(net(192, 168, 1, 0), net(255, 255, 255, 0), gateway1)],
+            vec![(net(192, 168, 0, 0), net(255, 255, 0, 0))],
+            false,
+        );
+        // 不在接受范围内的忽略
+        assert!(!route.accept_advertised(gateway2, vec![(net(172, 16, 0, 0), net(255, 240, 0, 0))]));
+        assert!(route.accept_advertised(
+            gateway2,
+            vec![
+                (net(192, 168, 1, 0), net(255, 255, 255, 0)),
+                (net(192, 168, 2, 1), net(255, 255, 255, 0)),
+                (net(192, 0, 0, 0), net(255, 0, 0, 0)),
+            ]
+        ));
+        // 没有变化
+        assert!(!route.accept_advertised(
+            gateway2,
+            vec![
+                (net(192, 168, 2, 0), net(255, 255, 255, 0)),
+                (net(192, 168, 1, 0), net(255, 255, 255, 0)),
+            ]
+        ));
+        // 和配置重复的以配置为准
+        assert_eq!(
+            route.route(&Ipv4Addr::new(192, 168, 1, 5), 0),
+            Some(gateway1)
+        );
+        assert_eq!(
+            route.route(&Ipv4Addr::new(192, 168, 2, 5), 0),
+            Some(gateway2)
+        );
+        assert_eq!(
+            route.advertised_routes(),
+            vec![(
+                Ipv4Addr::new(192, 168, 2, 0),
+                Ipv4Addr::new(255, 255, 255, 0)
+            )]
+        );
+        assert!(route.remove_advertised(&gateway2));
+        assert_eq!(route.route(&Ipv4Addr::new(192, 168, 2, 5), 0), None);
+        assert!(route.advertised_routes().is_empty());
+    }
+
+    #[test]
+    fn longest_prefix() {
+        let gateway1 = Ipv4Addr::new(10, 26, 0, 2);
+        let gateway2 = Ipv4Addr::new(10, 26, 0, 3);
+        let net = |a, b, c, d| u32::from(Ipv4Addr::new(a, b, c, d));
+        let route = ExternalRoute::new(
+            vec![
+                (net(192, 168, 0, 0), net(255, 255, 0, 0), gateway1),
+                (net(192, 168, 1, 0), net(255, 255, 255, 0), gateway1),
+            ],
+            vec![(net(192, 168, 0, 0), net(255, 255, 0, 0))],
+            false,
+        );
+        // 通告的网段比配置的更精确
+        assert!(route.accept_advertised(
+            gateway2,
+            vec![
+                (net(192, 168, 2, 0), net(255, 255, 255, 0)),
+                (net(192, 168, 1, 128), net(255, 255, 255, 128)),
+            ]
+        ));
+        assert_eq!(
+            route.route(&Ipv4Addr::new(192, 168, 2, 5), 0),
+            Some(gateway2)
+        );
+        assert_eq!(
+            route.route(&Ipv4Addr::new(192, 168, 1, 200), 0),
+            Some(gateway2)
+        );
+        assert_eq!(
+            route.route(&Ipv4Addr::new(192, 168, 1, 5), 0),
+            Some(gateway1)
+        );
+        assert_eq!(
+            route.route(&Ipv4Addr::new(192, 168, 3, 5), 0),
+            Some(gateway1)
+        );
+    }
+
+    #[test]
+    fn fragment_flow_hash() {
+        let packet = |flags_offset: u16, ports: [u8; 4]| {
+            let mut buf = [0u8; 28];
+            buf[0] = 0x45;
+            buf[6..8].copy_from_slice(&flags_offset.to_be_bytes());
+            buf[9] = 17;
+            buf[12..16].copy_from_slice(&[10, 26, 0, 2]);
+            buf[16..20].copy_from_slice(&[192, 168, 1, 5]);
+            buf[20..24].copy_from_slice(&ports);
+            buf
+        };
+        // 第一片带端口，后续分片的相同位置是数据，哈希要一致
+        let first = flow_hash(&packet(0x2000, [0x13, 0x88, 0x00, 0x35]));
+        let middle = flow_hash(&packet(0x2000 | 185, [1, 2, 3, 4]));
+        let last = flow_hash(&packet(370, [5, 6, 7, 8]));
+        assert_eq!(first, middle);
+        assert_eq!(first, last);
+        // 没有分片时区分端口
+        assert_ne!(
+            flow_hash(&packet(0x4000, [0x13, 0x88, 0x00, 0x35])),
+            flow_hash(&packet(0x4000, [0x13, 0x89, 0x00, 0x35]))
+        );
+    }
+
+    #[test]
+    fn failover() {
+        let gateway1 = Ipv4Addr::new(10, 26, 0, 2);
+        let gateway2 = Ipv4Addr::new(10, 26, 0, 3);
+        let net = u32::from(Ipv4Addr::new(192, 168, 1, 0));
+        let mask = u32::from(Ipv4Addr::new(255, 255, 255, 0));
+        let dest = Ipv4Addr::new(192, 168, 1, 5);
+        let route = ExternalRoute::new(
+            vec![(net, mask, gateway1), (net, mask, gateway2)],
+            vec![],
+            false,
+        );
+        assert_eq!(route.gateways(), vec![gateway1, gateway2]);
+        assert_eq!(route.route(&dest, 1), Some(gateway1));
+        let changed = route.update_health(HashMap::from([
+            (gateway1, GatewayHealth::Down),
+            (gateway2, GatewayHealth::Up),
+        ]));
+        assert_eq!(changed, vec![(gateway1, GatewayHealth::Down)]);
+        assert_eq!(route.route(&dest, 1), Some(gateway2));
+        // 高延迟的也比不可用的好
+        route.update_health(HashMap::from([
+            (gateway1, GatewayHealth::Slow),
+            (gateway2, GatewayHealth::Down),
+        ]));
+        assert_eq!(route.route(&dest, 1), Some(gateway1));
+
+        let route = ExternalRoute::new(
+            vec![(net, mask, gateway1), (net, mask, gateway2)],
+            vec![],
+            true,
+        );
+        assert_eq!(route.route(&dest, 0), Some(gateway1));
+        assert_eq!(route.route(&dest, 1), Some(gateway2));
+        route.update_health(HashMap::from([(gateway2, GatewayHealth::Down)]));
+        assert_eq!(route.route(&dest, 1), Some(gateway1));
+    }
+}
@@ -3,7 +3,7 @@ use crate::handle::PeerDeviceStatus;
 use rsa::RsaPublicKey;
 use std::fmt::{Display, Formatter};
 use std::io;
-use std::net::{Ipv4Addr, SocketAddr};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
 
 #[cfg(any(target_os = "windows", target_os = "linux", target_os = "macos"))]
 #[derive(Debug)]
@@ -127,6 +127,43 @@ impl RegisterInfo {
     }
 }
 
+#[derive(Debug)]
+pub struct NetworkChangeInfo {
+    //变化前的本地ipv4
+    pub old_ipv4: Option<Ipv4Addr>,
+    //变化后的本地ipv4
+    pub new_ipv4: Option<Ipv4Addr>,
+    //变化前的本地ipv6
+    pub old_ipv6: Option<Ipv6Addr>,
+    //变化后的本地ipv6
+    pub new_ipv6: Option<Ipv6Addr>,
+}
+
+impl Display for NetworkChangeInfo {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&format!(
+            "ipv4={:?}->{:?} ,ipv6={:?}->{:?}",
+            self.old_ipv4, self.new_ipv4, self.old_ipv6, self.new_ipv6
+        ))
+    }
+}
+
+impl NetworkChangeInfo {
+    pub fn new(
+        old_ipv4: Option<Ipv4Addr>,
+        new_ipv4: Option<Ipv4Addr>,
+        old_ipv6: Option<Ipv6Addr>,
+        new_ipv6: Option<Ipv6Addr>,
+    ) -> Self {
+        Self {
+            old_ipv4,
+            new_ipv4,
+            old_ipv6,
+            new_ipv6,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct ErrorInfo {
     pub code: ErrorType,
@@ -201,6 +238,13 @@ pub struct DeviceConfig {
     #[cfg(feature = "integrated_tun")]
     #[cfg(any(target_os = "windows", target_os = "linux", target_os = "macos"))]
     pub device_name: Option<String>,
+    // tun多队列数
+    #[cfg(feature = "integrated_tun")]
+    #[cfg(target_os = "linux")]
+    pub tun_queues: usize,
+    #[cfg(feature = "integrated_tun")]
+    #[cfg(target_os = "linux")]
+    pub tun_offload: bool,
     //虚拟网卡mtu值
     pub mtu: u32,
     //本机虚拟IP
@@ -223,6 +267,12 @@ impl DeviceConfig {
         #[cfg(feature = "integrated_tun")]
         #[cfg(any(target_os = "windows", target_os = "linux", target_os = "macos"))]
         device_name: Option<String>,
+        #[cfg(feature = "integrated_tun")]
+        #[cfg(target_os = "linux")]
+        tun_queues: usize,
+        #[cfg(feature = "integrated_tun")]
+        #[cfg(target_os = "linux")]
+        tun_offload: bool,
         mtu: u32,
         virtual_ip: Ipv4Addr,
         virtual_netmask: Ipv4Addr,
@@ -237,6 +287,12 @@ impl DeviceConfig {
             #[cfg(feature = "integrated_tun")]
             #[cfg(any(target_os = "windows", target_os = "linux", target_os = "macos"))]
             device_name,
+            #[cfg(feature = "integrated_tun")]
+            #[cfg(target_os = "linux")]
+            tun_queues,
+            #[cfg(feature = "integrated_tun")]
+            #[cfg(target_os = "linux")]
+            tun_offload,
             mtu,
             virtual_ip,
             virtual_netmask,
@@ -315,6 +371,10 @@ pub trait VntCallback: Clone + Send + Sync + 'static {
         0
     }
     fn peer_client_list(&self, _info: Vec<PeerClientInfo>) {}
+    /// 本地网络变化，已开始重新探测和连接
+    fn network_change(&self, _info: NetworkChangeInfo) {}
+    /// 网关通告的路由变化，参数为当前生效的通告路由(目标网段，子网掩码)
+    fn external_route_change(&self, _routes: Vec<(Ipv4Addr, Ipv4Addr)>) {}
     /// 异常信息
     fn error(&self, _info: ErrorInfo) {}
     /// 服务停止
@@ -0,0 +1,302 @@
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+use protobuf::Message;
+use rand::prelude::SliceRandom;
+
+use crate::channel::candidate::Candidate;
+use crate::cipher::{verify, DeviceIdentity, NetworkKey, PUBLIC_KEY_LEN};
+use crate::proto::message::{GossipEntry, GossipList};
+
+// 来源设备超过这么久没有更新的条目不再使用和传播
+pub const GOSSIP_TTL: u64 = 5 * 60 * 1000;
+// 允许的时钟偏差
+const MAX_CLOCK_SKEW: u64 = 60 * 1000;
+const SIGNATURE_LEN: usize = 16;
+const MAX_ENDPOINTS: usize = 8;
+// 单个包的负载上限，避免分片
+const MAX_PAYLOAD: usize = 1100;
+
+/// 通过p2p通道互相传播的设备目录，服务器离线时用来维持设备列表和提供打洞地址。
+/// 条目用组网密钥计算HMAC，其他网络的设备无法伪造；
+/// 同时由来源设备的身份私钥签名，网络内的其他成员也无法伪造或篡改。
+/// 设备的公钥第一次见到时记录下来(静态对端使用配置的公钥)，之后的条目必须用同一个公钥签名，
+/// 只有该设备的条目都过期后，直接从该设备收到的条目才能更换公钥(设备重装等)
+#[derive(Clone)]
+pub struct Gossip {
+    key: NetworkKey,
+    identity: DeviceIdentity,
+    entries: Arc<Mutex<HashMap<Ipv4Addr, GossipEntry>>>,
+    // 虚拟ip对应的设备公钥
+    identities: Arc<Mutex<HashMap<Ipv4Addr, [u8; PUBLIC_KEY_LEN]>>>,
+    // 配置的公钥，不会更换
+    trusted: Arc<HashMap<Ipv4Addr, [u8; PUBLIC_KEY_LEN]>>,
+}
+
+impl Gossip {
+    pub fn new(
+        token: &str,
+        client_secret_hash: Option<&[u8; 16]>,
+        identity: DeviceIdentity,
+        trusted: HashMap<Ipv4Addr, [u8; PUBLIC_KEY_LEN]>,
+    ) -> Self {
+        Self {
+            key: NetworkKey::new("vnt-gossip", token, client_secret_hash),
+            identity,
+            entries: Default::default(),
+            identities: Default::default(),
+            trusted: Arc::new(trusted),
+        }
+    }
+    /// 生成本机的条目，计算HMAC并用设备私钥签名
+    pub fn update_local(
+        &self,
+        virtual_ip: Ipv4Addr,
+        name: &str,
+        client_secret_hash: Option<&[u8; 16]>,
+        endpoints: &[Candidate],
+        now: u64,
+    ) {
+        let mut entry = GossipEntry::new();
+        entry.virtual_ip = virtual_ip.into();
+        entry.name = name.to_string();
+        entry.version = now;
+        entry.client_secret = client_secret_hash.is_some();
+        entry.client_secret_hash = client_secret_hash.map_or(Vec::new(), |v| v.to_vec());
+        entry.endpoints = endpoints
+            .iter()
+            .filter(|v| !v.tcp)
+            .take(MAX_ENDPOINTS)
+            .map(|v| v.into_proto())
+            .collect();
+        entry.public_key = self.identity.public_key().to_vec();
+        let data = sign_data(&entry);
+        entry.signature = self.key.sign(&data)[..SIGNATURE_LEN].to_vec();
+        entry.device_signature = self.identity.sign(&data).to_vec();
+        self.entries.lock().insert(virtual_ip, entry);
+    }
+    /// 合并收到的条目，只保留验证通过且更新的，返回更新的数量。
+    /// direct是通过自己的通道直接发来这个列表的设备，用于更换该设备的公钥
+    pub fn merge(
+        &self,
+        list: GossipList,
+        self_ip: Ipv4Addr,
+        direct: Option<Ipv4Addr>,
+        now: u64,
+    ) -> usize {
+        let mut count = 0;
+        let mut entries = self.entries.lock();
+        entries.retain(|_, v| v.version + GOSSIP_TTL > now);
+        let mut identities = self.identities.lock();
+        for entry in list.entries {
+            let ip = Ipv4Addr::from(entry.virtual_ip);
+            if ip == self_ip || !self.verify(&entry, now) {
+                continue;
+            }
+            let public_key: [u8; PUBLIC_KEY_LEN] = entry.public_key[..].try_into().unwrap();
+            let known = self
+                .trusted
+                .get(&ip)
+                .or_else(|| identities.get(&ip))
+                .copied();
+            if let Some(known) = known {
+                // 更换公钥需要是该设备直接发来的，并且旧公钥的条目已经过期
+                let replace = !self.trusted.contains_key(&ip)
+                    && direct == Some(ip)
+                    && !entries.contains_key(&ip);
+                if known != public_key && !replace {
+                    log::warn!("设备目录条目{}的公钥和记录的不一致", ip);
+                    continue;
+                }
+            }
+            if let Some(old) = entries.get(&ip) {
+                if old.version >= entry.version {
+                    continue;
+                }
+            }
+            identities.insert(ip, public_key);
+            entries.insert(ip, entry);
+            count += 1;
+        }
+        count
+    }
+    /// 选出要发送的条目，本机的排在最前，其余随机选取直到达到包大小上限
+    pub fn select(&self, self_ip: Ipv4Addr, now: u64) -> GossipList {
+        let mut list = GossipList::new();
+        let mut size = 0;
+        let mut entries = self.fresh(now);
+        entries.shuffle(&mut rand::thread_rng());
+        if let Some(pos) = entries
+            .iter()
+            .position(|v| v.virtual_ip == u32::from(self_ip))
+        {
+            entries.swap(0, pos);
+        }
+        for entry in entries {
+            // 每个条目在列表中还有几个字节的标签和长度
+            let len = entry.compute_size() as usize + 4;
+            if size + len > MAX_PAYLOAD {
+                continue;
+            }
+            size += len;
+            list.entries.push(entry);
+        }
+        list
+    }
+    /// 还在有效期内的条目
+    pub fn fresh(&self, now: u64) -> Vec<GossipEntry> {
+        let mut entries = self.entries.lock();
+        entries.retain(|_, v| v.version + GOSSIP_TTL > now);
+        entries.values().cloned().collect()
+    }
+    fn verify(&self, entry: &GossipEntry, now: u64) -> bool {
+        if entry.version + GOSSIP_TTL <= now || entry.version > now + MAX_CLOCK_SKEW {
+            return false;
+        }
+        if entry.endpoints.len() > MAX_ENDPOINTS {
+            return false;
+        }
+        let Ok(public_key) = entry.public_key[..].try_into() else {
+            return false;
+        };
+        let data = sign_data(entry);
+        self.key.verify(&data, &entry.signature)
+            && verify(&public_key, &data, &entry.device_signature)
+    }
+}
+
+/// 计算HMAC和签名的内容，不直接用protobuf编码，避免不同版本编码结果不一致
+fn sign_data(entry: &GossipEntry) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(64);
+    buf.extend_from_slice(&entry.virtual_ip.to_be_bytes());
+    buf.extend_from_slice(&entry.version.to_be_bytes());
+    buf.extend_from_slice(&(entry.name.len() as u16).to_be_bytes());
+    buf.extend_from_slice(entry.name.as_bytes());
+    buf.push(entry.client_secret as u8);
+    buf.push(entry.client_secret_hash.len() as u8);
+    buf.extend_from_slice(&entry.client_secret_hash);
+    buf.push(entry.endpoints.len() as u8);
+    for endpoint in &entry.endpoints {
+        buf.extend_from_slice(&endpoint.kind.value().to_be_bytes());
+        buf.push(endpoint.tcp as u8);
+        buf.push(endpoint.ip.len() as u8);
+        buf.extend_from_slice(&endpoint.ip);
+        buf.extend_from_slice(&endpoint.port.to_be_bytes());
+        buf.extend_from_slice(&endpoint.priority.to_be_bytes());
+        buf.extend_from_slice(&endpoint.base.to_be_bytes());
+    }
+    buf.extend_from_slice(&entry.public_key);
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channel::candidate::CandidateKind;
+
+    fn new_gossip(client_secret_hash: Option<&[u8; 16]>) -> Gossip {
+        Gossip::new(
+            "token",
+            client_secret_hash,
+            DeviceIdentity::load(None).unwrap(),
+            HashMap::new(),
+        )
+    }
+
+    #[test]
+    fn merge() {
+        let a = new_gossip(None);
+        let b = new_gossip(None);
+        let ip_a = Ipv4Addr::new(10, 26, 0, 2);
+        let ip_b = Ipv4Addr::new(10, 26, 0, 3);
+        let endpoint = Candidate::new(
+            CandidateKind::ServerReflexive,
+            false,
+            "1.2.3.4:5000".parse().unwrap(),
+            Some(0),
+        );
+        a.update_local(ip_a, "a", None, &[endpoint], 10_000);
+        assert_eq!(b.merge(a.select(ip_a, 10_000), ip_b, None, 10_000), 1);
+        // 旧的或重复的不更新
+        assert_eq!(b.merge(a.select(ip_a, 10_000), ip_b, None, 10_000), 0);
+        let fresh = b.fresh(10_000);
+        assert_eq!(fresh.len(), 1);
+        assert_eq!(
+            Candidate::from_proto(&fresh[0].endpoints[0]).unwrap().addr,
+            endpoint.addr
+        );
+        // 过期
+        assert!(b.fresh(10_000 + GOSSIP_TTL).is_empty());
+    }
+
+    #[test]
+    fn reject() {
+        let a = new_gossip(None);
+        let ip_a = Ipv4Addr::new(10, 26, 0, 2);
+        let ip_b = Ipv4Addr::new(10, 26, 0, 3);
+        a.update_local(ip_a, "a", None, &[], 10_000);
+        // 其他网络
+        let other = new_gossip(Some(&[1; 16]));
+        assert_eq!(other.merge(a.select(ip_a, 10_000), ip_b, None, 10_000), 0);
+        // 被篡改
+        let b = new_gossip(None);
+        let mut list = a.select(ip_a, 10_000);
+        list.entries[0].name = "b".into();
+        assert_eq!(b.merge(list, ip_b, None, 10_000), 0);
+        // 时间太超前
+        a.update_local(ip_a, "a", None, &[], 10_000 + MAX_CLOCK_SKEW + 1);
+        assert_eq!(b.merge(a.select(ip_a, 10_000), ip_b, None, 10_000), 0);
+    }
+
+    #[test]
+    fn forge() {
+        let a = new_gossip(None);
+        let b = new_gossip(None);
+        // 同一网络的其他成员，持有相同的组网密钥
+        let c = new_gossip(None);
+        let ip_a = Ipv4Addr::new(10, 26, 0, 2);
+        let ip_b = Ipv4Addr::new(10, 26, 0, 3);
+        let ip_c = Ipv4Addr::new(10, 26, 0, 4);
+        a.update_local(ip_a, "a", None, &[], 10_000);
+        assert_eq!(b.merge(a.select(ip_a, 10_000), ip_b, Some(ip_a), 10_000), 1);
+        // 篡改后重新计算HMAC，设备签名不对
+        let mut list = a.select(ip_a, 10_000);
+        list.entries[0].name = "c".into();
+        list.entries[0].version = 11_000;
+        list.entries[0].signature =
+            c.key.sign(&sign_data(&list.entries[0]))[..SIGNATURE_LEN].to_vec();
+        assert_eq!(b.merge(list, ip_b, None, 11_000), 0);
+        // 用自己的密钥冒充a，公钥和记录的不一致
+        c.update_local(ip_a, "c", None, &[], 11_000);
+        assert_eq!(b.merge(c.select(ip_a, 11_000), ip_b, Some(ip_c), 11_000), 0);
+        // 冒充a直接发送，a的条目还没过期也不能更换
+        assert_eq!(b.merge(c.select(ip_a, 11_000), ip_b, Some(ip_a), 11_000), 0);
+        // a的条目过期后，a直接发来新公钥的条目可以更换
+        let now = 10_000 + GOSSIP_TTL;
+        let a2 = new_gossip(None);
+        a2.update_local(ip_a, "a", None, &[], now);
+        assert_eq!(b.merge(a2.select(ip_a, now), ip_b, None, now), 0);
+        assert_eq!(b.merge(a2.select(ip_a, now), ip_b, Some(ip_a), now), 1);
+    }
+
+    #[test]
+    fn trusted() {
+        let a = new_gossip(None);
+        let c = new_gossip(None);
+        let ip_a = Ipv4Addr::new(10, 26, 0, 2);
+        let ip_b = Ipv4Addr::new(10, 26, 0, 3);
+        let b = Gossip::new(
+            "token",
+            None,
+            DeviceIdentity::load(None).unwrap(),
+            HashMap::from([(ip_a, a.identity.public_key())]),
+        );
+        // 配置了公钥的设备，第一次见到的条目也要验证公钥
+        c.update_local(ip_a, "c", None, &[], 10_000);
+        assert_eq!(b.merge(c.select(ip_a, 10_000), ip_b, Some(ip_a), 10_000), 0);
+        a.update_local(ip_a, "a", None, &[], 10_000);
+        assert_eq!(b.merge(a.select(ip_a, 10_000), ip_b, None, 10_000), 1);
+    }
+}
@@ -1,10 +1,9 @@
 use std::io;
-use std::net::SocketAddr;
+use std::net::{Ipv4Addr, SocketAddr};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 
 use crossbeam_utils::atomic::AtomicCell;
-#[cfg(feature = "server_encrypt")]
 use parking_lot::Mutex;
 use protobuf::Message;
 
@@ -12,18 +11,30 @@ use crate::channel::context::ChannelContext;
 #[cfg(feature = "server_encrypt")]
 use crate::cipher::RsaCipher;
 use crate::handle::{GATEWAY_IP, SELF_IP};
-use crate::proto::message::HandshakeRequest;
 #[cfg(feature = "server_encrypt")]
 use crate::proto::message::SecretHandshakeRequest;
+use crate::proto::message::{HandshakeRequest, ResumeRequest};
 #[cfg(feature = "server_encrypt")]
 use crate::protocol::body::RSA_ENCRYPTION_RESERVED;
 use crate::protocol::{service_packet, NetPacket, Protocol, MAX_TTL};
 
+// 服务端没有给出有效期时使用
+const DEFAULT_TICKET_LIFETIME: Duration = Duration::from_secs(60);
+
+/// 服务端签发的会话票据
+struct SessionTicket {
+    ticket: Vec<u8>,
+    device_id: String,
+    virtual_ip: Ipv4Addr,
+    expire: Instant,
+}
+
 #[derive(Clone)]
 pub struct Handshake {
     time: Arc<AtomicCell<Instant>>,
     #[cfg(feature = "server_encrypt")]
     rsa_cipher: Arc<Mutex<Option<RsaCipher>>>,
+    ticket: Arc<Mutex<Option<SessionTicket>>>,
 }
 impl Handshake {
     pub fn new(
@@ -37,6 +48,7 @@ impl Handshake {
             )),
             #[cfg(feature = "server_encrypt")]
             rsa_cipher,
+            ticket: Arc::new(Mutex::new(None)),
         }
     }
     pub fn send(&self, context: &ChannelContext, secret: bool, addr: SocketAddr) -> io::Result<()> {
@@ -45,12 +57,108 @@ impl Handshake {
         if last.elapsed() < Duration::from_secs(3) {
             return Ok(());
         }
-        let request_packet = self.handshake_request_packet(secret)?;
-        log::info!("发送握手请求,secret={},{:?}", secret, addr);
-        context.send_default(&request_packet, addr)?;
+        let mut ticket = self.take_ticket();
+        let resume_packet = match &ticket {
+            Some(v) => self.resume_request_packet(v, secret)?,
+            None => None,
+        };
+        let request_packet = if let Some(packet) = resume_packet {
+            log::info!("发送会话恢复请求,{:?}", addr);
+            packet
+        } else {
+            ticket = None;
+            log::info!("发送握手请求,secret={},{:?}", secret, addr);
+            self.handshake_request_packet(secret)?
+        };
+        if let Err(e) = context.send_default(&request_packet, addr) {
+            // 没有发出去，票据留给重新建立连接时使用
+            if let Some(ticket) = ticket {
+                self.ticket.lock().get_or_insert(ticket);
+            }
+            return Err(e);
+        }
         self.time.store(Instant::now());
         Ok(())
     }
+    /// 建立新连接时发送的第一个包，有可用的会话票据时直接恢复会话
+    pub fn request_packet(&self, secret: bool) -> io::Result<NetPacket<Vec<u8>>> {
+        if let Some(ticket) = self.take_ticket() {
+            if let Some(packet) = self.resume_request_packet(&ticket, secret)? {
+                return Ok(packet);
+            }
+        }
+        self.handshake_request_packet(secret)
+    }
+    /// 保存注册成功时服务端签发的票据
+    pub fn set_ticket(
+        &self,
+        ticket: Vec<u8>,
+        lifetime: u32,
+        device_id: String,
+        virtual_ip: Ipv4Addr,
+    ) {
+        if ticket.is_empty() {
+            self.clear_ticket();
+            return;
+        }
+        let lifetime = if lifetime == 0 {
+            DEFAULT_TICKET_LIFETIME
+        } else {
+            Duration::from_secs(lifetime as u64)
+        };
+        self.ticket.lock().replace(SessionTicket {
+            ticket,
+            device_id,
+            virtual_ip,
+            expire: Instant::now() + lifetime,
+        });
+    }
+    pub fn clear_ticket(&self) {
+        self.ticket.lock().take();
+    }
+    /// 票据只尝试一次，服务端没有回应时下次重新握手
+    fn take_ticket(&self) -> Option<SessionTicket> {
+        self.ticket
+            .lock()
+            .take()
+            .filter(|v| v.expire > Instant::now())
+    }
+    /// 会话恢复请求。启用服务端加密时票据只用上次握手得到的服务端公钥加密发送，
+    /// 只有持有私钥的服务端能读取；没有公钥或者加密失败时返回None，改为完整握手
+    fn resume_request_packet(
+        &self,
+        ticket: &SessionTicket,
+        secret: bool,
+    ) -> io::Result<Option<NetPacket<Vec<u8>>>> {
+        let bytes = resume_request(ticket)?;
+        if !secret {
+            let mut net_packet = NetPacket::new(vec![0u8; 12 + bytes.len()])?;
+            set_resume_head(&mut net_packet);
+            net_packet.set_payload(&bytes)?;
+            return Ok(Some(net_packet));
+        }
+        #[cfg(feature = "server_encrypt")]
+        if let Some(rsa_cipher) = self.rsa_cipher.lock().as_ref() {
+            let mut net_packet = NetPacket::new0(
+                12 + bytes.len(),
+                vec![0u8; 12 + bytes.len() + RSA_ENCRYPTION_RESERVED],
+            )?;
+            set_resume_head(&mut net_packet);
+            net_packet.set_payload(&bytes)?;
+            return match rsa_cipher.encrypt(&mut net_packet) {
+                Ok(mut net_packet) => {
+                    // 告诉服务端请求是用公钥加密的
+                    net_packet.set_encrypt_flag(true);
+                    Ok(Some(net_packet))
+                }
+                Err(e) => {
+                    log::warn!("会话恢复请求加密失败 {:?}", e);
+                    Ok(None)
+                }
+            };
+        }
+        Ok(None)
+    }
     /// 第一次握手数据
     pub fn handshake_request_packet(&self, secret: bool) -> io::Result<NetPacket<Vec<u8>>> {
         let mut request = HandshakeRequest::new();
@@ -80,6 +188,30 @@ impl Handshake {
     }
 }
 
+fn resume_request(ticket: &SessionTicket) -> io::Result<Vec<u8>> {
+    let mut request = ResumeRequest::new();
+    request.session_ticket = ticket.ticket.clone();
+    request.device_id = ticket.device_id.clone();
+    request.virtual_ip = ticket.virtual_ip.into();
+    request.version = crate::VNT_VERSION.to_string();
+    request.write_to_bytes().map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::Other,
+            format!("resume_request_packet {:?}", e),
+        )
+    })
+}
+
+fn set_resume_head<B: AsRef<[u8]> + AsMut<[u8]>>(net_packet: &mut NetPacket<B>) {
+    net_packet.set_default_version();
+    net_packet.set_gateway_flag(true);
+    net_packet.set_destination(GATEWAY_IP);
+    net_packet.set_source(SELF_IP);
+    net_packet.set_protocol(Protocol::Service);
+    net_packet.set_transport_protocol(service_packet::Protocol::ResumeRequest.into());
+    net_packet.first_set_ttl(MAX_TTL);
+}
+
 /// 第二次加密握手
 #[cfg(feature = "server_encrypt")]
 pub fn secret_handshake_request_packet(
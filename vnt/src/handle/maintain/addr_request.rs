@@ -34,8 +34,13 @@ fn pub_address_request(
     let nat_info = nat_test.nat_info();
     let time = if !nat_info.public_ports.contains(&0) && !nat_info.public_ips.is_empty() {
         //对称网络探测端口没啥作用，把频率放低，（锥形网络也只在打洞前需要探测端口，后续可以改改）
+        //端口按步长分配时，预测需要最近的端口作为基准，保持较高频率
         if nat_info.nat_type == NatType::Symmetric {
-            600
+            if nat_info.port_model.delta().is_some() {
+                30
+            } else {
+                600
+            }
         } else {
             if index == channel_num - 1 {
                 19
@@ -0,0 +1,72 @@
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crossbeam_utils::atomic::AtomicCell;
+use parking_lot::Mutex;
+
+use crate::channel::context::ChannelContext;
+use crate::channel::DEFAULT_RT;
+use crate::external_route::{ExternalRoute, GatewayHealth};
+use crate::handle::{CurrentDeviceInfo, PeerDeviceInfo};
+use crate::util::Scheduler;
+
+// 延迟超过这个值(毫秒)视为高延迟，有其他可用网关时不使用
+const SLOW_RT: i64 = 1000;
+
+/// 定时根据路由延迟和设备在线状态更新子网网关的健康状态，不可用时切换到备用网关
+pub fn gateway_health(
+    scheduler: &Scheduler,
+    context: ChannelContext,
+    current_device: Arc<AtomicCell<CurrentDeviceInfo>>,
+    device_map: Arc<Mutex<(u16, HashMap<Ipv4Addr, PeerDeviceInfo>)>>,
+    external_route: ExternalRoute,
+) {
+    let current_device_info = current_device.load();
+    let mut health = HashMap::new();
+    for gateway in external_route.gateways() {
+        health.insert(
+            gateway,
+            gateway_state(&context, &current_device_info, &device_map, &gateway),
+        );
+    }
+    for (gateway, state) in external_route.update_health(health) {
+        log::info!("子网网关{}状态变化 {:?}", gateway, state);
+    }
+    let rs = scheduler.timeout(Duration::from_secs(2), move |s| {
+        gateway_health(s, context, current_device, device_map, external_route)
+    });
+    if !rs {
+        log::info!("定时任务停止");
+    }
+}
+
+fn gateway_state(
+    context: &ChannelContext,
+    current_device: &CurrentDeviceInfo,
+    device_map: &Mutex<(u16, HashMap<Ipv4Addr, PeerDeviceInfo>)>,
+    gateway: &Ipv4Addr,
+) -> GatewayHealth {
+    if let Some(route) = context.route_table.route_one(gateway) {
+        // 还没有测出延迟的视为可用
+        return if route.rt != DEFAULT_RT && route.rt > SLOW_RT {
+            GatewayHealth::Slow
+        } else {
+            GatewayHealth::Up
+        };
+    }
+    // 没有直连，能否经服务器转发
+    if current_device.status.online()
+        && !context.use_channel_type().is_only_p2p()
+        && device_map
+            .lock()
+            .1
+            .get(gateway)
+            .map_or(false, |v| v.status.is_online())
+    {
+        GatewayHealth::Up
+    } else {
+        GatewayHealth::Down
+    }
+}
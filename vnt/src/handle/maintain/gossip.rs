@@ -0,0 +1,185 @@
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::anyhow;
+use crossbeam_utils::atomic::AtomicCell;
+use parking_lot::Mutex;
+use protobuf::Message;
+use rand::prelude::SliceRandom;
+
+use crate::channel::candidate::{gather, Candidate};
+use crate::channel::context::ChannelContext;
+use crate::cipher::Cipher;
+use crate::handle::gossip::Gossip;
+use crate::handle::maintain::punch::punch_direct;
+use crate::handle::{
+    now_time, BaseConfigInfo, CurrentDeviceInfo, PeerDeviceInfo, PeerDeviceStatus,
+};
+use crate::nat::NatTest;
+use crate::protocol::body::ENCRYPTION_RESERVED;
+use crate::protocol::{other_turn_packet, NetPacket, Protocol};
+use crate::util::Scheduler;
+
+// 每轮发送的对端数
+const FANOUT: usize = 3;
+
+/// 定时向几个直连的对端传播设备目录，服务器离线时用目录维持设备列表并直接打洞
+pub fn gossip(
+    scheduler: &Scheduler,
+    context: ChannelContext,
+    current_device: Arc<AtomicCell<CurrentDeviceInfo>>,
+    device_map: Arc<Mutex<(u16, HashMap<Ipv4Addr, PeerDeviceInfo>)>>,
+    nat_test: NatTest,
+    config: BaseConfigInfo,
+    client_cipher: Cipher,
+    gossip: Gossip,
+) {
+    if let Err(e) = gossip0(
+        &context,
+        &current_device.load(),
+        &device_map,
+        &nat_test,
+        &config,
+        &client_cipher,
+        &gossip,
+    ) {
+        log::warn!("gossip {:?}", e);
+    }
+    let rs = scheduler.timeout(Duration::from_secs(10), move |s| {
+        self::gossip(
+            s,
+            context,
+            current_device,
+            device_map,
+            nat_test,
+            config,
+            client_cipher,
+            gossip,
+        )
+    });
+    if !rs {
+        log::info!("定时任务停止");
+    }
+}
+
+fn gossip0(
+    context: &ChannelContext,
+    current_device: &CurrentDeviceInfo,
+    device_map: &Mutex<(u16, HashMap<Ipv4Addr, PeerDeviceInfo>)>,
+    nat_test: &NatTest,
+    config: &BaseConfigInfo,
+    client_cipher: &Cipher,
+    gossip: &Gossip,
+) -> anyhow::Result<()> {
+    let self_ip = current_device.virtual_ip;
+    if self_ip.is_unspecified() {
+        return Ok(());
+    }
+    let now = now_time();
+    let endpoints = if context.use_channel_type().is_only_relay() {
+        Vec::new()
+    } else {
+        gather(&nat_test.nat_info())
+    };
+    gossip.update_local(
+        self_ip,
+        &config.name,
+        config.client_secret_hash.as_ref(),
+        &endpoints,
+        now,
+    );
+    let mut targets: Vec<Ipv4Addr> = context
+        .route_table
+        .route_table()
+        .into_iter()
+        .filter(|(ip, routes)| {
+            !current_device.is_gateway(ip) && routes.iter().any(|route| route.is_p2p())
+        })
+        .map(|(ip, _)| ip)
+        .collect();
+    targets.shuffle(&mut rand::thread_rng());
+    targets.truncate(FANOUT);
+    if !targets.is_empty() {
+        let bytes = gossip
+            .select(self_ip, now)
+            .write_to_bytes()
+            .map_err(|e| anyhow!("GossipList {:?}", e))?;
+        for dest in targets {
+            let mut net_packet =
+                NetPacket::new_encrypt(vec![0u8; 12 + bytes.len() + ENCRYPTION_RESERVED])?;
+            net_packet.set_default_version();
+            net_packet.set_protocol(Protocol::OtherTurn);
+            net_packet.set_transport_protocol(other_turn_packet::Protocol::Gossip.into());
+            net_packet.first_set_ttl(1);
+            net_packet.set_source(self_ip);
+            net_packet.set_destination(dest);
+            net_packet.set_payload(&bytes)?;
+            client_cipher.encrypt_ipv4(&mut net_packet)?;
+            if let Err(e) = context.send_by_id(&net_packet, &dest) {
+                log::debug!("gossip {} {:?}", dest, e);
+            }
+        }
+    }
+    if current_device.status.online() {
+        // 服务器在线时设备列表以服务器为准
+        return Ok(());
+    }
+    // 服务器离线，用目录补充设备列表，并向还没有直连的对端打洞
+    let entries = gossip.fresh(now);
+    {
+        let mut guard = device_map.lock();
+        for info in guard.1.values_mut() {
+            // 目录中已过期且没有直连的设备视为离线
+            if !entries
+                .iter()
+                .any(|v| v.virtual_ip == u32::from(info.virtual_ip))
+                && context.route_table.route_one(&info.virtual_ip).is_none()
+            {
+                info.status = PeerDeviceStatus::Offline;
+            }
+        }
+        for entry in &entries {
+            let ip = Ipv4Addr::from(entry.virtual_ip);
+            if ip == self_ip || current_device.not_in_network(ip) {
+                continue;
+            }
+            let info = guard.1.entry(ip).or_insert_with(|| {
+                PeerDeviceInfo::new(
+                    ip,
+                    entry.name.clone(),
+                    0,
+                    entry.client_secret,
+                    entry.client_secret_hash.clone(),
+                    false,
+                )
+            });
+            info.status = PeerDeviceStatus::Online;
+        }
+    }
+    if context.use_channel_type().is_only_relay() {
+        return Ok(());
+    }
+    for entry in &entries {
+        let ip = Ipv4Addr::from(entry.virtual_ip);
+        if ip == self_ip
+            || current_device.not_in_network(ip)
+            || context.route_table.p2p_num(&ip) > 0
+        {
+            continue;
+        }
+        let addrs: Vec<SocketAddr> = entry
+            .endpoints
+            .iter()
+            .filter_map(Candidate::from_proto)
+            .filter(|v| !v.tcp)
+            .map(|v| v.addr)
+            .collect();
+        if addrs.is_empty() {
+            continue;
+        }
+        punch_direct(context, client_cipher, self_ip, ip, &addrs)?;
+    }
+    Ok(())
+}
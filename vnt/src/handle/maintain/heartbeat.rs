@@ -59,7 +59,10 @@ fn heartbeat0(
     let channel_num = context.channel_num();
     // 可能服务器ip发生变化，导致发送失败
     let mut is_send_gateway = false;
+    // 无服务器模式没有服务器地址
+    let has_server = !current_device.connect_server.ip().is_unspecified();
     match heartbeat_packet_server(device_map, server_cipher, src_ip, gateway_ip) {
+        Ok(_) if !has_server => {}
         Ok(net_packet) => {
             if let Err(e) = context.send_default(&net_packet, current_device.connect_server) {
                 log::warn!("heartbeat err={:?}", e)
@@ -67,6 +67,34 @@ pub fn idle_gateway<Call: VntCallback>(
     }
 }
 
+/// 本地网络变化后立即重连服务端，不等下一轮检查
+pub fn reconnect_gateway<Call: VntCallback>(
+    context: &ChannelContext,
+    current_device_info: &AtomicCell<CurrentDeviceInfo>,
+    config: &BaseConfigInfo,
+    connect_util: &ConnectUtil,
+    call: &Call,
+    handshake: &Handshake,
+) {
+    let cur = crate::handle::change_status(current_device_info, ConnectStatus::Connecting);
+    // 旧网络上的服务端通道已经不可用
+    if let Some(routes) = context.route_table.route(&cur.virtual_gateway) {
+        for route in routes {
+            context.remove_route(&cur.virtual_gateway, route.route_key());
+        }
+    }
+    let mut connect_count = 0;
+    idle_gateway0(
+        context,
+        current_device_info,
+        config,
+        connect_util,
+        call,
+        &mut connect_count,
+        handshake,
+    );
+}
+
 fn idle_gateway0<Call: VntCallback>(
     context: &ChannelContext,
     current_device: &AtomicCell<CurrentDeviceInfo>,
@@ -140,9 +168,9 @@ fn check_gateway_channel<Call: VntCallback>(
         if let Err(e) = handshake.send(context, config.server_secret, current_device.connect_server)
         {
             log::warn!("{:?}", e);
-            let request_packet = handshake.handshake_request_packet(config.server_secret)?;
+            let request_packet = handshake.request_packet(config.server_secret)?;
             match connect_protocol {
-                ConnectProtocol::UDP => {}
+                ConnectProtocol::UDP | ConnectProtocol::TURN => {}
                 ConnectProtocol::TCP => {
                     connect_util.try_connect_tcp(
                         request_packet.into_buffer(),
@@ -0,0 +1,326 @@
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crossbeam_utils::atomic::AtomicCell;
+use network_interface::{Addr, NetworkInterface, NetworkInterfaceConfig};
+use rand::Rng;
+
+use crate::channel::context::ChannelContext;
+use crate::channel::socket::{LocalInterface, VntSocketTrait};
+use crate::cipher::{Cipher, NetworkKey};
+use crate::handle::maintain::punch::punch_direct;
+use crate::handle::{BaseConfigInfo, CurrentDeviceInfo};
+use crate::util::{Scheduler, StopManager};
+
+/// 局域网发现使用的固定端口
+pub const LAN_DISCOVERY_PORT: u16 = 29871;
+const MAGIC: &[u8; 4] = b"VNTL";
+const TAG_LEN: usize = 16;
+// 头部: magic(4) 虚拟ip(4) 时间戳(8) 随机数(8) 端口数(1)
+const HEAD_LEN: usize = 25;
+const MAX_PORTS: usize = 32;
+// 信标有效期，超过的视为重放
+const MAX_CLOCK_SKEW: u64 = 60;
+// 有效期内收到的随机数，用于识别重放
+const MAX_REPLAY_CACHE: usize = 4096;
+const BEACON_INTERVAL: Duration = Duration::from_secs(5);
+
+/// 在物理网卡上广播信标，同一网段的设备不经过服务器直接建立路由
+pub fn lan_discovery(
+    scheduler: &Scheduler,
+    stop_manager: &StopManager,
+    context: ChannelContext,
+    current_device: Arc<AtomicCell<CurrentDeviceInfo>>,
+    config: &BaseConfigInfo,
+    client_cipher: Cipher,
+) -> anyhow::Result<()> {
+    let socket = Arc::new(bind_beacon(context.default_interface())?);
+    let key = NetworkKey::new(
+        "vnt-lan-discovery",
+        &config.token,
+        config.client_secret_hash.as_ref(),
+    );
+    let stopped = Arc::new(AtomicBool::new(false));
+    let stopped_ = stopped.clone();
+    let worker = stop_manager.add_listener("lanDiscovery".into(), move || {
+        stopped_.store(true, Ordering::Release);
+    })?;
+    {
+        let socket = socket.clone();
+        let context = context.clone();
+        let current_device = current_device.clone();
+        thread::Builder::new()
+            .name("lanDiscovery".into())
+            .spawn(move || {
+                let mut buf = [0u8; 1024];
+                let mut replay = ReplayCache::default();
+                while !stopped.load(Ordering::Acquire) {
+                    let (len, addr) = match socket.recv_from(&mut buf) {
+                        Ok(rs) => rs,
+                        Err(e) => {
+                            if e.kind() != io::ErrorKind::WouldBlock
+                                && e.kind() != io::ErrorKind::TimedOut
+                            {
+                                log::warn!("lanDiscovery {:?}", e);
+                            }
+                            continue;
+                        }
+                    };
+                    let SocketAddr::V4(addr) = addr else {
+                        continue;
+                    };
+                    let Some((virtual_ip, ports)) =
+                        parse_beacon(&key, &buf[..len], now_secs(), &mut replay)
+                    else {
+                        continue;
+                    };
+                    let current = current_device.load();
+                    if virtual_ip == current.virtual_ip || current.not_in_network(virtual_ip) {
+                        continue;
+                    }
+                    // 只向和本机物理网卡同一网段的来源地址打洞
+                    if !in_local_subnet(*addr.ip(), &current) {
+                        log::debug!("lanDiscovery 来源不在本地网段 {} {}", addr, virtual_ip);
+                        continue;
+                    }
+                    if context.route_table.p2p_num(&virtual_ip) >= context.channel_num() {
+                        continue;
+                    }
+                    let addrs: Vec<SocketAddr> = ports
+                        .iter()
+                        .map(|port| SocketAddr::V4(SocketAddrV4::new(*addr.ip(), *port)))
+                        .collect();
+                    if let Err(e) = punch_direct(
+                        &context,
+                        &client_cipher,
+                        current.virtual_ip,
+                        virtual_ip,
+                        &addrs,
+                    ) {
+                        log::warn!("lanDiscovery punch {} {:?}", virtual_ip, e);
+                    }
+                }
+                drop(worker);
+            })?;
+    }
+    lan_beacon(scheduler, socket, context, current_device, key);
+    Ok(())
+}
+
+fn bind_beacon(default_interface: &LocalInterface) -> anyhow::Result<UdpSocket> {
+    let socket = socket2::Socket::new(
+        socket2::Domain::IPV4,
+        socket2::Type::DGRAM,
+        Some(socket2::Protocol::UDP),
+    )?;
+    // 同一台机器上可能运行多个客户端
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    if let Err(e) = socket.set_reuse_port(true) {
+        log::warn!("set_reuse_port {:?}", e)
+    }
+    socket.set_broadcast(true)?;
+    if let Err(e) = socket.set_ip_unicast_if(default_interface) {
+        log::warn!("set_ip_unicast_if {:?}", e)
+    }
+    let addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, LAN_DISCOVERY_PORT));
+    socket.bind(&addr.into())?;
+    let socket: UdpSocket = socket.into();
+    socket.set_read_timeout(Some(Duration::from_secs(1)))?;
+    Ok(socket)
+}
+
+/// 定时广播信标
+fn lan_beacon(
+    scheduler: &Scheduler,
+    socket: Arc<UdpSocket>,
+    context: ChannelContext,
+    current_device: Arc<AtomicCell<CurrentDeviceInfo>>,
+    key: NetworkKey,
+) {
+    let current = current_device.load();
+    if !current.virtual_ip.is_unspecified() {
+        match context.main_local_udp_port() {
+            Ok(ports) => {
+                let beacon = build_beacon(&key, current.virtual_ip, &ports, now_secs());
+                for broadcast in broadcast_addrs(&current) {
+                    let addr = SocketAddrV4::new(broadcast, LAN_DISCOVERY_PORT);
+                    if let Err(e) = socket.send_to(&beacon, addr) {
+                        log::debug!("lanDiscovery send {} {:?}", addr, e);
+                    }
+                }
+            }
+            Err(e) => log::warn!("{:?}", e),
+        }
+    }
+    let rs = scheduler.timeout(BEACON_INTERVAL, move |s| {
+        lan_beacon(s, socket, context, current_device, key)
+    });
+    if !rs {
+        log::info!("定时任务停止");
+    }
+}
+
+/// 物理网卡的广播地址，排除虚拟网卡
+fn broadcast_addrs(current: &CurrentDeviceInfo) -> HashSet<Ipv4Addr> {
+    let mut set = HashSet::new();
+    match NetworkInterface::show() {
+        Ok(list) => {
+            for iface in list {
+                for addr in iface.addr {
+                    if let Addr::V4(v4) = addr {
+                        if v4.ip.is_loopback() || !current.not_in_network(v4.ip) {
+                            continue;
+                        }
+                        if let Some(broadcast) = v4.broadcast {
+                            set.insert(broadcast);
+                        }
+                    }
+                }
+            }
+        }
+        Err(e) => log::warn!("NetworkInterface::show {:?}", e),
+    }
+    if set.is_empty() {
+        set.insert(Ipv4Addr::BROADCAST);
+    }
+    set
+}
+
+/// 来源地址是否和某个物理网卡在同一网段
+fn in_local_subnet(ip: Ipv4Addr, current: &CurrentDeviceInfo) -> bool {
+    let list = match NetworkInterface::show() {
+        Ok(list) => list,
+        Err(e) => {
+            log::warn!("NetworkInterface::show {:?}", e);
+            return false;
+        }
+    };
+    list.into_iter()
+        .flat_map(|iface| iface.addr)
+        .any(|addr| match addr {
+            Addr::V4(v4) => {
+                let Some(netmask) = v4.netmask else {
+                    return false;
+                };
+                let mask = u32::from(netmask);
+                !v4.ip.is_loopback()
+                    && current.not_in_network(v4.ip)
+                    && u32::from(v4.ip) & mask == u32::from(ip) & mask
+            }
+            Addr::V6(_) => false,
+        })
+}
+
+/// 有效期内已经收到过的信标随机数
+#[derive(Default)]
+struct ReplayCache {
+    nonces: HashMap<u64, u64>,
+}
+
+impl ReplayCache {
+    /// 第一次收到时返回true
+    fn check(&mut self, nonce: u64, time: u64, now: u64) -> bool {
+        if self.nonces.contains_key(&nonce) {
+            return false;
+        }
+        if self.nonces.len() >= MAX_REPLAY_CACHE {
+            self.nonces
+                .retain(|_, time| time.abs_diff(now) <= MAX_CLOCK_SKEW);
+            if self.nonces.len() >= MAX_REPLAY_CACHE {
+                return false;
+            }
+        }
+        self.nonces.insert(nonce, time);
+        true
+    }
+}
+
+fn now_secs() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |v| v.as_secs())
+}
+
+fn build_beacon(key: &NetworkKey, virtual_ip: Ipv4Addr, ports: &[u16], time: u64) -> Vec<u8> {
+    let ports = &ports[..ports.len().min(MAX_PORTS)];
+    let mut buf = Vec::with_capacity(HEAD_LEN + ports.len() * 2 + TAG_LEN);
+    buf.extend_from_slice(MAGIC);
+    buf.extend_from_slice(&virtual_ip.octets());
+    buf.extend_from_slice(&time.to_be_bytes());
+    buf.extend_from_slice(&rand::thread_rng().gen::<u64>().to_be_bytes());
+    buf.push(ports.len() as u8);
+    for port in ports {
+        buf.extend_from_slice(&port.to_be_bytes());
+    }
+    let tag = key.sign(&buf);
+    buf.extend_from_slice(&tag[..TAG_LEN]);
+    buf
+}
+
+fn parse_beacon(
+    key: &NetworkKey,
+    buf: &[u8],
+    now: u64,
+    replay: &mut ReplayCache,
+) -> Option<(Ipv4Addr, Vec<u16>)> {
+    if buf.len() < HEAD_LEN + TAG_LEN || &buf[..4] != MAGIC {
+        return None;
+    }
+    let num = buf[HEAD_LEN - 1] as usize;
+    let len = HEAD_LEN + num * 2;
+    if buf.len() != len + TAG_LEN {
+        return None;
+    }
+    if !key.verify(&buf[..len], &buf[len..]) {
+        return None;
+    }
+    let time = u64::from_be_bytes(buf[8..16].try_into().unwrap());
+    if time.abs_diff(now) > MAX_CLOCK_SKEW {
+        return None;
+    }
+    let nonce = u64::from_be_bytes(buf[16..24].try_into().unwrap());
+    if !replay.check(nonce, time, now) {
+        return None;
+    }
+    let virtual_ip = Ipv4Addr::new(buf[4], buf[5], buf[6], buf[7]);
+    let ports = buf[HEAD_LEN..len]
+        .chunks(2)
+        .map(|v| u16::from_be_bytes([v[0], v[1]]))
+        .filter(|v| *v != 0)
+        .collect();
+    Some((virtual_ip, ports))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn beacon() {
+        let key = NetworkKey::new("vnt-lan-discovery", "token", Some(&[1; 16]));
+        let ip = Ipv4Addr::new(10, 26, 0, 2);
+        let beacon = build_beacon(&key, ip, &[5000, 5001], 1000);
+        let mut replay = ReplayCache::default();
+        assert_eq!(
+            parse_beacon(&key, &beacon, 1010, &mut replay),
+            Some((ip, vec![5000, 5001]))
+        );
+        // 重放
+        assert_eq!(parse_beacon(&key, &beacon, 1010, &mut replay), None);
+        let beacon = build_beacon(&key, ip, &[5000, 5001], 1000);
+        // 过期
+        assert_eq!(parse_beacon(&key, &beacon, 2000, &mut replay), None);
+        // 其他网络
+        let other = NetworkKey::new("vnt-lan-discovery", "token", Some(&[2; 16]));
+        assert_eq!(parse_beacon(&other, &beacon, 1000, &mut replay), None);
+        let mut tampered = beacon.clone();
+        tampered[HEAD_LEN] ^= 1;
+        assert_eq!(parse_beacon(&key, &tampered, 1000, &mut replay), None);
+    }
+}
@@ -0,0 +1,60 @@
+use crate::channel::context::ChannelContext;
+use crate::cipher::Cipher;
+use crate::handle::CurrentDeviceInfo;
+use crate::protocol::body::ENCRYPTION_RESERVED;
+use crate::protocol::{control_packet, NetPacket, Protocol, MAX_TTL};
+
+/// 停止时通知服务端和直连的对端本机离开，对端立即删除路由，不用等心跳超时
+pub fn leave(
+    context: &ChannelContext,
+    current_device: &CurrentDeviceInfo,
+    client_cipher: &Cipher,
+    server_cipher: &Cipher,
+) {
+    if let Err(e) = leave0(context, current_device, client_cipher, server_cipher) {
+        log::warn!("leave {:?}", e);
+    }
+}
+
+fn leave0(
+    context: &ChannelContext,
+    current_device: &CurrentDeviceInfo,
+    client_cipher: &Cipher,
+    server_cipher: &Cipher,
+) -> anyhow::Result<()> {
+    let src_ip = current_device.virtual_ip;
+    if src_ip.is_unspecified() {
+        return Ok(());
+    }
+    if current_device.status.online() {
+        let mut packet = NetPacket::new_encrypt([0u8; 12 + ENCRYPTION_RESERVED])?;
+        packet.set_default_version();
+        packet.set_gateway_flag(true);
+        packet.first_set_ttl(MAX_TTL);
+        packet.set_protocol(Protocol::Control);
+        packet.set_transport_protocol(control_packet::Protocol::Leave.into());
+        packet.set_source(src_ip);
+        packet.set_destination(current_device.virtual_gateway);
+        server_cipher.encrypt_ipv4(&mut packet)?;
+        if let Err(e) = context.send_default(&packet, current_device.connect_server) {
+            log::warn!("leave server {:?}", e);
+        }
+    }
+    for (peer_ip, routes) in context.route_table.route_table() {
+        if current_device.is_gateway(&peer_ip) || !routes.iter().any(|route| route.is_p2p()) {
+            continue;
+        }
+        let mut packet = NetPacket::new_encrypt([0u8; 12 + ENCRYPTION_RESERVED])?;
+        packet.set_default_version();
+        packet.first_set_ttl(1);
+        packet.set_protocol(Protocol::Control);
+        packet.set_transport_protocol(control_packet::Protocol::Leave.into());
+        packet.set_source(src_ip);
+        packet.set_destination(peer_ip);
+        client_cipher.encrypt_ipv4(&mut packet)?;
+        if let Err(e) = context.send_by_id(&packet, &peer_ip) {
+            log::debug!("leave {} {:?}", peer_ip, e);
+        }
+    }
+    Ok(())
+}
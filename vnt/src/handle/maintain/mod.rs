@@ -3,7 +3,13 @@ pub use heartbeat::client_relay;
 pub use heartbeat::heartbeat;
 
 mod re_nat_type;
-pub use re_nat_type::retrieve_nat_type;
+#[cfg(feature = "turn")]
+pub use re_nat_type::turn_refresh;
+pub use re_nat_type::{binding_lifetime, retrieve_nat_type};
+#[cfg(feature = "natpmp")]
+pub use re_nat_type::{natpmp_release_on_stop, natpmp_renew};
+#[cfg(feature = "upnp")]
+pub use re_nat_type::{upnp_release_on_stop, upnp_renew};
 
 mod addr_request;
 pub use addr_request::*;
@@ -17,3 +23,32 @@ pub use idle::idle_route;
 
 mod up_status;
 pub use up_status::*;
+
+#[cfg(target_os = "linux")]
+mod net_watch;
+#[cfg(target_os = "linux")]
+pub use net_watch::network_watch;
+
+mod lan_discovery;
+pub use lan_discovery::lan_discovery;
+
+mod static_peer;
+pub use static_peer::static_peer;
+
+mod gossip;
+pub use gossip::gossip;
+
+mod leave;
+pub use leave::leave;
+
+mod state_cache;
+pub use state_cache::{resume_peers, save_state, save_state_on_stop};
+
+mod route_advertise;
+pub use route_advertise::{route_advertise, sync_external_route, RouteSyncState};
+
+mod gateway_health;
+pub use gateway_health::gateway_health;
+
+mod overlay_dns;
+pub use overlay_dns::{overlay_dns, OverlayDnsState};
@@ -0,0 +1,271 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crossbeam_utils::atomic::AtomicCell;
+use network_interface::{NetworkInterface, NetworkInterfaceConfig};
+
+use crate::channel::context::ChannelContext;
+use crate::channel::sender::{AcceptSocketSender, ConnectUtil};
+use crate::handle::handshaker::Handshake;
+use crate::handle::maintain::idle::reconnect_gateway;
+use crate::handle::maintain::re_nat_type::re_test_nat;
+use crate::handle::{BaseConfigInfo, CurrentDeviceInfo};
+use crate::nat;
+use crate::nat::NatTest;
+use crate::util::{NetlinkWatcher, StopManager};
+use crate::{NetworkChangeInfo, VntCallback};
+
+// 收到通知后等待网络稳定，连续这么久没有新通知才处理
+const QUIET_TIME: Duration = Duration::from_secs(1);
+// 通知持续不断时最多等待的时间
+const MAX_SETTLE_TIME: Duration = Duration::from_secs(5);
+// 两次处理网络变化的最小间隔，地址来回抖动时不会反复重建连接
+const MIN_HANDLE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// 影响vnt连接的本地网络状态，只有这些变化时才重建连接
+#[derive(Clone, PartialEq, Debug)]
+struct LocalNetwork {
+    ipv4: Option<Ipv4Addr>,
+    ipv6: Option<Ipv6Addr>,
+    // 默认路由的(网卡名,网关)
+    default_route: Option<(String, Ipv4Addr)>,
+}
+
+impl LocalNetwork {
+    fn load(nat_test: &NatTest, virtual_ip: Ipv4Addr) -> Self {
+        Self {
+            ipv4: local_ipv4(nat_test, virtual_ip),
+            ipv6: nat::local_ipv6(),
+            default_route: default_route(),
+        }
+    }
+    /// 和旧状态相比是否需要重建连接。
+    /// ipv6临时地址轮换时旧地址仍然可用，旧地址还在网卡上就不算变化
+    fn changed(&self, old: &LocalNetwork) -> bool {
+        if self.ipv4 != old.ipv4 || self.default_route != old.default_route {
+            return true;
+        }
+        match (old.ipv6, self.ipv6) {
+            (Some(old_ipv6), Some(new_ipv6)) => old_ipv6 != new_ipv6 && !ip_assigned(old_ipv6),
+            (old_ipv6, new_ipv6) => old_ipv6.is_some() != new_ipv6.is_some(),
+        }
+    }
+}
+
+/// 监听本地网卡、地址、路由的变化，变化后立即重新探测nat、重连服务端和重新打洞
+pub fn network_watch<Call: VntCallback>(
+    stop_manager: &StopManager,
+    context: ChannelContext,
+    nat_test: NatTest,
+    current_device: Arc<AtomicCell<CurrentDeviceInfo>>,
+    config: BaseConfigInfo,
+    connect_util: ConnectUtil,
+    handshake: Handshake,
+    udp_socket_sender: AcceptSocketSender<Option<Vec<mio::net::UdpSocket>>>,
+    call: Call,
+) -> anyhow::Result<()> {
+    let watcher = NetlinkWatcher::new(QUIET_TIME)?;
+    let stopped = Arc::new(AtomicBool::new(false));
+    let stopped_ = stopped.clone();
+    let worker = stop_manager.add_listener("networkWatch".into(), move || {
+        stopped_.store(true, Ordering::Release);
+    })?;
+    thread::Builder::new()
+        .name("networkWatch".into())
+        .spawn(move || {
+            let mut buf = vec![0u8; 16 * 1024];
+            let mut changes = Vec::new();
+            let mut network = LocalNetwork::load(&nat_test, current_device.load().virtual_ip);
+            let mut last_handle: Option<Instant> = None;
+            while !stopped.load(Ordering::Acquire) {
+                changes.clear();
+                match watcher.wait(&mut buf, &mut changes) {
+                    Ok(true) => {}
+                    Ok(false) => continue,
+                    Err(e) => {
+                        log::warn!("网络变化监听失败 {:?}", e);
+                        break;
+                    }
+                }
+                // 切换网络时会连续产生很多通知，等稳定后再处理
+                let start = Instant::now();
+                while start.elapsed() < MAX_SETTLE_TIME && !stopped.load(Ordering::Acquire) {
+                    match watcher.wait(&mut buf, &mut changes) {
+                        Ok(true) => {}
+                        _ => break,
+                    }
+                }
+                // 虚拟网卡自身的地址和路由(包括出口节点的路由)变化不影响物理网络
+                let virtual_ip = current_device.load().virtual_ip;
+                let tun_index = tun_index(virtual_ip);
+                if tun_index.is_some() && changes.iter().all(|index| *index == tun_index) {
+                    continue;
+                }
+                // 距上次处理太近时等到间隔结束，期间的通知一并处理
+                if let Some(last) = last_handle {
+                    while last.elapsed() < MIN_HANDLE_INTERVAL && !stopped.load(Ordering::Acquire) {
+                        if watcher.wait(&mut buf, &mut changes).is_err() {
+                            break;
+                        }
+                    }
+                    if stopped.load(Ordering::Acquire) {
+                        break;
+                    }
+                }
+                let new_network = LocalNetwork::load(&nat_test, virtual_ip);
+                if !new_network.changed(&network) {
+                    // docker的veth、其他网卡的地址和路由变化，不影响vnt的连接
+                    log::debug!("忽略网络变化 {:?}", new_network);
+                    network = new_network;
+                    continue;
+                }
+                let old_network = std::mem::replace(&mut network, new_network.clone());
+                last_handle = Some(Instant::now());
+                network_changed(
+                    &context,
+                    &nat_test,
+                    &current_device,
+                    &config,
+                    &connect_util,
+                    &handshake,
+                    &udp_socket_sender,
+                    &call,
+                    (old_network.ipv4, old_network.ipv6),
+                    (new_network.ipv4, new_network.ipv6),
+                );
+            }
+            drop(worker);
+        })?;
+    Ok(())
+}
+
+/// 指定了本地ipv4时不重新获取；
+/// 路由指向虚拟网卡时获取到的是虚拟ip，不能当作本地地址
+fn local_ipv4(nat_test: &NatTest, virtual_ip: Ipv4Addr) -> Option<Ipv4Addr> {
+    if !nat_test.update_local_ipv4 {
+        return nat_test.nat_info().local_ipv4;
+    }
+    nat::local_ipv4().filter(|ip| *ip != virtual_ip)
+}
+
+/// 主路由表中度量值最小的ipv4默认路由
+fn default_route() -> Option<(String, Ipv4Addr)> {
+    let table = std::fs::read_to_string("/proc/net/route").ok()?;
+    table
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            // Iface Destination Gateway Flags RefCnt Use Metric Mask ...
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 8 || fields[1] != "00000000" || fields[7] != "00000000" {
+                return None;
+            }
+            let gateway = u32::from_str_radix(fields[2], 16).ok()?;
+            let metric: u32 = fields[6].parse().ok()?;
+            // 文件中是小端序
+            let gateway = Ipv4Addr::from(gateway.swap_bytes());
+            Some((metric, fields[0].to_string(), gateway))
+        })
+        .min_by_key(|(metric, _, _)| *metric)
+        .map(|(_, iface, gateway)| (iface, gateway))
+}
+
+/// 地址是否还在本机网卡上
+fn ip_assigned(ip: Ipv6Addr) -> bool {
+    NetworkInterface::show().is_ok_and(|interfaces| {
+        interfaces
+            .iter()
+            .any(|iface| iface.addr.iter().any(|addr| addr.ip() == IpAddr::V6(ip)))
+    })
+}
+
+/// 虚拟网卡的序号，还没有分配虚拟ip时为None
+fn tun_index(virtual_ip: Ipv4Addr) -> Option<u32> {
+    if virtual_ip.is_unspecified() {
+        return None;
+    }
+    let interfaces = NetworkInterface::show().ok()?;
+    interfaces
+        .into_iter()
+        .find(|iface| {
+            iface
+                .addr
+                .iter()
+                .any(|addr| addr.ip() == IpAddr::V4(virtual_ip))
+        })
+        .map(|iface| iface.index)
+}
+
+fn network_changed<Call: VntCallback>(
+    context: &ChannelContext,
+    nat_test: &NatTest,
+    current_device: &AtomicCell<CurrentDeviceInfo>,
+    config: &BaseConfigInfo,
+    connect_util: &ConnectUtil,
+    handshake: &Handshake,
+    udp_socket_sender: &AcceptSocketSender<Option<Vec<mio::net::UdpSocket>>>,
+    call: &Call,
+    (old_ipv4, old_ipv6): (Option<Ipv4Addr>, Option<Ipv6Addr>),
+    (new_ipv4, new_ipv6): (Option<Ipv4Addr>, Option<Ipv6Addr>),
+) {
+    let info = NetworkChangeInfo::new(old_ipv4, new_ipv4, old_ipv6, new_ipv6);
+    log::info!("本地网络变化 {}", info);
+    call.network_change(info);
+    // 旧网络上的直连通道都已失效，删除后回退到服务器中继，打洞记录也会随之重置
+    let cur = current_device.load();
+    for (ip, routes) in context.route_table.route_table() {
+        if cur.is_gateway(&ip) {
+            continue;
+        }
+        for route in routes {
+            context.remove_route(&ip, route.route_key());
+        }
+    }
+    reconnect_gateway(
+        context,
+        current_device,
+        config,
+        connect_util,
+        call,
+        handshake,
+    );
+    if context.use_channel_type().is_only_relay() {
+        return;
+    }
+    if let Err(e) = context.rebind_sub_udp(udp_socket_sender) {
+        log::warn!("rebind_sub_udp {:?}", e);
+    }
+    nat_test.mark_update();
+    re_test_nat(context, nat_test, udp_socket_sender);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn local_network_changed() {
+        let old = LocalNetwork {
+            ipv4: Some(Ipv4Addr::new(192, 168, 1, 2)),
+            ipv6: None,
+            default_route: Some(("eth0".into(), Ipv4Addr::new(192, 168, 1, 1))),
+        };
+        assert!(!old.clone().changed(&old));
+        let mut new = old.clone();
+        new.default_route = Some(("wlan0".into(), Ipv4Addr::new(192, 168, 1, 1)));
+        assert!(new.changed(&old));
+        let mut new = old.clone();
+        new.ipv4 = Some(Ipv4Addr::new(192, 168, 1, 3));
+        assert!(new.changed(&old));
+        let mut new = old.clone();
+        new.ipv6 = Some("2001:db8::1".parse().unwrap());
+        assert!(new.changed(&old));
+        // 旧的ipv6地址已不在网卡上
+        let mut with_v6 = old.clone();
+        with_v6.ipv6 = Some("2001:db8::2".parse().unwrap());
+        assert!(new.changed(&with_v6));
+    }
+}
@@ -0,0 +1,114 @@
+#[cfg(feature = "integrated_tun")]
+#[cfg(target_os = "linux")]
+use std::net::Ipv4Addr;
+#[cfg(feature = "integrated_tun")]
+#[cfg(target_os = "linux")]
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::overlay_dns::OverlayDns;
+#[cfg(feature = "integrated_tun")]
+use crate::tun_tap_device::tun_create_helper::DeviceAdapter;
+use crate::util::Scheduler;
+
+/// 虚拟ip变化后重新监听，需要时通过systemd-resolved把监听地址设置为网卡的dns
+pub fn overlay_dns(
+    scheduler: &Scheduler,
+    dns: OverlayDns,
+    #[cfg(feature = "integrated_tun")] device: DeviceAdapter,
+    mut state: OverlayDnsState,
+) {
+    match dns.listen() {
+        Ok(Some(ip)) => {
+            log::info!("虚拟网络dns监听 {}:53", ip);
+            state.warned = false;
+        }
+        Ok(None) => {}
+        Err(e) => {
+            // 网卡还没创建好时会失败，下次再试
+            if !state.warned {
+                log::warn!("{:?}", e);
+                state.warned = true;
+            }
+        }
+    }
+    #[cfg(feature = "integrated_tun")]
+    #[cfg(target_os = "linux")]
+    if state.resolved {
+        state.sync_device(&dns, &device);
+    }
+    let rs = scheduler.timeout(Duration::from_secs(3), move |s| {
+        overlay_dns(
+            s,
+            dns,
+            #[cfg(feature = "integrated_tun")]
+            device,
+            state,
+        )
+    });
+    if !rs {
+        log::info!("定时任务停止");
+    }
+}
+
+pub struct OverlayDnsState {
+    warned: bool,
+    #[cfg(feature = "integrated_tun")]
+    #[cfg(target_os = "linux")]
+    resolved: bool,
+    // 已设置过dns的网卡和地址，网卡重建或地址变化后重新设置
+    #[cfg(feature = "integrated_tun")]
+    #[cfg(target_os = "linux")]
+    device_id: usize,
+    #[cfg(feature = "integrated_tun")]
+    #[cfg(target_os = "linux")]
+    installed: Ipv4Addr,
+}
+
+impl OverlayDnsState {
+    pub fn new(_resolved: bool) -> Self {
+        #[cfg(not(all(feature = "integrated_tun", target_os = "linux")))]
+        if _resolved {
+            log::warn!("只有linux支持通过systemd-resolved设置虚拟网络dns");
+        }
+        Self {
+            warned: false,
+            #[cfg(feature = "integrated_tun")]
+            #[cfg(target_os = "linux")]
+            resolved: _resolved,
+            #[cfg(feature = "integrated_tun")]
+            #[cfg(target_os = "linux")]
+            device_id: 0,
+            #[cfg(feature = "integrated_tun")]
+            #[cfg(target_os = "linux")]
+            installed: Ipv4Addr::UNSPECIFIED,
+        }
+    }
+}
+
+#[cfg(feature = "integrated_tun")]
+#[cfg(target_os = "linux")]
+impl OverlayDnsState {
+    fn sync_device(&mut self, dns: &OverlayDns, device: &DeviceAdapter) {
+        let Some(tun) = device.device() else {
+            self.device_id = 0;
+            return;
+        };
+        let ip = dns.listen_ip();
+        let device_id = Arc::as_ptr(&tun) as usize;
+        if ip.is_unspecified() || (self.device_id == device_id && self.installed == ip) {
+            return;
+        }
+        self.device_id = device_id;
+        self.installed = ip;
+        let domains = dns.routing_domains();
+        match crate::tun_tap_device::set_link_dns(&tun, &[ip], &domains) {
+            Ok(_) => {
+                log::info!("已设置网卡dns {} {:?}", ip, domains);
+            }
+            Err(e) => {
+                log::warn!("设置网卡dns失败 {:?}", e);
+            }
+        }
+    }
+}
@@ -1,5 +1,5 @@
 use std::collections::HashMap;
-use std::net::Ipv4Addr;
+use std::net::{Ipv4Addr, SocketAddr};
 use std::ops::{Div, Mul};
 use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
 use std::sync::Arc;
@@ -12,9 +12,11 @@ use parking_lot::Mutex;
 use protobuf::Message;
 use rand::prelude::SliceRandom;
 
+use crate::channel::candidate::{gather, CandidateChecker};
 use crate::channel::context::ChannelContext;
 use crate::channel::punch::{NatInfo, NatType, Punch};
 use crate::cipher::Cipher;
+use crate::handle::static_peers::CHALLENGE_LEN;
 use crate::handle::{CurrentDeviceInfo, PeerDeviceInfo};
 use crate::nat::NatTest;
 use crate::proto::message::{PunchInfo, PunchNatType};
@@ -108,6 +110,7 @@ pub fn punch(
         0,
         punch_record.clone(),
         last_punch_record,
+        punch.checker().clone(),
     );
     let f = |receiver: Receiver<(Ipv4Addr, NatInfo)>| {
         let punch = punch.clone();
@@ -127,6 +130,72 @@ pub fn punch(
     f(receiver.receiver_cone_self);
 }
 
+pub(crate) fn punch_request_packet(
+    client_cipher: &Cipher,
+    src_ip: Ipv4Addr,
+    peer_ip: Ipv4Addr,
+) -> anyhow::Result<NetPacket<[u8; 12 + ENCRYPTION_RESERVED]>> {
+    let mut packet = NetPacket::new_encrypt([0u8; 12 + ENCRYPTION_RESERVED])?;
+    packet.set_default_version();
+    packet.first_set_ttl(1);
+    packet.set_protocol(Protocol::Control);
+    packet.set_transport_protocol(control_packet::Protocol::PunchRequest.into());
+    packet.set_source(src_ip);
+    packet.set_destination(peer_ip);
+    client_cipher.encrypt_ipv4(&mut packet)?;
+    Ok(packet)
+}
+
+/// 带挑战值的打洞请求，静态对端用设备私钥签名后回应
+pub(crate) fn punch_challenge_packet(
+    client_cipher: &Cipher,
+    src_ip: Ipv4Addr,
+    peer_ip: Ipv4Addr,
+    challenge: &[u8; CHALLENGE_LEN],
+) -> anyhow::Result<NetPacket<[u8; 12 + CHALLENGE_LEN + ENCRYPTION_RESERVED]>> {
+    let mut packet = NetPacket::new_encrypt([0u8; 12 + CHALLENGE_LEN + ENCRYPTION_RESERVED])?;
+    packet.set_default_version();
+    packet.first_set_ttl(1);
+    packet.set_protocol(Protocol::Control);
+    packet.set_transport_protocol(control_packet::Protocol::PunchRequest.into());
+    packet.set_source(src_ip);
+    packet.set_destination(peer_ip);
+    packet.payload_mut().copy_from_slice(challenge);
+    client_cipher.encrypt_ipv4(&mut packet)?;
+    Ok(packet)
+}
+
+/// 不经过服务器，直接向已知的对端地址发送打洞请求，对端回应后建立路由
+pub(crate) fn punch_direct(
+    context: &ChannelContext,
+    client_cipher: &Cipher,
+    src_ip: Ipv4Addr,
+    peer_ip: Ipv4Addr,
+    addrs: &[SocketAddr],
+) -> anyhow::Result<()> {
+    let packet = punch_request_packet(client_cipher, src_ip, peer_ip)?;
+    send_direct(context, packet.buffer(), addrs);
+    Ok(())
+}
+
+/// 把打洞请求发到对端的每个地址
+pub(crate) fn send_direct(context: &ChannelContext, buf: &[u8], addrs: &[SocketAddr]) {
+    let v4_len = context.channel_num();
+    let v6_len = context.main_len() - v4_len;
+    for (index, addr) in addrs.iter().enumerate() {
+        let index = if addr.is_ipv4() {
+            index % v4_len
+        } else if v6_len > 0 {
+            v4_len + index % v6_len
+        } else {
+            continue;
+        };
+        if let Err(e) = context.send_main_udp(index, buf, *addr) {
+            log::debug!("punch_direct {} {:?}", addr, e);
+        }
+    }
+}
+
 /// 接收打洞消息，配合对端打洞
 fn punch_start(
     receiver: Receiver<(Ipv4Addr, NatInfo)>,
@@ -176,6 +245,7 @@ fn punch_request(
     count: usize,
     punch_record: Arc<Mutex<HashMap<Ipv4Addr, usize>>>,
     mut last_punch_record: HashMap<Ipv4Addr, PunchRecordItem>,
+    checker: CandidateChecker,
 ) {
     let curr = current_device.load();
     let secs = if curr.status.online() {
@@ -187,6 +257,7 @@ fn punch_request(
             &client_cipher,
             &punch_record,
             &mut last_punch_record,
+            &checker,
             count,
         ) {
             log::warn!("{:?}", e)
@@ -207,6 +278,7 @@ fn punch_request(
             count + 1,
             punch_record,
             last_punch_record,
+            checker,
         );
     });
     if !rs {
@@ -228,6 +300,7 @@ fn punch0(
     client_cipher: &Cipher,
     punch_record: &Mutex<HashMap<Ipv4Addr, usize>>,
     last_punch_record: &mut HashMap<Ipv4Addr, PunchRecordItem>,
+    checker: &CandidateChecker,
     total_count: usize,
 ) -> anyhow::Result<()> {
     let nat_info = nat_test.nat_info();
@@ -250,10 +323,13 @@ fn punch0(
         .cloned()
         .collect();
     list.shuffle(&mut rand::thread_rng());
+    // 先按当前路由提名，淘汰已被更优候选对取代的通道
+    checker.nominate(&context.route_table);
     for info in list {
         if info.status.is_offline() {
             // 客户端掉线了要重置打洞记录
             punch_record.lock().remove(&info.virtual_ip);
+            checker.remove(&info.virtual_ip);
             continue;
         }
         let mut punch_count = punch_record
@@ -266,7 +342,7 @@ fn punch0(
         let p2p_num = context.route_table.p2p_num(&info.virtual_ip);
         let mut max_punch_interval = 50;
         if p2p_num > 0 {
-            if p2p_num >= context.channel_num() {
+            if p2p_num >= context.channel_num() && !checker.needs_recheck(&info.virtual_ip) {
                 //通道数满足要求，不再打洞
                 if punch_count != 0 {
                     punch_record.lock().remove(&info.virtual_ip);
@@ -316,8 +392,27 @@ fn punch_packet(
     nat_info: &NatInfo,
     dest: Ipv4Addr,
 ) -> anyhow::Result<NetPacket<Vec<u8>>> {
+    let punch_reply = local_punch_info(nat_info, false);
+    log::info!("请求打洞={:?}", punch_reply);
+    let bytes = punch_reply
+        .write_to_bytes()
+        .map_err(|e| anyhow!("punch_packet {:?}", e))?;
+    let mut net_packet = NetPacket::new_encrypt(vec![0u8; 12 + bytes.len() + ENCRYPTION_RESERVED])?;
+    net_packet.set_default_version();
+    net_packet.set_protocol(Protocol::OtherTurn);
+    net_packet.set_transport_protocol(other_turn_packet::Protocol::Punch.into());
+    net_packet.first_set_ttl(MAX_TTL);
+    net_packet.set_source(virtual_ip);
+    net_packet.set_destination(dest);
+    net_packet.set_payload(&bytes)?;
+    client_cipher.encrypt_ipv4(&mut net_packet)?;
+    Ok(net_packet)
+}
+
+/// 把本地的nat信息和候选地址填入打洞协商消息，旧字段继续保留以兼容老版本
+pub fn local_punch_info(nat_info: &NatInfo, reply: bool) -> PunchInfo {
     let mut punch_reply = PunchInfo::new();
-    punch_reply.reply = false;
+    punch_reply.reply = reply;
     punch_reply.public_ip_list = nat_info
         .public_ips
         .iter()
@@ -337,18 +432,29 @@ fn punch_packet(
     }
     punch_reply.nat_type = protobuf::EnumOrUnknown::new(PunchNatType::from(nat_info.nat_type));
     punch_reply.punch_model = protobuf::EnumOrUnknown::new(nat_info.punch_model.into());
-    log::info!("请求打洞={:?}", punch_reply);
-    let bytes = punch_reply
-        .write_to_bytes()
-        .map_err(|e| anyhow!("punch_packet {:?}", e))?;
-    let mut net_packet = NetPacket::new_encrypt(vec![0u8; 12 + bytes.len() + ENCRYPTION_RESERVED])?;
-    net_packet.set_default_version();
-    net_packet.set_protocol(Protocol::OtherTurn);
-    net_packet.set_transport_protocol(other_turn_packet::Protocol::Punch.into());
-    net_packet.first_set_ttl(MAX_TTL);
-    net_packet.set_source(virtual_ip);
-    net_packet.set_destination(dest);
-    net_packet.set_payload(&bytes)?;
-    client_cipher.encrypt_ipv4(&mut net_packet)?;
-    Ok(net_packet)
+    punch_reply.mapping = protobuf::EnumOrUnknown::new(nat_info.mapping.into());
+    punch_reply.filtering = protobuf::EnumOrUnknown::new(nat_info.filtering.into());
+    // 协议中只有确认支持才为true，对方收到false按未知处理
+    punch_reply.hairpin = nat_info.hairpin == Some(true);
+    punch_reply.binding_lifetime = nat_info.binding_lifetime;
+    let (port_model, port_delta) = nat_info.port_model.into_proto();
+    punch_reply.port_model = protobuf::EnumOrUnknown::new(port_model);
+    punch_reply.port_delta = port_delta;
+    for addr in &nat_info.mapped_udp {
+        punch_reply.mapped_udp_ips.push(u32::from(*addr.ip()));
+        punch_reply.mapped_udp_ports.push(addr.port() as u32);
+    }
+    if let Some(addr) = nat_info.mapped_tcp {
+        punch_reply.mapped_tcp_ip = u32::from(*addr.ip());
+        punch_reply.mapped_tcp_port = addr.port() as u32;
+    }
+    for addr in &nat_info.relayed {
+        punch_reply.relayed_ips.push(u32::from(*addr.ip()));
+        punch_reply.relayed_ports.push(addr.port() as u32);
+    }
+    punch_reply.candidates = gather(nat_info)
+        .iter()
+        .map(|candidate| candidate.into_proto())
+        .collect();
+    punch_reply
 }
@@ -6,6 +6,8 @@ use crate::channel::sender::AcceptSocketSender;
 use crate::nat;
 use crate::nat::NatTest;
 use crate::util::Scheduler;
+#[cfg(any(feature = "upnp", feature = "natpmp"))]
+use crate::util::StopManager;
 
 /// 10分钟探测一次nat
 pub fn retrieve_nat_type(
@@ -20,6 +22,51 @@ pub fn retrieve_nat_type(
     });
 }
 
+/// 测量nat绑定的存活时间，结果确定后停止
+pub fn binding_lifetime(scheduler: &Scheduler, context: ChannelContext, nat_test: NatTest) {
+    if let Some(wait) = nat_test.probe_binding_lifetime(context.default_interface()) {
+        scheduler.timeout(wait, move |s| binding_lifetime(s, context, nat_test));
+    }
+}
+
+/// 续期NAT-PMP/PCP映射，还没有映射时定时检查
+#[cfg(feature = "natpmp")]
+pub fn natpmp_renew(scheduler: &Scheduler, nat_test: NatTest) {
+    let wait = nat_test.renew_natpmp().unwrap_or(Duration::from_secs(60));
+    scheduler.timeout(wait, move |s| natpmp_renew(s, nat_test));
+}
+
+/// 定时续期UPnP映射
+#[cfg(feature = "upnp")]
+pub fn upnp_renew(scheduler: &Scheduler, nat_test: NatTest) {
+    nat_test.renew_upnp();
+    scheduler.timeout(crate::util::UPNP_RENEW_INTERVAL, move |s| {
+        upnp_renew(s, nat_test)
+    });
+}
+
+/// 分配和续期TURN中继，把中继地址加入打洞信息
+#[cfg(feature = "turn")]
+pub fn turn_refresh(scheduler: &Scheduler, context: ChannelContext, nat_test: NatTest) {
+    context.turn().maintain();
+    nat_test.update_relayed(context.turn().relayed_addrs());
+    scheduler.timeout(Duration::from_secs(30), move |s| {
+        turn_refresh(s, context, nat_test)
+    });
+}
+
+/// vnt停止时删除网关上的映射
+#[cfg(feature = "natpmp")]
+pub fn natpmp_release_on_stop(stop_manager: &StopManager, nat_test: NatTest) -> anyhow::Result<()> {
+    stop_manager.add_stop_task("natPmpRelease", move || nat_test.release_natpmp())
+}
+
+/// vnt停止时删除UPnP映射和ipv6防火墙规则
+#[cfg(feature = "upnp")]
+pub fn upnp_release_on_stop(stop_manager: &StopManager, nat_test: NatTest) -> anyhow::Result<()> {
+    stop_manager.add_stop_task("upnpRelease", move || nat_test.release_upnp())
+}
+
 fn retrieve_nat_type0(
     context: ChannelContext,
     nat_test: NatTest,
@@ -29,27 +76,38 @@ fn retrieve_nat_type0(
         .name("natTest".into())
         .spawn(move || {
             if nat_test.can_update() {
-                let local_ipv4 = if nat_test.update_local_ipv4 {
-                    nat::local_ipv4()
-                } else {
-                    None
-                };
-                let local_ipv6 = nat::local_ipv6();
-                match nat_test.re_test(local_ipv4, local_ipv6, context.default_interface()) {
-                    Ok(nat_info) => {
-                        log::info!("当前nat信息:{:?}", nat_info);
-                        if let Err(e) = context.switch(nat_info.nat_type, &udp_socket_sender) {
-                            log::warn!("{:?}", e);
-                        }
-                    }
-                    Err(e) => {
-                        log::warn!("nat re_test {:?}", e);
-                    }
-                };
-                #[cfg(feature = "upnp")]
-                nat_test.reset_upnp();
-                log::info!("刷新nat结束")
+                re_test_nat(&context, &nat_test, &udp_socket_sender);
             }
         })
         .expect("natTest");
 }
+
+/// 重新探测nat类型并刷新网关上的映射，会阻塞当前线程
+pub(crate) fn re_test_nat(
+    context: &ChannelContext,
+    nat_test: &NatTest,
+    udp_socket_sender: &AcceptSocketSender<Option<Vec<mio::net::UdpSocket>>>,
+) {
+    let local_ipv4 = if nat_test.update_local_ipv4 {
+        nat::local_ipv4()
+    } else {
+        None
+    };
+    let local_ipv6 = nat::local_ipv6();
+    match nat_test.re_test(local_ipv4, local_ipv6, context.default_interface()) {
+        Ok(nat_info) => {
+            log::info!("当前nat信息:{:?}", nat_info);
+            if let Err(e) = context.switch(nat_info.nat_type, udp_socket_sender) {
+                log::warn!("{:?}", e);
+            }
+        }
+        Err(e) => {
+            log::warn!("nat re_test {:?}", e);
+        }
+    };
+    #[cfg(feature = "upnp")]
+    nat_test.reset_upnp();
+    #[cfg(feature = "natpmp")]
+    nat_test.reset_natpmp();
+    log::info!("刷新nat结束")
+}
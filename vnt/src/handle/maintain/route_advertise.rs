@@ -0,0 +1,196 @@
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::anyhow;
+use crossbeam_utils::atomic::AtomicCell;
+use parking_lot::Mutex;
+use protobuf::Message;
+
+use crate::channel::context::ChannelContext;
+use crate::cipher::Cipher;
+use crate::external_route::ExternalRoute;
+use crate::handle::{CurrentDeviceInfo, PeerDeviceInfo, PeerDeviceStatus};
+use crate::proto::message::{RouteAdvertisement, SubnetRoute};
+use crate::protocol::body::ENCRYPTION_RESERVED;
+use crate::protocol::{other_turn_packet, NetPacket, Protocol, MAX_TTL};
+#[cfg(feature = "integrated_tun")]
+use crate::tun_tap_device::tun_create_helper::DeviceAdapter;
+use crate::util::Scheduler;
+use crate::VntCallback;
+
+/// 定时向在线的对端通告本机转发的网段(out_ips)
+pub fn route_advertise(
+    scheduler: &Scheduler,
+    context: ChannelContext,
+    current_device: Arc<AtomicCell<CurrentDeviceInfo>>,
+    device_map: Arc<Mutex<(u16, HashMap<Ipv4Addr, PeerDeviceInfo>)>>,
+    client_cipher: Cipher,
+    out_ips: Arc<Vec<(u32, u32)>>,
+) {
+    if let Err(e) = route_advertise0(
+        &context,
+        &current_device.load(),
+        &device_map,
+        &client_cipher,
+        &out_ips,
+    ) {
+        log::warn!("route advertise {:?}", e);
+    }
+    let rs = scheduler.timeout(Duration::from_secs(20), move |s| {
+        route_advertise(
+            s,
+            context,
+            current_device,
+            device_map,
+            client_cipher,
+            out_ips,
+        )
+    });
+    if !rs {
+        log::info!("定时任务停止");
+    }
+}
+
+fn route_advertise0(
+    context: &ChannelContext,
+    current_device: &CurrentDeviceInfo,
+    device_map: &Mutex<(u16, HashMap<Ipv4Addr, PeerDeviceInfo>)>,
+    client_cipher: &Cipher,
+    out_ips: &[(u32, u32)],
+) -> anyhow::Result<()> {
+    let self_ip = current_device.virtual_ip;
+    if self_ip.is_unspecified() {
+        return Ok(());
+    }
+    let mut advertisement = RouteAdvertisement::new();
+    // allow_exit加入的默认路由不通告，对端只有显式指定--exit-node时才走出口节点
+    for (dest, mask) in out_ips.iter().filter(|(_, mask)| *mask != 0) {
+        let mut route = SubnetRoute::new();
+        route.dest = *dest;
+        route.mask = *mask;
+        advertisement.routes.push(route);
+    }
+    if advertisement.routes.is_empty() {
+        return Ok(());
+    }
+    let bytes = advertisement
+        .write_to_bytes()
+        .map_err(|e| anyhow!("RouteAdvertisement {:?}", e))?;
+    let targets: Vec<Ipv4Addr> = device_map
+        .lock()
+        .1
+        .values()
+        .filter(|v| v.status == PeerDeviceStatus::Online && !v.wireguard && v.virtual_ip != self_ip)
+        .map(|v| v.virtual_ip)
+        .collect();
+    let online = current_device.status.online();
+    for dest in targets {
+        let mut net_packet =
+            NetPacket::new_encrypt(vec![0u8; 12 + bytes.len() + ENCRYPTION_RESERVED])?;
+        net_packet.set_default_version();
+        net_packet.set_protocol(Protocol::OtherTurn);
+        net_packet.set_transport_protocol(other_turn_packet::Protocol::RouteAdvertise.into());
+        net_packet.first_set_ttl(MAX_TTL);
+        net_packet.set_source(self_ip);
+        net_packet.set_destination(dest);
+        net_packet.set_payload(&bytes)?;
+        client_cipher.encrypt_ipv4(&mut net_packet)?;
+        // 没有直连时经服务器转发，服务器离线时不发
+        if let Err(e) = context.send_by_id(&net_packet, &dest) {
+            if online && !context.use_channel_type().is_only_p2p() {
+                // 一个对端发送失败不影响其他对端
+                if let Err(e) = context.send_default(&net_packet, current_device.connect_server) {
+                    log::warn!("route advertise {} {:?}", dest, e);
+                }
+            } else {
+                log::debug!("route advertise {} {:?}", dest, e);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// 删除过期的通告路由，并把通告路由的变化同步到网卡
+pub fn sync_external_route<Call: VntCallback>(
+    scheduler: &Scheduler,
+    external_route: ExternalRoute,
+    callback: Call,
+    #[cfg(feature = "integrated_tun")] device: DeviceAdapter,
+    mut state: RouteSyncState,
+) {
+    external_route.expire_advertised();
+    let routes = external_route.advertised_routes();
+    if routes != state.routes {
+        log::info!("通告路由变化 {:?}", routes);
+        callback.external_route_change(routes.clone());
+        state.routes = routes;
+    }
+    #[cfg(feature = "integrated_tun")]
+    #[cfg(any(target_os = "windows", target_os = "linux", target_os = "macos"))]
+    state.sync_device(&device);
+    let rs = scheduler.timeout(Duration::from_secs(5), move |s| {
+        sync_external_route(
+            s,
+            external_route,
+            callback,
+            #[cfg(feature = "integrated_tun")]
+            device,
+            state,
+        )
+    });
+    if !rs {
+        log::info!("定时任务停止");
+    }
+}
+
+#[derive(Default)]
+pub struct RouteSyncState {
+    routes: Vec<(Ipv4Addr, Ipv4Addr)>,
+    // 已添加到网卡的路由，网卡重建后需要重新添加
+    #[cfg(feature = "integrated_tun")]
+    #[cfg(any(target_os = "windows", target_os = "linux", target_os = "macos"))]
+    device_id: usize,
+    #[cfg(feature = "integrated_tun")]
+    #[cfg(any(target_os = "windows", target_os = "linux", target_os = "macos"))]
+    installed: Vec<(Ipv4Addr, Ipv4Addr)>,
+}
+
+#[cfg(feature = "integrated_tun")]
+#[cfg(any(target_os = "windows", target_os = "linux", target_os = "macos"))]
+impl RouteSyncState {
+    fn sync_device(&mut self, device: &DeviceAdapter) {
+        use crate::tun_tap_device::change_route;
+        let Some(tun) = device.device() else {
+            self.device_id = 0;
+            self.installed.clear();
+            return;
+        };
+        let device_id = Arc::as_ptr(&tun) as usize;
+        if self.device_id != device_id {
+            self.device_id = device_id;
+            self.installed.clear();
+        }
+        for (dest, mask) in &self.installed {
+            if !self.routes.contains(&(*dest, *mask)) {
+                if let Err(e) = change_route(&tun, *dest, *mask, false) {
+                    log::warn!("删除通告路由失败 {}/{} {:?}", dest, mask, e);
+                }
+            }
+        }
+        for (dest, mask) in &self.routes {
+            if !self.installed.contains(&(*dest, *mask)) {
+                if let Err(e) = change_route(&tun, *dest, *mask, true) {
+                    log::warn!(
+                        "添加通告路由失败,请检查是否和现有路由冲突 {}/{} {:?}",
+                        dest,
+                        mask,
+                        e
+                    );
+                }
+            }
+        }
+        self.installed = self.routes.clone();
+    }
+}
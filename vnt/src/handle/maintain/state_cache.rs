@@ -0,0 +1,130 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use crossbeam_utils::atomic::AtomicCell;
+
+use crate::channel::context::ChannelContext;
+use crate::channel::sender::ConnectUtil;
+use crate::cipher::Cipher;
+use crate::handle::maintain::punch::{punch_direct, punch_request_packet};
+use crate::handle::state_cache::StateCache;
+use crate::handle::{now_time, CurrentDeviceInfo};
+use crate::nat::NatTest;
+use crate::util::{Scheduler, StopManager};
+
+// 启动时向缓存地址打洞的轮数，防止丢包和对端回应时本机还没准备好
+const RESUME_ROUNDS: usize = 3;
+
+/// 启动时向上次可用的对端地址打洞，和注册同时进行，对端回应后立即建立直连
+pub fn resume_peers(
+    scheduler: &Scheduler,
+    context: ChannelContext,
+    current_device: Arc<AtomicCell<CurrentDeviceInfo>>,
+    connect_util: ConnectUtil,
+    client_cipher: Cipher,
+    cache: StateCache,
+    round: usize,
+) {
+    let src_ip = current_device.load().virtual_ip;
+    if src_ip.is_unspecified() {
+        return;
+    }
+    for (peer_ip, route) in cache.peers(now_time()) {
+        if peer_ip == src_ip || context.route_table.p2p_num(&peer_ip) > 0 {
+            continue;
+        }
+        if route.tcp {
+            // tcp只连接一次
+            if round == 0 {
+                match punch_request_packet(&client_cipher, src_ip, peer_ip) {
+                    Ok(packet) => {
+                        connect_util.try_connect_tcp_punch(packet.buffer().to_vec(), route.addr)
+                    }
+                    Err(e) => log::warn!("resume {} {:?}", peer_ip, e),
+                }
+            }
+        } else if let Err(e) =
+            punch_direct(&context, &client_cipher, src_ip, peer_ip, &[route.addr])
+        {
+            log::warn!("resume {} {:?}", peer_ip, e);
+        }
+    }
+    if round + 1 >= RESUME_ROUNDS {
+        return;
+    }
+    let rs = scheduler.timeout(Duration::from_millis(500), move |s| {
+        resume_peers(
+            s,
+            context,
+            current_device,
+            connect_util,
+            client_cipher,
+            cache,
+            round + 1,
+        )
+    });
+    if !rs {
+        log::info!("定时任务停止");
+    }
+}
+
+/// 定时记录当前的虚拟ip、nat信息和直连地址，停止时保存到文件
+pub fn save_state(
+    scheduler: &Scheduler,
+    context: ChannelContext,
+    current_device: Arc<AtomicCell<CurrentDeviceInfo>>,
+    nat_test: NatTest,
+    cache: StateCache,
+) {
+    update_state(&context, &current_device.load(), &nat_test, &cache);
+    cache.save();
+    let rs = scheduler.timeout(Duration::from_secs(60), move |s| {
+        save_state(s, context, current_device, nat_test, cache)
+    });
+    if !rs {
+        log::info!("定时任务停止");
+    }
+}
+
+pub fn save_state_on_stop(
+    stop_manager: &StopManager,
+    context: ChannelContext,
+    current_device: Arc<AtomicCell<CurrentDeviceInfo>>,
+    nat_test: NatTest,
+    cache: StateCache,
+) -> anyhow::Result<()> {
+    stop_manager.add_stop_task("stateCacheSave", move || {
+        update_state(&context, &current_device.load(), &nat_test, &cache);
+        cache.save();
+    })
+}
+
+fn update_state(
+    context: &ChannelContext,
+    current_device: &CurrentDeviceInfo,
+    nat_test: &NatTest,
+    cache: &StateCache,
+) {
+    if current_device.virtual_ip.is_unspecified() {
+        return;
+    }
+    let nat_info = nat_test.nat_info();
+    let mut routes = Vec::new();
+    for (ip, list) in context.route_table.route_table() {
+        if current_device.is_gateway(&ip) {
+            continue;
+        }
+        for route in list {
+            if route.is_p2p() && route.protocol.is_transport() {
+                routes.push((ip, route.protocol.is_tcp(), route.addr));
+            }
+        }
+    }
+    cache.update(
+        current_device.virtual_ip,
+        nat_info.nat_type,
+        nat_info.public_ips,
+        routes,
+        now_time(),
+    );
+}
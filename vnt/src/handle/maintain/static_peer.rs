@@ -0,0 +1,48 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use crossbeam_utils::atomic::AtomicCell;
+
+use crate::channel::context::ChannelContext;
+use crate::cipher::Cipher;
+use crate::handle::maintain::punch::{punch_challenge_packet, send_direct};
+use crate::handle::static_peers::StaticPeers;
+use crate::handle::{now_time, CurrentDeviceInfo};
+use crate::util::Scheduler;
+
+/// 无服务器模式，定时向静态对端的地址发送打洞请求，直到建立直连路由
+pub fn static_peer(
+    scheduler: &Scheduler,
+    context: ChannelContext,
+    current_device: Arc<AtomicCell<CurrentDeviceInfo>>,
+    static_peers: StaticPeers,
+    client_cipher: Cipher,
+) {
+    let src_ip = current_device.load().virtual_ip;
+    let now = now_time();
+    let mut connected = true;
+    for peer in static_peers.peers() {
+        if context.route_table.p2p_num(&peer.virtual_ip) > 0 {
+            continue;
+        }
+        connected = false;
+        // 总是带上挑战值，对端配置了公钥时用来验证回应
+        let challenge = static_peers.challenge(peer.virtual_ip, now);
+        match punch_challenge_packet(&client_cipher, src_ip, peer.virtual_ip, &challenge) {
+            Ok(packet) => send_direct(&context, packet.buffer(), &peer.endpoints),
+            Err(e) => log::warn!("static peer {} {:?}", peer.virtual_ip, e),
+        }
+    }
+    // 有对端未连接时尽快重试，全部连接后只需要检查路由是否过期
+    let time = if connected {
+        Duration::from_secs(10)
+    } else {
+        Duration::from_secs(3)
+    };
+    let rs = scheduler.timeout(time, move |s| {
+        static_peer(s, context, current_device, static_peers, client_cipher)
+    });
+    if !rs {
+        log::info!("定时任务停止");
+    }
+}
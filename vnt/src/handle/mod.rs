@@ -4,10 +4,13 @@ use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 
 pub mod callback;
 mod extension;
+pub mod gossip;
 pub mod handshaker;
 pub mod maintain;
 pub mod recv_data;
 pub mod registrar;
+pub mod state_cache;
+pub mod static_peers;
 #[cfg(feature = "integrated_tun")]
 pub mod tun_tap;
 
@@ -70,8 +73,18 @@ pub struct BaseConfigInfo {
     #[cfg(feature = "integrated_tun")]
     #[cfg(any(target_os = "windows", target_os = "linux", target_os = "macos"))]
     pub device_name: Option<String>,
+    #[cfg(feature = "integrated_tun")]
+    #[cfg(target_os = "linux")]
+    pub tun_queues: usize,
+    #[cfg(feature = "integrated_tun")]
+    #[cfg(target_os = "linux")]
+    pub tun_offload: bool,
     pub allow_wire_guard: bool,
     pub default_interface: LocalInterface,
+    // 使用出口节点时网卡上设置的dns
+    pub exit_dns: Vec<Ipv4Addr>,
+    // 作为出口节点并且没有内置代理时，使用内核nat转发
+    pub kernel_nat: bool,
 }
 
 impl BaseConfigInfo {
@@ -91,8 +104,16 @@ impl BaseConfigInfo {
         #[cfg(feature = "integrated_tun")]
         #[cfg(any(target_os = "windows", target_os = "linux", target_os = "macos"))]
         device_name: Option<String>,
+        #[cfg(feature = "integrated_tun")]
+        #[cfg(target_os = "linux")]
+        tun_queues: usize,
+        #[cfg(feature = "integrated_tun")]
+        #[cfg(target_os = "linux")]
+        tun_offload: bool,
         allow_wire_guard: bool,
         default_interface: LocalInterface,
+        exit_dns: Vec<Ipv4Addr>,
+        kernel_nat: bool,
     ) -> Self {
         Self {
             name,
@@ -110,8 +131,16 @@ impl BaseConfigInfo {
             #[cfg(feature = "integrated_tun")]
             #[cfg(any(target_os = "windows", target_os = "linux", target_os = "macos"))]
             device_name,
+            #[cfg(feature = "integrated_tun")]
+            #[cfg(target_os = "linux")]
+            tun_queues,
+            #[cfg(feature = "integrated_tun")]
+            #[cfg(target_os = "linux")]
+            tun_offload,
             allow_wire_guard,
             default_interface,
+            exit_dns,
+            kernel_nat,
         }
     }
 }
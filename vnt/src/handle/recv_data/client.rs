@@ -1,50 +1,65 @@
 use anyhow::anyhow;
 use std::collections::HashMap;
-use std::net::{Ipv4Addr, Ipv6Addr};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV4};
 use std::sync::Arc;
 
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
 use protobuf::Message;
 
 use packet::icmp::{icmp, Kind};
 use packet::ip::ipv4;
 use packet::ip::ipv4::packet::IpV4Packet;
 
+use crate::channel::candidate::Candidate;
 use crate::channel::context::ChannelContext;
-use crate::channel::punch::NatInfo;
+use crate::channel::punch::{NatInfo, PortModel};
 use crate::channel::{Route, RouteKey};
-use crate::cipher::Cipher;
-use crate::external_route::AllowExternalRoute;
+use crate::cipher::{Cipher, SIGNATURE_LEN};
+use crate::external_route::{AllowExternalRoute, ExternalRoute};
+use crate::handle::callback::VntCallback;
 use crate::handle::extension::handle_extension_tail;
-use crate::handle::maintain::PunchSender;
+use crate::handle::gossip::Gossip;
+use crate::handle::maintain::{local_punch_info, punch_challenge_packet, PunchSender};
 use crate::handle::recv_data::PacketHandler;
-use crate::handle::CurrentDeviceInfo;
+use crate::handle::static_peers::{StaticPeers, CHALLENGE_LEN};
+use crate::handle::{now_time, CurrentDeviceInfo, PeerDeviceInfo, PeerDeviceStatus};
 #[cfg(feature = "ip_proxy")]
 use crate::ip_proxy::{IpProxyMap, ProxyHandler};
+#[cfg(target_os = "linux")]
+use crate::masquerade::Masquerade;
 use crate::nat::NatTest;
-use crate::proto::message::{PunchInfo, PunchNatType};
+use crate::proto::message::{GossipList, PunchInfo, RouteAdvertisement};
 use crate::protocol::body::ENCRYPTION_RESERVED;
 use crate::protocol::control_packet::ControlPacket;
 use crate::protocol::{
     control_packet, ip_turn_packet, other_turn_packet, NetPacket, Protocol, MAX_TTL,
 };
 use crate::tun_tap_device::vnt_device::DeviceWrite;
+use crate::PeerClientInfo;
 
 /// 处理来源于客户端的包
 #[derive(Clone)]
-pub struct ClientPacketHandler<Device> {
+pub struct ClientPacketHandler<Call, Device> {
     device: Device,
     client_cipher: Cipher,
     punch_sender: PunchSender,
     peer_nat_info_map: Arc<RwLock<HashMap<Ipv4Addr, NatInfo>>>,
     nat_test: NatTest,
     route: AllowExternalRoute,
+    device_map: Arc<Mutex<(u16, HashMap<Ipv4Addr, PeerDeviceInfo>)>>,
+    gossip: Gossip,
+    external_route: ExternalRoute,
+    // 无服务器模式的静态对端，对端只能通过直连通信
+    static_peers: Option<StaticPeers>,
+    callback: Call,
+    #[cfg(target_os = "linux")]
+    masquerade: Option<Masquerade>,
     #[cfg(feature = "ip_proxy")]
     #[cfg(feature = "integrated_tun")]
     ip_proxy_map: Option<IpProxyMap>,
 }
 
-impl<Device: DeviceWrite> ClientPacketHandler<Device> {
+impl<Call: VntCallback, Device: DeviceWrite> ClientPacketHandler<Call, Device> {
     pub fn new(
         device: Device,
         client_cipher: Cipher,
@@ -52,6 +67,12 @@ impl<Device: DeviceWrite> ClientPacketHandler<Device> {
         peer_nat_info_map: Arc<RwLock<HashMap<Ipv4Addr, NatInfo>>>,
         nat_test: NatTest,
         route: AllowExternalRoute,
+        device_map: Arc<Mutex<(u16, HashMap<Ipv4Addr, PeerDeviceInfo>)>>,
+        gossip: Gossip,
+        external_route: ExternalRoute,
+        static_peers: Option<StaticPeers>,
+        callback: Call,
+        #[cfg(target_os = "linux")] masquerade: Option<Masquerade>,
         #[cfg(feature = "integrated_tun")]
         #[cfg(feature = "ip_proxy")]
         ip_proxy_map: Option<IpProxyMap>,
@@ -63,6 +84,13 @@ impl<Device: DeviceWrite> ClientPacketHandler<Device> {
             peer_nat_info_map,
             nat_test,
             route,
+            device_map,
+            gossip,
+            external_route,
+            static_peers,
+            callback,
+            #[cfg(target_os = "linux")]
+            masquerade,
             #[cfg(feature = "integrated_tun")]
             #[cfg(feature = "ip_proxy")]
             ip_proxy_map,
@@ -70,7 +98,7 @@ impl<Device: DeviceWrite> ClientPacketHandler<Device> {
     }
 }
 
-impl<Device: DeviceWrite> PacketHandler for ClientPacketHandler<Device> {
+impl<Call: VntCallback, Device: DeviceWrite> PacketHandler for ClientPacketHandler<Call, Device> {
     fn handle(
         &self,
         mut net_packet: NetPacket<&mut [u8]>,
@@ -112,7 +140,7 @@ impl<Device: DeviceWrite> PacketHandler for ClientPacketHandler<Device> {
     }
 }
 
-impl<Device: DeviceWrite> ClientPacketHandler<Device> {
+impl<Call: VntCallback, Device: DeviceWrite> ClientPacketHandler<Call, Device> {
     fn ip_turn(
         &self,
         mut net_packet: NetPacket<&mut [u8]>,
@@ -184,6 +212,13 @@ impl<Device: DeviceWrite> ClientPacketHandler<Device> {
                         }
                         _ => {}
                     }
+                    // 内置nat优先，不支持的协议再交给ip代理
+                    #[cfg(target_os = "linux")]
+                    if let Some(masquerade) = &self.masquerade {
+                        if masquerade.recv_handle(&mut ipv4, source)? {
+                            return Ok(());
+                        }
+                    }
                     #[cfg(feature = "ip_proxy")]
                     #[cfg(feature = "integrated_tun")]
                     if let Some(ip_proxy_map) = &self.ip_proxy_map {
@@ -204,6 +239,41 @@ impl<Device: DeviceWrite> ClientPacketHandler<Device> {
         }
         Ok(())
     }
+    /// 原地把打洞请求改成响应发回
+    fn punch_response(
+        &self,
+        context: &ChannelContext,
+        current_device: &CurrentDeviceInfo,
+        mut net_packet: NetPacket<&mut [u8]>,
+        route_key: RouteKey,
+    ) -> anyhow::Result<()> {
+        let source = net_packet.source();
+        net_packet.set_transport_protocol(control_packet::Protocol::PunchResponse.into());
+        net_packet.set_source(current_device.virtual_ip);
+        net_packet.set_destination(source);
+        net_packet.first_set_ttl(1);
+        self.client_cipher.encrypt_ipv4(&mut net_packet)?;
+        context.send_by_key(&net_packet, route_key)?;
+        Ok(())
+    }
+    /// 需要认证的静态对端只能刷新签名验证后建立的路由，不能通过心跳添加新路由
+    fn unauthenticated(
+        &self,
+        context: &ChannelContext,
+        source: Ipv4Addr,
+        route_key: RouteKey,
+    ) -> bool {
+        let Some(static_peers) = &self.static_peers else {
+            return false;
+        };
+        if !static_peers.requires_auth(&source) {
+            return false;
+        }
+        !context
+            .route_table
+            .route(&source)
+            .is_some_and(|routes| routes.iter().any(|route| route.route_key() == route_key))
+    }
     fn control(
         &self,
         context: &ChannelContext,
@@ -215,6 +285,9 @@ impl<Device: DeviceWrite> ClientPacketHandler<Device> {
         let source = net_packet.source();
         match ControlPacket::new(net_packet.transport_protocol(), net_packet.payload())? {
             ControlPacket::PingPacket(_) => {
+                if self.unauthenticated(context, source, route_key) {
+                    return Ok(());
+                }
                 let route = Route::from_default_rt(route_key, metric);
                 context.route_table.add_route_if_absent(source, route);
                 net_packet.set_transport_protocol(control_packet::Protocol::Pong.into());
@@ -225,6 +298,9 @@ impl<Device: DeviceWrite> ClientPacketHandler<Device> {
                 context.send_by_key(&net_packet, route_key)?;
             }
             ControlPacket::PongPacket(pong_packet) => {
+                if self.unauthenticated(context, source, route_key) {
+                    return Ok(());
+                }
                 let current_time = crate::handle::now_time() as u16;
                 if current_time < pong_packet.time() {
                     return Ok(());
@@ -247,12 +323,43 @@ impl<Device: DeviceWrite> ClientPacketHandler<Device> {
                 }
 
                 //回应
-                net_packet.set_transport_protocol(control_packet::Protocol::PunchResponse.into());
-                net_packet.set_source(current_device.virtual_ip);
-                net_packet.set_destination(source);
-                net_packet.first_set_ttl(1);
-                self.client_cipher.encrypt_ipv4(&mut net_packet)?;
-                context.send_by_key(&net_packet, route_key)?;
+                if let Some(static_peers) = &self.static_peers {
+                    let challenge = net_packet.payload();
+                    if challenge.len() == CHALLENGE_LEN {
+                        // 对挑战值签名，对端用配置的公钥验证本机身份
+                        let signature =
+                            static_peers.sign(current_device.virtual_ip, source, challenge);
+                        let mut packet = NetPacket::new_encrypt(
+                            [0u8; 12 + SIGNATURE_LEN + ENCRYPTION_RESERVED],
+                        )?;
+                        packet.set_default_version();
+                        packet.set_protocol(Protocol::Control);
+                        packet
+                            .set_transport_protocol(control_packet::Protocol::PunchResponse.into());
+                        packet.set_source(current_device.virtual_ip);
+                        packet.set_destination(source);
+                        packet.first_set_ttl(1);
+                        packet.payload_mut().copy_from_slice(&signature);
+                        self.client_cipher.encrypt_ipv4(&mut packet)?;
+                        context.send_by_key(&packet, route_key)?;
+                    } else {
+                        self.punch_response(context, current_device, net_packet, route_key)?;
+                    }
+                    // 无服务器模式下对端可能无法被本机主动连接，还没有直连路由时反向发起请求，
+                    // 收到回应后本机也会添加路由
+                    if context.route_table.p2p_num(&source) == 0 {
+                        let challenge = static_peers.challenge(source, now_time());
+                        let packet = punch_challenge_packet(
+                            &self.client_cipher,
+                            current_device.virtual_ip,
+                            source,
+                            &challenge,
+                        )?;
+                        context.send_by_key(&packet, route_key)?;
+                    }
+                } else {
+                    self.punch_response(context, current_device, net_packet, route_key)?;
+                }
                 // 收到PunchRequest就添加路由，会导致单向通信的问题，删掉试试
                 // let route = Route::from_default_rt(route_key, 1);
                 // context.route_table.add_route_if_absent(source, route);
@@ -268,6 +375,17 @@ impl<Device: DeviceWrite> ClientPacketHandler<Device> {
                 {
                     return Ok(());
                 }
+                if let Some(static_peers) = &self.static_peers {
+                    if !static_peers.verify(
+                        current_device.virtual_ip,
+                        source,
+                        net_packet.payload(),
+                        now_time(),
+                    ) {
+                        log::warn!("静态对端签名验证失败={:?},source={}", route_key, source);
+                        return Ok(());
+                    }
+                }
                 let route = Route::from_default_rt(route_key, metric);
                 context.route_table.add_route_if_absent(source, route);
             }
@@ -289,6 +407,40 @@ impl<Device: DeviceWrite> ClientPacketHandler<Device> {
                 std::net::IpAddr::V6(_) => {}
             },
             ControlPacket::AddrResponse(_) => {}
+            ControlPacket::Leave => {
+                // 只接受对端自己的通道或者服务端转发来的离开通知，防止伪造来源踢掉别人的路由
+                let routes = context.route_table.route(&source).unwrap_or_default();
+                let from_source = routes.iter().any(|route| route.route_key() == route_key);
+                let from_server = route_key.addr == current_device.connect_server;
+                if !from_source && !from_server {
+                    log::warn!("忽略来源不符的Leave={:?},source={}", route_key, source);
+                    return Ok(());
+                }
+                log::info!("Leave={:?},source={}", route_key, source);
+                // 对端主动离开，不用等路由超时
+                for route in routes {
+                    context.remove_route(&source, route.route_key());
+                }
+                let list = {
+                    let mut dev = self.device_map.lock();
+                    if let Some(info) = dev.1.get_mut(&source) {
+                        info.status = PeerDeviceStatus::Offline;
+                        Some(dev.1.values().cloned().collect::<Vec<_>>())
+                    } else {
+                        None
+                    }
+                };
+                self.external_route.remove_advertised(&source);
+                if let Some(list) = list {
+                    self.callback.peer_client_list(
+                        list.into_iter()
+                            .map(|v| {
+                                PeerClientInfo::new(v.virtual_ip, v.name, v.status, v.client_secret)
+                            })
+                            .collect(),
+                    );
+                }
+            }
         }
         Ok(())
     }
@@ -299,12 +451,12 @@ impl<Device: DeviceWrite> ClientPacketHandler<Device> {
         net_packet: NetPacket<&mut [u8]>,
         route_key: RouteKey,
     ) -> anyhow::Result<()> {
-        if context.use_channel_type().is_only_relay() {
-            return Ok(());
-        }
         let source = net_packet.source();
         match other_turn_packet::Protocol::from(net_packet.transport_protocol()) {
             other_turn_packet::Protocol::Punch => {
+                if context.use_channel_type().is_only_relay() {
+                    return Ok(());
+                }
                 let mut punch_info = PunchInfo::parse_from_bytes(net_packet.payload())
                     .map_err(|e| anyhow!("PunchInfo {:?}", e))?;
                 let public_ips = punch_info
@@ -340,38 +492,54 @@ impl<Device: DeviceWrite> ClientPacketHandler<Device> {
                     public_tcp_port,
                     punch_info.nat_type.enum_value_or_default().into(),
                     punch_info.punch_model.enum_value_or_default().into(),
+                )
+                .with_behavior(
+                    punch_info.mapping.enum_value_or_default().into(),
+                    punch_info.filtering.enum_value_or_default().into(),
+                    punch_info.hairpin.then_some(true),
+                    punch_info.binding_lifetime,
+                )
+                .with_port_model(PortModel::from_proto(
+                    punch_info.port_model.enum_value_or_default(),
+                    punch_info.port_delta,
+                ))
+                .with_mapped(
+                    punch_info
+                        .mapped_udp_ips
+                        .iter()
+                        .zip(punch_info.mapped_udp_ports.iter())
+                        .map(|(ip, port)| SocketAddrV4::new(Ipv4Addr::from(*ip), *port as u16))
+                        .collect(),
+                    if punch_info.mapped_tcp_port != 0 {
+                        Some(SocketAddrV4::new(
+                            Ipv4Addr::from(punch_info.mapped_tcp_ip),
+                            punch_info.mapped_tcp_port as u16,
+                        ))
+                    } else {
+                        None
+                    },
+                )
+                .with_relayed(
+                    punch_info
+                        .relayed_ips
+                        .iter()
+                        .zip(punch_info.relayed_ports.iter())
+                        .map(|(ip, port)| SocketAddrV4::new(Ipv4Addr::from(*ip), *port as u16))
+                        .collect(),
+                )
+                .with_candidates(
+                    punch_info
+                        .candidates
+                        .iter()
+                        .filter_map(Candidate::from_proto)
+                        .collect(),
                 );
                 {
                     let peer_nat_info = peer_nat_info.clone();
                     self.peer_nat_info_map.write().insert(source, peer_nat_info);
                 }
                 if !punch_info.reply {
-                    let mut punch_reply = PunchInfo::new();
-                    punch_reply.reply = true;
-                    let nat_info = self.nat_test.nat_info();
-                    punch_reply.public_ip_list = nat_info
-                        .public_ips
-                        .iter()
-                        .map(|ip| u32::from_be_bytes(ip.octets()))
-                        .collect();
-                    punch_reply.public_port = nat_info.public_ports.get(0).map_or(0, |v| *v as u32);
-                    punch_reply.public_ports =
-                        nat_info.public_ports.iter().map(|e| *e as u32).collect();
-                    punch_reply.public_port_range = nat_info.public_port_range as u32;
-                    punch_reply.tcp_port = nat_info.tcp_port as u32;
-                    punch_reply.public_tcp_port = nat_info.public_tcp_port as u32;
-                    punch_reply.nat_type =
-                        protobuf::EnumOrUnknown::new(PunchNatType::from(nat_info.nat_type));
-                    punch_reply.punch_model =
-                        protobuf::EnumOrUnknown::new(nat_info.punch_model.into());
-                    punch_reply.local_ip =
-                        u32::from(nat_info.local_ipv4().unwrap_or(Ipv4Addr::UNSPECIFIED));
-                    punch_reply.local_port = nat_info.udp_ports[0] as u32;
-                    punch_reply.udp_ports = nat_info.udp_ports.iter().map(|e| *e as u32).collect();
-                    if let Some(ipv6) = nat_info.ipv6() {
-                        punch_reply.ipv6 = ipv6.octets().to_vec();
-                        punch_reply.ipv6_port = nat_info.udp_ports[0] as u32;
-                    }
+                    let punch_reply = local_punch_info(&self.nat_test.nat_info(), true);
                     let bytes = punch_reply
                         .write_to_bytes()
                         .map_err(|e| anyhow!("punch_reply {:?}", e))?;
@@ -392,6 +560,52 @@ impl<Device: DeviceWrite> ClientPacketHandler<Device> {
                     self.punch_sender.send(false, source, peer_nat_info);
                 }
             }
+            other_turn_packet::Protocol::Gossip => {
+                if context.use_channel_type().is_only_relay() {
+                    return Ok(());
+                }
+                let list = GossipList::parse_from_bytes(net_packet.payload())
+                    .map_err(|e| anyhow!("GossipList {:?}", e))?;
+                // 通过来源设备自己的通道发来的列表，才能用于更换来源设备的公钥
+                let direct = context.route_table.route(&source).is_some_and(|routes| {
+                    routes.iter().any(|route| route.route_key() == route_key)
+                });
+                let count = self.gossip.merge(
+                    list,
+                    current_device.virtual_ip,
+                    direct.then_some(source),
+                    now_time(),
+                );
+                if count > 0 {
+                    log::debug!("收到{}的设备目录，更新{}条", source, count);
+                }
+            }
+            other_turn_packet::Protocol::RouteAdvertise => {
+                if current_device.not_in_network(source) {
+                    return Ok(());
+                }
+                let advertisement = RouteAdvertisement::parse_from_bytes(net_packet.payload())
+                    .map_err(|e| anyhow!("RouteAdvertisement {:?}", e))?;
+                let network = u32::from(current_device.virtual_network);
+                let netmask = u32::from(current_device.virtual_netmask);
+                let routes: Vec<(u32, u32)> = advertisement
+                    .routes
+                    .iter()
+                    .map(|v| (v.dest & v.mask, v.mask))
+                    // 和虚拟网络重叠的、本机自己转发的网段不接受
+                    .filter(|(dest, mask)| {
+                        (dest ^ network) & (mask & netmask) != 0
+                            && !self.route.allow(&Ipv4Addr::from(*dest))
+                    })
+                    .collect();
+                if self.external_route.accept_advertised(source, routes) {
+                    log::info!(
+                        "网关{}通告的路由已更新，当前通告路由 {:?}",
+                        source,
+                        self.external_route.advertised_routes()
+                    );
+                }
+            }
             other_turn_packet::Protocol::Unknown(e) => {
                 log::warn!("不支持的转发协议 {:?},source:{:?}", e, source);
             }
@@ -13,16 +13,21 @@ use crate::channel::RouteKey;
 use crate::cipher::Cipher;
 #[cfg(feature = "server_encrypt")]
 use crate::cipher::RsaCipher;
+use crate::core::StaticPeer;
 use crate::external_route::{AllowExternalRoute, ExternalRoute};
 use crate::handle::callback::VntCallback;
+use crate::handle::gossip::Gossip;
 use crate::handle::handshaker::Handshake;
 use crate::handle::maintain::PunchSender;
 use crate::handle::recv_data::client::ClientPacketHandler;
 use crate::handle::recv_data::server::ServerPacketHandler;
 use crate::handle::recv_data::turn::TurnPacketHandler;
+use crate::handle::static_peers::StaticPeers;
 use crate::handle::{BaseConfigInfo, CurrentDeviceInfo, PeerDeviceInfo, SELF_IP};
 #[cfg(feature = "ip_proxy")]
 use crate::ip_proxy::IpProxyMap;
+#[cfg(target_os = "linux")]
+use crate::masquerade::Masquerade;
 use crate::nat::NatTest;
 use crate::protocol::{NetPacket, HEAD_LEN};
 use crate::tun_tap_device::vnt_device::DeviceWrite;
@@ -34,8 +39,9 @@ mod turn;
 #[derive(Clone)]
 pub struct RecvDataHandler<Call, Device> {
     current_device: Arc<AtomicCell<CurrentDeviceInfo>>,
+    device: Device,
     turn: TurnPacketHandler,
-    client: ClientPacketHandler<Device>,
+    client: ClientPacketHandler<Call, Device>,
     server: ServerPacketHandler<Call, Device>,
     nat_test: NatTest,
 }
@@ -71,6 +77,12 @@ impl<Call: VntCallback, Device: DeviceWrite> RecvChannelHandler for RecvDataHand
             );
         }
     }
+    fn batch_begin(&self) {
+        self.device.batch_begin();
+    }
+    fn batch_end(&self) {
+        self.device.batch_end();
+    }
 }
 
 impl<Call: VntCallback, Device: DeviceWrite> RecvDataHandler<Call, Device> {
@@ -88,6 +100,9 @@ impl<Call: VntCallback, Device: DeviceWrite> RecvDataHandler<Call, Device> {
         peer_nat_info_map: Arc<RwLock<HashMap<Ipv4Addr, NatInfo>>>,
         external_route: ExternalRoute,
         route: AllowExternalRoute,
+        gossip: Gossip,
+        static_peers: Option<StaticPeers>,
+        #[cfg(target_os = "linux")] masquerade: Option<Masquerade>,
         #[cfg(feature = "integrated_tun")]
         #[cfg(feature = "ip_proxy")]
         ip_proxy_map: Option<IpProxyMap>,
@@ -101,10 +116,10 @@ impl<Call: VntCallback, Device: DeviceWrite> RecvDataHandler<Call, Device> {
             server_cipher,
             current_device.clone(),
             device.clone(),
-            device_map,
+            device_map.clone(),
             config_info,
             nat_test.clone(),
-            callback,
+            callback.clone(),
             external_route.clone(),
             handshake,
             #[cfg(feature = "integrated_tun")]
@@ -117,6 +132,13 @@ impl<Call: VntCallback, Device: DeviceWrite> RecvDataHandler<Call, Device> {
             peer_nat_info_map,
             nat_test.clone(),
             route,
+            device_map,
+            gossip,
+            external_route,
+            static_peers,
+            callback,
+            #[cfg(target_os = "linux")]
+            masquerade,
             #[cfg(feature = "integrated_tun")]
             #[cfg(feature = "ip_proxy")]
             ip_proxy_map,
@@ -124,12 +146,23 @@ impl<Call: VntCallback, Device: DeviceWrite> RecvDataHandler<Call, Device> {
         let turn = TurnPacketHandler::new();
         Self {
             current_device,
+            device,
             turn,
             client,
             server,
             nat_test,
         }
     }
+    /// 无服务器模式下直接使用配置的虚拟ip
+    pub fn register_static(
+        &self,
+        virtual_ip: Ipv4Addr,
+        virtual_netmask: Ipv4Addr,
+        static_peers: &[StaticPeer],
+    ) -> anyhow::Result<()> {
+        self.server
+            .register_static(virtual_ip, virtual_netmask, static_peers)
+    }
     fn handle0(
         &self,
         buf: &mut [u8],
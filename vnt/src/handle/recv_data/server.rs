@@ -18,6 +18,7 @@ use crate::channel::{Route, RouteKey};
 use crate::cipher::Cipher;
 #[cfg(feature = "server_encrypt")]
 use crate::cipher::RsaCipher;
+use crate::core::StaticPeer;
 use crate::external_route::ExternalRoute;
 use crate::handle::callback::{ErrorInfo, ErrorType, HandshakeInfo, RegisterInfo, VntCallback};
 #[cfg(feature = "server_encrypt")]
@@ -26,7 +27,9 @@ use crate::handle::handshaker::Handshake;
 use crate::handle::recv_data::PacketHandler;
 use crate::handle::{registrar, BaseConfigInfo, ConnectStatus, CurrentDeviceInfo, PeerDeviceInfo};
 use crate::nat::NatTest;
-use crate::proto::message::{DeviceList, HandshakeResponse, RegistrationResponse};
+use crate::proto::message::{
+    DeviceList, DeviceListDelta, DeviceListDeltaRequest, HandshakeResponse, RegistrationResponse,
+};
 use crate::protocol::body::ENCRYPTION_RESERVED;
 use crate::protocol::control_packet::ControlPacket;
 use crate::protocol::error_packet::InErrorPacket;
@@ -50,6 +53,8 @@ pub struct ServerPacketHandler<Call, Device> {
     up_key_time: Arc<AtomicCell<Instant>>,
     external_route: ExternalRoute,
     handshake: Handshake,
+    // 服务端支持增量同步设备列表
+    device_list_delta: Arc<AtomicCell<bool>>,
     #[cfg(feature = "integrated_tun")]
     tun_device_helper: crate::tun_tap_device::tun_create_helper::TunDeviceHelper,
 }
@@ -87,6 +92,7 @@ impl<Call, Device> ServerPacketHandler<Call, Device> {
             )),
             external_route,
             handshake,
+            device_list_delta: Arc::new(AtomicCell::new(false)),
             #[cfg(feature = "integrated_tun")]
             tun_device_helper,
         }
@@ -326,87 +332,39 @@ impl<Call: VntCallback, Device: DeviceWrite> ServerPacketHandler<Call, Device> {
                         if old.virtual_ip != Ipv4Addr::UNSPECIFIED {
                             log::info!("ip发生变化,old:{:?},response={:?}", old, response);
                         }
-                        let device_config = crate::handle::callback::DeviceConfig::new(
-                            #[cfg(feature = "integrated_tun")]
-                            #[cfg(target_os = "windows")]
-                            self.config_info.tap,
-                            #[cfg(feature = "integrated_tun")]
-                            #[cfg(any(
-                                target_os = "windows",
-                                target_os = "linux",
-                                target_os = "macos"
-                            ))]
-                            self.config_info.device_name.clone(),
-                            self.config_info.mtu,
+                        self.create_device(
                             virtual_ip,
                             virtual_netmask,
                             virtual_gateway,
                             virtual_network,
-                            self.external_route.to_route(),
-                        );
-                        #[cfg(not(feature = "integrated_tun"))]
-                        self.callback.create_device(device_config);
-                        #[cfg(feature = "integrated_tun")]
-                        {
-                            self.tun_device_helper.stop();
-                            #[cfg(any(
-                                target_os = "windows",
-                                target_os = "linux",
-                                target_os = "macos"
-                            ))]
-                            match crate::tun_tap_device::create_device(
-                                device_config,
-                                &self.callback,
-                            ) {
-                                Ok(device) => {
-                                    let tun_info = crate::handle::callback::DeviceInfo::new(
-                                        device.name().unwrap_or("unknown".into()),
-                                        "".into(),
-                                    );
-                                    log::info!("tun信息{:?}", tun_info);
-                                    self.callback.create_tun(tun_info);
-                                    self.tun_device_helper
-                                        .start(device, self.config_info.allow_wire_guard)?;
-                                }
+                        )?;
+                    }
+                    self.device_list_delta.store(response.device_list_delta);
+                    self.handshake.set_ticket(
+                        response.session_ticket,
+                        response.session_ticket_lifetime,
+                        self.config_info.device_id.clone(),
+                        virtual_ip,
+                    );
+                    self.set_device_info_list(response.device_info_list, response.epoch as _);
+                    if old.status.offline() {
+                        // 补发重连期间暂存的数据
+                        let connect_server = self.current_device.load().connect_server;
+                        // 单个包失败不影响其他包的补发和连接成功的回调
+                        for (dest, buf) in context.pending().take() {
+                            let packet = match NetPacket::new(buf) {
+                                Ok(packet) => packet,
                                 Err(e) => {
-                                    log::error!("{:?}", e);
-                                    self.callback.error(e);
+                                    log::warn!("补发暂存数据 {} {:?}", dest, e);
+                                    continue;
                                 }
-                            }
-                            #[cfg(target_os = "android")]
+                            };
+                            if let Err(e) =
+                                context.send_ipv4_by_id(&packet, &dest, connect_server, true)
                             {
-                                let device_config = crate::handle::callback::DeviceConfig::new(
-                                    self.config_info.mtu,
-                                    virtual_ip,
-                                    virtual_netmask,
-                                    virtual_gateway,
-                                    virtual_network,
-                                    self.external_route.to_route(),
-                                );
-                                let device_fd = self.callback.generate_tun(device_config);
-                                if device_fd == 0 {
-                                    self.callback.error(ErrorInfo::new_msg(
-                                        ErrorType::FailedToCrateDevice,
-                                        "device_fd == 0".into(),
-                                    ));
-                                } else {
-                                    let device =
-                                        unsafe { tun_rs::SyncDevice::from_fd(device_fd as _) };
-                                    if let Err(e) = self
-                                        .tun_device_helper
-                                        .start(Arc::new(device), self.config_info.allow_wire_guard)
-                                    {
-                                        self.callback.error(ErrorInfo::new_msg(
-                                            ErrorType::FailedToCrateDevice,
-                                            format!("{:?}", e),
-                                        ));
-                                    }
-                                }
+                                log::warn!("补发暂存数据 {} {:?}", dest, e);
                             }
                         }
-                    }
-                    self.set_device_info_list(response.device_info_list, response.epoch as _);
-                    if old.status.offline() {
                         self.callback.success();
                     }
                 }
@@ -417,6 +375,32 @@ impl<Call: VntCallback, Device: DeviceWrite> ServerPacketHandler<Call, Device> {
                 })?;
                 self.set_device_info_list(response.device_info_list, response.epoch as _);
             }
+            service_packet::Protocol::PushDeviceListDelta => {
+                let delta =
+                    DeviceListDelta::parse_from_bytes(net_packet.payload()).map_err(|e| {
+                        io::Error::new(io::ErrorKind::Other, format!("PushDeviceListDelta {:?}", e))
+                    })?;
+                let list = {
+                    let mut dev = self.device_map.lock();
+                    if apply_delta(&mut dev, delta) {
+                        Some(dev.1.values().cloned().collect::<Vec<_>>())
+                    } else {
+                        None
+                    }
+                };
+                if let Some(list) = list {
+                    self.callback.peer_client_list(
+                        list.into_iter()
+                            .map(|v| {
+                                PeerClientInfo::new(v.virtual_ip, v.name, v.status, v.client_secret)
+                            })
+                            .collect(),
+                    );
+                } else {
+                    // 和本地纪元对不上，可能丢失了中间的变化，重新拉取完整的列表
+                    self.pull_device_list(context, current_device, true)?;
+                }
+            }
             service_packet::Protocol::SecretHandshakeResponse => {
                 log::info!("SecretHandshakeResponse");
                 //加密握手结束，发送注册数据
@@ -431,6 +415,143 @@ impl<Call: VntCallback, Device: DeviceWrite> ServerPacketHandler<Call, Device> {
         }
         Ok(())
     }
+    /// 无服务器模式，使用配置的虚拟ip创建网卡，静态对端作为设备列表
+    pub fn register_static(
+        &self,
+        virtual_ip: Ipv4Addr,
+        virtual_netmask: Ipv4Addr,
+        static_peers: &[StaticPeer],
+    ) -> anyhow::Result<()> {
+        let (virtual_network, virtual_gateway) = static_network(virtual_ip, virtual_netmask);
+        let register_info = RegisterInfo::new(virtual_ip, virtual_netmask, virtual_gateway);
+        log::info!("无服务器模式：{:?}", register_info);
+        if !self.callback.register(register_info) {
+            return Ok(());
+        }
+        let mut cur = self.current_device.load();
+        loop {
+            let mut new_current_device = cur;
+            new_current_device.update(virtual_ip, virtual_netmask, virtual_gateway);
+            if let Err(c) = self
+                .current_device
+                .compare_exchange(cur, new_current_device)
+            {
+                cur = c;
+            } else {
+                break;
+            }
+        }
+        self.create_device(
+            virtual_ip,
+            virtual_netmask,
+            virtual_gateway,
+            virtual_network,
+        )?;
+        let device_info_list =
+            static_device_list(static_peers, self.config_info.client_secret_hash.as_ref());
+        self.set_device_info_list(device_info_list, 0);
+        Ok(())
+    }
+    /// 创建虚拟网卡
+    fn create_device(
+        &self,
+        virtual_ip: Ipv4Addr,
+        virtual_netmask: Ipv4Addr,
+        virtual_gateway: Ipv4Addr,
+        virtual_network: Ipv4Addr,
+    ) -> anyhow::Result<()> {
+        let device_config = crate::handle::callback::DeviceConfig::new(
+            #[cfg(feature = "integrated_tun")]
+            #[cfg(target_os = "windows")]
+            self.config_info.tap,
+            #[cfg(feature = "integrated_tun")]
+            #[cfg(any(target_os = "windows", target_os = "linux", target_os = "macos"))]
+            self.config_info.device_name.clone(),
+            #[cfg(feature = "integrated_tun")]
+            #[cfg(target_os = "linux")]
+            self.config_info.tun_queues,
+            #[cfg(feature = "integrated_tun")]
+            #[cfg(target_os = "linux")]
+            self.config_info.tun_offload,
+            self.config_info.mtu,
+            virtual_ip,
+            virtual_netmask,
+            virtual_gateway,
+            virtual_network,
+            self.external_route.to_route(),
+        );
+        #[cfg(not(feature = "integrated_tun"))]
+        self.callback.create_device(device_config);
+        #[cfg(feature = "integrated_tun")]
+        {
+            self.tun_device_helper.stop();
+            #[cfg(any(target_os = "windows", target_os = "linux", target_os = "macos"))]
+            match crate::tun_tap_device::create_device(device_config, &self.callback) {
+                Ok(device) => {
+                    let tun_info = crate::handle::callback::DeviceInfo::new(
+                        device.name().unwrap_or("unknown".into()),
+                        "".into(),
+                    );
+                    log::info!("tun信息{:?}", tun_info);
+                    self.callback.create_tun(tun_info);
+                    #[cfg(any(target_os = "windows", target_os = "linux"))]
+                    if !self.config_info.exit_dns.is_empty() {
+                        if let Err(e) =
+                            crate::tun_tap_device::set_dns(&device, &self.config_info.exit_dns)
+                        {
+                            log::warn!("设置出口节点dns失败,dns请求可能泄露 {:?}", e);
+                        }
+                    }
+                    #[cfg(target_os = "linux")]
+                    if self.config_info.kernel_nat {
+                        if let Err(e) = self
+                            .tun_device_helper
+                            .masquerade()
+                            .enable(virtual_network, virtual_netmask)
+                        {
+                            log::warn!("开启内核nat失败 {:?}", e);
+                        }
+                    }
+                    self.tun_device_helper
+                        .start(device, self.config_info.allow_wire_guard)?;
+                }
+                Err(e) => {
+                    log::error!("{:?}", e);
+                    self.callback.error(e);
+                }
+            }
+            #[cfg(target_os = "android")]
+            {
+                let device_config = crate::handle::callback::DeviceConfig::new(
+                    self.config_info.mtu,
+                    virtual_ip,
+                    virtual_netmask,
+                    virtual_gateway,
+                    virtual_network,
+                    self.external_route.to_route(),
+                );
+                let device_fd = self.callback.generate_tun(device_config);
+                if device_fd == 0 {
+                    self.callback.error(ErrorInfo::new_msg(
+                        ErrorType::FailedToCrateDevice,
+                        "device_fd == 0".into(),
+                    ));
+                } else {
+                    let device = unsafe { tun_rs::SyncDevice::from_fd(device_fd as _) };
+                    if let Err(e) = self
+                        .tun_device_helper
+                        .start(Arc::new(device), self.config_info.allow_wire_guard)
+                    {
+                        self.callback.error(ErrorInfo::new_msg(
+                            ErrorType::FailedToCrateDevice,
+                            format!("{:?}", e),
+                        ));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
     fn set_device_info_list(&self, device_info_list: Vec<proto::message::DeviceInfo>, epoch: u16) {
         let ip_list: Vec<PeerDeviceInfo> = device_info_list
             .into_iter()
@@ -461,6 +582,39 @@ impl<Call: VntCallback, Device: DeviceWrite> ServerPacketHandler<Call, Device> {
                 .collect(),
         );
     }
+    /// 拉取设备列表，服务端支持时只拉取本地纪元之后的变化
+    fn pull_device_list(
+        &self,
+        context: &ChannelContext,
+        current_device: &CurrentDeviceInfo,
+        full: bool,
+    ) -> anyhow::Result<()> {
+        let epoch = self.device_map.lock().0;
+        let (protocol, bytes) = if !full && epoch != 0 && self.device_list_delta.load() {
+            let mut request = DeviceListDeltaRequest::new();
+            request.epoch = epoch as u32;
+            let bytes = request
+                .write_to_bytes()
+                .map_err(|e| anyhow!("DeviceListDeltaRequest {:?}", e))?;
+            (service_packet::Protocol::PullDeviceListDelta, bytes)
+        } else {
+            (service_packet::Protocol::PullDeviceList, Vec::new())
+        };
+        let mut poll_device =
+            NetPacket::new_encrypt(vec![0; 12 + bytes.len() + ENCRYPTION_RESERVED])?;
+        poll_device.set_source(current_device.virtual_ip);
+        poll_device.set_destination(current_device.virtual_gateway);
+        poll_device.set_default_version();
+        poll_device.set_gateway_flag(true);
+        poll_device.first_set_ttl(MAX_TTL);
+        poll_device.set_protocol(Protocol::Service);
+        poll_device.set_transport_protocol(protocol.into());
+        poll_device.set_payload(&bytes)?;
+        self.server_cipher.encrypt_ipv4(&mut poll_device)?;
+        //发送到默认服务端即可
+        context.send_default(&poll_device, current_device.connect_server)?;
+        Ok(())
+    }
     fn register(
         &self,
         current_device: &CurrentDeviceInfo,
@@ -515,6 +669,16 @@ impl<Call: VntCallback, Device: DeviceWrite> ServerPacketHandler<Call, Device> {
             }
             InErrorPacket::Disconnect => {
                 crate::handle::change_status(&self.current_device, ConnectStatus::Connecting);
+                // 服务端已经没有这个会话，票据也不能再用，旧的服务端路由也不再使用
+                self.handshake.clear_ticket();
+                let gateway = self.current_device.load().virtual_gateway;
+                if let Some(routes) = context.route_table.route(&gateway) {
+                    for route in routes {
+                        context.remove_route(&gateway, route.route_key());
+                    }
+                }
+                // 暂存的数据属于旧会话，重新注册后虚拟ip和对端都可能变化，不再补发
+                context.pending().clear();
                 let err = ErrorInfo::new(ErrorType::Disconnect);
                 self.callback.error(err);
                 //掉线epoch要归零
@@ -570,18 +734,7 @@ impl<Call: VntCallback, Device: DeviceWrite> ServerPacketHandler<Call, Device> {
                 let epoch = self.device_map.lock().0;
                 if pong_packet.epoch() != epoch {
                     //纪元不一致，可能有新客户端连接，向服务端拉取客户端列表
-                    let mut poll_device = NetPacket::new_encrypt([0; 12 + ENCRYPTION_RESERVED])?;
-                    poll_device.set_source(current_device.virtual_ip);
-                    poll_device.set_destination(current_device.virtual_gateway);
-                    poll_device.set_default_version();
-                    poll_device.set_gateway_flag(true);
-                    poll_device.first_set_ttl(MAX_TTL);
-                    poll_device.set_protocol(Protocol::Service);
-                    poll_device
-                        .set_transport_protocol(service_packet::Protocol::PullDeviceList.into());
-                    self.server_cipher.encrypt_ipv4(&mut poll_device)?;
-                    //发送到默认服务端即可
-                    context.send_default(&poll_device, current_device.connect_server)?;
+                    self.pull_device_list(context, current_device, false)?;
                 }
             }
             ControlPacket::AddrResponse(addr_packet) => {
@@ -597,3 +750,116 @@ impl<Call: VntCallback, Device: DeviceWrite> ServerPacketHandler<Call, Device> {
         Ok(())
     }
 }
+
+/// 在本地设备列表上应用增量，起始纪元和本地不一致时不修改并返回false
+fn apply_delta(dev: &mut (u16, HashMap<Ipv4Addr, PeerDeviceInfo>), delta: DeviceListDelta) -> bool {
+    if delta.from_epoch as u16 != dev.0 {
+        return false;
+    }
+    for ip in delta.removed {
+        dev.1.remove(&Ipv4Addr::from(ip));
+    }
+    for info in delta.changed {
+        let ip = Ipv4Addr::from(info.virtual_ip);
+        dev.1.insert(
+            ip,
+            PeerDeviceInfo::new(
+                ip,
+                info.name,
+                info.device_status as u8,
+                info.client_secret,
+                info.client_secret_hash,
+                info.wireguard,
+            ),
+        );
+    }
+    dev.0 = delta.epoch as u16;
+    true
+}
+
+/// 无服务器模式的网络地址和网关。
+/// 没有网关，用网络地址代替，不会和任何设备冲突
+fn static_network(virtual_ip: Ipv4Addr, virtual_netmask: Ipv4Addr) -> (Ipv4Addr, Ipv4Addr) {
+    let virtual_network = Ipv4Addr::from(u32::from(virtual_ip) & u32::from(virtual_netmask));
+    (virtual_network, virtual_network)
+}
+
+/// 静态对端作为设备列表，初始都是在线状态，由路由决定是否可达
+fn static_device_list(
+    static_peers: &[StaticPeer],
+    client_secret_hash: Option<&[u8; 16]>,
+) -> Vec<proto::message::DeviceInfo> {
+    let client_secret_hash = client_secret_hash.map_or(Vec::new(), |v| v.to_vec());
+    static_peers
+        .iter()
+        .map(|peer| {
+            let mut info = proto::message::DeviceInfo::new();
+            info.name = peer.virtual_ip.to_string();
+            info.virtual_ip = peer.virtual_ip.into();
+            info.client_secret = !client_secret_hash.is_empty();
+            info.client_secret_hash = client_secret_hash.clone();
+            info
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handle::PeerDeviceStatus;
+
+    #[test]
+    fn delta() {
+        let a = Ipv4Addr::new(10, 26, 0, 2);
+        let b = Ipv4Addr::new(10, 26, 0, 3);
+        let mut dev = (3u16, HashMap::new());
+        dev.1.insert(
+            a,
+            PeerDeviceInfo::new(a, "a".into(), 0, false, vec![], false),
+        );
+        let mut delta = DeviceListDelta::new();
+        delta.from_epoch = 3;
+        delta.epoch = 5;
+        delta.removed.push(a.into());
+        let mut info = proto::message::DeviceInfo::new();
+        info.virtual_ip = b.into();
+        info.name = "b".into();
+        info.device_status = 1;
+        delta.changed.push(info);
+        // 纪元不一致
+        let mut stale = delta.clone();
+        stale.from_epoch = 2;
+        assert!(!apply_delta(&mut dev, stale));
+        assert_eq!(dev.0, 3);
+        assert!(apply_delta(&mut dev, delta));
+        assert_eq!(dev.0, 5);
+        assert!(!dev.1.contains_key(&a));
+        assert_eq!(dev.1[&b].status, PeerDeviceStatus::Offline);
+    }
+
+    #[test]
+    fn register_static() {
+        let ip = Ipv4Addr::new(10, 26, 0, 2);
+        let netmask = Ipv4Addr::new(255, 255, 255, 0);
+        assert_eq!(
+            static_network(ip, netmask),
+            (Ipv4Addr::new(10, 26, 0, 0), Ipv4Addr::new(10, 26, 0, 0))
+        );
+        let peers = vec![
+            StaticPeer::new(Ipv4Addr::new(10, 26, 0, 3), vec![], None),
+            StaticPeer::new(Ipv4Addr::new(10, 26, 0, 4), vec![], Some([1; 32])),
+        ];
+        let list = static_device_list(&peers, None);
+        assert_eq!(list.len(), 2);
+        assert_eq!(
+            Ipv4Addr::from(list[1].virtual_ip),
+            Ipv4Addr::new(10, 26, 0, 4)
+        );
+        assert_eq!(list[1].name, "10.26.0.4");
+        assert_eq!(list[0].device_status, 0);
+        assert!(!list[0].client_secret);
+        let list = static_device_list(&peers, Some(&[2; 16]));
+        assert!(list[0].client_secret);
+        assert_eq!(list[0].client_secret_hash, vec![2; 16]);
+    }
+}
@@ -0,0 +1,246 @@
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+use sha2::Digest;
+
+use crate::channel::punch::NatType;
+
+const FILE_NAME: &str = "state-cache";
+// 超过这么久没有连上的对端地址不再尝试
+const PEER_EXPIRE: u64 = 7 * 24 * 3600 * 1000;
+// 每个对端最多保存的地址数
+const MAX_PEER_ROUTES: usize = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CachedRoute {
+    pub tcp: bool,
+    pub addr: SocketAddr,
+    // 最后一次可用的时间(毫秒)
+    pub time: u64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+struct State {
+    virtual_ip: Option<Ipv4Addr>,
+    nat_type: Option<NatType>,
+    public_ips: Vec<Ipv4Addr>,
+    peers: HashMap<Ipv4Addr, Vec<CachedRoute>>,
+}
+
+/// 上次运行的状态，启动时直接用上次的虚拟ip和对端地址打洞，不用等注册和nat探测完成
+#[derive(Clone, Default)]
+pub struct StateCache {
+    state: Arc<Mutex<State>>,
+    path: Option<PathBuf>,
+    // 区分不同的组网配置，配置变化后旧的状态不再使用
+    network: String,
+}
+
+impl StateCache {
+    pub fn load(dir: Option<PathBuf>, token: &str, device_id: &str, server: &str) -> Self {
+        let path = dir.map(|v| v.join(FILE_NAME));
+        let mut hasher = sha2::Sha256::new();
+        for v in [token, device_id, server] {
+            hasher.update(v.as_bytes());
+            hasher.update([0]);
+        }
+        let network: String = hasher.finalize()[..8]
+            .iter()
+            .map(|v| format!("{:02x}", v))
+            .collect();
+        let mut state = State::default();
+        if let Some(path) = &path {
+            if let Ok(text) = std::fs::read_to_string(path) {
+                if let Some(v) = parse(&text, &network) {
+                    state = v;
+                }
+            }
+        }
+        Self {
+            state: Arc::new(Mutex::new(state)),
+            path,
+            network,
+        }
+    }
+    pub fn save(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let text = to_text(&self.state.lock(), &self.network);
+        if let Err(e) = std::fs::write(path, text) {
+            log::warn!("保存运行状态失败 {:?} {:?}", path, e);
+        }
+    }
+    pub fn virtual_ip(&self) -> Option<Ipv4Addr> {
+        self.state.lock().virtual_ip
+    }
+    pub fn nat(&self) -> Option<(NatType, Vec<Ipv4Addr>)> {
+        let state = self.state.lock();
+        state
+            .nat_type
+            .map(|nat_type| (nat_type, state.public_ips.clone()))
+    }
+    /// 没有过期的对端地址，最近可用的排在前面
+    pub fn peers(&self, now: u64) -> Vec<(Ipv4Addr, CachedRoute)> {
+        let state = self.state.lock();
+        let mut list = Vec::new();
+        for (ip, routes) in &state.peers {
+            for route in routes {
+                if route.time + PEER_EXPIRE > now {
+                    list.push((*ip, *route));
+                }
+            }
+        }
+        list.sort_by(|a, b| b.1.time.cmp(&a.1.time));
+        list
+    }
+    /// 记录当前的状态，当前没有直连的对端保留之前的地址
+    pub fn update(
+        &self,
+        virtual_ip: Ipv4Addr,
+        nat_type: NatType,
+        public_ips: Vec<Ipv4Addr>,
+        routes: Vec<(Ipv4Addr, bool, SocketAddr)>,
+        now: u64,
+    ) {
+        let mut state = self.state.lock();
+        if !virtual_ip.is_unspecified() {
+            state.virtual_ip = Some(virtual_ip);
+        }
+        if !public_ips.is_empty() {
+            state.nat_type = Some(nat_type);
+            state.public_ips = public_ips;
+        }
+        for (ip, tcp, addr) in routes {
+            let list = state.peers.entry(ip).or_default();
+            if let Some(route) = list.iter_mut().find(|v| v.tcp == tcp && v.addr == addr) {
+                route.time = now;
+            } else {
+                list.push(CachedRoute {
+                    tcp,
+                    addr,
+                    time: now,
+                });
+            }
+        }
+        state.peers.retain(|_, list| {
+            list.retain(|v| v.time + PEER_EXPIRE > now);
+            list.sort_by(|a, b| b.time.cmp(&a.time));
+            list.truncate(MAX_PEER_ROUTES);
+            !list.is_empty()
+        });
+    }
+}
+
+fn to_text(state: &State, network: &str) -> String {
+    let mut text = format!("network {}\n", network);
+    if let Some(ip) = state.virtual_ip {
+        text.push_str(&format!("ip {}\n", ip));
+    }
+    if let Some(nat_type) = state.nat_type {
+        let ips: Vec<String> = state.public_ips.iter().map(|v| v.to_string()).collect();
+        let nat_type = if nat_type.is_cone() {
+            "cone"
+        } else {
+            "symmetric"
+        };
+        text.push_str(&format!("nat {} {}\n", nat_type, ips.join(",")));
+    }
+    for (ip, routes) in &state.peers {
+        for route in routes {
+            let protocol = if route.tcp { "tcp" } else { "udp" };
+            text.push_str(&format!(
+                "peer {} {} {} {}\n",
+                ip, protocol, route.addr, route.time
+            ));
+        }
+    }
+    text
+}
+
+/// 组网配置不一致时返回None，无法识别的行忽略
+fn parse(text: &str, network: &str) -> Option<State> {
+    let mut lines = text.lines();
+    if lines.next()? != format!("network {}", network) {
+        return None;
+    }
+    let mut state = State::default();
+    for line in lines {
+        let mut split = line.split_whitespace();
+        match split.next() {
+            Some("ip") => {
+                state.virtual_ip = split.next().and_then(|v| v.parse().ok());
+            }
+            Some("nat") => {
+                state.nat_type = match split.next() {
+                    Some("cone") => Some(NatType::Cone),
+                    Some("symmetric") => Some(NatType::Symmetric),
+                    _ => continue,
+                };
+                state.public_ips = split
+                    .next()
+                    .unwrap_or("")
+                    .split(',')
+                    .filter_map(|v| v.parse().ok())
+                    .collect();
+            }
+            Some("peer") => {
+                let mut f = || -> Option<(Ipv4Addr, CachedRoute)> {
+                    let ip = split.next()?.parse().ok()?;
+                    let tcp = match split.next()? {
+                        "tcp" => true,
+                        "udp" => false,
+                        _ => return None,
+                    };
+                    let addr = split.next()?.parse().ok()?;
+                    let time = split.next()?.parse().ok()?;
+                    Some((ip, CachedRoute { tcp, addr, time }))
+                };
+                if let Some((ip, route)) = f() {
+                    state.peers.entry(ip).or_default().push(route);
+                }
+            }
+            _ => {}
+        }
+    }
+    Some(state)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn text() {
+        let cache = StateCache::default();
+        let peer = Ipv4Addr::new(10, 26, 0, 3);
+        let addr: SocketAddr = "1.2.3.4:5000".parse().unwrap();
+        cache.update(
+            Ipv4Addr::new(10, 26, 0, 2),
+            NatType::Cone,
+            vec![Ipv4Addr::new(1, 1, 1, 1)],
+            vec![(peer, false, addr)],
+            1000,
+        );
+        let state = cache.state.lock().clone();
+        let text = to_text(&state, "abc");
+        assert_eq!(parse(&text, "abc"), Some(state));
+        // 配置变化
+        assert_eq!(parse(&text, "abd"), None);
+        assert_eq!(
+            cache.peers(2000),
+            vec![(
+                peer,
+                CachedRoute {
+                    tcp: false,
+                    addr,
+                    time: 1000
+                }
+            )]
+        );
+        assert!(cache.peers(1000 + PEER_EXPIRE).is_empty());
+    }
+}
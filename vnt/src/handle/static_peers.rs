@@ -0,0 +1,128 @@
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::Sha256;
+
+use crate::cipher::{verify, DeviceIdentity, PUBLIC_KEY_LEN, SIGNATURE_LEN};
+use crate::core::StaticPeer;
+
+pub const CHALLENGE_LEN: usize = 16;
+// 挑战值的有效期，收到响应时也接受上一个周期的挑战值
+const CHALLENGE_PERIOD: u64 = 30 * 1000;
+const DOMAIN: &[u8] = b"vnt-static-peer";
+
+/// 无服务器模式的静态对端。
+/// 配置了公钥的对端需要认证：打洞请求带上挑战值，对端用设备私钥对挑战值签名后回应，
+/// 签名验证通过才建立路由，只知道组网密码的设备无法冒充该对端
+#[derive(Clone)]
+pub struct StaticPeers {
+    peers: Arc<Vec<StaticPeer>>,
+    keys: Arc<HashMap<Ipv4Addr, [u8; PUBLIC_KEY_LEN]>>,
+    identity: DeviceIdentity,
+    // 每次启动随机生成，挑战值由它派生，不需要记录发出过的挑战值
+    challenge_key: [u8; 32],
+}
+
+impl StaticPeers {
+    pub fn new(peers: Vec<StaticPeer>, identity: DeviceIdentity) -> Self {
+        let keys = peers
+            .iter()
+            .filter_map(|peer| peer.public_key.map(|key| (peer.virtual_ip, key)))
+            .collect();
+        let mut challenge_key = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut challenge_key);
+        Self {
+            peers: Arc::new(peers),
+            keys: Arc::new(keys),
+            identity,
+            challenge_key,
+        }
+    }
+    pub fn peers(&self) -> &[StaticPeer] {
+        &self.peers
+    }
+    /// 对端配置了公钥，路由只能由签名验证通过的打洞响应建立
+    pub fn requires_auth(&self, ip: &Ipv4Addr) -> bool {
+        self.keys.contains_key(ip)
+    }
+    /// 发给对端的挑战值
+    pub fn challenge(&self, peer_ip: Ipv4Addr, now: u64) -> [u8; CHALLENGE_LEN] {
+        self.challenge0(peer_ip, now / CHALLENGE_PERIOD)
+    }
+    fn challenge0(&self, peer_ip: Ipv4Addr, period: u64) -> [u8; CHALLENGE_LEN] {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.challenge_key).unwrap();
+        mac.update(&peer_ip.octets());
+        mac.update(&period.to_be_bytes());
+        mac.finalize().into_bytes()[..CHALLENGE_LEN]
+            .try_into()
+            .unwrap()
+    }
+    /// 回应对端的挑战值
+    pub fn sign(
+        &self,
+        self_ip: Ipv4Addr,
+        peer_ip: Ipv4Addr,
+        challenge: &[u8],
+    ) -> [u8; SIGNATURE_LEN] {
+        self.identity.sign(&sign_data(challenge, self_ip, peer_ip))
+    }
+    /// 验证对端对本机挑战值的签名，对端没有配置公钥时总是通过
+    pub fn verify(&self, self_ip: Ipv4Addr, peer_ip: Ipv4Addr, signature: &[u8], now: u64) -> bool {
+        let Some(public_key) = self.keys.get(&peer_ip) else {
+            return true;
+        };
+        let period = now / CHALLENGE_PERIOD;
+        [period, period.saturating_sub(1)].iter().any(|period| {
+            let challenge = self.challenge0(peer_ip, *period);
+            verify(
+                public_key,
+                &sign_data(&challenge, peer_ip, self_ip),
+                signature,
+            )
+        })
+    }
+}
+
+/// 签名内容包含双方的虚拟ip，签名不能用于其他设备
+fn sign_data(challenge: &[u8], signer: Ipv4Addr, requester: Ipv4Addr) -> Vec<u8> {
+    let mut data = Vec::with_capacity(DOMAIN.len() + challenge.len() + 8);
+    data.extend_from_slice(DOMAIN);
+    data.extend_from_slice(challenge);
+    data.extend_from_slice(&signer.octets());
+    data.extend_from_slice(&requester.octets());
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn challenge_response() {
+        let a_ip = Ipv4Addr::new(10, 26, 0, 2);
+        let b_ip = Ipv4Addr::new(10, 26, 0, 3);
+        let a_identity = DeviceIdentity::load(None).unwrap();
+        let b_identity = DeviceIdentity::load(None).unwrap();
+        let a = StaticPeers::new(
+            vec![StaticPeer::new(b_ip, vec![], Some(b_identity.public_key()))],
+            a_identity.clone(),
+        );
+        let b = StaticPeers::new(vec![StaticPeer::new(a_ip, vec![], None)], b_identity);
+        let now = 1_000_000;
+        let challenge = a.challenge(b_ip, now);
+        let signature = b.sign(b_ip, a_ip, &challenge);
+        assert!(a.verify(a_ip, b_ip, &signature, now));
+        assert!(a.verify(a_ip, b_ip, &signature, now + CHALLENGE_PERIOD));
+        // 过期的挑战值
+        assert!(!a.verify(a_ip, b_ip, &signature, now + 2 * CHALLENGE_PERIOD));
+        // 其他设备的签名
+        let forged = StaticPeers::new(vec![], a_identity).sign(b_ip, a_ip, &challenge);
+        assert!(!a.verify(a_ip, b_ip, &forged, now));
+        // 没有配置公钥
+        assert!(!b.requires_auth(&a_ip));
+        assert!(b.verify(b_ip, a_ip, &[], now));
+    }
+}
@@ -19,6 +19,8 @@ pub(crate) fn start_simple(
     stop_manager: StopManager,
     context: &ChannelContext,
     device: Arc<SyncDevice>,
+    queue: usize,
+    #[cfg(target_os = "linux")] offload: bool,
     current_device: Arc<AtomicCell<CurrentDeviceInfo>>,
     ip_route: ExternalRoute,
     #[cfg(feature = "ip_proxy")] ip_proxy_map: Option<IpProxyMap>,
@@ -32,7 +34,13 @@ pub(crate) fn start_simple(
     let event = Arc::new(InterruptEvent::new()?);
     let worker = {
         let event = event.clone();
-        stop_manager.add_listener("tun_device".into(), move || {
+        // 多队列时每个读线程都要注册，名称不能重复
+        let name = if queue == 0 {
+            "tun_device".to_string()
+        } else {
+            format!("tun_device_{}", queue)
+        };
+        stop_manager.add_listener(name, move || {
             if let Err(e) = event.trigger() {
                 log::warn!("{:?}", e);
             }
@@ -48,7 +56,15 @@ pub(crate) fn start_simple(
             }
         });
     }
-    if let Err(e) = start_simple0(
+    #[cfg(target_os = "linux")]
+    let start_fn = if offload {
+        start_offload0
+    } else {
+        start_simple0
+    };
+    #[cfg(not(target_os = "linux"))]
+    let start_fn = start_simple0;
+    if let Err(e) = start_fn(
         context,
         device,
         event,
@@ -86,16 +102,17 @@ fn start_simple0(
 ) -> anyhow::Result<()> {
     let mut buf = [0; BUFFER_SIZE];
     let mut extend = [0; BUFFER_SIZE];
-    loop {
-        let len = device.recv_intr(&mut buf[12..],&event)? + 12;
+    let mut handle = |buf: &mut [u8], len: usize| {
         // buf是重复利用的，需要重置头部
         buf[..12].fill(0);
         match crate::handle::tun_tap::tun_handler::handle(
             context,
-            &mut buf,
+            buf,
             len,
             &mut extend,
             &device,
+            #[cfg(target_os = "linux")]
+            false,
             current_device.load(),
             &ip_route,
             #[cfg(feature = "ip_proxy")]
@@ -111,5 +128,106 @@ fn start_simple0(
                 log::warn!("tun/tap {:?}", e)
             }
         }
+    };
+    loop {
+        let len = device.recv_intr(&mut buf[12..], &event)? + 12;
+        #[cfg(target_os = "linux")]
+        context.send_batch_begin();
+        handle(&mut buf, len);
+        // 把已经到达的数据一起读出来，通过sendmmsg批量发送
+        #[cfg(target_os = "linux")]
+        {
+            for _ in 1..crate::channel::udp_batch::BATCH_SIZE {
+                if !readable(&device) {
+                    break;
+                }
+                match device.recv(&mut buf[12..]) {
+                    Ok(len) => handle(&mut buf, len + 12),
+                    Err(e) => {
+                        if e.kind() != std::io::ErrorKind::WouldBlock {
+                            log::warn!("tun/tap {:?}", e)
+                        }
+                        break;
+                    }
+                }
+            }
+            context.send_batch_end();
+        }
+    }
+}
+
+/// tun是否还有数据可读，不阻塞
+#[cfg(target_os = "linux")]
+fn readable(device: &SyncDevice) -> bool {
+    use std::os::fd::AsRawFd;
+    let mut pfd = libc::pollfd {
+        fd: device.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    unsafe { libc::poll(&mut pfd, 1, 0) > 0 && pfd.revents & libc::POLLIN != 0 }
+}
+
+/// 开启IFF_VNET_HDR时，一次读取的可能是TSO/GSO大包，由recv_multiple按gso_size分段后逐个处理
+#[cfg(target_os = "linux")]
+fn start_offload0(
+    context: &ChannelContext,
+    device: Arc<SyncDevice>,
+    event: Arc<InterruptEvent>,
+    current_device: Arc<AtomicCell<CurrentDeviceInfo>>,
+    ip_route: ExternalRoute,
+    #[cfg(feature = "ip_proxy")] ip_proxy_map: Option<IpProxyMap>,
+    client_cipher: Cipher,
+    server_cipher: Cipher,
+    device_map: Arc<Mutex<(u16, HashMap<Ipv4Addr, PeerDeviceInfo>)>>,
+    compressor: Compressor,
+    allow_wire_guard: bool,
+) -> anyhow::Result<()> {
+    use tun_rs::{IDEAL_BATCH_SIZE, VIRTIO_NET_HDR_LEN};
+    let mtu = device.mtu()? as usize;
+    let mut original_buffer = vec![0; VIRTIO_NET_HDR_LEN + 65535];
+    // 每个分段都要满足 |12字节开头|ip报文|至少1024字节结尾| 的结构
+    let mut bufs = vec![vec![0u8; 12 + mtu + 1024]; IDEAL_BATCH_SIZE];
+    let mut sizes = vec![0; IDEAL_BATCH_SIZE];
+    let mut extend = [0; BUFFER_SIZE];
+    loop {
+        device.wait_readable_intr(&event)?;
+        let num = match device.recv_multiple(&mut original_buffer, &mut bufs, &mut sizes, 12) {
+            Ok(num) => num,
+            Err(e) => {
+                if e.kind() == std::io::ErrorKind::WouldBlock {
+                    continue;
+                }
+                crate::ignore_io_interrupted(e)?;
+                continue;
+            }
+        };
+        // 这一批分段发往udp的数据通过sendmmsg/GSO合并发送
+        context.send_batch_begin();
+        for index in 0..num {
+            let buf = &mut bufs[index];
+            let len = sizes[index] + 12;
+            buf[..12].fill(0);
+            if let Err(e) = crate::handle::tun_tap::tun_handler::handle(
+                context,
+                buf,
+                len,
+                &mut extend,
+                &device,
+                true,
+                current_device.load(),
+                &ip_route,
+                #[cfg(feature = "ip_proxy")]
+                &ip_proxy_map,
+                &client_cipher,
+                &server_cipher,
+                &device_map,
+                &compressor,
+                allow_wire_guard,
+            ) {
+                log::warn!("tun/tap {:?}", e)
+            }
+        }
+        context.send_batch_end();
     }
 }
@@ -14,7 +14,7 @@ use crate::channel::context::ChannelContext;
 use crate::channel::sender::{send_to_wg, send_to_wg_broadcast};
 use crate::cipher::Cipher;
 use crate::compression::Compressor;
-use crate::external_route::ExternalRoute;
+use crate::external_route::{flow_hash, ExternalRoute};
 use crate::handle::tun_tap::DeviceStop;
 use crate::handle::{CurrentDeviceInfo, PeerDeviceInfo};
 #[cfg(feature = "ip_proxy")]
@@ -26,7 +26,11 @@ use crate::protocol::body::ENCRYPTION_RESERVED;
 use crate::protocol::ip_turn_packet::BroadcastPacket;
 use crate::protocol::{ip_turn_packet, NetPacket, MAX_TTL};
 use crate::util::StopManager;
-fn icmp(device_writer: &SyncDevice, mut ipv4_packet: IpV4Packet<&mut [u8]>) -> anyhow::Result<()> {
+fn icmp(
+    device_writer: &SyncDevice,
+    #[cfg(target_os = "linux")] offload: bool,
+    mut ipv4_packet: IpV4Packet<&mut [u8]>,
+) -> anyhow::Result<()> {
     if ipv4_packet.protocol() == Protocol::Icmp {
         let mut icmp = IcmpPacket::new(ipv4_packet.payload_mut())?;
         if icmp.kind() == Kind::EchoRequest {
@@ -36,6 +40,14 @@ fn icmp(device_writer: &SyncDevice, mut ipv4_packet: IpV4Packet<&mut [u8]>) -> a
             ipv4_packet.set_source_ip(ipv4_packet.destination_ip());
             ipv4_packet.set_destination_ip(src);
             ipv4_packet.update_checksum();
+            #[cfg(target_os = "linux")]
+            if offload {
+                crate::tun_tap_device::tun_create_helper::send_with_vnet_hdr(
+                    device_writer,
+                    ipv4_packet.buffer,
+                )?;
+                return Ok(());
+            }
             device_writer.send(ipv4_packet.buffer)?;
         }
     }
@@ -46,6 +58,8 @@ pub fn start(
     stop_manager: StopManager,
     context: ChannelContext,
     device: Arc<SyncDevice>,
+    queue: usize,
+    #[cfg(target_os = "linux")] offload: bool,
     current_device: Arc<AtomicCell<CurrentDeviceInfo>>,
     ip_route: ExternalRoute,
     #[cfg(feature = "ip_proxy")] ip_proxy_map: Option<IpProxyMap>,
@@ -56,27 +70,33 @@ pub fn start(
     device_stop: DeviceStop,
     allow_wire_guard: bool,
 ) -> io::Result<()> {
-    thread::Builder::new()
-        .name("tunHandlerS".into())
-        .spawn(move || {
-            if let Err(e) = crate::handle::tun_tap::start_simple(
-                stop_manager,
-                &context,
-                device,
-                current_device,
-                ip_route,
-                #[cfg(feature = "ip_proxy")]
-                ip_proxy_map,
-                client_cipher,
-                server_cipher,
-                device_map,
-                compressor,
-                device_stop,
-                allow_wire_guard,
-            ) {
-                log::warn!("stop:{}", e);
-            }
-        })?;
+    let name = if queue == 0 {
+        "tunHandlerS".to_string()
+    } else {
+        format!("tunHandlerS{}", queue)
+    };
+    thread::Builder::new().name(name).spawn(move || {
+        if let Err(e) = crate::handle::tun_tap::start_simple(
+            stop_manager,
+            &context,
+            device,
+            queue,
+            #[cfg(target_os = "linux")]
+            offload,
+            current_device,
+            ip_route,
+            #[cfg(feature = "ip_proxy")]
+            ip_proxy_map,
+            client_cipher,
+            server_cipher,
+            device_map,
+            compressor,
+            device_stop,
+            allow_wire_guard,
+        ) {
+            log::warn!("stop:{}", e);
+        }
+    })?;
 
     Ok(())
 }
@@ -102,13 +122,31 @@ fn broadcast(
     let mut p2p_ips = Vec::with_capacity(8);
     let mut relay = false;
     let mut overflow = false;
+    // tcp通道共享同一份数据，不用每个目标拷贝一次
+    let mut shared = None;
     for (index, peer_ip) in list.into_iter().enumerate() {
         if index > MAX_COUNT {
             overflow = true;
             break;
         }
         if let Some(route) = sender.route_table.route_one_p2p(&peer_ip) {
-            if sender.send_by_key(&net_packet, route.route_key()).is_ok() {
+            let rs = if route.protocol.is_base_tcp() {
+                let buf = shared
+                    .get_or_insert_with(|| {
+                        sender.buffer_pool().copy_from(net_packet.buffer()).freeze()
+                    })
+                    .clone();
+                sender.send_tcp_shared(buf, &route.route_key())
+            } else {
+                sender.send_by_key(&net_packet, route.route_key())
+            };
+            if rs.is_ok() {
+                if route.protocol.is_base_tcp() {
+                    if let Some(up_traffic_meter) = &sender.up_traffic_meter {
+                        up_traffic_meter
+                            .add_traffic(net_packet.destination(), net_packet.data_len());
+                    }
+                }
                 p2p_ips.push(peer_ip);
                 continue;
             }
@@ -129,7 +167,9 @@ fn broadcast(
         return Ok(());
     }
 
-    let buf = vec![0u8; 12 + 1 + p2p_ips.len() * 4 + net_packet.data_len() + ENCRYPTION_RESERVED];
+    let buf = sender
+        .buffer_pool()
+        .get(12 + 1 + p2p_ips.len() * 4 + net_packet.data_len() + ENCRYPTION_RESERVED);
     //剩余的发送到服务端，需要告知哪些已发送过
     let mut server_packet = NetPacket::new_encrypt(buf)?;
     server_packet.set_default_version();
@@ -159,6 +199,7 @@ pub(crate) fn handle(
     data_len: usize, //数据总长度=12+ip包长度
     extend: &mut [u8],
     device_writer: &SyncDevice,
+    #[cfg(target_os = "linux")] offload: bool,
     current_device: CurrentDeviceInfo,
     ip_route: &ExternalRoute,
     #[cfg(feature = "ip_proxy")] proxy_map: &Option<IpProxyMap>,
@@ -176,7 +217,12 @@ pub(crate) fn handle(
     let src_ip = ipv4_packet.source_ip();
     let dest_ip = ipv4_packet.destination_ip();
     if src_ip == dest_ip {
-        return icmp(&device_writer, ipv4_packet);
+        return icmp(
+            &device_writer,
+            #[cfg(target_os = "linux")]
+            offload,
+            ipv4_packet,
+        );
     }
     let protocol = ipv4_packet.protocol();
     let src_ip = ipv4_packet.source_ip();
@@ -201,7 +247,7 @@ pub(crate) fn handle(
     if !dest_ip.is_multicast() && !dest_ip.is_broadcast() && current_device.broadcast_ip != dest_ip
     {
         if current_device.not_in_network(dest_ip) {
-            if let Some(r_dest_ip) = ip_route.route(&dest_ip) {
+            if let Some(r_dest_ip) = ip_route.route(&dest_ip, flow_hash(net_packet.payload())) {
                 //路由的目标不能是自己
                 if r_dest_ip == src_ip {
                     return Ok(());
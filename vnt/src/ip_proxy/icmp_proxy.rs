@@ -19,6 +19,7 @@ use crate::handle::CurrentDeviceInfo;
 use crate::ip_proxy::ProxyHandler;
 use crate::protocol;
 use crate::protocol::{NetPacket, MAX_TTL};
+use crate::util::VntRuntime;
 #[derive(Clone)]
 pub struct IcmpProxy {
     icmp_socket: Arc<std::net::UdpSocket>,
@@ -28,6 +29,7 @@ pub struct IcmpProxy {
 
 impl IcmpProxy {
     pub async fn new(
+        runtime: &VntRuntime,
         context: ChannelContext,
         current_device: Arc<AtomicCell<CurrentDeviceInfo>>,
         client_cipher: Cipher,
@@ -62,7 +64,7 @@ impl IcmpProxy {
             Arc::new(Mutex::new(HashMap::with_capacity(16)));
         {
             let nat_map = nat_map.clone();
-            tokio::spawn(async {
+            runtime.spawn(async {
                 if let Err(e) = icmp_proxy(
                     tokio_icmp_socket,
                     nat_map,
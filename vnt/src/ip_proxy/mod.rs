@@ -1,6 +1,6 @@
+use std::io;
 use std::net::Ipv4Addr;
 use std::sync::Arc;
-use std::{io, thread};
 
 use crossbeam_utils::atomic::AtomicCell;
 
@@ -14,7 +14,7 @@ use crate::handle::CurrentDeviceInfo;
 use crate::ip_proxy::icmp_proxy::IcmpProxy;
 use crate::ip_proxy::tcp_proxy::TcpProxy;
 use crate::ip_proxy::udp_proxy::UdpProxy;
-use crate::util::StopManager;
+use crate::util::VntRuntime;
 
 #[cfg(any(target_os = "windows", target_os = "linux", target_os = "macos"))]
 pub mod icmp_proxy;
@@ -41,43 +41,34 @@ pub struct IpProxyMap {
 
 pub fn init_proxy(
     context: ChannelContext,
-    stop_manager: StopManager,
+    runtime: &VntRuntime,
     current_device: Arc<AtomicCell<CurrentDeviceInfo>>,
     client_cipher: Cipher,
 ) -> anyhow::Result<IpProxyMap> {
-    let runtime = tokio::runtime::Builder::new_multi_thread()
-        .enable_all()
-        .thread_name("ipProxy")
-        .build()?;
-    let proxy_map = runtime.block_on(init_proxy0(context, current_device, client_cipher))?;
-    let (sender, receiver) = tokio::sync::oneshot::channel::<()>();
-    let worker = stop_manager.add_listener("ipProxy".into(), move || {
-        let _ = sender.send(());
-    })?;
-    thread::Builder::new()
-        .name("ipProxy".into())
-        .spawn(move || {
-            runtime.block_on(async {
-                let _ = receiver.await;
-            });
-            runtime.shutdown_background();
-            drop(worker);
-        })?;
-
+    // 代理的监听任务运行在共用的运行时上，vnt停止时随运行时一起退出
+    let proxy_map =
+        runtime.block_on(init_proxy0(runtime, context, current_device, client_cipher))??;
     return Ok(proxy_map);
 }
 
 async fn init_proxy0(
+    runtime: &VntRuntime,
     context: ChannelContext,
     _current_device: Arc<AtomicCell<CurrentDeviceInfo>>,
     _client_cipher: Cipher,
 ) -> anyhow::Result<IpProxyMap> {
     let default_interface = context.default_interface().clone();
     #[cfg(any(target_os = "windows", target_os = "linux", target_os = "macos"))]
-    let icmp_proxy =
-        IcmpProxy::new(context, _current_device, _client_cipher, &default_interface).await?;
-    let tcp_proxy = TcpProxy::new(default_interface.clone()).await?;
-    let udp_proxy = UdpProxy::new(default_interface.clone()).await?;
+    let icmp_proxy = IcmpProxy::new(
+        runtime,
+        context,
+        _current_device,
+        _client_cipher,
+        &default_interface,
+    )
+    .await?;
+    let tcp_proxy = TcpProxy::new(runtime, default_interface.clone()).await?;
+    let udp_proxy = UdpProxy::new(runtime, default_interface.clone()).await?;
 
     Ok(IpProxyMap {
         #[cfg(any(target_os = "windows", target_os = "linux", target_os = "macos"))]
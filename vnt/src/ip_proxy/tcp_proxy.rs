@@ -9,6 +9,7 @@ use tokio::net::{TcpListener, TcpStream};
 
 use crate::channel::socket::{create_tcp, LocalInterface};
 use crate::ip_proxy::ProxyHandler;
+use crate::util::VntRuntime;
 use packet::ip::ipv4::packet::IpV4Packet;
 use packet::tcp::tcp::TcpPacket;
 
@@ -19,7 +20,10 @@ pub struct TcpProxy {
 }
 
 impl TcpProxy {
-    pub async fn new(default_interface: LocalInterface) -> anyhow::Result<Self> {
+    pub async fn new(
+        runtime: &VntRuntime,
+        default_interface: LocalInterface,
+    ) -> anyhow::Result<Self> {
         let nat_map: Arc<Mutex<HashMap<SocketAddrV4, SocketAddrV4>>> =
             Arc::new(Mutex::new(HashMap::with_capacity(16)));
         let tcp_listener = TcpListener::bind(format!("0.0.0.0:{}", 0))
@@ -28,7 +32,12 @@ impl TcpProxy {
         let port = tcp_listener.local_addr()?.port();
         {
             let nat_map = nat_map.clone();
-            tokio::spawn(tcp_proxy(tcp_listener, nat_map, default_interface));
+            runtime.spawn(tcp_proxy(
+                tcp_listener,
+                nat_map,
+                default_interface,
+                runtime.clone(),
+            ));
         }
         Ok(Self { port, nat_map })
     }
@@ -80,6 +89,7 @@ async fn tcp_proxy(
     tcp_listener: TcpListener,
     nat_map: Arc<Mutex<HashMap<SocketAddrV4, SocketAddrV4>>>,
     default_interface: LocalInterface,
+    runtime: VntRuntime,
 ) {
     loop {
         match tcp_listener.accept().await {
@@ -87,7 +97,8 @@ async fn tcp_proxy(
                 SocketAddr::V4(sender_addr) => {
                     if let Some(dest_addr) = nat_map.lock().get(&sender_addr).cloned() {
                         let default_interface = default_interface.clone();
-                        tokio::spawn(async move {
+                        let task_runtime = runtime.clone();
+                        runtime.spawn(async move {
                             let peer_tcp_stream = match tcp_connect(
                                 sender_addr.port(),
                                 dest_addr.into(),
@@ -106,7 +117,14 @@ async fn tcp_proxy(
                                     return;
                                 }
                             };
-                            proxy(sender_addr, dest_addr, tcp_stream, peer_tcp_stream).await
+                            proxy(
+                                sender_addr,
+                                dest_addr,
+                                tcp_stream,
+                                peer_tcp_stream,
+                                &task_runtime,
+                            )
+                            .await
                         });
                     } else {
                         log::warn!("tcp代理异常: 来源:{},未找到目标", sender_addr);
@@ -146,10 +164,11 @@ async fn proxy(
     dest_addr: SocketAddrV4,
     client: TcpStream,
     server: TcpStream,
+    runtime: &VntRuntime,
 ) {
     let (mut client_read, mut client_write) = client.into_split();
     let (mut server_read, mut server_write) = server.into_split();
-    tokio::spawn(async move {
+    runtime.spawn(async move {
         if let Err(e) = tokio::io::copy(&mut client_read, &mut server_write).await {
             log::warn!("client tcp proxy {}->{},{:?}", sender_addr, dest_addr, e);
         }
@@ -10,6 +10,7 @@ use tokio::net::UdpSocket;
 
 use crate::channel::socket::{bind_udp, LocalInterface};
 use crate::ip_proxy::ProxyHandler;
+use crate::util::VntRuntime;
 use packet::ip::ipv4::packet::IpV4Packet;
 use packet::udp::udp::UdpPacket;
 
@@ -20,7 +21,10 @@ pub struct UdpProxy {
 }
 
 impl UdpProxy {
-    pub async fn new(default_interface: LocalInterface) -> anyhow::Result<Self> {
+    pub async fn new(
+        runtime: &VntRuntime,
+        default_interface: LocalInterface,
+    ) -> anyhow::Result<Self> {
         let nat_map: Arc<Mutex<HashMap<SocketAddrV4, SocketAddrV4>>> =
             Arc::new(Mutex::new(HashMap::with_capacity(16)));
         let udp = UdpSocket::bind(format!("0.0.0.0:{}", 0))
@@ -29,8 +33,9 @@ impl UdpProxy {
         let port = udp.local_addr()?.port();
         {
             let nat_map = nat_map.clone();
-            tokio::spawn(async move {
-                if let Err(e) = udp_proxy(udp, nat_map, default_interface).await {
+            let task_runtime = runtime.clone();
+            runtime.spawn(async move {
+                if let Err(e) = udp_proxy(udp, nat_map, default_interface, task_runtime).await {
                     log::warn!("udp_proxy:{:?}", e);
                 }
             });
@@ -85,6 +90,7 @@ async fn udp_proxy(
     udp: UdpSocket,
     nat_map: Arc<Mutex<HashMap<SocketAddrV4, SocketAddrV4>>>,
     default_interface: LocalInterface,
+    runtime: VntRuntime,
 ) -> anyhow::Result<()> {
     let mut buf = [0u8; 65536];
 
@@ -102,6 +108,7 @@ async fn udp_proxy(
                         &nat_map,
                         &udp_socket,
                         &default_interface,
+                        &runtime,
                     )
                     .await
                     {
@@ -124,6 +131,7 @@ async fn udp_proxy0(
     map: &Arc<Mutex<HashMap<SocketAddrV4, SocketAddrV4>>>,
     udp_socket: &Arc<UdpSocket>,
     default_interface: &LocalInterface,
+    runtime: &VntRuntime,
 ) -> anyhow::Result<()> {
     let option = inner_map.lock().get(&sender_addr).cloned();
     if let Some((udp, time)) = option {
@@ -151,7 +159,7 @@ async fn udp_proxy0(
                 .insert(sender_addr, (peer_udp_socket.clone(), time.clone()));
             let udp_socket = udp_socket.clone();
             let map = map.clone();
-            tokio::spawn(async move {
+            runtime.spawn(async move {
                 let mut buf = [0u8; 65536];
                 loop {
                     match tokio::time::timeout(
@@ -7,7 +7,10 @@ mod external_route;
 pub mod handle;
 #[cfg(feature = "ip_proxy")]
 mod ip_proxy;
+#[cfg(target_os = "linux")]
+mod masquerade;
 pub mod nat;
+mod overlay_dns;
 #[cfg(feature = "port_mapping")]
 mod port_mapping;
 mod proto;
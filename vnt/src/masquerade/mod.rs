@@ -0,0 +1,372 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use crossbeam_utils::atomic::AtomicCell;
+use parking_lot::Mutex;
+use tokio::net::UdpSocket;
+
+use packet::ip::ipv4::packet::IpV4Packet;
+
+use crate::channel::context::ChannelContext;
+use crate::channel::socket::{LocalInterface, VntSocketTrait};
+use crate::cipher::Cipher;
+use crate::handle::CurrentDeviceInfo;
+use crate::masquerade::nat_table::{
+    dnat, dnat_icmp_error, inbound_key, outbound_flow, snat, tcp_closing, tcp_syn, time_exceeded,
+    NatTable, PORT_END, PORT_START, TCP, UDP,
+};
+use crate::protocol;
+use crate::protocol::body::ENCRYPTION_RESERVED;
+use crate::protocol::{NetPacket, HEAD_LEN, MAX_TTL};
+use crate::util::VntRuntime;
+
+mod nat_table;
+
+/// 用户态nat，对端访问out_ips网段的tcp、udp和icmp包做源地址转换后直接从原始套接字发出，
+/// 不需要开启内核转发和iptables。
+/// tcp端口用监听套接字占用，套接字上的过滤器丢弃所有包，内核不会对远端的回复回rst
+#[derive(Clone)]
+pub struct Masquerade {
+    send_socket: Arc<socket2::Socket>,
+    default_interface: LocalInterface,
+    nat_table: Arc<Mutex<NatTable>>,
+    context: ChannelContext,
+    current_device: Arc<AtomicCell<CurrentDeviceInfo>>,
+    client_cipher: Cipher,
+}
+
+impl Masquerade {
+    pub fn new(
+        runtime: &VntRuntime,
+        context: ChannelContext,
+        current_device: Arc<AtomicCell<CurrentDeviceInfo>>,
+        client_cipher: Cipher,
+    ) -> anyhow::Result<Self> {
+        let default_interface = context.default_interface().clone();
+        // IPPROTO_RAW默认带IP_HDRINCL，发送完整的ip包
+        let send_socket = socket2::Socket::new(
+            socket2::Domain::IPV4,
+            socket2::Type::RAW,
+            Some(socket2::Protocol::from(libc::IPPROTO_RAW)),
+        )
+        .context("new Socket RAW IPPROTO_RAW failed")?;
+        send_socket.set_nonblocking(true)?;
+        if let Err(e) = send_socket.set_ip_unicast_if(&default_interface) {
+            log::warn!("set_ip_unicast_if {:?}", e)
+        }
+        let tcp_socket = recv_socket(socket2::Protocol::TCP, &default_interface)?;
+        let udp_socket = recv_socket(socket2::Protocol::UDP, &default_interface)?;
+        // 只接收转换端口范围内的tcp和udp包，减少内核复制
+        for socket in [&tcp_socket, &udp_socket] {
+            socket
+                .attach_filter(&port_filter())
+                .context("attach_filter")?;
+        }
+        let icmp_socket = recv_socket(socket2::Protocol::ICMPV4, &default_interface)?;
+        let nat_table: Arc<Mutex<NatTable>> = Arc::new(Mutex::new(NatTable::default()));
+        for socket in [tcp_socket, udp_socket, icmp_socket] {
+            let nat_table = nat_table.clone();
+            let context = context.clone();
+            let current_device = current_device.clone();
+            let client_cipher = client_cipher.clone();
+            runtime.spawn(async move {
+                if let Err(e) = masquerade_recv(
+                    socket.into(),
+                    nat_table,
+                    context,
+                    current_device,
+                    client_cipher,
+                )
+                .await
+                {
+                    log::warn!("masquerade:{:?}", e);
+                }
+            });
+        }
+        {
+            let nat_table = nat_table.clone();
+            runtime.spawn(async move {
+                let mut interval = tokio::time::interval(Duration::from_secs(10));
+                loop {
+                    interval.tick().await;
+                    let mut nat_table = nat_table.lock();
+                    nat_table.expire(Instant::now());
+                    log::debug!("nat连接数 {}", nat_table.len());
+                }
+            });
+        }
+        Ok(Self {
+            send_socket: Arc::new(send_socket),
+            default_interface,
+            nat_table,
+            context,
+            current_device,
+            client_cipher,
+        })
+    }
+    /// 处理对端发来访问out_ips网段的包，返回false表示不支持，交给ip代理或网卡处理
+    pub fn recv_handle(
+        &self,
+        ipv4: &mut IpV4Packet<&mut [u8]>,
+        peer: Ipv4Addr,
+    ) -> anyhow::Result<bool> {
+        if ipv4.offset() != 0 || ipv4.flags() & 1 == 1 {
+            // ip分片的不转换
+            return Ok(false);
+        }
+        let Some(flow) = outbound_flow(ipv4) else {
+            return Ok(false);
+        };
+        let len = ipv4.length() as usize;
+        if len > ipv4.buffer.len() {
+            return Err(anyhow::anyhow!("ipv4 length"));
+        }
+        if ipv4.ttl() <= 1 {
+            // 转发前ttl耗尽，和路由器一样回复超时，traceroute能看到这一跳
+            let current_device = self.current_device.load();
+            let reply = time_exceeded(&ipv4.buffer[..len], current_device.virtual_ip)?;
+            let mut buf = vec![0u8; HEAD_LEN + reply.len() + ENCRYPTION_RESERVED];
+            buf[HEAD_LEN..HEAD_LEN + reply.len()].copy_from_slice(&reply);
+            send_to_peer(
+                &mut buf,
+                HEAD_LEN + reply.len(),
+                peer,
+                &self.context,
+                &current_device,
+                &self.client_cipher,
+            )?;
+            return Ok(true);
+        }
+        let now = Instant::now();
+        // 查找和分配在同一把锁内，避免同一条连接的并发包分配出两个端口
+        let (local_ip, port) = {
+            let mut nat_table = self.nat_table.lock();
+            match nat_table.get_out(&flow, now) {
+                Some(v) => v,
+                None => {
+                    if flow.protocol == TCP && !tcp_syn(ipv4) {
+                        // 连接已经超时，远端也不认识新端口，直接丢弃
+                        return Ok(true);
+                    }
+                    let local_ip = local_ip(*flow.destination.ip(), &self.default_interface)?;
+                    let port = nat_table
+                        .allocate(flow, peer, local_ip, now, |port| match flow.protocol {
+                            TCP => reserve_tcp(port).map(Some),
+                            UDP => reserve_udp(port).map(Some),
+                            _ => Ok(None),
+                        })
+                        .ok_or_else(|| {
+                            io::Error::new(io::ErrorKind::Other, "nat port exhausted")
+                        })?;
+                    log::debug!("nat {:?} -> {}:{}", flow, local_ip, port);
+                    (local_ip, port)
+                }
+            }
+        };
+        if flow.protocol == TCP && tcp_closing(ipv4) {
+            self.nat_table.lock().tcp_close(port);
+        }
+        let buf = &mut ipv4.buffer[..len];
+        snat(buf, local_ip, port)?;
+        self.send_socket.send_to(
+            buf,
+            &SocketAddr::from(SocketAddrV4::new(*flow.destination.ip(), 0)).into(),
+        )?;
+        Ok(true)
+    }
+}
+
+fn recv_socket(
+    protocol: socket2::Protocol,
+    default_interface: &LocalInterface,
+) -> anyhow::Result<socket2::Socket> {
+    let socket = socket2::Socket::new(socket2::Domain::IPV4, socket2::Type::RAW, Some(protocol))
+        .with_context(|| format!("new Socket RAW {:?} failed", protocol))?;
+    socket.set_nonblocking(true)?;
+    if let Err(e) = socket.set_ip_unicast_if(default_interface) {
+        log::warn!("set_ip_unicast_if {:?}", e)
+    }
+    Ok(socket)
+}
+
+/// 原始套接字收到的数据从ip头开始，过滤目的端口不在转换范围内的tcp和udp包
+fn port_filter() -> [libc::sock_filter; 6] {
+    let op = |code: u32, jt: u8, jf: u8, k: u32| libc::sock_filter {
+        code: code as u16,
+        jt,
+        jf,
+        k,
+    };
+    [
+        // x = ip头长度
+        op(libc::BPF_LDX | libc::BPF_B | libc::BPF_MSH, 0, 0, 0),
+        // a = 目的端口
+        op(libc::BPF_LD | libc::BPF_H | libc::BPF_IND, 0, 0, 2),
+        op(
+            libc::BPF_JMP | libc::BPF_JGE | libc::BPF_K,
+            0,
+            2,
+            PORT_START as u32,
+        ),
+        op(
+            libc::BPF_JMP | libc::BPF_JGT | libc::BPF_K,
+            1,
+            0,
+            PORT_END as u32,
+        ),
+        op(libc::BPF_RET | libc::BPF_K, 0, 0, u32::MAX),
+        op(libc::BPF_RET | libc::BPF_K, 0, 0, 0),
+    ]
+}
+
+/// 访问dest时使用的本地地址
+fn local_ip(dest: Ipv4Addr, default_interface: &LocalInterface) -> io::Result<Ipv4Addr> {
+    let socket = socket2::Socket::new(
+        socket2::Domain::IPV4,
+        socket2::Type::DGRAM,
+        Some(socket2::Protocol::UDP),
+    )?;
+    if let Err(e) = socket.set_ip_unicast_if(default_interface) {
+        log::warn!("set_ip_unicast_if {:?}", e)
+    }
+    // udp connect不会发送数据，只是选出本地地址
+    socket.connect(&SocketAddr::from(SocketAddrV4::new(dest, 53)).into())?;
+    match socket.local_addr()?.as_socket() {
+        Some(SocketAddr::V4(addr)) => Ok(*addr.ip()),
+        _ => Err(io::Error::new(io::ErrorKind::Other, "not ipv4")),
+    }
+}
+
+/// 占用本地udp端口
+fn reserve_udp(port: u16) -> io::Result<socket2::Socket> {
+    let socket = socket2::Socket::new(
+        socket2::Domain::IPV4,
+        socket2::Type::DGRAM,
+        Some(socket2::Protocol::UDP),
+    )?;
+    socket.set_nonblocking(true)?;
+    // 数据都从原始套接字读取，这里不需要缓冲
+    socket.set_recv_buffer_size(0)?;
+    socket.bind(&SocketAddr::from(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port)).into())?;
+    Ok(socket)
+}
+
+/// 占用本地tcp端口。套接字处于监听状态，内核把这个端口的包都交给它，
+/// 过滤器在协议栈处理之前丢弃，不会建立连接也不会回rst，原始套接字仍然能收到
+fn reserve_tcp(port: u16) -> io::Result<socket2::Socket> {
+    let socket = socket2::Socket::new(
+        socket2::Domain::IPV4,
+        socket2::Type::STREAM,
+        Some(socket2::Protocol::TCP),
+    )?;
+    socket.set_nonblocking(true)?;
+    socket.attach_filter(&[libc::sock_filter {
+        code: (libc::BPF_RET | libc::BPF_K) as u16,
+        jt: 0,
+        jf: 0,
+        k: 0,
+    }])?;
+    socket.bind(&SocketAddr::from(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port)).into())?;
+    socket.listen(1)?;
+    Ok(socket)
+}
+
+async fn masquerade_recv(
+    socket: std::net::UdpSocket,
+    nat_table: Arc<Mutex<NatTable>>,
+    context: ChannelContext,
+    current_device: Arc<AtomicCell<CurrentDeviceInfo>>,
+    client_cipher: Cipher,
+) -> io::Result<()> {
+    let socket = UdpSocket::from_std(socket)?;
+    let mut buf = [0u8; 65535];
+    let start = 12;
+    loop {
+        let (len, addr) = socket.recv_from(&mut buf[start..]).await?;
+        if let IpAddr::V4(_) = addr.ip() {
+            if let Err(e) = recv_handle(
+                &mut buf,
+                start + len,
+                &nat_table,
+                &context,
+                &current_device,
+                &client_cipher,
+            ) {
+                log::warn!("masquerade {:?}", e);
+            }
+        }
+    }
+}
+
+fn recv_handle(
+    buf: &mut [u8],
+    data_len: usize,
+    nat_table: &Mutex<NatTable>,
+    context: &ChannelContext,
+    current_device: &AtomicCell<CurrentDeviceInfo>,
+    client_cipher: &Cipher,
+) -> anyhow::Result<()> {
+    let packet = &mut buf[HEAD_LEN..data_len];
+    let Some((protocol, port, remote, icmp_error)) = inbound_key(packet) else {
+        return Ok(());
+    };
+    let ipv4 = IpV4Packet::unchecked(&mut *packet);
+    let local_ip = ipv4.destination_ip();
+    let closing = protocol == TCP && !icmp_error && tcp_closing(&ipv4);
+    let Some((flow, peer)) = ({
+        let mut nat_table = nat_table.lock();
+        let rs = nat_table.get_in(protocol, port, &remote, local_ip, Instant::now());
+        if rs.is_some() && closing {
+            nat_table.tcp_close(port);
+        }
+        rs
+    }) else {
+        return Ok(());
+    };
+    let rs = if icmp_error {
+        dnat_icmp_error(packet, &flow, local_ip, port)
+    } else {
+        dnat(packet, &flow)
+    };
+    if let Err(e) = rs {
+        log::debug!("nat {:?} {:?}", flow, e);
+        return Ok(());
+    }
+    send_to_peer(
+        buf,
+        data_len,
+        peer,
+        context,
+        &current_device.load(),
+        client_cipher,
+    )
+}
+
+/// buf前HEAD_LEN字节留给vnt协议头，后面是发给对端的ip包
+fn send_to_peer(
+    buf: &mut [u8],
+    data_len: usize,
+    peer: Ipv4Addr,
+    context: &ChannelContext,
+    current_device: &CurrentDeviceInfo,
+    client_cipher: &Cipher,
+) -> anyhow::Result<()> {
+    let mut net_packet = NetPacket::new0(data_len, buf)?;
+    net_packet.set_default_version();
+    net_packet.set_protocol(protocol::Protocol::IpTurn);
+    net_packet.set_transport_protocol(protocol::ip_turn_packet::Protocol::Ipv4.into());
+    net_packet.first_set_ttl(MAX_TTL);
+    net_packet.set_source(current_device.virtual_ip());
+    net_packet.set_destination(peer);
+    client_cipher.encrypt_ipv4(&mut net_packet)?;
+    context.send_ipv4_by_id(
+        &net_packet,
+        &peer,
+        current_device.connect_server,
+        current_device.status.online(),
+    )?;
+    Ok(())
+}
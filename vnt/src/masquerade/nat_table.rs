@@ -0,0 +1,629 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::time::{Duration, Instant};
+
+use packet::icmp::icmp::IcmpPacket;
+use packet::icmp::Kind;
+use packet::ip::ipv4::packet::IpV4Packet;
+use packet::ip::ipv4::protocol::Protocol;
+use packet::tcp::tcp::TcpPacket;
+use packet::udp::udp::UdpPacket;
+
+// 转换后使用的端口(icmp为identifier)，避开linux默认的临时端口范围(32768-60999)
+pub const PORT_START: u16 = 61000;
+pub const PORT_END: u16 = 65000;
+const UDP_TIMEOUT: Duration = Duration::from_secs(120);
+const ICMP_TIMEOUT: Duration = Duration::from_secs(30);
+const TCP_TIMEOUT: Duration = Duration::from_secs(2 * 60 * 60);
+// 收到fin或rst后留一段时间给最后的ack和重传
+const TCP_CLOSE_TIMEOUT: Duration = Duration::from_secs(60);
+// 一次分配最多尝试占用的端口数，端口被其他程序占满时不至于把整个范围都绑一遍
+const MAX_RESERVE_ATTEMPTS: usize = 32;
+
+pub const TCP: u8 = 6;
+pub const UDP: u8 = 17;
+pub const ICMP: u8 = 1;
+
+/// 一条连接，icmp的端口为identifier，目的端口为0
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub struct Flow {
+    pub protocol: u8,
+    pub source: SocketAddrV4,
+    pub destination: SocketAddrV4,
+}
+
+struct NatEntry {
+    flow: Flow,
+    // 数据来自哪个对端，回复发回给它
+    peer: Ipv4Addr,
+    local_ip: Ipv4Addr,
+    last: Instant,
+    // tcp连接已经结束
+    closing: bool,
+    // 占用本地端口，避免分配给其他程序，也避免内核回复端口不可达
+    _reserve: Option<socket2::Socket>,
+}
+
+/// 连接跟踪表
+#[derive(Default)]
+pub struct NatTable {
+    out_map: HashMap<Flow, u16>,
+    // (协议,转换后的端口) -> 连接
+    in_map: HashMap<(u8, u16), NatEntry>,
+    next_port: u16,
+}
+
+impl NatTable {
+    /// 已有连接的本地地址和端口
+    pub fn get_out(&mut self, flow: &Flow, now: Instant) -> Option<(Ipv4Addr, u16)> {
+        let port = *self.out_map.get(flow)?;
+        let entry = self.in_map.get_mut(&(flow.protocol, port))?;
+        entry.last = now;
+        Some((entry.local_ip, port))
+    }
+    /// 给新连接分配端口，reserve失败的端口跳过，最多尝试MAX_RESERVE_ATTEMPTS次
+    pub fn allocate(
+        &mut self,
+        flow: Flow,
+        peer: Ipv4Addr,
+        local_ip: Ipv4Addr,
+        now: Instant,
+        mut reserve: impl FnMut(u16) -> io::Result<Option<socket2::Socket>>,
+    ) -> Option<u16> {
+        if let Some(port) = self.out_map.get(&flow) {
+            return Some(*port);
+        }
+        let mut attempts = 0;
+        for _ in PORT_START..=PORT_END {
+            if attempts >= MAX_RESERVE_ATTEMPTS {
+                break;
+            }
+            let port = if self.next_port < PORT_START || self.next_port > PORT_END {
+                PORT_START
+            } else {
+                self.next_port
+            };
+            self.next_port = port.wrapping_add(1);
+            if self.in_map.contains_key(&(flow.protocol, port)) {
+                continue;
+            }
+            attempts += 1;
+            let Ok(reserve) = reserve(port) else {
+                continue;
+            };
+            self.out_map.insert(flow, port);
+            self.in_map.insert(
+                (flow.protocol, port),
+                NatEntry {
+                    flow,
+                    peer,
+                    local_ip,
+                    last: now,
+                    closing: false,
+                    _reserve: reserve,
+                },
+            );
+            return Some(port);
+        }
+        None
+    }
+    /// 回复对应的连接和对端，只接收访问过的远端的回复
+    pub fn get_in(
+        &mut self,
+        protocol: u8,
+        port: u16,
+        remote: &SocketAddrV4,
+        local_ip: Ipv4Addr,
+        now: Instant,
+    ) -> Option<(Flow, Ipv4Addr)> {
+        let entry = self.in_map.get_mut(&(protocol, port))?;
+        if entry.flow.destination != *remote || entry.local_ip != local_ip {
+            return None;
+        }
+        entry.last = now;
+        Some((entry.flow, entry.peer))
+    }
+    /// tcp连接收到fin或rst，缩短超时时间
+    pub fn tcp_close(&mut self, port: u16) {
+        if let Some(entry) = self.in_map.get_mut(&(TCP, port)) {
+            entry.closing = true;
+        }
+    }
+    pub fn expire(&mut self, now: Instant) {
+        self.in_map.retain(|_, v| {
+            let timeout = match v.flow.protocol {
+                UDP => UDP_TIMEOUT,
+                TCP if v.closing => TCP_CLOSE_TIMEOUT,
+                TCP => TCP_TIMEOUT,
+                _ => ICMP_TIMEOUT,
+            };
+            now.duration_since(v.last) < timeout
+        });
+        let in_map = &self.in_map;
+        self.out_map
+            .retain(|flow, port| in_map.contains_key(&(flow.protocol, *port)));
+    }
+    pub fn len(&self) -> usize {
+        self.in_map.len()
+    }
+}
+
+/// 出站的连接，支持tcp、udp和icmp echo
+pub fn outbound_flow(ipv4: &IpV4Packet<&mut [u8]>) -> Option<Flow> {
+    let payload = ipv4.payload();
+    if payload.len() < 8 {
+        return None;
+    }
+    let (protocol, source_port, destination_port) = match ipv4.protocol() {
+        Protocol::Tcp if payload.len() >= 20 => (
+            TCP,
+            u16::from_be_bytes([payload[0], payload[1]]),
+            u16::from_be_bytes([payload[2], payload[3]]),
+        ),
+        Protocol::Udp => (
+            UDP,
+            u16::from_be_bytes([payload[0], payload[1]]),
+            u16::from_be_bytes([payload[2], payload[3]]),
+        ),
+        Protocol::Icmp if Kind::from(payload[0]) == Kind::EchoRequest => {
+            (ICMP, u16::from_be_bytes([payload[4], payload[5]]), 0)
+        }
+        _ => return None,
+    };
+    Some(Flow {
+        protocol,
+        source: SocketAddrV4::new(ipv4.source_ip(), source_port),
+        destination: SocketAddrV4::new(ipv4.destination_ip(), destination_port),
+    })
+}
+
+/// 入站包对应的(协议,转换后的端口,远端地址,是否icmp差错)，icmp差错报文取内层的原始包
+pub fn inbound_key(buf: &[u8]) -> Option<(u8, u16, SocketAddrV4, bool)> {
+    let ipv4 = IpV4Packet::new(buf).ok()?;
+    let payload = ipv4.payload();
+    if payload.len() < 8 {
+        return None;
+    }
+    match ipv4.protocol() {
+        Protocol::Tcp if payload.len() >= 20 => Some((
+            TCP,
+            u16::from_be_bytes([payload[2], payload[3]]),
+            SocketAddrV4::new(
+                ipv4.source_ip(),
+                u16::from_be_bytes([payload[0], payload[1]]),
+            ),
+            false,
+        )),
+        Protocol::Udp => Some((
+            UDP,
+            u16::from_be_bytes([payload[2], payload[3]]),
+            SocketAddrV4::new(
+                ipv4.source_ip(),
+                u16::from_be_bytes([payload[0], payload[1]]),
+            ),
+            false,
+        )),
+        Protocol::Icmp => match Kind::from(payload[0]) {
+            Kind::EchoReply => Some((
+                ICMP,
+                u16::from_be_bytes([payload[4], payload[5]]),
+                SocketAddrV4::new(ipv4.source_ip(), 0),
+                false,
+            )),
+            Kind::DestinationUnreachable
+            | Kind::TimeExceeded
+            | Kind::ParameterProblem
+            | Kind::SourceQuench => {
+                let inner = IpV4Packet::new(&payload[8..]).ok()?;
+                let l4 = inner.payload();
+                if l4.len() < 8 {
+                    return None;
+                }
+                match inner.protocol() {
+                    Protocol::Tcp => Some((
+                        TCP,
+                        u16::from_be_bytes([l4[0], l4[1]]),
+                        SocketAddrV4::new(
+                            inner.destination_ip(),
+                            u16::from_be_bytes([l4[2], l4[3]]),
+                        ),
+                        true,
+                    )),
+                    Protocol::Udp => Some((
+                        UDP,
+                        u16::from_be_bytes([l4[0], l4[1]]),
+                        SocketAddrV4::new(
+                            inner.destination_ip(),
+                            u16::from_be_bytes([l4[2], l4[3]]),
+                        ),
+                        true,
+                    )),
+                    Protocol::Icmp if Kind::from(l4[0]) == Kind::EchoRequest => Some((
+                        ICMP,
+                        u16::from_be_bytes([l4[4], l4[5]]),
+                        SocketAddrV4::new(inner.destination_ip(), 0),
+                        true,
+                    )),
+                    _ => None,
+                }
+            }
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// 源地址转换，buf为完整的ip包
+pub fn snat(buf: &mut [u8], local_ip: Ipv4Addr, port: u16) -> io::Result<()> {
+    let mut ipv4 = IpV4Packet::new(buf)?;
+    let destination = ipv4.destination_ip();
+    let ttl = ipv4.ttl();
+    ipv4.set_ttl(ttl.saturating_sub(1));
+    ipv4.set_source_ip(local_ip);
+    ipv4.update_checksum();
+    match ipv4.protocol() {
+        Protocol::Tcp => {
+            let mut tcp = TcpPacket::new(local_ip, destination, ipv4.payload_mut())?;
+            tcp.set_source_port(port);
+            tcp.update_checksum();
+        }
+        Protocol::Udp => {
+            let mut udp = UdpPacket::new(local_ip, destination, ipv4.payload_mut())?;
+            let checksum = udp.checksum();
+            udp.set_source_port(port);
+            // 0表示不使用校验和
+            if checksum != 0 {
+                udp.update_checksum();
+            }
+        }
+        Protocol::Icmp => {
+            let mut icmp = IcmpPacket::new(ipv4.payload_mut())?;
+            icmp.buffer[4..6].copy_from_slice(&port.to_be_bytes());
+            icmp.update_checksum();
+        }
+        _ => Err(io::Error::from(io::ErrorKind::Unsupported))?,
+    }
+    Ok(())
+}
+
+/// 回复的目的地址换回原始来源
+pub fn dnat(buf: &mut [u8], flow: &Flow) -> io::Result<()> {
+    let mut ipv4 = IpV4Packet::new(buf)?;
+    let source = ipv4.source_ip();
+    ipv4.set_destination_ip(*flow.source.ip());
+    ipv4.update_checksum();
+    match ipv4.protocol() {
+        Protocol::Tcp => {
+            let mut tcp = TcpPacket::new(source, *flow.source.ip(), ipv4.payload_mut())?;
+            tcp.set_destination_port(flow.source.port());
+            tcp.update_checksum();
+        }
+        Protocol::Udp => {
+            let mut udp = UdpPacket::new(source, *flow.source.ip(), ipv4.payload_mut())?;
+            let checksum = udp.checksum();
+            udp.set_destination_port(flow.source.port());
+            if checksum != 0 {
+                udp.update_checksum();
+            }
+        }
+        Protocol::Icmp => {
+            let mut icmp = IcmpPacket::new(ipv4.payload_mut())?;
+            icmp.buffer[4..6].copy_from_slice(&flow.source.port().to_be_bytes());
+            icmp.update_checksum();
+        }
+        _ => Err(io::Error::from(io::ErrorKind::Unsupported))?,
+    }
+    Ok(())
+}
+
+/// icmp差错报文，外层目的地址和内层原始包的源地址都换回原始来源
+pub fn dnat_icmp_error(
+    buf: &mut [u8],
+    flow: &Flow,
+    local_ip: Ipv4Addr,
+    port: u16,
+) -> io::Result<()> {
+    let mut ipv4 = IpV4Packet::new(buf)?;
+    ipv4.set_destination_ip(*flow.source.ip());
+    ipv4.update_checksum();
+    let mut icmp = IcmpPacket::new(ipv4.payload_mut())?;
+    {
+        let mut inner = IpV4Packet::new(&mut icmp.buffer[8..])?;
+        inner.set_source_ip(*flow.source.ip());
+        inner.update_checksum();
+        let l4 = inner.payload_mut();
+        // 内层只有头部8字节，校验和只能增量更新
+        let mut old = [0u8; 6];
+        old[..4].copy_from_slice(&local_ip.octets());
+        old[4..].copy_from_slice(&port.to_be_bytes());
+        let mut new = [0u8; 6];
+        new[..4].copy_from_slice(&flow.source.ip().octets());
+        new[4..].copy_from_slice(&flow.source.port().to_be_bytes());
+        if flow.protocol == UDP {
+            l4[0..2].copy_from_slice(&flow.source.port().to_be_bytes());
+            let checksum = u16::from_be_bytes([l4[6], l4[7]]);
+            if checksum != 0 {
+                // udp伪首部包含源地址
+                let checksum = checksum_adjust(checksum, &old, &new);
+                l4[6..8].copy_from_slice(&checksum.to_be_bytes());
+            }
+        } else if flow.protocol == TCP {
+            l4[0..2].copy_from_slice(&flow.source.port().to_be_bytes());
+            // tcp校验和在头部8字节之后，差错报文带了才更新
+            if l4.len() >= 18 {
+                let checksum = checksum_adjust(u16::from_be_bytes([l4[16], l4[17]]), &old, &new);
+                l4[16..18].copy_from_slice(&checksum.to_be_bytes());
+            }
+        } else {
+            l4[4..6].copy_from_slice(&flow.source.port().to_be_bytes());
+            let checksum =
+                checksum_adjust(u16::from_be_bytes([l4[2], l4[3]]), &old[4..], &new[4..]);
+            l4[2..4].copy_from_slice(&checksum.to_be_bytes());
+        }
+    }
+    icmp.update_checksum();
+    Ok(())
+}
+
+/// tcp包带有fin或rst标志
+pub fn tcp_closing(ipv4: &IpV4Packet<&mut [u8]>) -> bool {
+    let payload = ipv4.payload();
+    payload.len() >= 20 && payload[13] & (packet::tcp::FIN | packet::tcp::RST) != 0
+}
+
+/// 新的tcp连接只能由syn建立，超时后的残留包不分配端口
+pub fn tcp_syn(ipv4: &IpV4Packet<&mut [u8]>) -> bool {
+    let payload = ipv4.payload();
+    payload.len() >= 20 && payload[13] & (packet::tcp::SYN | packet::tcp::ACK) == packet::tcp::SYN
+}
+
+/// ttl耗尽时回复的icmp超时报文，带上原始包的ip头和前8字节数据
+pub fn time_exceeded(original: &[u8], source: Ipv4Addr) -> io::Result<Vec<u8>> {
+    let ipv4 = IpV4Packet::new(original)?;
+    let quote_len = (ipv4.header_len() as usize * 4 + 8).min(original.len());
+    let len = 20 + 8 + quote_len;
+    let mut buf = vec![0u8; len];
+    buf[0] = 0x45;
+    buf[2..4].copy_from_slice(&(len as u16).to_be_bytes());
+    buf[8] = 64;
+    buf[9] = ICMP;
+    buf[20] = Kind::TimeExceeded.into();
+    buf[28..].copy_from_slice(&original[..quote_len]);
+    let mut packet = IpV4Packet::new(&mut buf[..])?;
+    packet.set_source_ip(source);
+    packet.set_destination_ip(ipv4.source_ip());
+    packet.update_checksum();
+    IcmpPacket::new(packet.payload_mut())?.update_checksum();
+    Ok(buf)
+}
+
+/// 校验和增量更新 https://datatracker.ietf.org/doc/html/rfc1624
+fn checksum_adjust(checksum: u16, old: &[u8], new: &[u8]) -> u16 {
+    let mut sum = u32::from(!checksum);
+    for v in old.chunks(2) {
+        sum += u32::from(!u16::from_be_bytes([v[0], v[1]]));
+    }
+    for v in new.chunks(2) {
+        sum += u32::from(u16::from_be_bytes([v[0], v[1]]));
+    }
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn udp_packet(source: SocketAddrV4, destination: SocketAddrV4) -> Vec<u8> {
+        let mut buf = vec![0u8; 20 + 8 + 4];
+        buf[0] = 0x45;
+        buf[2..4].copy_from_slice(&32u16.to_be_bytes());
+        buf[8] = 64;
+        buf[9] = UDP;
+        buf[24..26].copy_from_slice(&12u16.to_be_bytes());
+        buf[28..].copy_from_slice(b"ping");
+        let mut ipv4 = IpV4Packet::new(&mut buf[..]).unwrap();
+        ipv4.set_source_ip(*source.ip());
+        ipv4.set_destination_ip(*destination.ip());
+        ipv4.update_checksum();
+        let mut udp = UdpPacket::new(*source.ip(), *destination.ip(), ipv4.payload_mut()).unwrap();
+        udp.set_source_port(source.port());
+        udp.set_destination_port(destination.port());
+        udp.update_checksum();
+        buf
+    }
+
+    #[test]
+    fn translate() {
+        let source = SocketAddrV4::new(Ipv4Addr::new(10, 26, 0, 2), 5000);
+        let remote = SocketAddrV4::new(Ipv4Addr::new(192, 168, 1, 8), 53);
+        let local_ip = Ipv4Addr::new(192, 168, 1, 2);
+        let peer = Ipv4Addr::new(10, 26, 0, 2);
+        let now = Instant::now();
+        let mut table = NatTable::default();
+
+        let mut buf = udp_packet(source, remote);
+        let flow = outbound_flow(&IpV4Packet::new(&mut buf[..]).unwrap()).unwrap();
+        let port = table
+            .allocate(flow, peer, local_ip, now, |_| Ok(None))
+            .unwrap();
+        assert_eq!(table.get_out(&flow, now), Some((local_ip, port)));
+        snat(&mut buf, local_ip, port).unwrap();
+        let sent = buf.clone();
+        let ipv4 = IpV4Packet::new(&sent[..]).unwrap();
+        assert!(ipv4.is_valid());
+        assert_eq!(ipv4.source_ip(), local_ip);
+        let udp = UdpPacket::new(local_ip, *remote.ip(), ipv4.payload()).unwrap();
+        assert!(udp.is_valid());
+        assert_eq!(udp.source_port(), port);
+
+        // 回复
+        let mut reply = udp_packet(remote, SocketAddrV4::new(local_ip, port));
+        let (protocol, nat_port, from, error) = inbound_key(&reply).unwrap();
+        assert!(!error);
+        // 其他远端的包不转发
+        let other = SocketAddrV4::new(Ipv4Addr::new(192, 168, 1, 9), 53);
+        assert!(table
+            .get_in(protocol, nat_port, &other, local_ip, now)
+            .is_none());
+        let (in_flow, in_peer) = table
+            .get_in(protocol, nat_port, &from, local_ip, now)
+            .unwrap();
+        assert_eq!((in_flow, in_peer), (flow, peer));
+        dnat(&mut reply, &in_flow).unwrap();
+        let ipv4 = IpV4Packet::new(&reply[..]).unwrap();
+        assert_eq!(ipv4.destination_ip(), *source.ip());
+        let udp = UdpPacket::new(*remote.ip(), *source.ip(), ipv4.payload()).unwrap();
+        assert!(udp.is_valid());
+        assert_eq!(udp.destination_port(), source.port());
+
+        // 端口不可达，内层是转换后发出的包
+        let original = udp_packet(source, remote);
+        let mut error = vec![0u8; 20 + 8 + 28];
+        error[0] = 0x45;
+        error[2..4].copy_from_slice(&56u16.to_be_bytes());
+        error[8] = 64;
+        error[9] = ICMP;
+        error[20] = 3;
+        error[21] = 3;
+        error[28..].copy_from_slice(&sent[..28]);
+        {
+            let mut ipv4 = IpV4Packet::new(&mut error[..]).unwrap();
+            ipv4.set_source_ip(*remote.ip());
+            ipv4.set_destination_ip(local_ip);
+            ipv4.update_checksum();
+            IcmpPacket::new(ipv4.payload_mut())
+                .unwrap()
+                .update_checksum();
+        }
+        let (protocol, nat_port, from, is_error) = inbound_key(&error).unwrap();
+        assert!(is_error);
+        let (in_flow, _) = table
+            .get_in(protocol, nat_port, &from, local_ip, now)
+            .unwrap();
+        dnat_icmp_error(&mut error, &in_flow, local_ip, nat_port).unwrap();
+        let ipv4 = IpV4Packet::new(&error[..]).unwrap();
+        assert!(ipv4.is_valid());
+        assert_eq!(ipv4.destination_ip(), *source.ip());
+        assert!(IcmpPacket::new(ipv4.payload()).unwrap().is_valid());
+        // 内层还原后和原始包除ttl外一致
+        let inner = &ipv4.payload()[8..];
+        assert_eq!(inner[12..28], original[12..28]);
+
+        table.expire(now + UDP_TIMEOUT);
+        assert_eq!(table.len(), 0);
+        assert!(table.get_out(&flow, now).is_none());
+    }
+
+    fn tcp_packet(source: SocketAddrV4, destination: SocketAddrV4, flags: u8) -> Vec<u8> {
+        let mut buf = vec![0u8; 20 + 20];
+        buf[0] = 0x45;
+        buf[2..4].copy_from_slice(&40u16.to_be_bytes());
+        buf[8] = 64;
+        buf[9] = TCP;
+        buf[32] = 5 << 4;
+        buf[33] = flags;
+        let mut ipv4 = IpV4Packet::new(&mut buf[..]).unwrap();
+        ipv4.set_source_ip(*source.ip());
+        ipv4.set_destination_ip(*destination.ip());
+        ipv4.update_checksum();
+        let mut tcp = TcpPacket::new(*source.ip(), *destination.ip(), ipv4.payload_mut()).unwrap();
+        tcp.set_source_port(source.port());
+        tcp.set_destination_port(destination.port());
+        tcp.update_checksum();
+        buf
+    }
+
+    #[test]
+    fn translate_tcp() {
+        let source = SocketAddrV4::new(Ipv4Addr::new(10, 26, 0, 2), 5000);
+        let remote = SocketAddrV4::new(Ipv4Addr::new(192, 168, 1, 8), 80);
+        let local_ip = Ipv4Addr::new(192, 168, 1, 2);
+        let peer = Ipv4Addr::new(10, 26, 0, 2);
+        let now = Instant::now();
+        let mut table = NatTable::default();
+
+        let mut buf = tcp_packet(source, remote, packet::tcp::SYN);
+        let ipv4 = IpV4Packet::new(&mut buf[..]).unwrap();
+        assert!(tcp_syn(&ipv4));
+        assert!(!tcp_closing(&ipv4));
+        let flow = outbound_flow(&ipv4).unwrap();
+        assert_eq!(flow.protocol, TCP);
+        let port = table
+            .allocate(flow, peer, local_ip, now, |_| Ok(None))
+            .unwrap();
+        snat(&mut buf, local_ip, port).unwrap();
+        let ipv4 = IpV4Packet::new(&buf[..]).unwrap();
+        assert!(ipv4.is_valid());
+        let tcp = TcpPacket::new(local_ip, *remote.ip(), ipv4.payload()).unwrap();
+        assert!(tcp.is_valid());
+        assert_eq!(tcp.source_port(), port);
+
+        let mut reply = tcp_packet(
+            remote,
+            SocketAddrV4::new(local_ip, port),
+            packet::tcp::SYN | packet::tcp::ACK,
+        );
+        let (protocol, nat_port, from, error) = inbound_key(&reply).unwrap();
+        assert!(!error);
+        let (in_flow, _) = table
+            .get_in(protocol, nat_port, &from, local_ip, now)
+            .unwrap();
+        dnat(&mut reply, &in_flow).unwrap();
+        let ipv4 = IpV4Packet::new(&reply[..]).unwrap();
+        let tcp = TcpPacket::new(*remote.ip(), *source.ip(), ipv4.payload()).unwrap();
+        assert!(tcp.is_valid());
+        assert_eq!(tcp.destination_port(), source.port());
+
+        // 收到fin后很快超时
+        table.expire(now + TCP_CLOSE_TIMEOUT);
+        assert_eq!(table.len(), 1);
+        table.tcp_close(port);
+        table.expire(now + TCP_CLOSE_TIMEOUT);
+        assert_eq!(table.len(), 0);
+    }
+
+    #[test]
+    fn ttl_time_exceeded() {
+        let source = SocketAddrV4::new(Ipv4Addr::new(10, 26, 0, 2), 5000);
+        let remote = SocketAddrV4::new(Ipv4Addr::new(192, 168, 1, 8), 53);
+        let gateway = Ipv4Addr::new(10, 26, 0, 1);
+        let original = udp_packet(source, remote);
+        let reply = time_exceeded(&original, gateway).unwrap();
+        let ipv4 = IpV4Packet::new(&reply[..]).unwrap();
+        assert!(ipv4.is_valid());
+        assert_eq!(ipv4.source_ip(), gateway);
+        assert_eq!(ipv4.destination_ip(), *source.ip());
+        let icmp = IcmpPacket::new(ipv4.payload()).unwrap();
+        assert!(icmp.is_valid());
+        assert_eq!(icmp.kind(), Kind::TimeExceeded);
+        // ip头和前8字节
+        assert_eq!(&ipv4.payload()[8..], &original[..28]);
+    }
+
+    #[test]
+    fn allocate_attempts() {
+        let flow = Flow {
+            protocol: UDP,
+            source: SocketAddrV4::new(Ipv4Addr::new(10, 26, 0, 2), 5000),
+            destination: SocketAddrV4::new(Ipv4Addr::new(192, 168, 1, 8), 53),
+        };
+        let mut table = NatTable::default();
+        let mut count = 0;
+        let port = table.allocate(
+            flow,
+            Ipv4Addr::new(10, 26, 0, 2),
+            Ipv4Addr::new(192, 168, 1, 2),
+            Instant::now(),
+            |_| {
+                count += 1;
+                Err(io::Error::from(io::ErrorKind::AddrInUse))
+            },
+        );
+        assert!(port.is_none());
+        assert_eq!(count, MAX_RESERVE_ATTEMPTS);
+    }
+}
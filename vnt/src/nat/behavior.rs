@@ -0,0 +1,404 @@
+//! RFC 5780 nat行为探测，区分映射行为、过滤行为、是否支持回环，以及测量绑定存活时间
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::time::{Duration, Instant};
+
+use rand::Rng;
+use stun_format::{Attr, Msg, MsgBuilder, MsgType};
+
+use crate::channel::punch::NatBehavior;
+use crate::channel::socket::{bind_udp, LocalInterface};
+use crate::nat::stun::stun_addr;
+
+const ATTR_CHANGE_REQUEST: u16 = 0x0003;
+pub(super) const CHANGE_IP: u32 = 0x04;
+pub(super) const CHANGE_PORT: u32 = 0x02;
+
+/// 单次请求等待响应的时间
+const RECV_TIMEOUT: Duration = Duration::from_millis(500);
+/// 绑定存活时间测量的起始间隔和上限(秒)
+const LIFETIME_START: u32 = 15;
+const LIFETIME_MAX: u32 = 1200;
+/// 上下界相差小于该值时结束测量(秒)
+const LIFETIME_PRECISION: u32 = 10;
+
+#[derive(Clone, Copy, Debug)]
+pub struct BehaviorResult {
+    pub mapping: NatBehavior,
+    pub filtering: NatBehavior,
+    /// 是否支持回环，None表示无法确认
+    pub hairpin: Option<bool>,
+    /// 支持RFC 5780的stun服务器，用于后续测量绑定存活时间
+    pub server: SocketAddr,
+}
+
+pub(super) struct StunResponse {
+    pub(super) mapped: SocketAddr,
+    pub(super) other: Option<SocketAddr>,
+}
+
+/// 96位事务id，高32位由stun-format填充magic cookie
+pub(super) fn new_tid() -> u128 {
+    rand::thread_rng().gen::<u128>() & ((1 << 96) - 1)
+}
+
+pub(super) fn build_request(tid: u128, change: u32, response_port: Option<u16>) -> Vec<u8> {
+    let mut buf = [0u8; 28];
+    let mut msg = MsgBuilder::from(buf.as_mut_slice());
+    msg.typ(MsgType::BindingRequest);
+    msg.tid(tid);
+    if let Some(port) = response_port {
+        msg.add_attr(Attr::ResponsePort(port));
+    }
+    let mut buf = msg.as_bytes().to_vec();
+    if change != 0 {
+        // stun-format把CHANGE-REQUEST的标志位写成0x40/0x20，和RFC 5780的0x04/0x02不一致，
+        // 服务器会忽略，这个属性手动编码
+        buf.extend_from_slice(&ATTR_CHANGE_REQUEST.to_be_bytes());
+        buf.extend_from_slice(&4u16.to_be_bytes());
+        buf.extend_from_slice(&change.to_be_bytes());
+        let len = (buf.len() - 20) as u16;
+        buf[2..4].copy_from_slice(&len.to_be_bytes());
+    }
+    buf
+}
+
+pub(super) fn parse_response(buf: &[u8], tid: u128) -> Option<StunResponse> {
+    let msg = Msg::from(buf);
+    if !matches!(msg.typ(), Some(MsgType::BindingResponse)) || msg.tid() != Some(tid) {
+        return None;
+    }
+    let mut mapped = None;
+    let mut xor_mapped = None;
+    let mut other = None;
+    for attr in msg.attrs_iter() {
+        match attr {
+            Attr::MappedAddress(addr) => mapped = Some(stun_addr(addr)),
+            Attr::XorMappedAddress(addr) => xor_mapped = Some(stun_addr(addr)),
+            // 老版本服务器使用CHANGED-ADDRESS，含义相同
+            Attr::OtherAddress(addr) | Attr::ChangedAddress(addr) if other.is_none() => {
+                other = Some(stun_addr(addr))
+            }
+            _ => {}
+        }
+    }
+    Some(StunResponse {
+        mapped: xor_mapped.or(mapped)?,
+        other,
+    })
+}
+
+/// 等待指定事务的响应，其他数据丢弃
+fn recv_response(udp: &UdpSocket, tid: u128, timeout: Duration) -> Option<StunResponse> {
+    let deadline = Instant::now() + timeout;
+    let mut buf = [0u8; 1024];
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            return None;
+        }
+        if udp.set_read_timeout(Some(deadline - now)).is_err() {
+            return None;
+        }
+        match udp.recv_from(&mut buf) {
+            Ok((len, _)) => {
+                if let Some(response) = parse_response(&buf[..len], tid) {
+                    return Some(response);
+                }
+            }
+            Err(_) => return None,
+        }
+    }
+}
+
+pub(super) fn request(
+    udp: &UdpSocket,
+    server: SocketAddr,
+    change: u32,
+    response_port: Option<u16>,
+) -> io::Result<Option<StunResponse>> {
+    let tid = new_tid();
+    let buf = build_request(tid, change, response_port);
+    for _ in 0..2 {
+        udp.send_to(&buf, server)?;
+        if let Some(response) = recv_response(udp, tid, RECV_TIMEOUT) {
+            return Ok(Some(response));
+        }
+    }
+    Ok(None)
+}
+
+pub(super) fn new_socket(default_interface: &LocalInterface) -> anyhow::Result<UdpSocket> {
+    let udp = bind_udp("0.0.0.0:0".parse().unwrap(), default_interface)?;
+    udp.set_nonblocking(false)?;
+    Ok(udp.into())
+}
+
+/// 依次使用stun服务器做RFC 5780行为探测，直到找到支持的服务器
+pub fn behavior_test(
+    stun_servers: &[String],
+    local_ipv4: Option<Ipv4Addr>,
+    default_interface: &LocalInterface,
+) -> anyhow::Result<BehaviorResult> {
+    for stun_server in stun_servers {
+        let server = match stun_server
+            .to_socket_addrs()
+            .map(|mut v| v.find(|addr| addr.is_ipv4()))
+        {
+            Ok(Some(server)) => server,
+            _ => continue,
+        };
+        match behavior_test0(server, local_ipv4, default_interface) {
+            Ok(Some(rs)) => {
+                log::info!("stun {} nat行为 {:?}", stun_server, rs);
+                return Ok(rs);
+            }
+            Ok(None) => {
+                log::info!("stun {} 不支持RFC 5780", stun_server);
+            }
+            Err(e) => {
+                log::warn!("stun {} nat行为探测失败 {:?}", stun_server, e);
+            }
+        }
+    }
+    Err(anyhow::anyhow!("no stun server supports RFC 5780"))
+}
+
+fn behavior_test0(
+    server: SocketAddr,
+    local_ipv4: Option<Ipv4Addr>,
+    default_interface: &LocalInterface,
+) -> anyhow::Result<Option<BehaviorResult>> {
+    let udp = new_socket(default_interface)?;
+    // Test I: 基础绑定，获取映射地址和服务器的备用地址
+    let Some(response) = request(&udp, server, 0, None)? else {
+        return Err(anyhow::anyhow!("stun no response"));
+    };
+    let Some(other) = response.other else {
+        return Ok(None);
+    };
+    if other.ip() == server.ip() || other.port() == server.port() {
+        return Ok(None);
+    }
+    let mapped = response.mapped;
+    let local_port = udp.local_addr()?.port();
+    let no_nat = local_ipv4.is_some_and(|ip| mapped == SocketAddr::new(IpAddr::V4(ip), local_port));
+    // 过滤行为要在向备用地址发送数据之前测试，否则备用地址已经被放行
+    let filtering = if request(&udp, server, CHANGE_IP | CHANGE_PORT, None)?.is_some() {
+        NatBehavior::EndpointIndependent
+    } else if request(&udp, server, CHANGE_PORT, None)?.is_some() {
+        NatBehavior::AddressDependent
+    } else {
+        NatBehavior::AddressAndPortDependent
+    };
+    let mapping = if no_nat {
+        NatBehavior::EndpointIndependent
+    } else {
+        // Test II: 换ip不换端口
+        let addr2 = SocketAddr::new(other.ip(), server.port());
+        match request(&udp, addr2, 0, None)? {
+            None => NatBehavior::Unknown,
+            Some(response2) if response2.mapped == mapped => NatBehavior::EndpointIndependent,
+            Some(response2) => {
+                // Test III: ip和端口都换
+                match request(&udp, other, 0, None)? {
+                    None => NatBehavior::Unknown,
+                    Some(response3) if response3.mapped == response2.mapped => {
+                        NatBehavior::AddressDependent
+                    }
+                    Some(_) => NatBehavior::AddressAndPortDependent,
+                }
+            }
+        }
+    };
+    let hairpin = if no_nat {
+        None
+    } else {
+        hairpin_test(&udp, server, mapped, mapping, filtering, default_interface).unwrap_or(None)
+    };
+    Ok(Some(BehaviorResult {
+        mapping,
+        filtering,
+        hairpin,
+        server,
+    }))
+}
+
+/// 从另一个socket向自己的映射地址发包，能收到说明nat支持回环。
+/// 受限的过滤行为可能把回环的包丢掉，只有确认会放行时收不到才算不支持，否则结果未知
+fn hairpin_test(
+    udp: &UdpSocket,
+    server: SocketAddr,
+    mapped: SocketAddr,
+    mapping: NatBehavior,
+    filtering: NatBehavior,
+    default_interface: &LocalInterface,
+) -> anyhow::Result<Option<bool>> {
+    let other = new_socket(default_interface)?;
+    let token = new_tid().to_be_bytes();
+    // 先向另一个socket的映射地址发包，让过滤行为放行来自它的数据
+    let other_mapped = request(&other, server, 0, None)?.map(|v| v.mapped);
+    if let Some(other_mapped) = other_mapped {
+        udp.send_to(&token, other_mapped)?;
+    }
+    let mut buf = [0u8; 64];
+    for _ in 0..2 {
+        other.send_to(&token, mapped)?;
+        let deadline = Instant::now() + RECV_TIMEOUT;
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            udp.set_read_timeout(Some(deadline - now))?;
+            match udp.recv_from(&mut buf) {
+                Ok((len, _)) => {
+                    if buf[..len] == token {
+                        return Ok(Some(true));
+                    }
+                }
+                Err(_) => break,
+            }
+        }
+    }
+    // 过滤行为不限制来源，或者映射与目的无关且已经放行了对方的映射地址，才能确认不支持
+    let confirmed = filtering == NatBehavior::EndpointIndependent
+        || (mapping == NatBehavior::EndpointIndependent && other_mapped.is_some());
+    Ok(confirmed.then_some(false))
+}
+
+/// 测量nat绑定的存活时间：空闲一段时间后让服务器通过RESPONSE-PORT向旧绑定回包，
+/// 收到说明绑定仍然存在，逐步加倍间隔，确定上界后二分
+pub struct LifetimeProbe {
+    udp: UdpSocket,
+    server: SocketAddr,
+    mapped: SocketAddr,
+    refreshed: Instant,
+    // 确认存活的最长空闲时间
+    lower: u32,
+    // 确认失效的最短空闲时间
+    upper: Option<u32>,
+}
+
+impl LifetimeProbe {
+    pub fn new(server: SocketAddr, default_interface: &LocalInterface) -> anyhow::Result<Self> {
+        let udp = new_socket(default_interface)?;
+        let Some(response) = request(&udp, server, 0, None)? else {
+            return Err(anyhow::anyhow!("stun no response"));
+        };
+        Ok(Self {
+            udp,
+            server,
+            mapped: response.mapped,
+            refreshed: Instant::now(),
+            lower: 0,
+            upper: None,
+        })
+    }
+    pub fn server(&self) -> SocketAddr {
+        self.server
+    }
+    /// 确认存活的时间和确认失效的时间(秒)
+    pub fn lifetime(&self) -> (u32, Option<u32>) {
+        (self.lower, self.upper)
+    }
+    /// 下一次检测需要空闲的时间，None表示测量结束
+    pub fn next_wait(&self) -> Option<Duration> {
+        let next = match self.upper {
+            Some(upper) => {
+                if upper.saturating_sub(self.lower) <= LIFETIME_PRECISION {
+                    return None;
+                }
+                (self.lower + upper) / 2
+            }
+            None => {
+                if self.lower >= LIFETIME_MAX {
+                    return None;
+                }
+                (self.lower * 2).clamp(LIFETIME_START, LIFETIME_MAX)
+            }
+        };
+        Some(Duration::from_secs(next as u64))
+    }
+    /// 检测旧绑定是否存活，然后刷新绑定开始下一轮空闲
+    pub fn probe(&mut self, default_interface: &LocalInterface) -> anyhow::Result<()> {
+        let idle = self.refreshed.elapsed().as_secs() as u32;
+        let other = new_socket(default_interface)?;
+        let tid = new_tid();
+        let buf = build_request(tid, 0, Some(self.mapped.port()));
+        let mut alive = false;
+        for _ in 0..2 {
+            other.send_to(&buf, self.server)?;
+            if recv_response(&self.udp, tid, Duration::from_secs(1)).is_some() {
+                alive = true;
+                break;
+            }
+            // 服务器忽略了RESPONSE-PORT，响应回到了发送方，无法测量
+            if recv_response(&other, tid, Duration::from_millis(10)).is_some() {
+                return Err(anyhow::anyhow!("RESPONSE-PORT not supported"));
+            }
+        }
+        if alive {
+            self.lower = self.lower.max(idle);
+        } else {
+            self.upper = Some(self.upper.map_or(idle, |v| v.min(idle)));
+        }
+        if let Some(upper) = self.upper {
+            if upper < self.lower {
+                // 网络抖动导致的矛盾结果，重新测量
+                self.lower = 0;
+                self.upper = None;
+            }
+        }
+        let Some(response) = request(&self.udp, self.server, 0, None)? else {
+            return Err(anyhow::anyhow!("stun no response"));
+        };
+        self.mapped = response.mapped;
+        self.refreshed = Instant::now();
+        log::info!(
+            "nat绑定存活时间 idle={}s alive={} lifetime={:?}",
+            idle,
+            alive,
+            self.lifetime()
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn xor_mapped_address() {
+        let tid = 0x0707_0707_0707_0707_0707_0707u128;
+        let cookie = 0x2112A442u32;
+        let mut buf = vec![0x01, 0x01, 0, 12];
+        buf.extend_from_slice(&cookie.to_be_bytes());
+        buf.extend_from_slice(&[7u8; 12]);
+        // XOR-MAPPED-ADDRESS
+        buf.extend_from_slice(&[0x00, 0x20, 0, 8]);
+        let port = 3478u16 ^ (cookie >> 16) as u16;
+        let ip = u32::from(Ipv4Addr::new(1, 2, 3, 4)) ^ cookie;
+        buf.extend_from_slice(&[0, 1]);
+        buf.extend_from_slice(&port.to_be_bytes());
+        buf.extend_from_slice(&ip.to_be_bytes());
+        let response = parse_response(&buf, tid).unwrap();
+        assert_eq!(response.mapped, "1.2.3.4:3478".parse().unwrap());
+        assert!(response.other.is_none());
+        assert!(parse_response(&buf, 0).is_none());
+    }
+
+    #[test]
+    fn change_request() {
+        let tid = new_tid();
+        let buf = build_request(tid, CHANGE_IP | CHANGE_PORT, Some(3478));
+        // 头部20字节，RESPONSE-PORT和CHANGE-REQUEST各8字节
+        assert_eq!(buf.len(), 36);
+        assert_eq!(&buf[2..4], &16u16.to_be_bytes());
+        assert_eq!(&buf[20..24], &[0x00, 0x27, 0, 4]);
+        assert_eq!(&buf[24..26], &3478u16.to_be_bytes());
+        assert_eq!(&buf[28..36], &[0x00, 0x03, 0, 4, 0, 0, 0, 0x06]);
+        assert_eq!(Msg::from(buf.as_slice()).tid(), Some(tid));
+    }
+}
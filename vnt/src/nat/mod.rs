@@ -7,15 +7,22 @@ use std::time::{Duration, Instant};
 
 use crossbeam_utils::atomic::AtomicCell;
 use parking_lot::Mutex;
-use rand::prelude::SliceRandom;
 use rand::Rng;
 
-use crate::channel::punch::{NatInfo, NatType, PunchModel};
+use crate::channel::punch::{NatBehavior, NatInfo, NatType, PortModel, PunchModel};
 use crate::channel::socket::LocalInterface;
 #[cfg(feature = "upnp")]
 use crate::util::UPnP;
+#[cfg(feature = "natpmp")]
+use crate::util::{GatewayProtocol, MappingProtocol, NatPmp};
 
+mod behavior;
+mod port_model;
 mod stun;
+mod stun_health;
+pub use stun_health::StunHealth;
+
+use behavior::LifetimeProbe;
 
 pub fn local_ipv4_() -> io::Result<Ipv4Addr> {
     let socket = UdpSocket::bind("0.0.0.0:0")?;
@@ -107,16 +114,35 @@ pub const fn is_ipv6_global(ipv6addr: &Ipv6Addr) -> bool {
         || (ipv6addr.segments()[0] & 0xffc0) == 0xfe80) //ipv6addr.is_unicast_link_local())
 }
 
+/// 网关(UPnP、NAT-PMP、PCP)端口映射的状态
+#[derive(Clone, Debug)]
+pub struct GatewayMapping {
+    pub source: &'static str,
+    pub tcp: bool,
+    pub port: u16,
+    pub external: Option<SocketAddr>,
+    /// 网关确认映射存在
+    pub verified: bool,
+    pub error: Option<String>,
+}
+
 #[derive(Clone)]
 pub struct NatTest {
     stun_server: Vec<String>,
+    // stun服务器健康度，决定探测顺序
+    stun_health: StunHealth,
     info: Arc<Mutex<NatInfo>>,
     time: Arc<AtomicCell<Instant>>,
     udp_ports: Vec<u16>,
     tcp_port: u16,
     #[cfg(feature = "upnp")]
     upnp: UPnP,
+    #[cfg(feature = "natpmp")]
+    natpmp: NatPmp,
     pub(crate) update_local_ipv4: bool,
+    // 支持RFC 5780的stun服务器
+    behavior_server: Arc<Mutex<Option<SocketAddr>>>,
+    lifetime_probe: Arc<Mutex<Option<LifetimeProbe>>>,
 }
 
 impl NatTest {
@@ -129,6 +155,7 @@ impl NatTest {
         tcp_port: u16,
         update_local_ipv4: bool,
         punch_model: PunchModel,
+        stun_health: StunHealth,
     ) -> NatTest {
         let ports = vec![0; udp_ports.len()];
         let nat_info = NatInfo::new(
@@ -152,9 +179,18 @@ impl NatTest {
         }
         #[cfg(feature = "upnp")]
         upnp.add_tcp_port(tcp_port);
+        #[cfg(feature = "natpmp")]
+        let natpmp = NatPmp::default();
+        #[cfg(feature = "natpmp")]
+        for port in &udp_ports {
+            natpmp.add_udp_port(*port);
+        }
+        #[cfg(feature = "natpmp")]
+        natpmp.add_tcp_port(tcp_port);
         let instant = Instant::now();
         NatTest {
             stun_server,
+            stun_health,
             info,
             time: Arc::new(AtomicCell::new(
                 instant
@@ -165,7 +201,11 @@ impl NatTest {
             tcp_port,
             #[cfg(feature = "upnp")]
             upnp,
+            #[cfg(feature = "natpmp")]
+            natpmp,
             update_local_ipv4,
+            behavior_server: Arc::new(Mutex::new(None)),
+            lifetime_probe: Arc::new(Mutex::new(None)),
         }
     }
     pub fn can_update(&self) -> bool {
@@ -173,10 +213,22 @@ impl NatTest {
         last.elapsed() > Duration::from_secs(10)
             && self.time.compare_exchange(last, Instant::now()).is_ok()
     }
+    /// 网络变化时立即探测，推迟下一次定时探测
+    pub fn mark_update(&self) {
+        self.time.store(Instant::now());
+    }
 
     pub fn nat_info(&self) -> NatInfo {
         self.info.lock().clone()
     }
+    /// 使用上次运行保存的nat类型和公网ip，首次探测完成前也能用于打洞
+    pub fn restore(&self, nat_type: NatType, public_ips: Vec<Ipv4Addr>) {
+        let mut guard = self.info.lock();
+        if guard.public_ips.is_empty() {
+            guard.nat_type = nat_type;
+            guard.public_ips = public_ips;
+        }
+    }
     pub fn is_local_udp(&self, ipv4: Ipv4Addr, port: u16) -> bool {
         for x in &self.udp_ports {
             if x == &port {
@@ -251,19 +303,58 @@ impl NatTest {
         ipv6: Option<Ipv6Addr>,
         default_interface: &LocalInterface,
     ) -> anyhow::Result<NatInfo> {
-        let mut stun_server = self.stun_server.clone();
+        // 优先使用上次探测中可用且延迟低的服务器
+        let mut stun_server = self.stun_health.order(&self.stun_server);
         if stun_server.len() > 5 {
-            stun_server.shuffle(&mut rand::thread_rng());
             stun_server.truncate(5);
             log::info!("stun_server truncate {:?}", stun_server);
         }
-        let (nat_type, public_ips, port_range) =
-            stun::stun_test_nat(stun_server, default_interface)?;
+        let (mut nat_type, public_ips, port_range) =
+            stun::stun_test_nat(stun_server.clone(), default_interface, &self.stun_health)?;
         if public_ips.is_empty() {
             Err(anyhow!("public_ips.is_empty"))?
         }
+        let test_local_ipv4 = local_ipv4.or(self.info.lock().local_ipv4);
+        let behavior =
+            match behavior::behavior_test(&stun_server, test_local_ipv4, default_interface) {
+                Ok(behavior) => {
+                    // 多个stun服务器得到不同映射时已经确定是对称网络，这里只会把锥形修正为对称
+                    if !behavior.mapping.is_unknown()
+                        && behavior.mapping != NatBehavior::EndpointIndependent
+                    {
+                        nat_type = NatType::Symmetric;
+                    }
+                    self.behavior_server.lock().replace(behavior.server);
+                    Some(behavior)
+                }
+                Err(e) => {
+                    log::info!("nat行为探测 {:?}", e);
+                    None
+                }
+            };
+        // 对称网络测量端口分配规律，用于打洞时预测端口
+        let port_model = if nat_type == NatType::Symmetric {
+            match port_model::port_model_test(&stun_server, default_interface) {
+                Ok(model) => {
+                    log::info!("端口分配模型 {:?}", model);
+                    model
+                }
+                Err(e) => {
+                    log::info!("端口分配模型探测 {:?}", e);
+                    PortModel::Unknown
+                }
+            }
+        } else {
+            PortModel::Unknown
+        };
         let mut guard = self.info.lock();
         guard.nat_type = nat_type;
+        guard.port_model = port_model;
+        if let Some(behavior) = behavior {
+            guard.mapping = behavior.mapping;
+            guard.filtering = behavior.filtering;
+            guard.hairpin = behavior.hairpin;
+        }
         guard.public_ips = public_ips;
         guard.public_port_range = port_range;
         if local_ipv4.is_some() {
@@ -273,12 +364,139 @@ impl NatTest {
 
         Ok(guard.clone())
     }
+    /// 测量一轮nat绑定存活时间，返回下一轮的等待时间，None表示不再测量
+    pub fn probe_binding_lifetime(&self, default_interface: &LocalInterface) -> Option<Duration> {
+        let Some(server) = *self.behavior_server.lock() else {
+            // 还没有找到支持RFC 5780的服务器，稍后再试
+            return Some(Duration::from_secs(60));
+        };
+        let mut guard = self.lifetime_probe.lock();
+        let probe = match guard.as_mut() {
+            Some(probe) if probe.server() == server => {
+                if let Err(e) = probe.probe(default_interface) {
+                    log::info!("nat绑定存活时间测量结束 {:?}", e);
+                    return None;
+                }
+                probe
+            }
+            _ => match LifetimeProbe::new(server, default_interface) {
+                Ok(probe) => guard.insert(probe),
+                Err(e) => {
+                    log::warn!("nat绑定存活时间测量 {:?}", e);
+                    return Some(Duration::from_secs(60));
+                }
+            },
+        };
+        let (lower, _) = probe.lifetime();
+        self.info.lock().binding_lifetime = lower;
+        probe.next_wait()
+    }
     #[cfg(feature = "upnp")]
     pub fn reset_upnp(&self) {
-        let local_ipv4 = self.info.lock().local_ipv4.clone();
+        let (local_ipv4, ipv6) = {
+            let guard = self.info.lock();
+            (guard.local_ipv4, guard.ipv6)
+        };
+        if let Some(local_ipv4) = local_ipv4 {
+            self.upnp.reset(local_ipv4, ipv6);
+            self.update_gateway_mapped();
+        }
+    }
+    /// 续期UPnP映射和ipv6防火墙规则
+    #[cfg(feature = "upnp")]
+    pub fn renew_upnp(&self) {
+        self.upnp.renew();
+        self.update_gateway_mapped();
+    }
+    /// 通过NAT-PMP/PCP映射监听端口
+    #[cfg(feature = "natpmp")]
+    pub fn reset_natpmp(&self) {
+        let local_ipv4 = self.info.lock().local_ipv4;
         if let Some(local_ipv4) = local_ipv4 {
-            self.upnp.reset(local_ipv4)
+            self.natpmp.reset(local_ipv4);
+            self.update_gateway_mapped();
+        }
+    }
+    /// 续期NAT-PMP/PCP映射，返回距离下一次续期的时间
+    #[cfg(feature = "natpmp")]
+    pub fn renew_natpmp(&self) -> Option<Duration> {
+        let wait = self.natpmp.renew();
+        self.update_gateway_mapped();
+        wait
+    }
+    #[cfg(feature = "natpmp")]
+    pub fn release_natpmp(&self) {
+        self.natpmp.release();
+        self.update_gateway_mapped();
+    }
+    /// 删除UPnP映射和ipv6防火墙规则
+    #[cfg(feature = "upnp")]
+    pub fn release_upnp(&self) {
+        self.upnp.release();
+        self.update_gateway_mapped();
+    }
+    /// 把网关确认的映射地址告诉对端，NAT-PMP/PCP排在前面
+    #[cfg(any(feature = "upnp", feature = "natpmp"))]
+    fn update_gateway_mapped(&self) {
+        let mut mapped_udp = Vec::new();
+        let mut mapped_tcp = None;
+        for mapping in self.gateway_mappings() {
+            let Some(SocketAddr::V4(external)) = mapping.external else {
+                continue;
+            };
+            if !mapping.verified {
+                continue;
+            }
+            if !mapping.tcp {
+                if !mapped_udp.contains(&external) {
+                    mapped_udp.push(external);
+                }
+            } else if mapped_tcp.is_none() {
+                mapped_tcp = Some(external);
+            }
+        }
+        let mut guard = self.info.lock();
+        guard.mapped_udp = mapped_udp;
+        guard.mapped_tcp = mapped_tcp;
+    }
+    /// 更新TURN服务器分配的中继地址
+    #[cfg(feature = "turn")]
+    pub fn update_relayed(&self, relayed: Vec<std::net::SocketAddrV4>) {
+        self.info.lock().relayed = relayed;
+    }
+    /// 网关端口映射的状态
+    pub fn gateway_mappings(&self) -> Vec<GatewayMapping> {
+        #[allow(unused_mut)]
+        let mut list = Vec::new();
+        #[cfg(feature = "natpmp")]
+        if let Some(protocol) = self.natpmp.protocol() {
+            let source = match protocol {
+                GatewayProtocol::Pcp => "PCP",
+                GatewayProtocol::NatPmp => "NAT-PMP",
+            };
+            for lease in self.natpmp.leases() {
+                list.push(GatewayMapping {
+                    source,
+                    tcp: lease.protocol == MappingProtocol::Tcp,
+                    port: lease.internal_port,
+                    external: Some(lease.external.into()),
+                    verified: true,
+                    error: None,
+                });
+            }
+        }
+        #[cfg(feature = "upnp")]
+        for mapping in self.upnp.mappings() {
+            list.push(GatewayMapping {
+                source: if mapping.ipv6 { "UPnP-IPv6" } else { "UPnP" },
+                tcp: mapping.is_tcp(),
+                port: mapping.port,
+                external: mapping.external,
+                verified: mapping.verified,
+                error: mapping.error,
+            });
         }
+        list
     }
     pub fn send_data(&self) -> anyhow::Result<(Vec<u8>, SocketAddr)> {
         let len = self.stun_server.len();
@@ -0,0 +1,97 @@
+//! 测量对称网络的公网端口分配规律，用于打洞时预测端口
+use std::net::{SocketAddr, ToSocketAddrs};
+
+use crate::channel::punch::PortModel;
+use crate::channel::socket::LocalInterface;
+use crate::nat::behavior::{new_socket, request};
+
+/// 新建socket的数量，用于观察不同socket之间的端口变化
+const SOCKET_SAMPLES: usize = 5;
+/// 步长超过该值视为随机分配
+const MAX_DELTA: i32 = 64;
+
+/// 用stun服务器测量端口分配规律，仅对称网络需要
+pub fn port_model_test(
+    stun_servers: &[String],
+    default_interface: &LocalInterface,
+) -> anyhow::Result<PortModel> {
+    let servers: Vec<SocketAddr> = stun_servers
+        .iter()
+        .filter_map(|v| v.to_socket_addrs().ok()?.find(|addr| addr.is_ipv4()))
+        .collect();
+    let Some(server) = servers.first().copied() else {
+        return Err(anyhow::anyhow!("no stun server"));
+    };
+    // 多个socket依次向同一个服务器请求
+    let mut preserve = true;
+    let mut ports = Vec::with_capacity(SOCKET_SAMPLES);
+    for _ in 0..SOCKET_SAMPLES {
+        let udp = new_socket(default_interface)?;
+        if let Some(response) = request(&udp, server, 0, None)? {
+            let local_port = udp.local_addr()?.port();
+            if response.mapped.port() != local_port {
+                preserve = false;
+            }
+            ports.push(response.mapped.port());
+        }
+    }
+    if ports.len() < 3 {
+        return Err(anyhow::anyhow!("stun response too few {}", ports.len()));
+    }
+    if preserve {
+        return Ok(PortModel::Preserve);
+    }
+    if let Some(delta) = stable_delta(&ports) {
+        return Ok(PortModel::Sequential(delta));
+    }
+    // 同一个socket依次请求不同的服务器
+    let udp = new_socket(default_interface)?;
+    let mut ports = Vec::with_capacity(servers.len());
+    for dest in servers.iter().copied().take(4) {
+        if let Some(response) = request(&udp, dest, 0, None)? {
+            ports.push(response.mapped.port());
+        }
+    }
+    if ports.len() >= 3 {
+        if let Some(delta) = stable_delta(&ports) {
+            return Ok(PortModel::PerDestination(delta));
+        }
+    }
+    log::info!("端口分配无规律 {:?}", ports);
+    Ok(PortModel::Random)
+}
+
+/// 相邻端口的差值基本一致时返回步长，允许一次被其他连接插入的跳变
+fn stable_delta(ports: &[u16]) -> Option<i32> {
+    let deltas: Vec<i32> = ports
+        .windows(2)
+        .map(|w| w[1] as i32 - w[0] as i32)
+        .collect();
+    let mut sorted = deltas.clone();
+    sorted.sort_unstable();
+    let median = sorted[sorted.len() / 2];
+    if median == 0 || median.abs() > MAX_DELTA {
+        return None;
+    }
+    let outliers = deltas
+        .iter()
+        .filter(|d| (**d - median).abs() > median.abs())
+        .count();
+    if outliers * 4 > deltas.len() {
+        return None;
+    }
+    Some(median)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::stable_delta;
+
+    #[test]
+    fn delta() {
+        assert_eq!(stable_delta(&[1000, 1001, 1002, 1003, 1004]), Some(1));
+        assert_eq!(stable_delta(&[1000, 1002, 1004, 1009, 1011]), Some(2));
+        assert_eq!(stable_delta(&[1000, 998, 996, 994]), Some(-2));
+        assert_eq!(stable_delta(&[1000, 31000, 2200, 50000, 433]), None);
+    }
+}
@@ -1,24 +1,62 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::io;
-use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
-use std::time::Duration;
+use std::io::{Read, Write};
+use std::net::{
+    Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, TcpStream, ToSocketAddrs,
+};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
 
 use crate::channel::punch::NatType;
-use crate::channel::socket::{bind_udp, LocalInterface};
+use crate::channel::socket::{bind_udp, LocalInterface, VntSocketTrait};
+use crate::nat::behavior::{build_request, new_tid, parse_response, CHANGE_IP, CHANGE_PORT};
+use crate::nat::StunHealth;
 use rand::RngCore;
 use std::net::UdpSocket;
 use stun_format::Attr;
 
+// 一轮探测的总时长，不可用的服务器不会拖慢启动
+const PROBE_TIMEOUT: Duration = Duration::from_millis(1500);
+// 没有响应时重发请求的间隔
+const RETRANSMIT_INTERVAL: Duration = Duration::from_millis(400);
+// 域名解析的总时长，超时的服务器本轮不使用
+const RESOLVE_TIMEOUT: Duration = Duration::from_secs(1);
+
 pub fn stun_test_nat(
     stun_servers: Vec<String>,
     default_interface: &LocalInterface,
+    health: &StunHealth,
 ) -> anyhow::Result<(NatType, Vec<Ipv4Addr>, u16)> {
+    let servers = resolve(&stun_servers, health);
     let mut nat_type = NatType::Cone;
     let mut port_range = 0;
     let mut hash_set = HashSet::new();
-    for _ in 0..2 {
-        let stun_servers = stun_servers.clone();
-        match stun_test_nat0(stun_servers, default_interface) {
+    // udp全部无响应时由tcp探测记录健康度，每个服务器每轮只记录一次
+    let mut udp_failed = Vec::new();
+    // 两个socket同时探测所有服务器
+    let results: Vec<_> = thread::scope(|s| {
+        let handles: Vec<_> = (0..2)
+            .map(|_| {
+                s.spawn(|| {
+                    let mut results = vec![ServerResult::default(); servers.len()];
+                    let rs = stun_test_nat0(&servers, default_interface, &mut results);
+                    (rs, results)
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|h| {
+                h.join()
+                    .unwrap_or_else(|_| (Err(anyhow::anyhow!("stun panic")), Vec::new()))
+            })
+            .collect()
+    });
+    // 两个socket的结果合并后，每个服务器每轮只记录一次健康度
+    let mut server_results: Option<Vec<ServerResult>> = None;
+    for (rs, results) in results {
+        match rs {
             Ok((nat_type_t, ip_list_t, port_range_t)) => {
                 if nat_type_t == NatType::Symmetric {
                     nat_type = NatType::Symmetric;
@@ -29,38 +67,263 @@ pub fn stun_test_nat(
                 if port_range < port_range_t {
                     port_range = port_range_t;
                 }
+                match &mut server_results {
+                    Some(merged) => {
+                        for (merged, result) in merged.iter_mut().zip(results) {
+                            merged.merge(result);
+                        }
+                    }
+                    None => server_results = Some(results),
+                }
             }
             Err(e) => {
                 log::warn!("{:?}", e);
             }
         }
     }
+    if let Some(server_results) = server_results {
+        for ((server, _), result) in servers.iter().zip(server_results) {
+            match result.latency {
+                Some(latency) => health.record_success(server, latency, false),
+                None => {
+                    log::warn!("stun {} 超时", server);
+                    udp_failed.push(server.clone());
+                }
+            }
+            if let Some(change_request) = result.change_request {
+                health.record_change_request(server, change_request);
+            }
+        }
+    } else {
+        udp_failed.extend(servers.iter().map(|(server, _)| server.clone()));
+    }
+    if hash_set.is_empty() && !servers.is_empty() {
+        // udp不通时通过tcp获取公网ip
+        let ips = stun_test_tcp(&servers, default_interface, health);
+        log::info!("udp stun无响应,tcp stun公网ip {:?}", ips);
+        hash_set.extend(ips);
+    } else {
+        for server in &udp_failed {
+            health.record_failure(server);
+        }
+    }
+    health.save();
     Ok((nat_type, hash_set.into_iter().collect(), port_range))
 }
 
-pub fn stun_test_nat0(
-    stun_servers: Vec<String>,
+/// 并行解析服务器地址，只使用ipv4。
+/// 系统解析没有超时设置，超过RESOLVE_TIMEOUT的解析线程不再等待，当作解析失败
+fn resolve(stun_servers: &[String], health: &StunHealth) -> Vec<(String, SocketAddr)> {
+    let (sender, receiver) = mpsc::channel();
+    for (index, server) in stun_servers.iter().enumerate() {
+        let sender = sender.clone();
+        let server = server.clone();
+        if let Err(e) = thread::Builder::new()
+            .name("stunResolve".into())
+            .spawn(move || {
+                let addr = server
+                    .to_socket_addrs()
+                    .ok()
+                    .and_then(|mut v| v.find(|addr| addr.is_ipv4()));
+                let _ = sender.send((index, addr));
+            })
+        {
+            log::warn!("stun解析线程启动失败 {:?}", e);
+        }
+    }
+    drop(sender);
+    let mut addrs = vec![None; stun_servers.len()];
+    let deadline = Instant::now() + RESOLVE_TIMEOUT;
+    while let Some(timeout) = deadline.checked_duration_since(Instant::now()) {
+        match receiver.recv_timeout(timeout) {
+            Ok((index, addr)) => addrs[index] = addr,
+            // 全部完成或超时
+            Err(_) => break,
+        }
+    }
+    stun_servers
+        .iter()
+        .zip(addrs)
+        .filter_map(|(server, addr)| match addr {
+            Some(addr) => Some((server.clone(), addr)),
+            None => {
+                log::warn!("stun {} 地址解析失败或超时", server);
+                health.record_failure(server);
+                None
+            }
+        })
+        .collect()
+}
+
+/// 一轮探测中单个服务器的结果
+#[derive(Clone, Copy, Default)]
+struct ServerResult {
+    // 最快的响应延迟，None表示没有响应
+    latency: Option<Duration>,
+    // 服务器是否按CHANGE-REQUEST从另一个地址响应，None表示没有探测
+    change_request: Option<bool>,
+}
+
+impl ServerResult {
+    fn merge(&mut self, other: ServerResult) {
+        self.latency = match (self.latency, other.latency) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        // 任意一个socket收到变更地址后的响应就算支持
+        self.change_request = self.change_request.max(other.change_request);
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum ProbeKind {
+    // 普通的绑定请求
+    Binding,
+    // 发往服务器的备用地址(OTHER-ADDRESS/CHANGED-ADDRESS)，判断是否为对称网络
+    Alternate,
+    // 带CHANGE-REQUEST发往服务器主地址，期望从备用地址响应。
+    // 在备用地址有响应之后才发送，nat已为备用地址打开映射，没有响应说明服务器不支持
+    ChangeRequest,
+}
+
+struct Probe {
+    index: usize,
+    addr: SocketAddr,
+    start: Instant,
+    last_send: Instant,
+    kind: ProbeKind,
+}
+
+impl Probe {
+    fn new(index: usize, addr: SocketAddr, kind: ProbeKind) -> Self {
+        let now = Instant::now();
+        Self {
+            index,
+            addr,
+            start: now,
+            last_send: now,
+            kind,
+        }
+    }
+    fn change(&self) -> u32 {
+        if self.kind == ProbeKind::ChangeRequest {
+            CHANGE_IP | CHANGE_PORT
+        } else {
+            0
+        }
+    }
+}
+
+fn stun_test_nat0(
+    servers: &[(String, SocketAddr)],
     default_interface: &LocalInterface,
+    results: &mut [ServerResult],
 ) -> anyhow::Result<(NatType, Vec<Ipv4Addr>, u16)> {
     let udp = bind_udp("0.0.0.0:0".parse().unwrap(), default_interface)?;
     udp.set_nonblocking(false)?;
     let udp: UdpSocket = udp.into();
-    udp.set_read_timeout(Some(Duration::from_millis(500)))?;
     let mut nat_type = NatType::Cone;
     let mut min_port = u16::MAX;
     let mut max_port = 0;
     let mut hash_set = HashSet::new();
     let mut pub_addrs = HashSet::new();
-    for x in &stun_servers {
-        match test_nat(&udp, x) {
-            Ok(addr) => {
-                pub_addrs.extend(addr);
+    let mut pending: HashMap<u128, Probe> = HashMap::new();
+    for (index, (_, addr)) in servers.iter().enumerate() {
+        let tid = new_tid();
+        if let Err(e) = udp.send_to(&build_request(tid, 0, None), addr) {
+            log::warn!("stun {} error {:?} ", addr, e);
+        }
+        pending.insert(tid, Probe::new(index, *addr, ProbeKind::Binding));
+    }
+    let start = Instant::now();
+    let mut buf = [0u8; 1500];
+    while !pending.is_empty() {
+        let elapsed = start.elapsed();
+        if elapsed >= PROBE_TIMEOUT {
+            break;
+        }
+        for (tid, probe) in pending.iter_mut() {
+            if probe.last_send.elapsed() >= RETRANSMIT_INTERVAL {
+                let _ = udp.send_to(&build_request(*tid, probe.change(), None), probe.addr);
+                probe.last_send = Instant::now();
             }
+        }
+        let timeout = RETRANSMIT_INTERVAL.min(PROBE_TIMEOUT - elapsed);
+        udp.set_read_timeout(Some(timeout.max(Duration::from_millis(1))))?;
+        let (len, from) = match udp.recv_from(&mut buf) {
+            Ok(rs) => rs,
             Err(e) => {
-                log::warn!("stun {} error {:?} ", x, e);
+                if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut {
+                    continue;
+                }
+                log::warn!("stun recv {:?}", e);
+                continue;
+            }
+        };
+        let Some(tid) = stun_format::Msg::from(&buf[..len]).tid() else {
+            continue;
+        };
+        let Some(response) = parse_response(&buf[..len], tid) else {
+            continue;
+        };
+        let Some(probe) = pending.remove(&tid) else {
+            continue;
+        };
+        let (mapped_addr, changed_addr) = (response.mapped, response.other);
+        let server = &servers[probe.index].0;
+        log::info!(
+            "stun {} mapped_addr {:?} changed_addr {:?} from {} {:?}",
+            server,
+            mapped_addr,
+            changed_addr,
+            from,
+            probe.kind
+        );
+        match probe.kind {
+            ProbeKind::Binding => {
+                if mapped_addr.is_ipv4() {
+                    pub_addrs.insert(mapped_addr);
+                }
+                results[probe.index].latency = Some(probe.start.elapsed());
+                match changed_addr {
+                    Some(changed_addr)
+                        if changed_addr.is_ipv4() && changed_addr.ip() != from.ip() =>
+                    {
+                        // 从不同的目的ip再探测一次，判断是否为对称网络
+                        let tid = new_tid();
+                        let _ = udp.send_to(&build_request(tid, 0, None), changed_addr);
+                        pending.insert(
+                            tid,
+                            Probe::new(probe.index, changed_addr, ProbeKind::Alternate),
+                        );
+                    }
+                    // 没有备用地址的服务器不支持CHANGE-REQUEST
+                    _ => results[probe.index].change_request = Some(false),
+                }
+            }
+            ProbeKind::Alternate => {
+                if mapped_addr.is_ipv4() {
+                    pub_addrs.insert(mapped_addr);
+                }
+                let primary = servers[probe.index].1;
+                let tid = new_tid();
+                let _ = udp.send_to(&build_request(tid, CHANGE_IP | CHANGE_PORT, None), primary);
+                pending.insert(
+                    tid,
+                    Probe::new(probe.index, primary, ProbeKind::ChangeRequest),
+                );
+            }
+            ProbeKind::ChangeRequest => {
+                // 忽略CHANGE-REQUEST的服务器会从主地址响应
+                results[probe.index].change_request = Some(from.ip() != probe.addr.ip());
             }
         }
     }
+    for probe in pending.values() {
+        if probe.kind == ProbeKind::ChangeRequest {
+            results[probe.index].change_request = Some(false);
+        }
+    }
     if pub_addrs.len() > 1 {
         nat_type = NatType::Symmetric;
     }
@@ -86,97 +349,73 @@ pub fn stun_test_nat0(
     }
 }
 
-fn test_nat(udp: &UdpSocket, stun_server: &String) -> io::Result<HashSet<SocketAddr>> {
-    udp.connect(stun_server)?;
-    let tid = rand::thread_rng().next_u64() as u128;
-    let mut addr = HashSet::new();
-    let (mapped_addr1, changed_addr1) = test_nat_(&udp, stun_server, true, true, tid)?;
-    if mapped_addr1.is_ipv4() {
-        addr.insert(mapped_addr1);
-    }
-    if let Some(changed_addr1) = changed_addr1 {
-        if udp.connect(changed_addr1).is_ok() {
-            match test_nat_(&udp, stun_server, false, false, tid + 1) {
-                Ok((mapped_addr2, _)) => {
-                    if mapped_addr2.is_ipv4() {
-                        addr.insert(mapped_addr1);
+/// 通过tcp连接stun服务器获取公网ip，用于udp被封锁的网络
+fn stun_test_tcp(
+    servers: &[(String, SocketAddr)],
+    default_interface: &LocalInterface,
+    health: &StunHealth,
+) -> Vec<Ipv4Addr> {
+    thread::scope(|s| {
+        let handles: Vec<_> = servers
+            .iter()
+            .map(|(server, addr)| {
+                s.spawn(move || {
+                    let start = Instant::now();
+                    let rs = tcp_binding(*addr, default_interface);
+                    match &rs {
+                        Ok(_) => health.record_success(server, start.elapsed(), true),
+                        Err(e) => {
+                            log::warn!("tcp stun {} error {:?}", server, e);
+                            health.record_failure(server);
+                        }
                     }
-                }
-                Err(e) => {
-                    log::warn!("stun {} error {:?} ", stun_server, e);
-                }
+                    rs
+                })
+            })
+            .collect();
+        let mut ips = HashSet::new();
+        for h in handles {
+            if let Ok(Ok(SocketAddr::V4(addr))) = h.join() {
+                ips.insert(*addr.ip());
             }
         }
-    }
-    log::info!(
-        "stun {} mapped_addr {:?}  changed_addr {:?}",
-        stun_server,
-        addr,
-        changed_addr1,
-    );
-
-    Ok(addr)
+        ips.into_iter().collect()
+    })
 }
 
-fn test_nat_(
-    udp: &UdpSocket,
-    stun_server: &String,
-    change_ip: bool,
-    change_port: bool,
-    tid: u128,
-) -> io::Result<(SocketAddr, Option<SocketAddr>)> {
-    for _ in 0..2 {
-        let mut buf = [0u8; 28];
-        let mut msg = stun_format::MsgBuilder::from(buf.as_mut_slice());
-        msg.typ(stun_format::MsgType::BindingRequest);
-        msg.tid(tid);
-        msg.add_attr(Attr::ChangeRequest {
-            change_ip,
-            change_port,
-        });
-        udp.send(msg.as_bytes())?;
-        let mut buf = [0; 10240];
-        let (len, _addr) = match udp.recv_from(&mut buf) {
-            Ok(rs) => rs,
-            Err(e) => {
-                log::warn!("stun {} error {:?}", stun_server, e);
-                continue;
-            }
-        };
-        let msg = stun_format::Msg::from(&buf[..len]);
-        let mut mapped_addr = None;
-        let mut changed_addr = None;
-        for x in msg.attrs_iter() {
-            match x {
-                Attr::MappedAddress(addr) => {
-                    if mapped_addr.is_none() {
-                        let _ = mapped_addr.insert(stun_addr(addr));
-                    }
-                }
-                Attr::ChangedAddress(addr) => {
-                    if changed_addr.is_none() {
-                        let _ = changed_addr.insert(stun_addr(addr));
-                    }
-                }
-                Attr::XorMappedAddress(addr) => {
-                    if mapped_addr.is_none() {
-                        let _ = mapped_addr.insert(stun_addr(addr));
-                    }
-                }
-                _ => {}
-            }
-            if changed_addr.is_some() && mapped_addr.is_some() {
-                return Ok((mapped_addr.unwrap(), changed_addr));
-            }
-        }
-        if let Some(addr) = mapped_addr {
-            return Ok((addr, changed_addr));
-        }
+fn tcp_binding(addr: SocketAddr, default_interface: &LocalInterface) -> io::Result<SocketAddr> {
+    let socket = socket2::Socket::new(
+        socket2::Domain::IPV4,
+        socket2::Type::STREAM,
+        Some(socket2::Protocol::TCP),
+    )?;
+    if let Err(e) = socket.set_ip_unicast_if(default_interface) {
+        log::warn!("set_ip_unicast_if {:?}", e)
+    }
+    socket.connect_timeout(&addr.into(), PROBE_TIMEOUT)?;
+    let mut stream: TcpStream = socket.into();
+    stream.set_read_timeout(Some(PROBE_TIMEOUT))?;
+    stream.set_write_timeout(Some(PROBE_TIMEOUT))?;
+    let tid = new_tid();
+    stream.write_all(&build_request(tid, 0, None))?;
+    // tcp上的stun消息没有分帧，根据头部的长度读取
+    let mut buf = [0u8; 1500];
+    stream.read_exact(&mut buf[..20])?;
+    let len = 20 + u16::from_be_bytes([buf[2], buf[3]]) as usize;
+    if len > buf.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "stun response too long",
+        ));
+    }
+    stream.read_exact(&mut buf[20..len])?;
+    match parse_response(&buf[..len], tid) {
+        Some(response) => Ok(response.mapped),
+        None => Err(io::Error::new(io::ErrorKind::Other, "stun response err")),
     }
-    Err(io::Error::new(io::ErrorKind::Other, "stun response err"))
 }
 
-fn stun_addr(addr: stun_format::SocketAddr) -> SocketAddr {
+pub(super) fn stun_addr(addr: stun_format::SocketAddr) -> SocketAddr {
     match addr {
         stun_format::SocketAddr::V4(ip, port) => {
             SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::from(ip), port))
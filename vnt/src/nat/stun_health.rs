@@ -0,0 +1,182 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use parking_lot::Mutex;
+
+const FILE_NAME: &str = "stun-health";
+// 没探测过的服务器按这个延迟排序，排在失败的服务器前面
+const UNKNOWN_LATENCY_MS: u32 = 250;
+// 每次连续失败增加的分数，相当于延迟增加1秒
+const FAILURE_PENALTY: u64 = 1000;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct ServerScore {
+    // 平滑后的响应延迟
+    latency_ms: u32,
+    // 累计成功次数
+    success: u32,
+    // 连续失败次数，成功后清零
+    failure: u32,
+    // 服务器按CHANGE-REQUEST从另一个地址响应，可用于nat行为探测，None表示没有探测
+    change_request: Option<bool>,
+    // 只有tcp能访问
+    tcp: bool,
+}
+
+impl ServerScore {
+    /// 分数越低越优先
+    fn score(&self) -> u64 {
+        let latency = if self.success == 0 {
+            UNKNOWN_LATENCY_MS
+        } else {
+            self.latency_ms
+        };
+        let mut score = latency as u64 + self.failure.min(10) as u64 * FAILURE_PENALTY;
+        if self.change_request == Some(true) {
+            score = score.saturating_sub(50);
+        }
+        score
+    }
+}
+
+/// stun服务器的健康度，探测时优先使用延迟低、可用的服务器，结果保存到文件下次启动使用
+#[derive(Clone, Default)]
+pub struct StunHealth {
+    scores: Arc<Mutex<HashMap<String, ServerScore>>>,
+    path: Option<PathBuf>,
+}
+
+impl StunHealth {
+    pub fn load(dir: Option<PathBuf>) -> Self {
+        let path = dir.map(|v| v.join(FILE_NAME));
+        let mut scores = HashMap::new();
+        if let Some(path) = &path {
+            if let Ok(text) = std::fs::read_to_string(path) {
+                for line in text.lines() {
+                    if let Some((server, score)) = parse_line(line) {
+                        scores.insert(server, score);
+                    }
+                }
+            }
+        }
+        Self {
+            scores: Arc::new(Mutex::new(scores)),
+            path,
+        }
+    }
+    pub fn save(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let mut text = String::new();
+        for (server, score) in self.scores.lock().iter() {
+            let change_request = match score.change_request {
+                None => "-",
+                Some(true) => "1",
+                Some(false) => "0",
+            };
+            text.push_str(&format!(
+                "{} {} {} {} {} {}\n",
+                server,
+                score.latency_ms,
+                score.success,
+                score.failure,
+                change_request,
+                score.tcp as u8
+            ));
+        }
+        if let Err(e) = std::fs::write(path, text) {
+            log::warn!("保存stun服务器健康度失败 {:?} {:?}", path, e);
+        }
+    }
+    /// 按健康度排序，分数相同时保持配置的顺序
+    pub fn order(&self, servers: &[String]) -> Vec<String> {
+        let scores = self.scores.lock();
+        let mut list = servers.to_vec();
+        list.sort_by_key(|server| scores.get(server).copied().unwrap_or_default().score());
+        list
+    }
+    pub fn record_success(&self, server: &str, latency: Duration, tcp: bool) {
+        let latency = latency.as_millis().min(u32::MAX as u128) as u32;
+        let mut scores = self.scores.lock();
+        let score = scores.entry(server.to_string()).or_default();
+        score.latency_ms = if score.success == 0 {
+            latency
+        } else {
+            (score.latency_ms * 3 + latency) / 4
+        };
+        score.success = score.success.saturating_add(1);
+        score.failure = 0;
+        score.tcp = tcp;
+    }
+    pub fn record_failure(&self, server: &str) {
+        let mut scores = self.scores.lock();
+        let score = scores.entry(server.to_string()).or_default();
+        score.failure = score.failure.saturating_add(1);
+    }
+    pub fn record_change_request(&self, server: &str, supported: bool) {
+        let mut scores = self.scores.lock();
+        scores.entry(server.to_string()).or_default().change_request = Some(supported);
+    }
+}
+
+fn parse_line(line: &str) -> Option<(String, ServerScore)> {
+    let mut split = line.split_whitespace();
+    let server = split.next()?.to_string();
+    let latency_ms = split.next()?.parse().ok()?;
+    let success = split.next()?.parse().ok()?;
+    let failure = split.next()?.parse().ok()?;
+    let change_request = match split.next()? {
+        "1" => Some(true),
+        "0" => Some(false),
+        _ => None,
+    };
+    let tcp = split.next()? == "1";
+    Some((
+        server,
+        ServerScore {
+            latency_ms,
+            success,
+            failure,
+            change_request,
+            tcp,
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn order() {
+        let health = StunHealth::default();
+        let servers = vec![
+            "dead.example:3478".to_string(),
+            "new.example:3478".to_string(),
+            "fast.example:3478".to_string(),
+        ];
+        health.record_failure("dead.example:3478");
+        health.record_success("fast.example:3478", Duration::from_millis(30), false);
+        assert_eq!(
+            health.order(&servers),
+            vec![
+                "fast.example:3478".to_string(),
+                "new.example:3478".to_string(),
+                "dead.example:3478".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn line() {
+        let (server, score) = parse_line("stun.example:3478 42 3 0 1 0").unwrap();
+        assert_eq!(server, "stun.example:3478");
+        assert_eq!(score.latency_ms, 42);
+        assert_eq!(score.change_request, Some(true));
+        assert!(!score.tcp);
+        assert!(parse_line("stun.example:3478 42").is_none());
+    }
+}
@@ -0,0 +1,248 @@
+use std::net::Ipv4Addr;
+
+const TTL: u32 = 60;
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const CLASS_IN: u16 = 1;
+const RCODE_NXDOMAIN: u8 = 3;
+
+/// 设备名转成域名标签，只保留字母数字和'-'
+pub fn label(name: &str) -> String {
+    let label: String = name
+        .trim()
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_lowercase()
+            } else {
+                '-'
+            }
+        })
+        .collect();
+    label.trim_matches('-').to_string()
+}
+
+/// 虚拟网段的反向解析域，按字节对齐
+pub fn reverse_zone(network: Ipv4Addr, netmask: Ipv4Addr) -> Option<String> {
+    let len = (u32::from(netmask).count_ones() / 8) as usize;
+    if len == 0 {
+        return None;
+    }
+    let octets = network.octets();
+    let mut zone: Vec<String> = octets[..len.min(4)]
+        .iter()
+        .rev()
+        .map(|v| v.to_string())
+        .collect();
+    zone.push("in-addr.arpa".into());
+    Some(zone.join("."))
+}
+
+/// 解析唯一的问题，返回(域名,类型,问题结束位置)
+fn question(query: &[u8]) -> Option<(String, u16, usize)> {
+    if query.len() < 12 {
+        return None;
+    }
+    // 只处理标准查询
+    if query[2] & 0xf8 != 0 || u16::from_be_bytes([query[4], query[5]]) != 1 {
+        return None;
+    }
+    let mut labels = Vec::new();
+    let mut pos = 12;
+    loop {
+        let len = *query.get(pos)? as usize;
+        pos += 1;
+        if len == 0 {
+            break;
+        }
+        // 查询里不会有压缩指针
+        if len & 0xc0 != 0 {
+            return None;
+        }
+        labels.push(String::from_utf8_lossy(query.get(pos..pos + len)?).to_ascii_lowercase());
+        pos += len;
+    }
+    let qtype = u16::from_be_bytes([*query.get(pos)?, *query.get(pos + 1)?]);
+    let qclass = u16::from_be_bytes([*query.get(pos + 2)?, *query.get(pos + 3)?]);
+    if qclass != CLASS_IN {
+        return None;
+    }
+    Some((labels.join("."), qtype, pos + 4))
+}
+
+fn ptr_ip(name: &str) -> Option<Ipv4Addr> {
+    let name = name.strip_suffix(".in-addr.arpa")?;
+    let mut octets = [0u8; 4];
+    let mut count = 0;
+    for (i, v) in name.split('.').enumerate() {
+        if i >= 4 {
+            return None;
+        }
+        octets[3 - i] = v.parse().ok()?;
+        count += 1;
+    }
+    if count != 4 {
+        return None;
+    }
+    Some(Ipv4Addr::from(octets))
+}
+
+fn encode_name(name: &str) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(name.len() + 2);
+    for label in name.split('.').filter(|v| !v.is_empty()) {
+        buf.push(label.len() as u8);
+        buf.extend_from_slice(label.as_bytes());
+    }
+    buf.push(0);
+    buf
+}
+
+fn response(
+    query: &[u8],
+    question_end: usize,
+    rcode: u8,
+    answer: Option<(u16, Vec<u8>)>,
+) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(question_end + 64);
+    buf.extend_from_slice(&query[..2]);
+    // QR=1 AA=1，保留RD
+    buf.push(0x84 | (query[2] & 0x01));
+    // RA=1
+    buf.push(0x80 | rcode);
+    buf.extend_from_slice(&1u16.to_be_bytes());
+    buf.extend_from_slice(&(answer.is_some() as u16).to_be_bytes());
+    buf.extend_from_slice(&[0, 0, 0, 0]);
+    buf.extend_from_slice(&query[12..question_end]);
+    if let Some((rtype, rdata)) = answer {
+        // 指向问题中的域名
+        buf.extend_from_slice(&[0xc0, 0x0c]);
+        buf.extend_from_slice(&rtype.to_be_bytes());
+        buf.extend_from_slice(&CLASS_IN.to_be_bytes());
+        buf.extend_from_slice(&TTL.to_be_bytes());
+        buf.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        buf.extend_from_slice(&rdata);
+    }
+    buf
+}
+
+/// 应答虚拟网络内的域名和反向解析，返回None表示需要转发到上游
+pub fn answer(
+    query: &[u8],
+    suffix: &str,
+    network: Ipv4Addr,
+    netmask: Ipv4Addr,
+    records: &[(String, Ipv4Addr)],
+) -> Option<Vec<u8>> {
+    let (name, qtype, end) = question(query)?;
+    let host = if name == suffix {
+        Some("")
+    } else {
+        name.strip_suffix(suffix).and_then(|v| v.strip_suffix('.'))
+    };
+    if let Some(host) = host {
+        if host.is_empty() {
+            return Some(response(query, end, 0, None));
+        }
+        let ip = records
+            .iter()
+            .find(|(label, _)| label == host)
+            .map(|(_, ip)| *ip);
+        return Some(match ip {
+            None => response(query, end, RCODE_NXDOMAIN, None),
+            Some(ip) if qtype == TYPE_A => {
+                response(query, end, 0, Some((TYPE_A, ip.octets().to_vec())))
+            }
+            // 没有其他类型的记录
+            Some(_) => response(query, end, 0, None),
+        });
+    }
+    // 只应答虚拟网段内地址的反向解析，反向解析域内的其他名字直接发给上游，不会再回到本机
+    let mask = u32::from(netmask);
+    let ip = ptr_ip(&name).filter(|ip| u32::from(*ip) & mask == u32::from(network) & mask)?;
+    let label = records
+        .iter()
+        .find(|(label, v)| !label.is_empty() && *v == ip)
+        .map(|(label, _)| label);
+    Some(match label {
+        None => response(query, end, RCODE_NXDOMAIN, None),
+        Some(label) if qtype == TYPE_PTR => {
+            let rdata = encode_name(&format!("{}.{}", label, suffix));
+            response(query, end, 0, Some((TYPE_PTR, rdata)))
+        }
+        Some(_) => response(query, end, 0, None),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(name: &str, qtype: u16) -> Vec<u8> {
+        let mut buf = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        buf.extend_from_slice(&encode_name(name));
+        buf.extend_from_slice(&qtype.to_be_bytes());
+        buf.extend_from_slice(&CLASS_IN.to_be_bytes());
+        buf
+    }
+
+    #[test]
+    fn overlay_answer() {
+        let network = Ipv4Addr::new(10, 26, 0, 0);
+        let netmask = Ipv4Addr::new(255, 255, 255, 0);
+        let records = vec![
+            (label("My PC"), Ipv4Addr::new(10, 26, 0, 2)),
+            (label("nas"), Ipv4Addr::new(10, 26, 0, 3)),
+        ];
+        assert_eq!(records[0].0, "my-pc");
+        let answer =
+            |name: &str, qtype: u16| answer(&query(name, qtype), "vnt", network, netmask, &records);
+
+        let rs = answer("NAS.vnt", TYPE_A).unwrap();
+        assert_eq!(&rs[..2], &[0x12, 0x34]);
+        assert_eq!(rs[3] & 0x0f, 0);
+        assert_eq!(u16::from_be_bytes([rs[6], rs[7]]), 1);
+        assert_eq!(&rs[rs.len() - 4..], &[10, 26, 0, 3]);
+        // 没有的设备
+        let rs = answer("tv.vnt", TYPE_A).unwrap();
+        assert_eq!(rs[3] & 0x0f, RCODE_NXDOMAIN);
+        // 有设备但没有aaaa记录
+        let rs = answer("nas.vnt", 28).unwrap();
+        assert_eq!(rs[3] & 0x0f, 0);
+        assert_eq!(u16::from_be_bytes([rs[6], rs[7]]), 0);
+
+        let rs = answer("2.0.26.10.in-addr.arpa", TYPE_PTR).unwrap();
+        assert!(rs.ends_with(&encode_name("my-pc.vnt")));
+        let rs = answer("9.0.26.10.in-addr.arpa", TYPE_PTR).unwrap();
+        assert_eq!(rs[3] & 0x0f, RCODE_NXDOMAIN);
+
+        // 其他域名转发
+        assert!(answer("example.com", TYPE_A).is_none());
+        assert!(answer("1.1.168.192.in-addr.arpa", TYPE_PTR).is_none());
+        assert_eq!(
+            reverse_zone(network, netmask).unwrap(),
+            "0.26.10.in-addr.arpa"
+        );
+    }
+
+    #[test]
+    fn reverse_zone_forward() {
+        // /20的反向解析域是26.10.in-addr.arpa，比网段大
+        let network = Ipv4Addr::new(10, 26, 0, 0);
+        let netmask = Ipv4Addr::new(255, 255, 240, 0);
+        let records = vec![(label("nas"), Ipv4Addr::new(10, 26, 0, 3))];
+        let answer =
+            |name: &str, qtype: u16| answer(&query(name, qtype), "vnt", network, netmask, &records);
+
+        let rs = answer("3.0.26.10.in-addr.arpa", TYPE_PTR).unwrap();
+        assert!(rs.ends_with(&encode_name("nas.vnt")));
+        // 网段内没有的设备
+        let rs = answer("9.15.26.10.in-addr.arpa", TYPE_PTR).unwrap();
+        assert_eq!(rs[3] & 0x0f, RCODE_NXDOMAIN);
+        // 域内但不在网段内的转发
+        assert!(answer("1.200.26.10.in-addr.arpa", TYPE_PTR).is_none());
+        assert!(answer("3.0.26.26.10.in-addr.arpa", TYPE_PTR).is_none());
+        assert!(answer("200.26.10.in-addr.arpa", TYPE_PTR).is_none());
+        assert!(answer("26.10.in-addr.arpa", TYPE_PTR).is_none());
+        assert!(answer("1.0.27.10.in-addr.arpa", TYPE_PTR).is_none());
+    }
+}
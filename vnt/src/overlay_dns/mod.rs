@@ -0,0 +1,248 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context;
+use crossbeam_utils::atomic::AtomicCell;
+use parking_lot::Mutex;
+use tokio::net::UdpSocket;
+use tokio::sync::Semaphore;
+
+use crate::handle::{CurrentDeviceInfo, PeerDeviceInfo};
+use crate::util::VntRuntime;
+
+mod message;
+
+// 同时转发到上游的请求数，超过时丢弃，客户端会重试
+const MAX_FORWARDING: usize = 64;
+
+/// 虚拟网络内的dns，在本机虚拟ip的53端口把`<设备名>.<后缀>`解析成虚拟ip，
+/// 同时应答虚拟网段的反向解析，其他请求转发到上游
+#[derive(Clone)]
+pub struct OverlayDns {
+    suffix: Arc<String>,
+    name: Arc<String>,
+    upstream: Arc<Vec<SocketAddr>>,
+    // 上游是出口节点的dns，网卡上需要接管所有域名
+    #[cfg(feature = "integrated_tun")]
+    #[cfg(target_os = "linux")]
+    exit: bool,
+    runtime: VntRuntime,
+    current_device: Arc<AtomicCell<CurrentDeviceInfo>>,
+    device_map: Arc<Mutex<(u16, HashMap<Ipv4Addr, PeerDeviceInfo>)>>,
+    // 正在监听的虚拟ip
+    listen_ip: Arc<AtomicCell<Ipv4Addr>>,
+    forwarding: Arc<Semaphore>,
+}
+
+impl OverlayDns {
+    pub fn new(
+        suffix: String,
+        name: String,
+        name_servers: &[String],
+        exit_dns: &[Ipv4Addr],
+        runtime: VntRuntime,
+        current_device: Arc<AtomicCell<CurrentDeviceInfo>>,
+        device_map: Arc<Mutex<(u16, HashMap<Ipv4Addr, PeerDeviceInfo>)>>,
+    ) -> Self {
+        Self {
+            suffix: Arc::new(suffix),
+            name: Arc::new(name),
+            upstream: Arc::new(upstream(name_servers, exit_dns)),
+            #[cfg(feature = "integrated_tun")]
+            #[cfg(target_os = "linux")]
+            exit: !exit_dns.is_empty(),
+            runtime,
+            current_device,
+            device_map,
+            listen_ip: Arc::new(AtomicCell::new(Ipv4Addr::UNSPECIFIED)),
+            forwarding: Arc::new(Semaphore::new(MAX_FORWARDING)),
+        }
+    }
+    #[cfg(feature = "integrated_tun")]
+    #[cfg(target_os = "linux")]
+    pub fn listen_ip(&self) -> Ipv4Addr {
+        self.listen_ip.load()
+    }
+    /// 虚拟ip变化后在新的地址上监听，返回新监听的地址
+    pub fn listen(&self) -> anyhow::Result<Option<Ipv4Addr>> {
+        let ip = self.current_device.load().virtual_ip;
+        if ip.is_unspecified() || self.listen_ip.load() == ip {
+            return Ok(None);
+        }
+        let socket = std::net::UdpSocket::bind(SocketAddrV4::new(ip, 53))
+            .with_context(|| format!("overlay dns bind {}:53", ip))?;
+        socket.set_nonblocking(true)?;
+        // 旧地址上的监听会自行退出
+        self.listen_ip.store(ip);
+        let dns = self.clone();
+        self.runtime.spawn(async move {
+            match UdpSocket::from_std(socket) {
+                Ok(socket) => serve(socket, ip, dns).await,
+                Err(e) => {
+                    log::warn!("overlay dns {:?}", e);
+                    let _ = dns.listen_ip.compare_exchange(ip, Ipv4Addr::UNSPECIFIED);
+                }
+            }
+        });
+        Ok(Some(ip))
+    }
+    /// 网卡上需要交给本机dns解析的域
+    #[cfg(feature = "integrated_tun")]
+    #[cfg(target_os = "linux")]
+    pub fn routing_domains(&self) -> Vec<String> {
+        let current_device = self.current_device.load();
+        let mut domains = vec![format!("~{}", self.suffix)];
+        if let Some(zone) = message::reverse_zone(
+            current_device.virtual_network,
+            current_device.virtual_netmask,
+        ) {
+            domains.push(format!("~{}", zone));
+        }
+        if self.exit {
+            domains.push("~.".into());
+        }
+        domains
+    }
+    fn answer(&self, query: &[u8]) -> Option<Vec<u8>> {
+        let current_device = self.current_device.load();
+        let mut records = vec![(message::label(&self.name), current_device.virtual_ip)];
+        records.extend(
+            self.device_map
+                .lock()
+                .1
+                .values()
+                .map(|v| (message::label(&v.name), v.virtual_ip)),
+        );
+        message::answer(
+            query,
+            &self.suffix,
+            current_device.virtual_network,
+            current_device.virtual_netmask,
+            &records,
+        )
+    }
+}
+
+/// 上游dns，使用出口节点时经过出口节点查询，防止泄露
+fn upstream(name_servers: &[String], exit_dns: &[Ipv4Addr]) -> Vec<SocketAddr> {
+    if !exit_dns.is_empty() {
+        return exit_dns
+            .iter()
+            .map(|ip| SocketAddr::new(IpAddr::V4(*ip), 53))
+            .collect();
+    }
+    let mut list: Vec<SocketAddr> = name_servers
+        .iter()
+        .filter_map(|v| v.parse::<SocketAddr>().ok())
+        .collect();
+    // systemd-resolved的本地监听地址会把虚拟网络的域名再转回本机，形成循环
+    let stub = |ip: &IpAddr| {
+        *ip == IpAddr::V4(Ipv4Addr::new(127, 0, 0, 53))
+            || *ip == IpAddr::V4(Ipv4Addr::new(127, 0, 0, 54))
+    };
+    list.retain(|v| !stub(&v.ip()));
+    // /etc/resolv.conf里只有本地监听地址时，读取systemd-resolved实际使用的上游
+    #[cfg(unix)]
+    for path in ["/etc/resolv.conf", "/run/systemd/resolve/resolv.conf"] {
+        if !list.is_empty() {
+            break;
+        }
+        if let Ok(conf) = std::fs::read_to_string(path) {
+            for line in conf.lines() {
+                let mut split = line.split_whitespace();
+                if split.next() == Some("nameserver") {
+                    if let Some(Ok(ip)) = split.next().map(|v| v.parse::<IpAddr>()) {
+                        if !stub(&ip) {
+                            list.push(SocketAddr::new(ip, 53));
+                        }
+                    }
+                }
+            }
+        }
+    }
+    if list.is_empty() {
+        list.push("223.5.5.5:53".parse().unwrap());
+        list.push("8.8.8.8:53".parse().unwrap());
+    }
+    list
+}
+
+async fn serve(socket: UdpSocket, ip: Ipv4Addr, dns: OverlayDns) {
+    let socket = Arc::new(socket);
+    let mut buf = [0u8; 4096];
+    loop {
+        let rs = tokio::time::timeout(Duration::from_secs(5), socket.recv_from(&mut buf)).await;
+        if dns.listen_ip.load() != ip {
+            break;
+        }
+        let (len, addr) = match rs {
+            Err(_) => continue,
+            Ok(Ok(v)) => v,
+            // windows上对端端口不可达时会收到这个错误
+            Ok(Err(e)) if e.kind() == io::ErrorKind::ConnectionReset => continue,
+            Ok(Err(e)) => {
+                log::warn!("overlay dns {}:53 {:?}", ip, e);
+                let _ = dns.listen_ip.compare_exchange(ip, Ipv4Addr::UNSPECIFIED);
+                break;
+            }
+        };
+        if let Some(response) = dns.answer(&buf[..len]) {
+            if let Err(e) = socket.send_to(&response, addr).await {
+                log::debug!("overlay dns {} {:?}", addr, e);
+            }
+        } else {
+            let Ok(permit) = dns.forwarding.clone().try_acquire_owned() else {
+                log::debug!("overlay dns 转发请求过多,丢弃 {}", addr);
+                continue;
+            };
+            let (socket, query, upstream) =
+                (socket.clone(), buf[..len].to_vec(), dns.upstream.clone());
+            dns.runtime.spawn(async move {
+                forward(socket, query, addr, upstream).await;
+                drop(permit);
+            });
+        }
+    }
+    log::info!("停止监听虚拟网络dns {}:53", ip);
+}
+
+async fn forward(
+    socket: Arc<UdpSocket>,
+    query: Vec<u8>,
+    client: SocketAddr,
+    upstream: Arc<Vec<SocketAddr>>,
+) {
+    for server in upstream.iter() {
+        match forward0(&query, *server).await {
+            Ok(response) => {
+                if let Err(e) = socket.send_to(&response, client).await {
+                    log::debug!("overlay dns {} {:?}", client, e);
+                }
+                return;
+            }
+            Err(e) => {
+                log::debug!("overlay dns upstream {} {:?}", server, e);
+            }
+        }
+    }
+}
+
+async fn forward0(query: &[u8], server: SocketAddr) -> io::Result<Vec<u8>> {
+    let addr: SocketAddr = if server.is_ipv4() {
+        "0.0.0.0:0".parse().unwrap()
+    } else {
+        "[::]:0".parse().unwrap()
+    };
+    let socket = UdpSocket::bind(addr).await?;
+    socket.connect(server).await?;
+    socket.send(query).await?;
+    let mut buf = vec![0u8; 4096];
+    let len = tokio::time::timeout(Duration::from_secs(3), socket.recv(&mut buf))
+        .await
+        .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+    buf.truncate(len);
+    Ok(buf)
+}
@@ -1,10 +1,9 @@
 use std::net::SocketAddr;
 use std::str::FromStr;
-use std::thread;
 
 use anyhow::Context;
 
-use crate::util::StopManager;
+use crate::util::VntRuntime;
 
 mod tcp_mapping;
 
@@ -60,41 +59,24 @@ pub fn convert(vec: Vec<String>) -> anyhow::Result<Vec<(bool, SocketAddr, String
     Ok(rs)
 }
 pub fn start_port_mapping(
-    stop_manager: StopManager,
+    runtime: &VntRuntime,
     vec: Vec<(bool, SocketAddr, String)>,
 ) -> anyhow::Result<()> {
     if vec.is_empty() {
         return Ok(());
     }
-
-    let (sender, receiver) = tokio::sync::oneshot::channel::<()>();
-    let worker = stop_manager.add_listener("portMapping".into(), move || {
-        let _ = sender.send(());
-    })?;
-    thread::Builder::new()
-        .name("portMapping".into())
-        .spawn(move || {
-            let runtime = tokio::runtime::Builder::new_multi_thread()
-                .enable_all()
-                .thread_name("portMapping")
-                .build().unwrap();
-            runtime.block_on(start_port_mapping0(vec)).unwrap();
-            runtime.block_on(async {
-                let _ = receiver.await;
-            });
-            runtime.shutdown_background();
-            drop(worker);
-        })?;
-
-    Ok(())
+    runtime.block_on(start_port_mapping0(runtime, vec))?
 }
 
-async fn start_port_mapping0(vec: Vec<(bool, SocketAddr, String)>) -> anyhow::Result<()> {
+async fn start_port_mapping0(
+    runtime: &VntRuntime,
+    vec: Vec<(bool, SocketAddr, String)>,
+) -> anyhow::Result<()> {
     for (is_tcp, bind_addr, destination) in vec {
         if is_tcp {
-            tcp_mapping::tcp_mapping(bind_addr, destination).await?;
+            tcp_mapping::tcp_mapping(runtime, bind_addr, destination).await?;
         } else {
-            udp_mapping::udp_mapping(bind_addr, destination).await?;
+            udp_mapping::udp_mapping(runtime, bind_addr, destination).await?;
         }
     }
     Ok(())
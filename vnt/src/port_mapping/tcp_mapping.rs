@@ -2,12 +2,19 @@ use anyhow::Context;
 use std::net::SocketAddr;
 use tokio::net::{TcpListener, TcpStream};
 
-pub async fn tcp_mapping(bind_addr: SocketAddr, destination: String) -> anyhow::Result<()> {
+use crate::util::VntRuntime;
+
+pub async fn tcp_mapping(
+    runtime: &VntRuntime,
+    bind_addr: SocketAddr,
+    destination: String,
+) -> anyhow::Result<()> {
     let tcp_listener = TcpListener::bind(bind_addr)
         .await
         .with_context(|| format!("TCP binding {:?} failed", bind_addr))?;
-    tokio::spawn(async move {
-        if let Err(e) = tcp_mapping_(bind_addr, tcp_listener, destination).await {
+    let task_runtime = runtime.clone();
+    runtime.spawn(async move {
+        if let Err(e) = tcp_mapping_(bind_addr, tcp_listener, destination, task_runtime).await {
             log::warn!("tcp_mapping {:?}", e);
         }
     });
@@ -18,20 +25,26 @@ async fn tcp_mapping_(
     bind_addr: SocketAddr,
     tcp_listener: TcpListener,
     destination: String,
+    runtime: VntRuntime,
 ) -> anyhow::Result<()> {
     loop {
         let (tcp_stream, _) = tcp_listener.accept().await?;
 
         let destination = destination.clone();
-        tokio::spawn(async move {
-            if let Err(e) = copy(tcp_stream, &destination).await {
+        let task_runtime = runtime.clone();
+        runtime.spawn(async move {
+            if let Err(e) = copy(tcp_stream, &destination, &task_runtime).await {
                 log::warn!("tcp port mapping {}->{} {:?}", bind_addr, destination, e);
             }
         });
     }
 }
 
-async fn copy(source_tcp: TcpStream, destination: &String) -> anyhow::Result<()> {
+async fn copy(
+    source_tcp: TcpStream,
+    destination: &String,
+    runtime: &VntRuntime,
+) -> anyhow::Result<()> {
     // 或许这里也应该绑定最匹配的网卡，不然全局代理会影响映射
     let dest_tcp = TcpStream::connect(destination)
         .await
@@ -42,7 +55,7 @@ async fn copy(source_tcp: TcpStream, destination: &String) -> anyhow::Result<()>
     let destination = dest_tcp.peer_addr()?;
     let (mut client_read, mut client_write) = source_tcp.into_split();
     let (mut server_read, mut server_write) = dest_tcp.into_split();
-    tokio::spawn(async move {
+    runtime.spawn(async move {
         if let Err(e) = tokio::io::copy(&mut client_read, &mut server_write).await {
             log::warn!("client tcp proxy ->{:},{:?}", destination, e);
         }
@@ -7,7 +7,13 @@ use std::sync::Arc;
 use std::time::{Duration, Instant};
 use tokio::net::UdpSocket;
 
-pub async fn udp_mapping(bind_addr: SocketAddr, destination: String) -> anyhow::Result<()> {
+use crate::util::VntRuntime;
+
+pub async fn udp_mapping(
+    runtime: &VntRuntime,
+    bind_addr: SocketAddr,
+    destination: String,
+) -> anyhow::Result<()> {
     let udp = UdpSocket::bind(bind_addr)
         .await
         .with_context(|| format!("port proxy UDP binding {:?} failed", bind_addr))?;
@@ -16,13 +22,21 @@ pub async fn udp_mapping(bind_addr: SocketAddr, destination: String) -> anyhow::
     let inner_map: Arc<Mutex<HashMap<SocketAddr, (Arc<UdpSocket>, Arc<AtomicCell<Instant>>)>>> =
         Arc::new(Mutex::new(HashMap::with_capacity(64)));
 
-    tokio::spawn(async move {
+    let task_runtime = runtime.clone();
+    runtime.spawn(async move {
         let mut buf = [0; 65536];
         loop {
             match udp.recv_from(&mut buf).await {
                 Ok((len, src_addr)) => {
-                    if let Err(e) =
-                        udp_mapping0(&buf[..len], src_addr, &inner_map, &udp, &destination).await
+                    if let Err(e) = udp_mapping0(
+                        &buf[..len],
+                        src_addr,
+                        &inner_map,
+                        &udp,
+                        &destination,
+                        &task_runtime,
+                    )
+                    .await
                     {
                         log::warn!("udp port mapping {}->{} {:?}", src_addr, destination, e);
                     }
@@ -42,6 +56,7 @@ async fn udp_mapping0(
     inner_map: &Arc<Mutex<HashMap<SocketAddr, (Arc<UdpSocket>, Arc<AtomicCell<Instant>>)>>>,
     udp_socket: &Arc<UdpSocket>,
     destination: &String,
+    runtime: &VntRuntime,
 ) -> anyhow::Result<()> {
     let option = inner_map.lock().get(&src_addr).cloned();
     if let Some((udp, time)) = option {
@@ -59,7 +74,7 @@ async fn udp_mapping0(
         inner_map
             .lock()
             .insert(src_addr, (dest_udp.clone(), time.clone()));
-        tokio::spawn(async move {
+        runtime.spawn(async move {
             let mut buf = [0u8; 65536];
             loop {
                 match tokio::time::timeout(Duration::from_secs(600), dest_udp.recv(&mut buf)).await
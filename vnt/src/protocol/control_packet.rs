@@ -27,6 +27,8 @@ pub enum Protocol {
     ///获取对端看到的地址
     AddrRequest,
     AddrResponse,
+    /// 离开网络，收到后立即删除路由并标记为离线，不用等心跳超时
+    Leave,
     Unknown(u8),
 }
 
@@ -39,6 +41,7 @@ impl From<u8> for Protocol {
             4 => Protocol::PunchResponse,
             5 => Protocol::AddrRequest,
             6 => Protocol::AddrResponse,
+            7 => Protocol::Leave,
             val => Protocol::Unknown(val),
         }
     }
@@ -53,6 +56,7 @@ impl Into<u8> for Protocol {
             Protocol::PunchResponse => 4,
             Protocol::AddrRequest => 5,
             Protocol::AddrResponse => 6,
+            Protocol::Leave => 7,
             Protocol::Unknown(val) => val,
         }
     }
@@ -65,6 +69,7 @@ pub enum ControlPacket<B> {
     PunchResponse,
     AddrRequest,
     AddrResponse(AddrPacket<B>),
+    Leave,
 }
 
 impl<B: AsRef<[u8]>> ControlPacket<B> {
@@ -76,6 +81,7 @@ impl<B: AsRef<[u8]>> ControlPacket<B> {
             Protocol::PunchResponse => Ok(ControlPacket::PunchResponse),
             Protocol::AddrRequest => Ok(ControlPacket::AddrRequest),
             Protocol::AddrResponse => Ok(ControlPacket::AddrResponse(AddrPacket::new(buffer)?)),
+            Protocol::Leave => Ok(ControlPacket::Leave),
             Protocol::Unknown(_) => Err(io::Error::new(io::ErrorKind::InvalidData, "Unsupported")),
         }
     }
@@ -1,6 +1,10 @@
 #[derive(Copy, Clone, Eq, PartialEq, Debug)]
 pub enum Protocol {
     Punch,
+    /// 设备目录传播
+    Gossip,
+    /// 网关通告子网路由
+    RouteAdvertise,
     Unknown(u8),
 }
 
@@ -8,6 +12,8 @@ impl From<u8> for Protocol {
     fn from(value: u8) -> Self {
         match value {
             1 => Protocol::Punch,
+            2 => Protocol::Gossip,
+            3 => Protocol::RouteAdvertise,
             val => Protocol::Unknown(val),
         }
     }
@@ -17,6 +23,8 @@ impl Into<u8> for Protocol {
     fn into(self) -> u8 {
         match self {
             Protocol::Punch => 1,
+            Protocol::Gossip => 2,
+            Protocol::RouteAdvertise => 3,
             Protocol::Unknown(val) => val,
         }
     }
@@ -15,6 +15,12 @@ pub enum Protocol {
     SecretHandshakeResponse,
     /// 客户端上报状态
     ClientStatusInfo,
+    /// 增量拉取设备列表
+    PullDeviceListDelta,
+    /// 推送设备列表的变化
+    PushDeviceListDelta,
+    /// 使用会话票据恢复会话
+    ResumeRequest,
     Unknown(u8),
 }
 
@@ -30,6 +36,9 @@ impl From<u8> for Protocol {
             7 => Self::SecretHandshakeRequest,
             8 => Self::SecretHandshakeResponse,
             9 => Self::ClientStatusInfo,
+            10 => Self::PullDeviceListDelta,
+            11 => Self::PushDeviceListDelta,
+            12 => Self::ResumeRequest,
             val => Self::Unknown(val),
         }
     }
@@ -47,6 +56,9 @@ impl Into<u8> for Protocol {
             Self::SecretHandshakeRequest => 7,
             Self::SecretHandshakeResponse => 8,
             Self::ClientStatusInfo => 9,
+            Self::PullDeviceListDelta => 10,
+            Self::PushDeviceListDelta => 11,
+            Self::ResumeRequest => 12,
             Self::Unknown(val) => val,
         }
     }
@@ -52,6 +52,24 @@ pub fn create_device<Call: VntCallback>(
     Ok(device)
 }
 
+/// 网卡创建后增删路由，用于网关通告的路由
+pub fn change_route(
+    device: &SyncDevice,
+    dest: Ipv4Addr,
+    netmask: Ipv4Addr,
+    add: bool,
+) -> io::Result<()> {
+    #[cfg(windows)]
+    let index = device.if_index()?;
+    #[cfg(unix)]
+    let index = &device.name()?;
+    if add {
+        add_route(index, dest, netmask)
+    } else {
+        delete_route(index, dest, netmask)
+    }
+}
+
 fn create_device0(config: &DeviceConfig) -> io::Result<Arc<SyncDevice>> {
     let mut tun_builder = tun_rs::DeviceBuilder::default();
     tun_builder = tun_builder.ipv4(config.virtual_ip, config.virtual_netmask, None);
@@ -87,6 +105,14 @@ fn create_device0(config: &DeviceConfig) -> io::Result<Arc<SyncDevice>> {
         if &device_name == DEFAULT_TUN_NAME {
             delete_device(DEFAULT_TUN_NAME);
         }
+        if config.tun_queues > 1 {
+            // 开启IFF_MULTI_QUEUE，后续通过try_clone打开其他队列
+            tun_builder = tun_builder.multi_queue(true);
+        }
+        if config.tun_offload {
+            // 开启IFF_VNET_HDR，内核会交付最大64KB的TSO/GSO大包，由读线程分段
+            tun_builder = tun_builder.offload(true);
+        }
     }
 
     let device = tun_builder.mtu(config.mtu as u16).build_sync()?;
@@ -180,6 +206,17 @@ pub fn add_route(index: u32, dest: Ipv4Addr, netmask: Ipv4Addr) -> io::Result<()
     exe_cmd(&cmd)
 }
 #[cfg(target_os = "windows")]
+pub fn delete_route(index: u32, dest: Ipv4Addr, netmask: Ipv4Addr) -> io::Result<()> {
+    let cmd = format!(
+        "route delete {:?} mask {:?} {:?} if {}",
+        dest,
+        netmask,
+        Ipv4Addr::UNSPECIFIED,
+        index
+    );
+    exe_cmd(&cmd)
+}
+#[cfg(target_os = "windows")]
 pub fn exe_cmd(cmd: &str) -> io::Result<()> {
     use std::os::windows::process::CommandExt;
 
@@ -207,6 +244,15 @@ pub fn add_route(name: &str, address: Ipv4Addr, netmask: Ipv4Addr) -> io::Result
     exe_cmd(&cmd)?;
     Ok(())
 }
+#[cfg(target_os = "macos")]
+pub fn delete_route(name: &str, address: Ipv4Addr, netmask: Ipv4Addr) -> io::Result<()> {
+    let cmd = format!(
+        "route -n delete {} -netmask {} -interface {}",
+        address, netmask, name
+    );
+    exe_cmd(&cmd)?;
+    Ok(())
+}
 #[cfg(target_os = "linux")]
 pub fn add_route(name: &str, address: Ipv4Addr, netmask: Ipv4Addr) -> io::Result<()> {
     let cmd = if netmask.is_broadcast() {
@@ -222,6 +268,17 @@ pub fn add_route(name: &str, address: Ipv4Addr, netmask: Ipv4Addr) -> io::Result
     exe_cmd(&cmd)?;
     Ok(())
 }
+#[cfg(target_os = "linux")]
+pub fn delete_route(name: &str, address: Ipv4Addr, netmask: Ipv4Addr) -> io::Result<()> {
+    let cmd = format!(
+        "route del -net {}/{} {}",
+        address,
+        u32::from(netmask).count_ones(),
+        name
+    );
+    exe_cmd(&cmd)?;
+    Ok(())
+}
 #[cfg(any(target_os = "macos", target_os = "linux"))]
 pub fn exe_cmd(cmd: &str) -> io::Result<std::process::Output> {
     use std::process::Command;
@@ -0,0 +1,116 @@
+use std::io;
+use std::net::Ipv4Addr;
+#[cfg(target_os = "linux")]
+use std::sync::Arc;
+
+#[cfg(target_os = "linux")]
+use parking_lot::Mutex;
+use tun_rs::SyncDevice;
+
+use super::create_device::exe_cmd;
+#[cfg(target_os = "linux")]
+use crate::util::StopManager;
+
+/// 使用出口节点时把dns设置到虚拟网卡上，防止dns请求从物理网卡泄露
+#[cfg(target_os = "linux")]
+pub fn set_dns(device: &SyncDevice, dns: &[Ipv4Addr]) -> io::Result<()> {
+    // 所有域名都从这个网卡查询
+    set_link_dns(device, dns, &["~.".to_string()])
+}
+
+/// 通过systemd-resolved设置网卡的dns和交给这些dns解析的域
+#[cfg(target_os = "linux")]
+pub fn set_link_dns(device: &SyncDevice, dns: &[Ipv4Addr], domains: &[String]) -> io::Result<()> {
+    let name = device.name()?;
+    let dns: Vec<String> = dns.iter().map(|v| v.to_string()).collect();
+    exe_cmd(&format!("resolvectl dns {} {}", name, dns.join(" ")))?;
+    let domains: Vec<String> = domains.iter().map(|v| format!("'{}'", v)).collect();
+    exe_cmd(&format!("resolvectl domain {} {}", name, domains.join(" ")))?;
+    let stub = std::fs::read_to_string("/etc/resolv.conf")
+        .map(|v| v.contains("127.0.0.53"))
+        .unwrap_or(false);
+    if !stub {
+        log::warn!("/etc/resolv.conf没有使用systemd-resolved,网卡上设置的dns可能不生效");
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "windows")]
+pub fn set_dns(device: &SyncDevice, dns: &[Ipv4Addr]) -> io::Result<()> {
+    // 虚拟网卡的跃点数是0，系统优先使用这个网卡上的dns
+    let index = device.if_index()?;
+    for (i, ip) in dns.iter().enumerate() {
+        let cmd = if i == 0 {
+            format!(
+                "netsh interface ipv4 set dnsservers name={} source=static address={} register=none validate=no",
+                index, ip
+            )
+        } else {
+            format!(
+                "netsh interface ipv4 add dnsservers name={} address={} index={} validate=no",
+                index,
+                ip,
+                i + 1
+            )
+        };
+        exe_cmd(&cmd)?;
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn masquerade_rules(network: Ipv4Addr, netmask: Ipv4Addr) -> Vec<(&'static str, String)> {
+    let cidr = format!("{}/{}", network, u32::from(netmask).count_ones());
+    vec![
+        (
+            "nat",
+            format!("POSTROUTING -s {} ! -d {} -j MASQUERADE", cidr, cidr),
+        ),
+        ("filter", format!("FORWARD -s {} -j ACCEPT", cidr)),
+        ("filter", format!("FORWARD -d {} -j ACCEPT", cidr)),
+    ]
+}
+
+/// 记录添加过的nat规则，虚拟网络变化后旧网段的规则也要在停止时删除
+#[cfg(target_os = "linux")]
+#[derive(Clone, Default)]
+pub struct MasqueradeRules {
+    installed: Arc<Mutex<Vec<(&'static str, String)>>>,
+}
+
+#[cfg(target_os = "linux")]
+impl MasqueradeRules {
+    /// 作为出口节点时开启内核转发，虚拟网络访问外部的流量做源地址转换
+    pub fn enable(&self, network: Ipv4Addr, netmask: Ipv4Addr) -> io::Result<()> {
+        exe_cmd("sysctl -w net.ipv4.ip_forward=1")?;
+        let mut installed = self.installed.lock();
+        for (table, rule) in masquerade_rules(network, netmask) {
+            if installed.iter().any(|(t, r)| *t == table && *r == rule) {
+                continue;
+            }
+            // 已经存在的规则不是本程序添加的，不记录也不删除
+            if exe_cmd(&format!("iptables -t {} -C {}", table, rule)).is_err() {
+                exe_cmd(&format!("iptables -t {} -I {}", table, rule))?;
+                installed.push((table, rule));
+            }
+        }
+        Ok(())
+    }
+    /// 删除所有添加过的规则
+    pub fn release(&self) {
+        for (table, rule) in self.installed.lock().drain(..) {
+            if let Err(e) = exe_cmd(&format!("iptables -t {} -D {}", table, rule)) {
+                log::warn!("删除nat规则失败 {:?}", e);
+            }
+        }
+    }
+}
+
+/// 停止时删除添加的nat规则
+#[cfg(target_os = "linux")]
+pub fn masquerade_release_on_stop(
+    stop_manager: &StopManager,
+    rules: MasqueradeRules,
+) -> anyhow::Result<()> {
+    stop_manager.add_stop_task("masqueradeRelease", move || rules.release())
+}
@@ -1,10 +1,19 @@
 #[cfg(any(target_os = "windows", target_os = "linux", target_os = "macos"))]
 #[cfg(feature = "integrated_tun")]
-pub use create_device::create_device;
+pub use create_device::{change_route, create_device};
 
 #[cfg(any(target_os = "windows", target_os = "linux", target_os = "macos"))]
 #[cfg(feature = "integrated_tun")]
 mod create_device;
+#[cfg(any(target_os = "windows", target_os = "linux"))]
+#[cfg(feature = "integrated_tun")]
+mod exit_node;
+#[cfg(any(target_os = "windows", target_os = "linux"))]
+#[cfg(feature = "integrated_tun")]
+pub use exit_node::set_dns;
+#[cfg(target_os = "linux")]
+#[cfg(feature = "integrated_tun")]
+pub use exit_node::{masquerade_release_on_stop, set_link_dns, MasqueradeRules};
 #[cfg(feature = "integrated_tun")]
 pub mod tun_create_helper;
 
@@ -20,29 +20,164 @@ use tun_rs::SyncDevice;
 #[repr(transparent)]
 #[derive(Clone, Default)]
 pub struct DeviceAdapter {
-    tun: Arc<Mutex<Option<Arc<SyncDevice>>>>,
+    tun: Arc<Mutex<Option<TunWriter>>>,
+}
+
+struct TunWriter {
+    device: Arc<SyncDevice>,
+    // tun开启了IFF_VNET_HDR，每次写入都要带virtio_net_hdr，批量写入时合并成大包
+    #[cfg(target_os = "linux")]
+    offload: bool,
+}
+
+impl TunWriter {
+    fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        #[cfg(target_os = "linux")]
+        if self.offload {
+            return send_with_vnet_hdr(&self.device, buf);
+        }
+        self.device.send(buf)
+    }
 }
 
 impl DeviceAdapter {
-    pub fn insert(&self, device: Arc<SyncDevice>) {
-        let r = self.tun.lock().replace(device);
+    pub fn insert(&self, device: Arc<SyncDevice>, #[cfg(target_os = "linux")] offload: bool) {
+        let r = self.tun.lock().replace(TunWriter {
+            device,
+            #[cfg(target_os = "linux")]
+            offload,
+        });
         assert!(r.is_none());
     }
+    pub fn device(&self) -> Option<Arc<SyncDevice>> {
+        self.tun.lock().as_ref().map(|v| v.device.clone())
+    }
     /// 要保证先remove 再insert
     pub fn remove(&self) {
         drop(self.tun.lock().take());
     }
 }
 
+/// 在数据包前面加上全0的virtio_net_hdr，表示不需要校验和卸载和分段
+#[cfg(target_os = "linux")]
+fn vnet_frame(frame: &mut Vec<u8>, buf: &[u8]) {
+    frame.clear();
+    frame.resize(tun_rs::VIRTIO_NET_HDR_LEN, 0);
+    frame.extend_from_slice(buf);
+}
+
+#[cfg(target_os = "linux")]
+thread_local! {
+    static VNET_FRAME: std::cell::RefCell<Vec<u8>> = const { std::cell::RefCell::new(Vec::new()) };
+}
+
+/// 开启IFF_VNET_HDR后，不经过批量合并的单个数据包也要带上virtio_net_hdr
+#[cfg(target_os = "linux")]
+pub(crate) fn send_with_vnet_hdr(device: &SyncDevice, buf: &[u8]) -> io::Result<usize> {
+    VNET_FRAME.with(|frame| {
+        let mut frame = frame.borrow_mut();
+        vnet_frame(&mut frame, buf);
+        device.send(&frame)?;
+        Ok(buf.len())
+    })
+}
+
+/// 每个接收线程各自缓存待写入tun的数据包，batch_end时通过GRO合并写入
+#[cfg(target_os = "linux")]
+struct GroBatch {
+    active: bool,
+    len: usize,
+    bufs: Vec<Vec<u8>>,
+    table: tun_rs::GROTable,
+}
+
+#[cfg(target_os = "linux")]
+impl GroBatch {
+    fn push(&mut self, buf: &[u8]) {
+        if self.len == self.bufs.len() {
+            self.bufs
+                .push(Vec::with_capacity(tun_rs::VIRTIO_NET_HDR_LEN + buf.len()));
+        }
+        // 头部留给virtio_net_hdr
+        vnet_frame(&mut self.bufs[self.len], buf);
+        self.len += 1;
+    }
+    fn flush(&mut self, tun: &SyncDevice) -> io::Result<()> {
+        if self.len == 0 {
+            return Ok(());
+        }
+        let len = self.len;
+        self.len = 0;
+        tun.send_multiple(
+            &mut self.table,
+            &mut self.bufs[..len],
+            tun_rs::VIRTIO_NET_HDR_LEN,
+        )?;
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+thread_local! {
+    static GRO_BATCH: std::cell::RefCell<GroBatch> = std::cell::RefCell::new(GroBatch {
+        active: false,
+        len: 0,
+        bufs: Vec::new(),
+        table: tun_rs::GROTable::default(),
+    });
+}
+
 impl DeviceWrite for DeviceAdapter {
     #[inline]
     fn write(&self, buf: &[u8]) -> io::Result<usize> {
-        if let Some(tun) = self.tun.lock().as_ref() {
-            tun.send(buf)
-        } else {
-            Err(io::Error::new(io::ErrorKind::NotFound, "not tun device"))
+        let guard = self.tun.lock();
+        let Some(tun) = guard.as_ref() else {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "not tun device"));
+        };
+        #[cfg(target_os = "linux")]
+        if tun.offload {
+            let cached = GRO_BATCH.with(|batch| {
+                let mut batch = batch.borrow_mut();
+                if !batch.active {
+                    return Ok(false);
+                }
+                batch.push(buf);
+                if batch.len >= tun_rs::IDEAL_BATCH_SIZE {
+                    batch.flush(&tun.device)?;
+                }
+                Ok::<bool, io::Error>(true)
+            })?;
+            if cached {
+                return Ok(buf.len());
+            }
+        }
+        tun.send(buf)
+    }
+    #[cfg(target_os = "linux")]
+    fn batch_begin(&self) {
+        if self.tun.lock().as_ref().is_some_and(|tun| tun.offload) {
+            GRO_BATCH.with(|batch| batch.borrow_mut().active = true);
         }
     }
+    #[cfg(target_os = "linux")]
+    fn batch_end(&self) {
+        GRO_BATCH.with(|batch| {
+            let mut batch = batch.borrow_mut();
+            if !batch.active {
+                return;
+            }
+            batch.active = false;
+            let rs = if let Some(tun) = self.tun.lock().as_ref() {
+                batch.flush(&tun.device)
+            } else {
+                batch.len = 0;
+                Ok(())
+            };
+            if let Err(e) = rs {
+                log::warn!("tun gro写入失败 {:?}", e);
+            }
+        });
+    }
 
     fn into_device_adapter(self) -> DeviceAdapter {
         self
@@ -53,7 +188,15 @@ impl DeviceWrite for DeviceAdapter {
 pub struct TunDeviceHelper {
     inner: Arc<Mutex<TunDeviceHelperInner>>,
     device_adapter: DeviceAdapter,
-    device_stop: Arc<Mutex<Option<DeviceStop>>>,
+    device_stop: Arc<Mutex<Option<Vec<DeviceStop>>>>,
+    // tun多队列数，每个队列一个读线程
+    #[cfg(target_os = "linux")]
+    tun_queues: usize,
+    #[cfg(target_os = "linux")]
+    tun_offload: bool,
+    // 作为出口节点时添加的nat规则
+    #[cfg(target_os = "linux")]
+    masquerade: super::MasqueradeRules,
 }
 
 #[derive(Clone)]
@@ -82,6 +225,8 @@ impl TunDeviceHelper {
         device_map: Arc<Mutex<(u16, HashMap<Ipv4Addr, PeerDeviceInfo>)>>,
         compressor: Compressor,
         device_adapter: DeviceAdapter,
+        #[cfg(target_os = "linux")] tun_queues: usize,
+        #[cfg(target_os = "linux")] tun_offload: bool,
     ) -> Self {
         let inner = TunDeviceHelperInner {
             stop_manager,
@@ -99,17 +244,29 @@ impl TunDeviceHelper {
             inner: Arc::new(Mutex::new(inner)),
             device_adapter,
             device_stop: Default::default(),
+            #[cfg(target_os = "linux")]
+            tun_queues,
+            #[cfg(target_os = "linux")]
+            tun_offload,
+            #[cfg(target_os = "linux")]
+            masquerade: Default::default(),
         }
     }
+    #[cfg(target_os = "linux")]
+    pub fn masquerade(&self) -> &super::MasqueradeRules {
+        &self.masquerade
+    }
     pub fn stop(&self) {
         //先停止旧的，再启动新的，改变旧网卡的IP太麻烦
-        if let Some(device_stop) = self.device_stop.lock().take() {
+        if let Some(device_stop_list) = self.device_stop.lock().take() {
             self.device_adapter.remove();
             loop {
-                device_stop.stop();
+                for device_stop in &device_stop_list {
+                    device_stop.stop();
+                }
                 std::thread::sleep(std::time::Duration::from_millis(300));
                 //确保停止了
-                if device_stop.is_stopped() {
+                if device_stop_list.iter().all(|v| v.is_stopped()) {
                     break;
                 }
             }
@@ -117,25 +274,74 @@ impl TunDeviceHelper {
     }
     /// 要保证先stop 再start
     pub fn start(&self, device: Arc<SyncDevice>, allow_wire_guard: bool) -> io::Result<()> {
-        self.device_adapter.insert(device.clone());
-        let device_stop = DeviceStop::default();
-        let s = self.device_stop.lock().replace(device_stop.clone());
+        self.device_adapter.insert(
+            device.clone(),
+            #[cfg(target_os = "linux")]
+            self.tun_offload,
+        );
+        #[allow(unused_mut)]
+        let mut devices = vec![device.clone()];
+        // 内核按流哈希把数据包分配到固定队列，每个队列单独读取即可保证同一条流的顺序
+        #[cfg(target_os = "linux")]
+        for _ in 1..self.tun_queues {
+            match device.try_clone() {
+                Ok(queue) => devices.push(Arc::new(queue)),
+                Err(e) => {
+                    log::warn!("打开tun队列失败,队列数={} {:?}", devices.len(), e);
+                    break;
+                }
+            }
+        }
+        let device_stop_list: Vec<DeviceStop> =
+            devices.iter().map(|_| DeviceStop::default()).collect();
+        let s = self.device_stop.lock().replace(device_stop_list.clone());
         assert!(s.is_none());
         let inner = self.inner.lock().clone();
-        crate::handle::tun_tap::tun_handler::start(
-            inner.stop_manager,
-            inner.context,
-            device,
-            inner.current_device,
-            inner.ip_route,
-            #[cfg(feature = "ip_proxy")]
-            inner.ip_proxy_map,
-            inner.client_cipher,
-            inner.server_cipher,
-            inner.device_map,
-            inner.compressor,
-            device_stop,
-            allow_wire_guard,
-        )
+        for (queue, (device, device_stop)) in devices
+            .into_iter()
+            .zip(device_stop_list.into_iter())
+            .enumerate()
+        {
+            let inner = inner.clone();
+            crate::handle::tun_tap::tun_handler::start(
+                inner.stop_manager,
+                inner.context,
+                device,
+                queue,
+                #[cfg(target_os = "linux")]
+                self.tun_offload,
+                inner.current_device,
+                inner.ip_route,
+                #[cfg(feature = "ip_proxy")]
+                inner.ip_proxy_map,
+                inner.client_cipher,
+                inner.server_cipher,
+                inner.device_map,
+                inner.compressor,
+                device_stop,
+                allow_wire_guard,
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+#[cfg(target_os = "linux")]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vnet_hdr_frame() {
+        let mut frame = vec![0xff; 100];
+        let packet = [0x45, 0, 0, 20, 1, 2, 3, 4];
+        vnet_frame(&mut frame, &packet);
+        assert_eq!(frame.len(), tun_rs::VIRTIO_NET_HDR_LEN + packet.len());
+        // flags、gso_type都是0，内核不做校验和卸载和分段
+        assert!(frame[..tun_rs::VIRTIO_NET_HDR_LEN].iter().all(|v| *v == 0));
+        assert_eq!(&frame[tun_rs::VIRTIO_NET_HDR_LEN..], &packet);
+        // 重复使用时不能残留上一个包的数据
+        vnet_frame(&mut frame, &packet[..4]);
+        assert_eq!(&frame[tun_rs::VIRTIO_NET_HDR_LEN..], &packet[..4]);
     }
 }
@@ -2,6 +2,10 @@ use std::io;
 
 pub trait DeviceWrite: Clone + Send + Sync + 'static {
     fn write(&self, buf: &[u8]) -> io::Result<usize>;
+    /// 开始一批写入，在batch_end之前的写入可以合并
+    fn batch_begin(&self) {}
+    /// 结束一批写入，把缓存的数据写出
+    fn batch_end(&self) {}
     #[cfg(feature = "integrated_tun")]
     fn into_device_adapter(self) -> crate::tun_tap_device::tun_create_helper::DeviceAdapter;
 }
@@ -1,6 +1,8 @@
 mod notify;
+mod runtime;
 mod scheduler;
 pub use notify::{StopManager, Worker};
+pub use runtime::VntRuntime;
 pub use scheduler::Scheduler;
 
 // mod counter;
@@ -13,5 +15,16 @@ pub use dns_query::*;
 mod upnp;
 #[cfg(feature = "upnp")]
 pub use upnp::*;
+#[cfg(feature = "natpmp")]
+mod natpmp;
+#[cfg(feature = "natpmp")]
+pub use natpmp::*;
+#[cfg(target_os = "linux")]
+mod netlink;
+#[cfg(target_os = "linux")]
+pub use netlink::NetlinkWatcher;
 
 pub mod limit;
+
+mod pool;
+pub use pool::*;
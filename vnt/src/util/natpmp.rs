@@ -0,0 +1,581 @@
+//! NAT-PMP(RFC 6886)和PCP(RFC 6887)端口映射，优先使用PCP，网关不支持时回退到NAT-PMP
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::ops::Deref;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+use rand::RngCore;
+
+/// 网关监听的端口
+pub const GATEWAY_PORT: u16 = 5351;
+/// 请求的映射时长，网关可能会缩短
+const REQUEST_LIFETIME: u32 = 7200;
+/// 重传间隔，RFC 6886 从250ms开始翻倍
+const RETRY_TIMEOUT: [u64; 3] = [250, 500, 1000];
+
+const PMP_VERSION: u8 = 0;
+const PCP_VERSION: u8 = 2;
+const PMP_OP_EXTERNAL_ADDRESS: u8 = 0;
+const PCP_OP_MAP: u8 = 1;
+const RESULT_UNSUPPORTED_VERSION: u8 = 1;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MappingProtocol {
+    Udp,
+    Tcp,
+}
+
+impl MappingProtocol {
+    fn pmp_op(&self) -> u8 {
+        match self {
+            MappingProtocol::Udp => 1,
+            MappingProtocol::Tcp => 2,
+        }
+    }
+    fn ip_protocol(&self) -> u8 {
+        match self {
+            MappingProtocol::Udp => 17,
+            MappingProtocol::Tcp => 6,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum GatewayProtocol {
+    Pcp,
+    NatPmp,
+}
+
+/// 网关分配的一条映射
+#[derive(Clone, Debug)]
+pub struct Lease {
+    pub protocol: MappingProtocol,
+    pub internal_port: u16,
+    pub external: SocketAddrV4,
+    pub lifetime: u32,
+    obtained: Instant,
+    // PCP用nonce区分映射的所有者，续期和删除时要带上同样的值
+    nonce: [u8; 12],
+}
+
+impl Lease {
+    /// 过半后续期
+    fn renew_at(&self) -> Instant {
+        self.obtained + Duration::from_secs(self.lifetime as u64 / 2)
+    }
+}
+
+struct GatewayState {
+    gateway: SocketAddr,
+    local_ip: Ipv4Addr,
+    protocol: GatewayProtocol,
+    leases: Vec<Lease>,
+}
+
+#[derive(Clone, Default)]
+pub struct NatPmp {
+    inner: Arc<NatPmpInner>,
+}
+
+impl Deref for NatPmp {
+    type Target = NatPmpInner;
+
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+
+#[derive(Default)]
+pub struct NatPmpInner {
+    list: Mutex<Vec<(MappingProtocol, u16)>>,
+    state: Mutex<Option<GatewayState>>,
+}
+
+impl NatPmpInner {
+    pub fn add_tcp_port(&self, port: u16) {
+        self.list.lock().push((MappingProtocol::Tcp, port));
+    }
+    pub fn add_udp_port(&self, port: u16) {
+        self.list.lock().push((MappingProtocol::Udp, port));
+    }
+    /// 向默认网关申请映射，网关或本地地址没变时只做续期
+    pub fn reset(&self, local_ip: Ipv4Addr) {
+        let gateway = SocketAddr::new(default_gateway(local_ip).into(), GATEWAY_PORT);
+        if let Err(e) = self.reset_with_gateway(local_ip, gateway) {
+            log::warn!("natpmp gateway={} {:?}", gateway, e);
+        }
+    }
+    pub(crate) fn reset_with_gateway(
+        &self,
+        local_ip: Ipv4Addr,
+        gateway: SocketAddr,
+    ) -> io::Result<()> {
+        {
+            let guard = self.state.lock();
+            if let Some(state) = guard.as_ref() {
+                if state.gateway == gateway && state.local_ip == local_ip {
+                    drop(guard);
+                    self.renew();
+                    return Ok(());
+                }
+            }
+        }
+        // 地址变了，旧的映射已经没用
+        self.release();
+        let list = self.list.lock().clone();
+        let Some((first_protocol, first_port)) = list.first().copied() else {
+            return Ok(());
+        };
+        let socket = UdpSocket::bind(SocketAddr::new(local_ip.into(), 0))?;
+        let nonce = new_nonce();
+        // 先尝试PCP，只支持NAT-PMP的网关会回复版本不支持
+        let (protocol, first) = match pcp_map(
+            &socket,
+            gateway,
+            local_ip,
+            first_protocol,
+            first_port,
+            REQUEST_LIFETIME,
+            &nonce,
+        ) {
+            Ok(lease) => (GatewayProtocol::Pcp, lease),
+            Err(e) => {
+                log::info!("pcp不可用,使用nat-pmp {:?}", e);
+                let lease = pmp_map(
+                    &socket,
+                    gateway,
+                    first_protocol,
+                    first_port,
+                    REQUEST_LIFETIME,
+                )?;
+                (GatewayProtocol::NatPmp, lease)
+            }
+        };
+        log::info!("{:?} 映射 {:?}", protocol, first);
+        let mut leases = vec![first];
+        for (mapping_protocol, port) in list.into_iter().skip(1) {
+            let rs = match protocol {
+                GatewayProtocol::Pcp => pcp_map(
+                    &socket,
+                    gateway,
+                    local_ip,
+                    mapping_protocol,
+                    port,
+                    REQUEST_LIFETIME,
+                    &new_nonce(),
+                ),
+                GatewayProtocol::NatPmp => {
+                    pmp_map(&socket, gateway, mapping_protocol, port, REQUEST_LIFETIME)
+                }
+            };
+            match rs {
+                Ok(lease) => {
+                    log::info!("{:?} 映射 {:?}", protocol, lease);
+                    leases.push(lease)
+                }
+                Err(e) => {
+                    log::warn!(
+                        "{:?} 映射失败 {:?} {} {:?}",
+                        protocol,
+                        mapping_protocol,
+                        port,
+                        e
+                    )
+                }
+            }
+        }
+        self.state.lock().replace(GatewayState {
+            gateway,
+            local_ip,
+            protocol,
+            leases,
+        });
+        Ok(())
+    }
+    /// 续期过半的映射，返回距离下一次续期的时间，没有映射时返回None
+    pub fn renew(&self) -> Option<Duration> {
+        let mut guard = self.state.lock();
+        let state = guard.as_mut()?;
+        let socket = match UdpSocket::bind(SocketAddr::new(state.local_ip.into(), 0)) {
+            Ok(socket) => socket,
+            Err(e) => {
+                log::warn!("natpmp renew {:?}", e);
+                return Some(Duration::from_secs(60));
+            }
+        };
+        let now = Instant::now();
+        for lease in state.leases.iter_mut() {
+            if lease.renew_at() > now {
+                continue;
+            }
+            let rs = match state.protocol {
+                GatewayProtocol::Pcp => pcp_map(
+                    &socket,
+                    state.gateway,
+                    state.local_ip,
+                    lease.protocol,
+                    lease.internal_port,
+                    REQUEST_LIFETIME,
+                    &lease.nonce,
+                ),
+                GatewayProtocol::NatPmp => pmp_map(
+                    &socket,
+                    state.gateway,
+                    lease.protocol,
+                    lease.internal_port,
+                    REQUEST_LIFETIME,
+                ),
+            };
+            match rs {
+                Ok(new_lease) => *lease = new_lease,
+                Err(e) => {
+                    log::warn!("natpmp renew {:?} {:?}", lease, e);
+                    // 稍后重试，过期前一直续不上就丢弃
+                    if lease.obtained.elapsed() >= Duration::from_secs(lease.lifetime as u64) {
+                        lease.lifetime = 0;
+                    }
+                }
+            }
+        }
+        state.leases.retain(|lease| lease.lifetime > 0);
+        if state.leases.is_empty() {
+            guard.take();
+            return None;
+        }
+        state
+            .leases
+            .iter()
+            .map(|lease| lease.renew_at().saturating_duration_since(now))
+            .min()
+            .map(|wait| wait.max(Duration::from_secs(10)))
+    }
+    /// 删除所有映射
+    pub fn release(&self) {
+        let Some(state) = self.state.lock().take() else {
+            return;
+        };
+        let socket = match UdpSocket::bind(SocketAddr::new(state.local_ip.into(), 0)) {
+            Ok(socket) => socket,
+            Err(e) => {
+                log::warn!("natpmp release {:?}", e);
+                return;
+            }
+        };
+        for lease in state.leases {
+            // 时长为0表示删除映射
+            let rs = match state.protocol {
+                GatewayProtocol::Pcp => pcp_map(
+                    &socket,
+                    state.gateway,
+                    state.local_ip,
+                    lease.protocol,
+                    lease.internal_port,
+                    0,
+                    &lease.nonce,
+                ),
+                GatewayProtocol::NatPmp => pmp_map(
+                    &socket,
+                    state.gateway,
+                    lease.protocol,
+                    lease.internal_port,
+                    0,
+                ),
+            };
+            log::info!("natpmp release {:?} {:?}", lease, rs.map(|_| ()));
+        }
+    }
+    pub fn protocol(&self) -> Option<GatewayProtocol> {
+        self.state.lock().as_ref().map(|state| state.protocol)
+    }
+    pub fn leases(&self) -> Vec<Lease> {
+        self.state
+            .lock()
+            .as_ref()
+            .map(|state| state.leases.clone())
+            .unwrap_or_default()
+    }
+}
+
+fn new_nonce() -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    rand::thread_rng().fill_bytes(&mut nonce);
+    nonce
+}
+
+/// 默认网关，linux从路由表读取，其他平台取本地网段的.1
+fn default_gateway(local_ip: Ipv4Addr) -> Ipv4Addr {
+    #[cfg(target_os = "linux")]
+    if let Some(gateway) = linux_default_gateway() {
+        return gateway;
+    }
+    let [a, b, c, _] = local_ip.octets();
+    Ipv4Addr::new(a, b, c, 1)
+}
+
+#[cfg(target_os = "linux")]
+fn linux_default_gateway() -> Option<Ipv4Addr> {
+    let route = std::fs::read_to_string("/proc/net/route").ok()?;
+    for line in route.lines().skip(1) {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 3 || fields[1] != "00000000" {
+            continue;
+        }
+        // 内核按内存中的网络字节序输出十六进制
+        let gateway = u32::from_str_radix(fields[2], 16).ok()?;
+        if gateway != 0 {
+            return Some(Ipv4Addr::from(gateway.to_ne_bytes()));
+        }
+    }
+    None
+}
+
+/// 发送请求并等待匹配的响应，超时后按间隔重传
+fn request(
+    socket: &UdpSocket,
+    gateway: SocketAddr,
+    packet: &[u8],
+    buf: &mut [u8],
+    matches: impl Fn(&[u8]) -> bool,
+) -> io::Result<usize> {
+    for timeout in RETRY_TIMEOUT {
+        socket.send_to(packet, gateway)?;
+        let deadline = Instant::now() + Duration::from_millis(timeout);
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            socket.set_read_timeout(Some(deadline - now))?;
+            match socket.recv_from(buf) {
+                Ok((len, addr)) => {
+                    if addr.ip() == gateway.ip() && matches(&buf[..len]) {
+                        return Ok(len);
+                    }
+                }
+                Err(e)
+                    if e.kind() == io::ErrorKind::WouldBlock
+                        || e.kind() == io::ErrorKind::TimedOut =>
+                {
+                    break;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::TimedOut,
+        format!("gateway {} no response", gateway),
+    ))
+}
+
+fn result_error(version: u8, code: u16) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Other,
+        format!("gateway version={} result code={}", version, code),
+    )
+}
+
+fn pmp_map(
+    socket: &UdpSocket,
+    gateway: SocketAddr,
+    protocol: MappingProtocol,
+    internal_port: u16,
+    lifetime: u32,
+) -> io::Result<Lease> {
+    let mut buf = [0u8; 1100];
+    let op = protocol.pmp_op();
+    let mut packet = [0u8; 12];
+    packet[0] = PMP_VERSION;
+    packet[1] = op;
+    packet[4..6].copy_from_slice(&internal_port.to_be_bytes());
+    // 建议使用和内部端口一样的外部端口，删除时要为0
+    if lifetime > 0 {
+        packet[6..8].copy_from_slice(&internal_port.to_be_bytes());
+    }
+    packet[8..12].copy_from_slice(&lifetime.to_be_bytes());
+    let len = request(socket, gateway, &packet, &mut buf, |v| {
+        v.len() >= 16 && v[1] == 128 + op && v[8..10] == internal_port.to_be_bytes()
+    })?;
+    let buf = &buf[..len];
+    let code = u16::from_be_bytes([buf[2], buf[3]]);
+    if code != 0 {
+        return Err(result_error(buf[0], code));
+    }
+    let external_port = u16::from_be_bytes([buf[10], buf[11]]);
+    let lifetime = u32::from_be_bytes([buf[12], buf[13], buf[14], buf[15]]);
+    let external_ip = if lifetime > 0 {
+        pmp_external_address(socket, gateway)?
+    } else {
+        Ipv4Addr::UNSPECIFIED
+    };
+    Ok(Lease {
+        protocol,
+        internal_port,
+        external: SocketAddrV4::new(external_ip, external_port),
+        lifetime,
+        obtained: Instant::now(),
+        nonce: [0; 12],
+    })
+}
+
+fn pmp_external_address(socket: &UdpSocket, gateway: SocketAddr) -> io::Result<Ipv4Addr> {
+    let mut buf = [0u8; 1100];
+    let packet = [PMP_VERSION, PMP_OP_EXTERNAL_ADDRESS];
+    let len = request(socket, gateway, &packet, &mut buf, |v| {
+        v.len() >= 12 && v[1] == 128 + PMP_OP_EXTERNAL_ADDRESS
+    })?;
+    let buf = &buf[..len];
+    let code = u16::from_be_bytes([buf[2], buf[3]]);
+    if code != 0 {
+        return Err(result_error(buf[0], code));
+    }
+    Ok(Ipv4Addr::new(buf[8], buf[9], buf[10], buf[11]))
+}
+
+fn pcp_map(
+    socket: &UdpSocket,
+    gateway: SocketAddr,
+    local_ip: Ipv4Addr,
+    protocol: MappingProtocol,
+    internal_port: u16,
+    lifetime: u32,
+    nonce: &[u8; 12],
+) -> io::Result<Lease> {
+    let mut buf = [0u8; 1100];
+    let mut packet = [0u8; 60];
+    packet[0] = PCP_VERSION;
+    packet[1] = PCP_OP_MAP;
+    packet[4..8].copy_from_slice(&lifetime.to_be_bytes());
+    packet[8..24].copy_from_slice(&local_ip.to_ipv6_mapped().octets());
+    packet[24..36].copy_from_slice(nonce);
+    packet[36] = protocol.ip_protocol();
+    packet[40..42].copy_from_slice(&internal_port.to_be_bytes());
+    packet[42..44].copy_from_slice(&internal_port.to_be_bytes());
+    // 建议的外部地址为空，由网关分配
+    packet[44..60].copy_from_slice(&Ipv4Addr::UNSPECIFIED.to_ipv6_mapped().octets());
+    let len = request(socket, gateway, &packet, &mut buf, |v| {
+        // 只支持NAT-PMP的网关回复版本0的错误
+        (v.len() >= 4 && v[0] == PMP_VERSION)
+            || (v.len() >= 60
+                && v[0] == PCP_VERSION
+                && v[1] == 0x80 | PCP_OP_MAP
+                && &v[24..36] == nonce)
+    })?;
+    let buf = &buf[..len];
+    if buf[0] != PCP_VERSION {
+        return Err(result_error(buf[0], RESULT_UNSUPPORTED_VERSION as u16));
+    }
+    if buf[3] != 0 {
+        return Err(result_error(buf[0], buf[3] as u16));
+    }
+    let lifetime = u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]);
+    let external_port = u16::from_be_bytes([buf[42], buf[43]]);
+    let mut ip = [0u8; 16];
+    ip.copy_from_slice(&buf[44..60]);
+    let external_ip = std::net::Ipv6Addr::from(ip)
+        .to_ipv4_mapped()
+        .unwrap_or(Ipv4Addr::UNSPECIFIED);
+    Ok(Lease {
+        protocol,
+        internal_port,
+        external: SocketAddrV4::new(external_ip, external_port),
+        lifetime,
+        obtained: Instant::now(),
+        nonce: *nonce,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    const EXTERNAL_IP: Ipv4Addr = Ipv4Addr::new(203, 0, 113, 7);
+
+    /// 模拟网关，pcp为false时只支持NAT-PMP，外部端口为内部端口+1000
+    fn fake_gateway(pcp: bool, requests: usize) -> (SocketAddr, thread::JoinHandle<Vec<u32>>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            let mut lifetimes = Vec::new();
+            let mut buf = [0u8; 1100];
+            for _ in 0..requests {
+                let (len, peer) = socket.recv_from(&mut buf).unwrap();
+                let req = &buf[..len];
+                let mut resp = Vec::new();
+                match (req[0], pcp) {
+                    (PCP_VERSION, true) => {
+                        let lifetime = u32::from_be_bytes(req[4..8].try_into().unwrap());
+                        lifetimes.push(lifetime);
+                        let port = u16::from_be_bytes([req[40], req[41]]) + 1000;
+                        resp.extend_from_slice(&[PCP_VERSION, 0x80 | PCP_OP_MAP, 0, 0]);
+                        resp.extend_from_slice(&lifetime.to_be_bytes());
+                        resp.extend_from_slice(&[0u8; 16]);
+                        resp.extend_from_slice(&req[24..42]);
+                        resp.extend_from_slice(&port.to_be_bytes());
+                        resp.extend_from_slice(&EXTERNAL_IP.to_ipv6_mapped().octets());
+                    }
+                    (PCP_VERSION, false) => {
+                        resp.extend_from_slice(&[PMP_VERSION, 0x80 | req[1], 0, 1]);
+                        resp.extend_from_slice(&[0u8; 4]);
+                    }
+                    (PMP_VERSION, _) if req[1] == PMP_OP_EXTERNAL_ADDRESS => {
+                        resp.extend_from_slice(&[PMP_VERSION, 128, 0, 0, 0, 0, 0, 1]);
+                        resp.extend_from_slice(&EXTERNAL_IP.octets());
+                    }
+                    _ => {
+                        let lifetime = u32::from_be_bytes(req[8..12].try_into().unwrap());
+                        lifetimes.push(lifetime);
+                        let port = u16::from_be_bytes([req[4], req[5]]) + 1000;
+                        resp.extend_from_slice(&[PMP_VERSION, 128 + req[1], 0, 0, 0, 0, 0, 1]);
+                        resp.extend_from_slice(&req[4..6]);
+                        resp.extend_from_slice(&port.to_be_bytes());
+                        resp.extend_from_slice(&lifetime.to_be_bytes());
+                    }
+                }
+                socket.send_to(&resp, peer).unwrap();
+            }
+            lifetimes
+        });
+        (addr, handle)
+    }
+
+    #[test]
+    fn pcp_map_release() {
+        let (gateway, handle) = fake_gateway(true, 4);
+        let natpmp = NatPmp::default();
+        natpmp.add_udp_port(5000);
+        natpmp.add_tcp_port(5001);
+        natpmp
+            .reset_with_gateway(Ipv4Addr::LOCALHOST, gateway)
+            .unwrap();
+        assert_eq!(natpmp.protocol(), Some(GatewayProtocol::Pcp));
+        let leases = natpmp.leases();
+        assert_eq!(leases.len(), 2);
+        assert_eq!(leases[0].external, SocketAddrV4::new(EXTERNAL_IP, 6000));
+        assert_eq!(leases[1].protocol, MappingProtocol::Tcp);
+        natpmp.release();
+        assert!(natpmp.leases().is_empty());
+        assert_eq!(handle.join().unwrap(), vec![7200, 7200, 0, 0]);
+    }
+
+    #[test]
+    fn pmp_fallback() {
+        // pcp请求、映射、外部地址、删除
+        let (gateway, handle) = fake_gateway(false, 4);
+        let natpmp = NatPmp::default();
+        natpmp.add_udp_port(5000);
+        natpmp
+            .reset_with_gateway(Ipv4Addr::LOCALHOST, gateway)
+            .unwrap();
+        assert_eq!(natpmp.protocol(), Some(GatewayProtocol::NatPmp));
+        assert_eq!(
+            natpmp.leases()[0].external,
+            SocketAddrV4::new(EXTERNAL_IP, 6000)
+        );
+        natpmp.release();
+        assert_eq!(handle.join().unwrap(), vec![7200, 0]);
+    }
+}
@@ -0,0 +1,181 @@
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::time::Duration;
+
+// nlmsghdr的长度，消息按4字节对齐
+const NLMSG_HDR_LEN: usize = 16;
+
+/// 订阅内核的地址、路由、网卡变化通知
+pub struct NetlinkWatcher {
+    fd: OwnedFd,
+}
+
+impl NetlinkWatcher {
+    pub fn new(read_timeout: Duration) -> io::Result<Self> {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+                libc::NETLINK_ROUTE,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        let mut addr: libc::sockaddr_nl = unsafe { std::mem::zeroed() };
+        addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        addr.nl_groups = (libc::RTMGRP_LINK
+            | libc::RTMGRP_IPV4_IFADDR
+            | libc::RTMGRP_IPV4_ROUTE
+            | libc::RTMGRP_IPV6_IFADDR
+            | libc::RTMGRP_IPV6_ROUTE) as u32;
+        let rs = unsafe {
+            libc::bind(
+                fd.as_raw_fd(),
+                &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+                std::mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+            )
+        };
+        if rs < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let timeout = libc::timeval {
+            tv_sec: read_timeout.as_secs() as libc::time_t,
+            tv_usec: read_timeout.subsec_micros() as libc::suseconds_t,
+        };
+        let rs = unsafe {
+            libc::setsockopt(
+                fd.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_RCVTIMEO,
+                &timeout as *const libc::timeval as *const libc::c_void,
+                std::mem::size_of::<libc::timeval>() as libc::socklen_t,
+            )
+        };
+        if rs < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self { fd })
+    }
+    /// 等待一批通知，把网卡、地址、路由变化所在的网卡序号追加到changes，
+    /// 不知道是哪个网卡时为None。返回是否有新的变化，超时返回Ok(false)
+    pub fn wait(&self, buf: &mut [u8], changes: &mut Vec<Option<u32>>) -> io::Result<bool> {
+        let len = unsafe {
+            libc::recv(
+                self.fd.as_raw_fd(),
+                buf.as_mut_ptr() as *mut libc::c_void,
+                buf.len(),
+                0,
+            )
+        };
+        if len < 0 {
+            let e = io::Error::last_os_error();
+            return match e.kind() {
+                io::ErrorKind::WouldBlock
+                | io::ErrorKind::TimedOut
+                | io::ErrorKind::Interrupted => Ok(false),
+                // 通知太多导致缓冲区溢出，丢了消息也当作有变化
+                _ if e.raw_os_error() == Some(libc::ENOBUFS) => {
+                    changes.push(None);
+                    Ok(true)
+                }
+                _ => Err(e),
+            };
+        }
+        let count = changes.len();
+        network_changes(&buf[..len as usize], changes);
+        Ok(changes.len() > count)
+    }
+}
+
+/// 解析netlink消息，找出网卡、地址、路由的增删
+fn network_changes(mut buf: &[u8], changes: &mut Vec<Option<u32>>) {
+    while buf.len() >= NLMSG_HDR_LEN {
+        let len = u32::from_ne_bytes(buf[..4].try_into().unwrap()) as usize;
+        let msg_type = u16::from_ne_bytes(buf[4..6].try_into().unwrap());
+        if len < NLMSG_HDR_LEN || len > buf.len() {
+            break;
+        }
+        let payload = &buf[NLMSG_HDR_LEN..len];
+        match msg_type {
+            // ifinfomsg和ifaddrmsg的网卡序号都在第4个字节
+            libc::RTM_NEWLINK | libc::RTM_DELLINK | libc::RTM_NEWADDR | libc::RTM_DELADDR => {
+                changes.push(read_u32(payload, 4));
+            }
+            libc::RTM_NEWROUTE | libc::RTM_DELROUTE => {
+                changes.push(route_oif(payload));
+            }
+            _ => {}
+        }
+        let aligned = (len + 3) & !3;
+        if aligned >= buf.len() {
+            break;
+        }
+        buf = &buf[aligned..];
+    }
+}
+
+/// 路由的出口网卡，在rtmsg后面的RTA_OIF属性中
+fn route_oif(payload: &[u8]) -> Option<u32> {
+    // rtmsg的长度
+    let mut attrs = payload.get(12..)?;
+    while attrs.len() >= 4 {
+        let len = u16::from_ne_bytes([attrs[0], attrs[1]]) as usize;
+        let typ = u16::from_ne_bytes([attrs[2], attrs[3]]);
+        if len < 4 || len > attrs.len() {
+            return None;
+        }
+        if typ == libc::RTA_OIF {
+            return read_u32(&attrs[..len], 4);
+        }
+        let aligned = (len + 3) & !3;
+        attrs = attrs.get(aligned..)?;
+    }
+    None
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> Option<u32> {
+    let value = buf.get(offset..offset + 4)?;
+    Some(u32::from_ne_bytes(value.try_into().unwrap()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(msg_type: u16, payload: &[u8]) -> Vec<u8> {
+        let len = (NLMSG_HDR_LEN + payload.len()) as u32;
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&len.to_ne_bytes());
+        buf.extend_from_slice(&msg_type.to_ne_bytes());
+        buf.resize(NLMSG_HDR_LEN, 0);
+        buf.extend_from_slice(payload);
+        buf.resize((buf.len() + 3) & !3, 0);
+        buf
+    }
+
+    #[test]
+    fn network_change() {
+        let mut changes = Vec::new();
+        network_changes(&message(libc::NLMSG_NOOP as u16, &[0; 5]), &mut changes);
+        assert!(changes.is_empty());
+        let mut addr = [0u8; 8];
+        addr[4..8].copy_from_slice(&3u32.to_ne_bytes());
+        let mut buf = message(libc::NLMSG_NOOP as u16, &[0; 5]);
+        buf.extend(message(libc::RTM_NEWADDR, &addr));
+        // rtmsg后面跟着RTA_OIF属性
+        let mut route = vec![0u8; 12];
+        route.extend_from_slice(&8u16.to_ne_bytes());
+        route.extend_from_slice(&libc::RTA_OIF.to_ne_bytes());
+        route.extend_from_slice(&7u32.to_ne_bytes());
+        buf.extend(message(libc::RTM_NEWROUTE, &route));
+        buf.extend(message(libc::RTM_DELROUTE, &[0; 12]));
+        network_changes(&buf, &mut changes);
+        assert_eq!(changes, vec![Some(3), Some(7), None]);
+        // 长度不完整的消息
+        changes.clear();
+        network_changes(&buf[..10], &mut changes);
+        assert!(changes.is_empty());
+    }
+}
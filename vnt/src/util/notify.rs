@@ -27,6 +27,29 @@ impl StopManager {
     {
         self.inner.add_listener(name, f)
     }
+    /// 停止时在单独的线程执行f，执行完之前vnt不算停止(用于删除映射、保存状态等清理工作)。
+    /// 监听器内不能释放自己的worker，所以worker放在槽里，由线程执行完后释放
+    pub fn add_stop_task<F>(&self, name: &str, f: F) -> anyhow::Result<()>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let slot: Arc<Mutex<Option<Worker>>> = Arc::new(Mutex::new(None));
+        let worker_slot = slot.clone();
+        let thread_name = name.to_string();
+        let worker = self.add_listener(name.into(), move || {
+            let rs = thread::Builder::new()
+                .name(thread_name.clone())
+                .spawn(move || {
+                    f();
+                    drop(worker_slot.lock().take());
+                });
+            if let Err(e) = rs {
+                log::warn!("{} {:?}", thread_name, e);
+            }
+        })?;
+        slot.lock().replace(worker);
+        Ok(())
+    }
     pub fn stop(&self) {
         self.inner.stop();
     }
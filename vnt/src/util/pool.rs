@@ -0,0 +1,273 @@
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use crossbeam_queue::ArrayQueue;
+
+/// 池中缓存的缓冲区数量上限
+const POOL_CAPACITY: usize = 1024;
+/// 单个缓冲区大小，能放下mtu大小的数据包和加密预留
+const POOL_BUF_SIZE: usize = 4096;
+
+/// 发送路径上使用的固定大小缓冲区池，用完自动归还，避免每个包都分配内存
+///
+/// tun和udp的收发在每个线程固定的缓冲区上原地加解密，不需要分配；
+/// 要交给其他线程或暂存的数据(tcp/ws发送、广播、重连暂存)都从这里取
+#[derive(Clone)]
+pub struct BufferPool {
+    inner: Arc<PoolInner>,
+}
+
+// 池中的缓冲区连同引用计数一起缓存，转成SharedBuf时不需要再分配
+type Slot = Arc<Vec<u8>>;
+
+struct PoolInner {
+    queue: ArrayQueue<Slot>,
+    buf_size: usize,
+    // 新分配的缓冲区数
+    alloc: AtomicU64,
+    // 从池中复用的次数
+    reuse: AtomicU64,
+    // 超过buf_size单独分配的次数
+    oversize: AtomicU64,
+    // 转交给其他库不再归还的次数
+    detach: AtomicU64,
+}
+
+/// 缓冲区池统计
+#[derive(Copy, Clone, Debug, Default)]
+pub struct PoolStats {
+    pub alloc: u64,
+    pub reuse: u64,
+    pub oversize: u64,
+    pub detach: u64,
+    pub idle: usize,
+}
+
+impl Default for BufferPool {
+    fn default() -> Self {
+        BufferPool::new(POOL_CAPACITY, POOL_BUF_SIZE)
+    }
+}
+
+impl BufferPool {
+    pub fn new(capacity: usize, buf_size: usize) -> Self {
+        Self {
+            inner: Arc::new(PoolInner {
+                queue: ArrayQueue::new(capacity.max(1)),
+                buf_size,
+                alloc: AtomicU64::new(0),
+                reuse: AtomicU64::new(0),
+                oversize: AtomicU64::new(0),
+                detach: AtomicU64::new(0),
+            }),
+        }
+    }
+    /// 获取一个长度为len的缓冲区，内容已清零
+    pub fn get(&self, len: usize) -> PooledBuf {
+        let mut slot = if len > self.inner.buf_size {
+            self.inner.oversize.fetch_add(1, Ordering::Relaxed);
+            Arc::new(Vec::with_capacity(len))
+        } else if let Some(slot) = self.inner.queue.pop() {
+            self.inner.reuse.fetch_add(1, Ordering::Relaxed);
+            slot
+        } else {
+            self.inner.alloc.fetch_add(1, Ordering::Relaxed);
+            Arc::new(Vec::with_capacity(self.inner.buf_size))
+        };
+        unique(&mut slot).resize(len, 0);
+        PooledBuf {
+            slot: Some(slot),
+            pool: self.clone(),
+        }
+    }
+    /// 拷贝数据到池中的缓冲区
+    pub fn copy_from(&self, data: &[u8]) -> PooledBuf {
+        let mut buf = self.get(data.len());
+        buf.copy_from_slice(data);
+        buf
+    }
+    pub fn stats(&self) -> PoolStats {
+        PoolStats {
+            alloc: self.inner.alloc.load(Ordering::Relaxed),
+            reuse: self.inner.reuse.load(Ordering::Relaxed),
+            oversize: self.inner.oversize.load(Ordering::Relaxed),
+            detach: self.inner.detach.load(Ordering::Relaxed),
+            idle: self.inner.queue.len(),
+        }
+    }
+    /// 只有最后一个持有者能归还，同时释放的极端情况下缓冲区会被丢弃，之后重新分配
+    fn release(&self, mut slot: Slot) {
+        let Some(buf) = Arc::get_mut(&mut slot) else {
+            return;
+        };
+        // 超大的缓冲区不回收，避免池占用过多内存
+        if buf.capacity() != self.inner.buf_size {
+            return;
+        }
+        buf.clear();
+        let _ = self.inner.queue.push(slot);
+    }
+}
+
+/// PooledBuf只持有唯一引用
+fn unique(slot: &mut Slot) -> &mut Vec<u8> {
+    Arc::get_mut(slot).expect("pooled buffer is shared")
+}
+
+/// 独占的池缓冲区，drop时归还
+pub struct PooledBuf {
+    slot: Option<Slot>,
+    pool: BufferPool,
+}
+
+impl PooledBuf {
+    /// 转成可共享的只读缓冲区，多处发送同一份数据时不需要拷贝
+    pub fn freeze(mut self) -> SharedBuf {
+        SharedBuf {
+            slot: self.slot.take(),
+            pool: self.pool.clone(),
+        }
+    }
+}
+
+impl Drop for PooledBuf {
+    fn drop(&mut self) {
+        if let Some(slot) = self.slot.take() {
+            self.pool.release(slot);
+        }
+    }
+}
+
+impl Deref for PooledBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        self.slot.as_deref().map_or(&[], |v| v)
+    }
+}
+
+impl DerefMut for PooledBuf {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        match self.slot.as_mut() {
+            Some(slot) => unique(slot),
+            None => &mut [],
+        }
+    }
+}
+
+impl AsRef<[u8]> for PooledBuf {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl AsMut<[u8]> for PooledBuf {
+    fn as_mut(&mut self) -> &mut [u8] {
+        self
+    }
+}
+
+/// 引用计数的只读缓冲区，最后一个引用释放时归还到池
+pub struct SharedBuf {
+    slot: Option<Slot>,
+    pool: BufferPool,
+}
+
+impl SharedBuf {
+    /// 取出数据交给需要独占Vec的库，只剩这一个引用时不拷贝，缓冲区不再归还到池
+    pub fn into_vec(mut self) -> Vec<u8> {
+        let Some(slot) = self.slot.take() else {
+            return Vec::new();
+        };
+        match Arc::try_unwrap(slot) {
+            Ok(buf) => {
+                self.pool.inner.detach.fetch_add(1, Ordering::Relaxed);
+                buf
+            }
+            Err(slot) => {
+                let buf = slot.to_vec();
+                self.pool.release(slot);
+                buf
+            }
+        }
+    }
+}
+
+impl Clone for SharedBuf {
+    fn clone(&self) -> Self {
+        Self {
+            slot: self.slot.clone(),
+            pool: self.pool.clone(),
+        }
+    }
+}
+
+impl Drop for SharedBuf {
+    fn drop(&mut self) {
+        if let Some(slot) = self.slot.take() {
+            self.pool.release(slot);
+        }
+    }
+}
+
+impl Deref for SharedBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        self.slot.as_deref().map_or(&[], |v| v)
+    }
+}
+
+impl AsRef<[u8]> for SharedBuf {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BufferPool;
+
+    #[test]
+    fn reuse() {
+        let pool = BufferPool::new(4, 64);
+        let buf = pool.copy_from(&[1, 2, 3]);
+        assert_eq!(&buf[..], &[1, 2, 3]);
+        let shared = buf.freeze();
+        let shared2 = shared.clone();
+        drop(shared);
+        assert_eq!(pool.stats().idle, 0);
+        drop(shared2);
+        assert_eq!(pool.stats().idle, 1);
+        let buf = pool.get(10);
+        assert_eq!(buf.len(), 10);
+        let stats = pool.stats();
+        assert_eq!(stats.alloc, 1);
+        assert_eq!(stats.reuse, 1);
+        drop(pool.get(100));
+        assert_eq!(pool.stats().oversize, 1);
+    }
+
+    #[test]
+    fn shared_no_alloc() {
+        let pool = BufferPool::new(4, 64);
+        // 第一轮分配，之后get、freeze、clone都复用池中的缓冲区和引用计数
+        drop(pool.copy_from(&[1]).freeze());
+        for i in 0..100u8 {
+            let shared = pool.copy_from(&[i]).freeze();
+            let shared2 = shared.clone();
+            assert_eq!(&shared2[..], &[i]);
+            drop(shared);
+            drop(shared2);
+        }
+        let stats = pool.stats();
+        assert_eq!(stats.alloc, 1);
+        assert_eq!(stats.reuse, 100);
+        // 取出独占的Vec时不拷贝，这个缓冲区不再归还
+        let buf = pool.copy_from(&[1, 2]).freeze().into_vec();
+        assert_eq!(buf, vec![1, 2]);
+        assert_eq!(pool.stats().detach, 1);
+        assert_eq!(pool.stats().idle, 0);
+    }
+}
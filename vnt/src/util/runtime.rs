@@ -0,0 +1,147 @@
+use std::future::Future;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use anyhow::Context;
+use parking_lot::Mutex;
+use tokio::runtime::{Handle, Runtime, RuntimeFlavor};
+use tokio::sync::watch;
+
+use crate::util::StopManager;
+
+/// 停止时等待任务退出的最长时间
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// 所有子系统(定时任务、ip代理、tcp/ws通道、端口映射)共用的异步运行时，
+/// 可以由vnt自己创建，也可以使用嵌入方传入的tokio handle
+#[derive(Clone)]
+pub struct VntRuntime {
+    handle: Handle,
+    shutdown: watch::Receiver<bool>,
+    inner: Arc<RuntimeInner>,
+}
+
+struct RuntimeInner {
+    shutdown_sender: watch::Sender<bool>,
+    // 自己创建的运行时，嵌入方传入handle时为None
+    runtime: Mutex<Option<Runtime>>,
+}
+
+impl VntRuntime {
+    /// 创建自有的运行时，worker_threads为0时使用默认线程数
+    pub fn new(worker_threads: usize) -> anyhow::Result<Self> {
+        let worker_threads = if worker_threads == 0 {
+            thread::available_parallelism()
+                .map(|v| v.get())
+                .unwrap_or(1)
+                .min(2)
+        } else {
+            worker_threads
+        };
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(worker_threads)
+            .max_blocking_threads(16)
+            .thread_name("vntRuntime")
+            .enable_all()
+            .build()
+            .context("vnt tokio runtime build failed")?;
+        Ok(Self::new0(runtime.handle().clone(), Some(runtime)))
+    }
+    /// 使用嵌入方的运行时，停止时只取消vnt自己的任务，不关闭运行时
+    pub fn from_handle(handle: Handle) -> Self {
+        Self::new0(handle, None)
+    }
+    fn new0(handle: Handle, runtime: Option<Runtime>) -> Self {
+        let (shutdown_sender, shutdown) = watch::channel(false);
+        Self {
+            handle,
+            shutdown,
+            inner: Arc::new(RuntimeInner {
+                shutdown_sender,
+                runtime: Mutex::new(runtime),
+            }),
+        }
+    }
+    /// 注册到停止管理器，vnt停止时取消所有任务，自有运行时会等待任务退出后关闭
+    pub(crate) fn bind_stop(&self, stop_manager: &StopManager) -> anyhow::Result<()> {
+        let (stop_sender, stop_receiver) = std::sync::mpsc::channel::<()>();
+        let worker = stop_manager.add_listener("vntRuntime".into(), move || {
+            let _ = stop_sender.send(());
+        })?;
+        let runtime = self.clone();
+        thread::Builder::new()
+            .name("vntRuntimeStop".into())
+            .spawn(move || {
+                let _ = stop_receiver.recv();
+                runtime.shutdown();
+                drop(worker);
+            })
+            .context("vnt runtime stop thread build failed")?;
+        Ok(())
+    }
+    pub fn handle(&self) -> &Handle {
+        &self.handle
+    }
+    pub fn is_shutdown(&self) -> bool {
+        *self.shutdown.borrow()
+    }
+    /// 在运行时上执行任务，vnt停止时任务会被取消
+    pub fn spawn<F>(&self, future: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let mut shutdown = self.shutdown.clone();
+        self.handle.spawn(async move {
+            tokio::select! {
+                _ = future => {}
+                _ = shutdown.wait_for(|v| *v) => {}
+            }
+        });
+    }
+    /// 执行会阻塞的同步任务
+    pub fn spawn_blocking<F>(&self, f: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.handle.spawn_blocking(f);
+    }
+    /// 同步等待异步任务完成，可以在多线程运行时内部的线程调用，
+    /// 在嵌入方的current_thread运行时内调用会死锁，直接返回错误
+    pub fn block_on<F>(&self, future: F) -> anyhow::Result<F::Output>
+    where
+        F: Future + Send,
+        F::Output: Send,
+    {
+        let Ok(current) = Handle::try_current() else {
+            return Ok(self.handle.block_on(future));
+        };
+        if current.runtime_flavor() == RuntimeFlavor::MultiThread {
+            // 运行时线程内不能直接block_on，先让出当前的工作线程
+            return Ok(tokio::task::block_in_place(|| self.handle.block_on(future)));
+        }
+        if self.inner.runtime.lock().is_none() {
+            // 任务需要嵌入方的运行时驱动，而它的唯一线程正阻塞在这里
+            return Err(anyhow::anyhow!(
+                "vnt cannot block inside a current_thread runtime, use a multi_thread runtime"
+            ));
+        }
+        // 自有运行时不依赖当前线程，换个线程等待即可
+        Ok(thread::scope(|s| {
+            s.spawn(|| self.handle.block_on(future))
+                .join()
+                .expect("block_on")
+        }))
+    }
+    /// 取消所有任务，自有运行时会被关闭
+    pub fn shutdown(&self) {
+        let _ = self.inner.shutdown_sender.send(true);
+        if let Some(runtime) = self.inner.runtime.lock().take() {
+            if Handle::try_current().is_ok() {
+                runtime.shutdown_background();
+            } else {
+                runtime.shutdown_timeout(SHUTDOWN_TIMEOUT);
+            }
+        }
+    }
+}
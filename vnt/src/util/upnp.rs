@@ -1,10 +1,24 @@
-use igd::{search_gateway, PortMappingProtocol};
-use std::net::{Ipv4Addr, SocketAddrV4};
+use igd::{search_gateway, Gateway, PortMappingProtocol, SearchOptions};
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, TcpStream};
 use std::ops::Deref;
 use std::sync::Arc;
+use std::time::Duration;
 
 use parking_lot::Mutex;
 
+/// 映射时长(秒)，定时续期
+const LEASE_DURATION: u32 = 700;
+/// 续期间隔，小于映射时长的一半
+pub const UPNP_RENEW_INTERVAL: Duration = Duration::from_secs(300);
+/// igd查找网关时接受的映射服务类型，查询映射时要使用网关实际提供的那个
+const WAN_CONNECTION_SERVICES: [&str; 3] = [
+    "urn:schemas-upnp-org:service:WANIPConnection:1",
+    "urn:schemas-upnp-org:service:WANIPConnection:2",
+    "urn:schemas-upnp-org:service:WANPPPConnection:1",
+];
+const IPV6_FIREWALL_SERVICE: &str = "urn:schemas-upnp-org:service:WANIPv6FirewallControl:1";
+
 #[derive(Clone, Default)]
 pub struct UPnP {
     inner: Arc<UpnpInner>,
@@ -18,9 +32,46 @@ impl Deref for UPnP {
     }
 }
 
+/// 一条映射或ipv6防火墙规则的状态
+#[derive(Clone, Debug)]
+pub struct UpnpMapping {
+    pub protocol: PortMappingProtocol,
+    pub port: u16,
+    /// ipv6防火墙规则
+    pub ipv6: bool,
+    /// 映射出的公网地址，ipv6为本机地址
+    pub external: Option<SocketAddr>,
+    /// 在网关的映射表中确认存在，查询失败时保留上一次的结果
+    pub verified: bool,
+    pub error: Option<String>,
+    // ipv6防火墙规则的id，续期和删除时使用
+    pinhole_id: Option<u16>,
+}
+
+impl UpnpMapping {
+    pub fn is_tcp(&self) -> bool {
+        self.protocol == PortMappingProtocol::TCP
+    }
+}
+
+#[derive(Clone, Default)]
+struct UpnpState {
+    local_ip: Option<Ipv4Addr>,
+    local_ipv6: Option<Ipv6Addr>,
+    gateway: Option<Gateway>,
+    // 网关映射服务的类型，查找时确定
+    service_type: Option<String>,
+    mappings: Vec<UpnpMapping>,
+    // 每次重新查找网关或删除映射后加一，续期结果只写回同一个网关的状态
+    generation: u64,
+}
+
 #[derive(Default)]
 pub struct UpnpInner {
     list: Mutex<Vec<(PortMappingProtocol, u16)>>,
+    state: Mutex<UpnpState>,
+    // 最近一次续期后的状态，查询时不用等待网络请求
+    status: Mutex<Vec<UpnpMapping>>,
 }
 
 impl UpnpInner {
@@ -30,52 +81,476 @@ impl UpnpInner {
     pub fn add_udp_port(&self, port: u16) {
         self.list.lock().push((PortMappingProtocol::UDP, port));
     }
-    pub fn reset(&self, local_ip: Ipv4Addr) {
-        let gateway = match search_gateway(Default::default()) {
+    /// 本地地址变化时重新查找网关并映射，否则只续期
+    pub fn reset(&self, local_ip: Ipv4Addr, local_ipv6: Option<Ipv6Addr>) {
+        {
+            let state = self.state.lock();
+            if state.gateway.is_some()
+                && state.local_ip == Some(local_ip)
+                && state.local_ipv6 == local_ipv6
+            {
+                drop(state);
+                self.renew();
+                return;
+            }
+        }
+        // 不支持upnp的情况会阻塞到超时
+        let gateway = match search_gateway(SearchOptions {
+            timeout: Some(Duration::from_secs(5)),
+            ..Default::default()
+        }) {
             Ok(gateway) => gateway,
             Err(e) => {
                 log::warn!("search_gateway {:?}", e);
                 return;
             }
         };
-        let guard = self.list.lock();
-
-        // 不支持upnp的情况会阻塞30秒，之后再改这个库
-        for (protocol, port) in guard.iter() {
-            let local_addr = SocketAddrV4::new(local_ip, *port);
-            log::info!("add upnp protocol={} {}", protocol, local_addr);
-            if let Err(e) = gateway.add_port(*protocol, *port, local_addr, 700, "upnp") {
-                log::warn!(
-                    "add upnp failed protocol={},port={} err:{:?}",
-                    protocol,
-                    port,
-                    e
-                );
+        let service_type = wan_service_type(&gateway)
+            .map_err(|e| log::warn!("upnp service type {:?}", e))
+            .ok()
+            .flatten();
+        let mut state = self.state.lock();
+        state.local_ip = Some(local_ip);
+        state.local_ipv6 = local_ipv6;
+        state.gateway = Some(gateway);
+        state.service_type = service_type;
+        state.generation += 1;
+        state.mappings = self
+            .list
+            .lock()
+            .iter()
+            .map(|(protocol, port)| UpnpMapping {
+                protocol: *protocol,
+                port: *port,
+                ipv6: false,
+                external: None,
+                verified: false,
+                error: None,
+                pinhole_id: None,
+            })
+            .collect();
+        if local_ipv6.is_some() {
+            let pinholes: Vec<UpnpMapping> = state
+                .mappings
+                .iter()
+                .map(|v| UpnpMapping {
+                    ipv6: true,
+                    ..v.clone()
+                })
+                .collect();
+            state.mappings.extend(pinholes);
+        }
+        drop(state);
+        self.renew();
+    }
+    /// 续期所有映射并确认映射存在，网络请求期间不持有锁
+    pub fn renew(&self) {
+        let mut state = self.state.lock().clone();
+        let (Some(gateway), Some(local_ip)) = (state.gateway.as_ref(), state.local_ip) else {
+            return;
+        };
+        let external_ip = gateway.get_external_ip().ok();
+        // 没有ipv6防火墙服务时不再尝试
+        let firewall_url = if state.local_ipv6.is_some() {
+            ipv6_firewall_control_url(gateway)
+                .map_err(|e| log::warn!("upnp ipv6 firewall {:?}", e))
+                .ok()
+                .flatten()
+        } else {
+            None
+        };
+        for mapping in state.mappings.iter_mut() {
+            if mapping.ipv6 {
+                let Some(local_ipv6) = state.local_ipv6 else {
+                    continue;
+                };
+                let Some(control_url) = &firewall_url else {
+                    mapping.error = Some("no WANIPv6FirewallControl".into());
+                    continue;
+                };
+                let rs = match mapping.pinhole_id {
+                    Some(id) => update_pinhole(gateway.addr, control_url, id).map(|_| id),
+                    None => add_pinhole(gateway.addr, control_url, local_ipv6, mapping),
+                };
+                match rs {
+                    Ok(id) => {
+                        mapping.pinhole_id = Some(id);
+                        mapping.external = Some(SocketAddr::new(local_ipv6.into(), mapping.port));
+                        mapping.verified = true;
+                        mapping.error = None;
+                    }
+                    Err(e) => {
+                        log::warn!("upnp pinhole {} {} {:?}", mapping.protocol, mapping.port, e);
+                        // 规则可能已经过期，下次重新添加
+                        mapping.pinhole_id = None;
+                        mapping.verified = false;
+                        mapping.error = Some(e.to_string());
+                    }
+                }
+                continue;
+            }
+            let local_addr = SocketAddrV4::new(local_ip, mapping.port);
+            // 重复添加同一个映射会刷新租期
+            match gateway.add_port(
+                mapping.protocol,
+                mapping.port,
+                local_addr,
+                LEASE_DURATION,
+                "vnt",
+            ) {
+                Ok(_) => {
+                    mapping.error = None;
+                    mapping.external =
+                        external_ip.map(|ip| SocketAddr::V4(SocketAddrV4::new(ip, mapping.port)));
+                }
+                Err(e) => {
+                    log::warn!(
+                        "add upnp failed protocol={},port={} err:{:?}",
+                        mapping.protocol,
+                        mapping.port,
+                        e
+                    );
+                    mapping.error = Some(e.to_string());
+                    mapping.external = None;
+                }
             }
         }
+        verify(
+            gateway,
+            state.service_type.as_deref(),
+            local_ip,
+            &mut state.mappings,
+        );
+        let mut guard = self.state.lock();
+        if guard.generation != state.generation {
+            // 期间重新查找了网关或者已经删除了映射
+            return;
+        }
+        guard.mappings = state.mappings;
+        *self.status.lock() = guard.mappings.clone();
+    }
+    /// 删除映射和ipv6防火墙规则
+    pub fn release(&self) {
+        let mut state = {
+            let mut guard = self.state.lock();
+            guard.generation += 1;
+            guard.local_ip = None;
+            UpnpState {
+                gateway: guard.gateway.take(),
+                mappings: std::mem::take(&mut guard.mappings),
+                ..Default::default()
+            }
+        };
+        self.status.lock().clear();
+        let Some(gateway) = state.gateway.take() else {
+            return;
+        };
+        let firewall_url = if state.mappings.iter().any(|v| v.pinhole_id.is_some()) {
+            ipv6_firewall_control_url(&gateway).ok().flatten()
+        } else {
+            None
+        };
+        for mapping in state.mappings.drain(..) {
+            if mapping.ipv6 {
+                if let (Some(id), Some(control_url)) = (mapping.pinhole_id, &firewall_url) {
+                    let rs = delete_pinhole(gateway.addr, control_url, id);
+                    log::info!("upnp delete pinhole {} {:?}", id, rs);
+                }
+            } else if mapping.external.is_some() {
+                let rs = gateway.remove_port(mapping.protocol, mapping.port);
+                log::info!("upnp remove {} {} {:?}", mapping.protocol, mapping.port, rs);
+            }
+        }
+    }
+    pub fn mappings(&self) -> Vec<UpnpMapping> {
+        self.status.lock().clone()
+    }
+}
+
+/// 逐条查询映射，确认指向本机。网关不支持查询或者网络错误时无法确定，保留上一次的结果
+fn verify(
+    gateway: &Gateway,
+    service_type: Option<&str>,
+    local_ip: Ipv4Addr,
+    mappings: &mut [UpnpMapping],
+) {
+    for mapping in mappings.iter_mut().filter(|v| !v.ipv6) {
+        if mapping.error.is_some() {
+            mapping.verified = false;
+            continue;
+        }
+        let Some(service_type) = service_type else {
+            continue;
+        };
+        match get_specific_entry(gateway, service_type, mapping) {
+            Ok(Some((client, port))) => {
+                mapping.verified = client == local_ip.to_string() && port == mapping.port;
+                if !mapping.verified {
+                    mapping.error = Some(format!("mapped to {}:{}", client, port));
+                }
+            }
+            Ok(None) => {
+                mapping.verified = false;
+                mapping.error = Some("mapping not found".into());
+            }
+            Err(e) => {
+                log::info!("upnp verify {} {} {:?}", mapping.protocol, mapping.port, e);
+            }
+        }
+    }
+}
+
+/// 查询指定外部端口的映射，返回内部地址和端口，None表示网关确认映射不存在
+fn get_specific_entry(
+    gateway: &Gateway,
+    service_type: &str,
+    mapping: &UpnpMapping,
+) -> io::Result<Option<(String, u16)>> {
+    let args = format!(
+        "<NewRemoteHost></NewRemoteHost><NewExternalPort>{}</NewExternalPort>\
+         <NewProtocol>{}</NewProtocol>",
+        mapping.port, mapping.protocol
+    );
+    let action = "GetSpecificPortMappingEntry";
+    let response = soap_request(
+        gateway.addr,
+        &gateway.control_url,
+        service_type,
+        action,
+        &args,
+    )?;
+    if !is_http_ok(&response) {
+        // 714 NoSuchEntryInArray，其他错误无法确定映射是否存在
+        if xml_value(&response, "errorCode") == Some("714") {
+            return Ok(None);
+        }
+        return Err(soap_error(action, &response));
+    }
+    let client = xml_value(&response, "NewInternalClient");
+    let port = xml_value(&response, "NewInternalPort").and_then(|v| v.parse().ok());
+    match (client, port) {
+        (Some(client), Some(port)) => Ok(Some((client.to_string(), port))),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} invalid response", action),
+        )),
+    }
+}
+
+/// 网关的设备描述
+fn description(gateway: &Gateway) -> io::Result<String> {
+    http_request(
+        gateway.addr,
+        format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+            gateway.root_url, gateway.addr
+        )
+        .as_bytes(),
+    )
+}
+
+/// 从网关的设备描述中查找IGDv2 ipv6防火墙服务的控制地址
+fn ipv6_firewall_control_url(gateway: &Gateway) -> io::Result<Option<String>> {
+    Ok(control_url(&description(gateway)?, IPV6_FIREWALL_SERVICE))
+}
+
+/// igd找到的映射服务的类型，按控制地址在设备描述中匹配
+fn wan_service_type(gateway: &Gateway) -> io::Result<Option<String>> {
+    let description = description(gateway)?;
+    Ok(service_type(&description, &gateway.control_url))
+}
+
+fn service_type(description: &str, control_url: &str) -> Option<String> {
+    let path = url_path(control_url);
+    WAN_CONNECTION_SERVICES
+        .iter()
+        .find(|v| self::control_url(description, v).as_deref() == Some(path.as_str()))
+        .map(|v| v.to_string())
+}
+
+fn control_url(description: &str, service_type: &str) -> Option<String> {
+    let start = description.find(&format!("<serviceType>{}</serviceType>", service_type))?;
+    let service = &description[start..];
+    let service = &service[..service.find("</service>").unwrap_or(service.len())];
+    Some(url_path(xml_value(service, "controlURL")?))
+}
+
+/// 可能是完整的url，只保留路径
+fn url_path(url: &str) -> String {
+    if let Some(url) = url.strip_prefix("http://") {
+        return url.find('/').map_or("/".into(), |i| url[i..].to_string());
     }
+    if url.starts_with('/') {
+        url.to_string()
+    } else {
+        format!("/{}", url)
+    }
+}
+
+fn xml_value<'a>(xml: &'a str, tag: &str) -> Option<&'a str> {
+    let start = xml.find(&format!("<{}>", tag))? + tag.len() + 2;
+    let end = start + xml[start..].find(&format!("</{}>", tag))?;
+    Some(xml[start..end].trim())
 }
 
-impl Drop for UpnpInner {
-    fn drop(&mut self) {
-        // let gateway = match search_gateway(Default::default()) {
-        //     Ok(gateway) => gateway,
-        //     Err(e) => {
-        //         log::warn!("search_gateway {:?}", e);
-        //         return;
-        //     }
-        // };
-        //
-        // let guard = self.list.lock();
-        // for (protocol, port) in guard.iter() {
-        //     if let Err(e) = gateway.remove_port(*protocol, *port) {
-        //         log::warn!(
-        //             "remove upnp failed protocol={},port={} err:{:?}",
-        //             protocol,
-        //             port,
-        //             e
-        //         );
-        //     }
-        // }
+fn ip_protocol(protocol: PortMappingProtocol) -> u8 {
+    match protocol {
+        PortMappingProtocol::TCP => 6,
+        PortMappingProtocol::UDP => 17,
+    }
+}
+
+fn add_pinhole(
+    addr: SocketAddrV4,
+    control_url: &str,
+    local_ipv6: Ipv6Addr,
+    mapping: &UpnpMapping,
+) -> io::Result<u16> {
+    // 远端地址为空表示允许任意来源
+    let args = format!(
+        "<RemoteHost></RemoteHost><RemotePort>0</RemotePort>\
+         <InternalClient>{}</InternalClient><InternalPort>{}</InternalPort>\
+         <Protocol>{}</Protocol><LeaseTime>{}</LeaseTime>",
+        local_ipv6,
+        mapping.port,
+        ip_protocol(mapping.protocol),
+        LEASE_DURATION
+    );
+    let response = soap_call(
+        addr,
+        control_url,
+        IPV6_FIREWALL_SERVICE,
+        "AddPinhole",
+        &args,
+    )?;
+    xml_value(&response, "UniqueID")
+        .and_then(|v| v.parse().ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "AddPinhole no UniqueID"))
+}
+
+fn update_pinhole(addr: SocketAddrV4, control_url: &str, id: u16) -> io::Result<()> {
+    let args = format!(
+        "<UniqueID>{}</UniqueID><NewLeaseTime>{}</NewLeaseTime>",
+        id, LEASE_DURATION
+    );
+    soap_call(
+        addr,
+        control_url,
+        IPV6_FIREWALL_SERVICE,
+        "UpdatePinhole",
+        &args,
+    )
+    .map(|_| ())
+}
+
+fn delete_pinhole(addr: SocketAddrV4, control_url: &str, id: u16) -> io::Result<()> {
+    let args = format!("<UniqueID>{}</UniqueID>", id);
+    soap_call(
+        addr,
+        control_url,
+        IPV6_FIREWALL_SERVICE,
+        "DeletePinhole",
+        &args,
+    )
+    .map(|_| ())
+}
+
+fn soap_call(
+    addr: SocketAddrV4,
+    control_url: &str,
+    service: &str,
+    action: &str,
+    args: &str,
+) -> io::Result<String> {
+    let response = soap_request(addr, control_url, service, action, args)?;
+    if !is_http_ok(&response) {
+        return Err(soap_error(action, &response));
+    }
+    Ok(response)
+}
+
+fn soap_request(
+    addr: SocketAddrV4,
+    control_url: &str,
+    service: &str,
+    action: &str,
+    args: &str,
+) -> io::Result<String> {
+    let body = format!(
+        "<?xml version=\"1.0\"?>\
+         <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+         s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">\
+         <s:Body><u:{action} xmlns:u=\"{service}\">{args}</u:{action}></s:Body></s:Envelope>",
+        action = action,
+        service = service,
+        args = args
+    );
+    let request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: text/xml; charset=\"utf-8\"\r\n\
+         SOAPAction: \"{}#{}\"\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        control_url,
+        addr,
+        service,
+        action,
+        body.len(),
+        body
+    );
+    http_request(addr, request.as_bytes())
+}
+
+fn is_http_ok(response: &str) -> bool {
+    response.starts_with("HTTP/1.1 200") || response.starts_with("HTTP/1.0 200")
+}
+
+fn soap_error(action: &str, response: &str) -> io::Error {
+    let code = xml_value(response, "errorCode").unwrap_or("");
+    let desc = xml_value(response, "errorDescription").unwrap_or("");
+    io::Error::new(
+        io::ErrorKind::Other,
+        format!("{} failed {} {}", action, code, desc),
+    )
+}
+
+fn http_request(addr: SocketAddrV4, request: &[u8]) -> io::Result<String> {
+    let timeout = Duration::from_secs(3);
+    let mut stream = TcpStream::connect_timeout(&addr.into(), timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    stream.write_all(request)?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)?;
+    Ok(String::from_utf8_lossy(&response).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn firewall_control_url() {
+        let description = "<root><device><serviceList>\
+            <service><serviceType>urn:schemas-upnp-org:service:WANIPConnection:2</serviceType>\
+            <controlURL>/ctl/IPConn</controlURL></service>\
+            <service><serviceType>urn:schemas-upnp-org:service:WANIPv6FirewallControl:1</serviceType>\
+            <controlURL>http://192.168.1.1:5000/ctl/IP6FCtl</controlURL></service>\
+            </serviceList></device></root>";
+        assert_eq!(
+            control_url(description, IPV6_FIREWALL_SERVICE).as_deref(),
+            Some("/ctl/IP6FCtl")
+        );
+        assert_eq!(
+            control_url(
+                description,
+                "urn:schemas-upnp-org:service:WANIPConnection:2"
+            )
+            .as_deref(),
+            Some("/ctl/IPConn")
+        );
+        assert!(control_url(description, "urn:x").is_none());
+        assert_eq!(
+            service_type(description, "ctl/IPConn").as_deref(),
+            Some("urn:schemas-upnp-org:service:WANIPConnection:2")
+        );
+        assert!(service_type(description, "/ctl/PPPConn").is_none());
     }
 }